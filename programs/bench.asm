@@ -0,0 +1,65 @@
+; Count the primes below 30000 by trial division. Compute-bound, for
+; comparing the interpreter with the JIT (`bench bench.bin`).
+;
+; Memory: n at 0, divisor at 8, count at 16
+    push 16
+    push 0
+    store64
+    push 0
+    push 2
+    store64
+next_n:
+    push 0
+    load64
+    push 30000
+    lt
+    jz done
+    push 8
+    push 2
+    store64
+next_divisor:
+    ; Prime once divisor * divisor > n
+    push 8
+    load64
+    dup
+    mul
+    push 0
+    load64
+    gt
+    jnz prime
+    push 0
+    load64
+    push 8
+    load64
+    mod
+    jz composite
+    push 8
+    push 8
+    load64
+    push 1
+    add
+    store64
+    jmp next_divisor
+prime:
+    push 16
+    push 16
+    load64
+    push 1
+    add
+    store64
+composite:
+    push 0
+    push 0
+    load64
+    push 1
+    add
+    store64
+    jmp next_n
+done:
+    print_string "Primes below 30000: "
+    push 16
+    load64
+    print_int
+    syscall newline
+    halt
+.memory 24
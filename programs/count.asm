@@ -0,0 +1,12 @@
+; Print ten stars
+    print_string "Counting: "
+    push 10
+loop:
+    print_char '*'
+    push 1
+    sub
+    dup
+    jnz loop
+    pop
+    syscall newline
+    halt
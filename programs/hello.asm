@@ -0,0 +1,4 @@
+; Print a greeting and exit
+    print_string "Hello, World!"
+    syscall newline
+    halt
@@ -94,8 +94,8 @@ fn align_up(addr: usize, align: usize) -> usize {
 // This is a simple approach that doesn't require page table manipulation
 static mut HEAP_MEMORY: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
 
-// Heap size: 100 KB
-pub const HEAP_SIZE: usize = 100 * 1024;
+// Heap size: 1 MB, enough to hold a loaded native executable image
+pub const HEAP_SIZE: usize = 1024 * 1024;
 
 #[global_allocator]
 static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
@@ -0,0 +1,500 @@
+//! Bytecode assembler and disassembler
+//!
+//! Source files have one statement per line; `;` starts a comment.
+//!
+//! ```text
+//! .entry main            ; start at a label (default: start of code)
+//! greet:
+//!     print_string "Hello\n"
+//!     ret
+//! main:
+//!     call greet
+//!     push 'A'           ; numbers may be decimal, 0x hex or 'c'
+//!     print_int
+//!     syscall putc '!'   ; services by name or number
+//!     halt
+//! .rodata                ; switch section: .code, .data, .rodata
+//!     .byte 1, 2, 0x41
+//!     .ascii "raw text"
+//!     .asciz "zero terminated"
+//! ```
+//!
+//! Jump and call operands are labels or absolute code offsets. `push` also
+//! takes a label, pushing its offset within its section; since data memory
+//! starts with the data section, that is the address of a `.data` label.
+//! `.memory <size>` sets the data memory size, which defaults to the size
+//! of the data section.
+use crate::bytecode::{Program, MAX_MEMORY};
+use crate::isa::{decode, Decoded, Instruction, Operand, Syscall};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Why a source file failed to assemble, and where
+#[derive(Debug, Clone, Copy)]
+pub struct AsmError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Code,
+    Data,
+    Rodata,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Number(i64),
+    Str(Vec<u8>),
+    Comma,
+    Colon,
+}
+
+/// A parsed statement waiting for label addresses to be known
+enum Item<'a> {
+    Instruction(Instruction, Vec<Token<'a>>),
+    Bytes(Vec<u8>),
+}
+
+struct Statement<'a> {
+    line: usize,
+    section: Section,
+    offset: usize,
+    item: Item<'a>,
+}
+
+/// Where `.entry` puts the entry point
+enum Entry<'a> {
+    /// A code label, and the line naming it
+    Label(&'a str, usize),
+    /// An absolute code offset
+    Offset(usize),
+}
+
+/// Assemble source text into a program
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    let mut statements = Vec::new();
+    let mut labels: BTreeMap<&str, (Section, usize)> = BTreeMap::new();
+    let mut sizes = [0usize; 3];
+    let mut section = Section::Code;
+    let mut entry = None;
+    let mut memory = None;
+
+    // Pass 1: parse statements and assign every label an offset
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let err = |reason| AsmError { line, reason };
+        let mut tokens = tokenize(text).map_err(err)?;
+
+        while let [Token::Ident(name), Token::Colon, ..] = tokens.as_slice() {
+            if labels.insert(name, (section, sizes[section as usize])).is_some() {
+                return Err(err("Duplicate label"));
+            }
+            tokens.drain(..2);
+        }
+
+        let (name, args) = match tokens.split_first() {
+            None => continue,
+            Some((Token::Ident(name), args)) => (*name, args),
+            Some(_) => return Err(err("Expected an instruction or directive")),
+        };
+
+        let item = match name {
+            ".code" | ".data" | ".rodata" => {
+                if !args.is_empty() {
+                    return Err(err("Unexpected operand"));
+                }
+                section = match name {
+                    ".code" => Section::Code,
+                    ".data" => Section::Data,
+                    _ => Section::Rodata,
+                };
+                continue;
+            }
+            ".entry" => {
+                match args {
+                    [Token::Ident(label)] => entry = Some(Entry::Label(label, line)),
+                    [Token::Number(offset)] if *offset >= 0 => entry = Some(Entry::Offset(*offset as usize)),
+                    _ => return Err(err("Expected a label")),
+                }
+                continue;
+            }
+            ".memory" => {
+                match args {
+                    [Token::Number(size)] if (0..=MAX_MEMORY as i64).contains(size) => memory = Some(*size as usize),
+                    [Token::Number(_)] => return Err(err("Data memory too large")),
+                    _ => return Err(err("Expected a size")),
+                }
+                continue;
+            }
+            ".byte" => Item::Bytes(byte_list(args).map_err(err)?),
+            ".ascii" | ".asciz" => match args {
+                [Token::Str(bytes)] => {
+                    let mut bytes = bytes.clone();
+                    if name == ".asciz" {
+                        bytes.push(0);
+                    }
+                    Item::Bytes(bytes)
+                }
+                _ => return Err(err("Expected a string")),
+            },
+            _ => {
+                let instruction = Instruction::from_mnemonic(name).ok_or(err("Unknown instruction"))?;
+                Item::Instruction(instruction, args.to_vec())
+            }
+        };
+
+        let size = match &item {
+            Item::Bytes(bytes) => bytes.len(),
+            Item::Instruction(instruction, args) => encoded_len(*instruction, args).map_err(err)?,
+        };
+        statements.push(Statement { line, section, offset: sizes[section as usize], item });
+        sizes[section as usize] += size;
+    }
+
+    // Pass 2: encode with label addresses resolved
+    let mut program = Program { entry: 0, code: Vec::new(), data: Vec::new(), rodata: Vec::new(), memory: 0 };
+    for statement in &statements {
+        let err = |reason| AsmError { line: statement.line, reason };
+        let out = match statement.section {
+            Section::Code => &mut program.code,
+            Section::Data => &mut program.data,
+            Section::Rodata => &mut program.rodata,
+        };
+        match &statement.item {
+            Item::Bytes(bytes) => out.extend_from_slice(bytes),
+            Item::Instruction(instruction, args) => {
+                encode(out, *instruction, args, statement.offset, &labels).map_err(err)?;
+            }
+        }
+    }
+
+    program.entry = match entry {
+        None => 0,
+        Some(Entry::Offset(offset)) => offset,
+        Some(Entry::Label(label, line)) => match labels.get(label) {
+            Some(&(Section::Code, offset)) => offset,
+            Some(_) => return Err(AsmError { line, reason: "Entry label is not in code" }),
+            None => return Err(AsmError { line, reason: "Unknown label" }),
+        },
+    };
+    let line = source.lines().count().max(1);
+    if program.entry >= program.code.len() {
+        return Err(AsmError { line, reason: "Entry point outside of code" });
+    }
+    program.memory = memory.unwrap_or(program.data.len());
+    if program.memory < program.data.len() {
+        return Err(AsmError { line, reason: "Data section larger than data memory" });
+    }
+    Ok(program)
+}
+
+/// Size of an instruction once encoded
+fn encoded_len(instruction: Instruction, args: &[Token]) -> Result<usize, &'static str> {
+    let len = match instruction {
+        Instruction::PrintChar => 2,
+        Instruction::PrintString => match args {
+            [Token::Str(bytes)] => bytes.len() + 2,
+            _ => return Err("Expected a string"),
+        },
+        Instruction::Push => 5,
+        Instruction::Jmp | Instruction::Jz | Instruction::Jnz | Instruction::Call => 3,
+        Instruction::Syscall => match syscall(args)? {
+            (Syscall::PutChar, _) => 3,
+            _ => 2,
+        },
+        _ => 1,
+    };
+    Ok(len)
+}
+
+fn encode(
+    out: &mut Vec<u8>,
+    instruction: Instruction,
+    args: &[Token],
+    offset: usize,
+    labels: &BTreeMap<&str, (Section, usize)>,
+) -> Result<(), &'static str> {
+    out.push(instruction as u8);
+    match instruction {
+        Instruction::PrintChar => out.push(byte(args)?),
+        Instruction::PrintString => match args {
+            [Token::Str(bytes)] => {
+                if bytes.contains(&0) {
+                    return Err("String contains a zero byte");
+                }
+                out.extend_from_slice(bytes);
+                out.push(0);
+            }
+            _ => return Err("Expected a string"),
+        },
+        Instruction::Push => {
+            let value = match args {
+                [Token::Number(n)] => *n,
+                [Token::Ident(label)] => labels.get(label).ok_or("Unknown label")?.1 as i64,
+                _ => return Err("Expected a number"),
+            };
+            let value = i32::try_from(value).map_err(|_| "Immediate out of range")?;
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        Instruction::Jmp | Instruction::Jz | Instruction::Jnz | Instruction::Call => {
+            let target = match args {
+                [Token::Ident(label)] => match labels.get(label) {
+                    Some(&(Section::Code, target)) => target,
+                    Some(_) => return Err("Jump to a label outside of code"),
+                    None => return Err("Unknown label"),
+                },
+                [Token::Number(target)] if *target >= 0 => *target as usize,
+                _ => return Err("Expected a label"),
+            };
+            let rel = target as isize - (offset + 3) as isize;
+            let rel = i16::try_from(rel).map_err(|_| "Jump out of range")?;
+            out.extend_from_slice(&rel.to_le_bytes());
+        }
+        Instruction::Syscall => {
+            let (call, rest) = syscall(args)?;
+            out.push(call as u8);
+            match call {
+                Syscall::PutChar => out.push(byte(rest)?),
+                _ if !rest.is_empty() => return Err("Unexpected operand"),
+                _ => {}
+            }
+        }
+        _ => {
+            if !args.is_empty() {
+                return Err("Unexpected operand");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The service named by a `syscall` operand, and the operands after it
+fn syscall<'t, 'a>(args: &'t [Token<'a>]) -> Result<(Syscall, &'t [Token<'a>]), &'static str> {
+    let call = match args.first() {
+        Some(Token::Ident(name)) => Syscall::from_name(name),
+        Some(Token::Number(n)) => u8::try_from(*n).ok().and_then(Syscall::from_byte),
+        _ => None,
+    };
+    Ok((call.ok_or("Unknown syscall")?, &args[1..]))
+}
+
+/// A single byte operand
+fn byte(args: &[Token]) -> Result<u8, &'static str> {
+    match args {
+        [Token::Number(n)] => u8::try_from(*n).map_err(|_| "Byte out of range"),
+        _ => Err("Expected a byte"),
+    }
+}
+
+/// Comma-separated byte values for `.byte`
+fn byte_list(args: &[Token]) -> Result<Vec<u8>, &'static str> {
+    args.split(|token| *token == Token::Comma)
+        .map(|item| match item {
+            [Token::Number(n)] => u8::try_from(*n).map_err(|_| "Byte out of range"),
+            _ => Err("Expected a byte list"),
+        })
+        .collect()
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token<'_>>, &'static str> {
+    let bytes = line.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        match c {
+            b';' => break,
+            b' ' | b'\t' | b'\r' => i += 1,
+            b',' => {
+                tokens.push(Token::Comma);
+                i += 1;
+            }
+            b':' => {
+                tokens.push(Token::Colon);
+                i += 1;
+            }
+            b'"' => {
+                let mut value = Vec::new();
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        None => return Err("Unterminated string"),
+                        Some(b'"') => break,
+                        Some(_) => {
+                            let (byte, len) = unescape(&bytes[i..])?;
+                            value.push(byte);
+                            i += len;
+                        }
+                    }
+                }
+                tokens.push(Token::Str(value));
+                i += 1;
+            }
+            b'\'' => {
+                let (byte, len) = unescape(bytes.get(i + 1..).unwrap_or(&[]))?;
+                if bytes.get(i + 1 + len) != Some(&b'\'') {
+                    return Err("Unterminated character literal");
+                }
+                tokens.push(Token::Number(byte as i64));
+                i += len + 2;
+            }
+            b'-' | b'0'..=b'9' => {
+                let start = i;
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                tokens.push(Token::Number(parse_number(&line[start..i])?));
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' || c == b'.' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || bytes[i] == b'.') {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(&line[start..i]));
+            }
+            _ => return Err("Unexpected character"),
+        }
+    }
+    Ok(tokens)
+}
+
+/// A decimal or `0x` hex number, optionally negative
+pub fn parse_number(text: &str) -> Result<i64, &'static str> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>(),
+    };
+    let value = value.map_err(|_| "Invalid number")?;
+    Ok(if negative { -value } else { value })
+}
+
+/// Decode one possibly escaped character, returning it and its length
+fn unescape(bytes: &[u8]) -> Result<(u8, usize), &'static str> {
+    match bytes {
+        [b'\\', b'n', ..] => Ok((b'\n', 2)),
+        [b'\\', b't', ..] => Ok((b'\t', 2)),
+        [b'\\', b'0', ..] => Ok((0, 2)),
+        [b'\\', b'\\', ..] => Ok((b'\\', 2)),
+        [b'\\', b'"', ..] => Ok((b'"', 2)),
+        [b'\\', b'\'', ..] => Ok((b'\'', 2)),
+        [b'\\', b'x', hi, lo, ..] => {
+            let hex = [*hi, *lo];
+            let text = core::str::from_utf8(&hex).map_err(|_| "Invalid escape")?;
+            let value = u8::from_str_radix(text, 16).map_err(|_| "Invalid escape")?;
+            Ok((value, 4))
+        }
+        [b'\\', ..] => Err("Invalid escape"),
+        [c, ..] => Ok((*c, 1)),
+        [] => Err("Unterminated literal"),
+    }
+}
+
+/// Render bytes as a quoted, escaped string literal
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Render a decoded instruction at `offset`, with jump targets as
+/// absolute offsets
+pub fn format_instruction(code: &[u8], offset: usize, decoded: &Decoded) -> String {
+    let name = decoded.instruction.mnemonic();
+    match decoded.operand {
+        Operand::None => String::from(name),
+        Operand::Byte(b) => format!("{} {}", name, char_literal(b)),
+        Operand::Str { start, len } => format!("{} {}", name, quote(&code[start..start + len])),
+        Operand::Imm(value) => format!("{} {}", name, value),
+        Operand::Offset(_) => format!("{} {:#06x}", name, decoded.target(offset).unwrap_or(0)),
+        Operand::Syscall { call, arg: Some(arg) } => format!("{} {} {}", name, call.name(), char_literal(arg)),
+        Operand::Syscall { call, arg: None } => format!("{} {}", name, call.name()),
+    }
+}
+
+fn char_literal(byte: u8) -> String {
+    match byte {
+        b'\'' | b'\\' => format!("'\\{}'", byte as char),
+        0x20..=0x7e => format!("'{}'", byte as char),
+        _ => format!("{:#04x}", byte),
+    }
+}
+
+/// Produce a listing of a program: one line per instruction with its
+/// offset, followed by the data sections as `.byte` rows
+pub fn disassemble(program: &Program) -> Vec<String> {
+    let mut lines = Vec::new();
+    lines.push(format!(
+        "; entry {:#06x}, code {} bytes, data {} bytes, rodata {} bytes, memory {} bytes",
+        program.entry,
+        program.code.len(),
+        program.data.len(),
+        program.rodata.len(),
+        program.memory
+    ));
+
+    let mut offset = 0;
+    while offset < program.code.len() {
+        match decode(&program.code, offset) {
+            Ok(decoded) => {
+                let marker = if offset == program.entry { ">" } else { " " };
+                let text = format_instruction(&program.code, offset, &decoded);
+                lines.push(format!("{:04x}{} {}", offset, marker, text));
+                offset += decoded.len;
+            }
+            Err(reason) => {
+                lines.push(format!("{:04x}  ; {}", offset, reason));
+                lines.extend(byte_rows(&program.code[offset..], offset));
+                break;
+            }
+        }
+    }
+
+    for (name, bytes) in [(".data", &program.data), (".rodata", &program.rodata)] {
+        if !bytes.is_empty() {
+            lines.push(String::from(name));
+            lines.extend(byte_rows(bytes, 0));
+        }
+    }
+    lines
+}
+
+fn byte_rows(bytes: &[u8], base: usize) -> Vec<String> {
+    bytes.chunks(8).enumerate().map(|(i, chunk)| {
+        let mut row = format!("{:04x}  .byte ", base + i * 8);
+        for (j, byte) in chunk.iter().enumerate() {
+            if j > 0 {
+                row.push_str(", ");
+            }
+            row.push_str(&format!("{:#04x}", byte));
+        }
+        row
+    }).collect()
+}
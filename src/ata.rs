@@ -0,0 +1,287 @@
+//! ATA PIO driver for the primary IDE bus
+//!
+//! Drives are found with IDENTIFY and accessed with polled 28-bit LBA
+//! transfers, switching to 48-bit commands past the first 128 GiB.
+//! Interrupts from the controller are switched off, so nothing here
+//! depends on IRQ 14. Under QEMU the boot image is the primary master
+//! (`ata0`) and a second `-drive` the primary slave (`ata1`).
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+
+/// I/O and control port bases of the primary bus
+const PRIMARY_IO: u16 = 0x1F0;
+const PRIMARY_CONTROL: u16 = 0x3F6;
+
+// Registers, as offsets from the I/O base
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+/// Status when read, command when written
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+const CMD_READ: u8 = 0x20;
+const CMD_READ_EXT: u8 = 0x24;
+const CMD_WRITE: u8 = 0x30;
+const CMD_WRITE_EXT: u8 = 0x34;
+const CMD_FLUSH: u8 = 0xE7;
+const CMD_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+/// Control register bit that stops the drives raising interrupts
+const CONTROL_NIEN: u8 = 0x02;
+
+/// Status polls before giving up on a drive
+const TIMEOUT: usize = 1_000_000;
+
+/// Most sectors moved by one command
+const MAX_TRANSFER: usize = 255;
+
+/// First sector that needs a 48-bit command
+const LBA28_LIMIT: u64 = 1 << 28;
+
+#[derive(Debug, Clone, Copy)]
+struct Bus {
+    io: u16,
+    control: u16,
+}
+
+impl Bus {
+    fn read(self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + register).read() }
+    }
+
+    fn write(self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + register).write(value) }
+    }
+
+    /// Let about 400 ns pass by reading the alternate status, so the
+    /// status register reflects the last drive select or command
+    fn delay(self) {
+        for _ in 0..4 {
+            unsafe { Port::<u8>::new(self.control).read() };
+        }
+    }
+
+    /// Select the master or slave drive in LBA mode, with the top four
+    /// bits of a 28-bit address
+    fn select(self, slave: bool, lba_top: u8) {
+        self.write(DRIVE, 0xE0 | (u8::from(slave) << 4) | (lba_top & 0x0F));
+        self.delay();
+    }
+
+    /// Wait for the selected drive to finish its current operation,
+    /// returning the final status
+    fn wait_idle(self) -> Result<u8, &'static str> {
+        for _ in 0..TIMEOUT {
+            let status = self.read(STATUS);
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err("Device timed out")
+    }
+
+    /// Wait until the selected drive is ready to transfer a sector
+    fn wait_data(self) -> Result<(), &'static str> {
+        for _ in 0..TIMEOUT {
+            let status = self.wait_idle()?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err("I/O error");
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err("Device timed out")
+    }
+
+    fn read_words(self, buf: &mut [u8]) {
+        let mut port = Port::<u16>::new(self.io + DATA);
+        for pair in buf.as_chunks_mut::<2>().0 {
+            *pair = unsafe { port.read() }.to_le_bytes();
+        }
+    }
+
+    fn write_words(self, buf: &[u8]) {
+        let mut port = Port::<u16>::new(self.io + DATA);
+        for pair in buf.as_chunks::<2>().0 {
+            unsafe { port.write(u16::from_le_bytes(*pair)) };
+        }
+    }
+}
+
+/// A drive on an ATA bus
+pub struct AtaDrive {
+    name: String,
+    model: String,
+    bus: Bus,
+    slave: bool,
+    sectors: u64,
+    /// Supports 48-bit addressing
+    lba48: bool,
+}
+
+impl AtaDrive {
+    /// Ask a drive to identify itself, returning `None` if there is no
+    /// drive or it is not an ATA disk (e.g. an ATAPI CD-ROM)
+    fn identify(bus: Bus, slave: bool) -> Option<Self> {
+        bus.select(slave, 0);
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            bus.write(register, 0);
+        }
+        bus.write(COMMAND, CMD_IDENTIFY);
+        bus.delay();
+        if bus.read(STATUS) == 0 {
+            return None;
+        }
+        bus.wait_idle().ok()?;
+        // Packet devices put a signature in the LBA registers instead
+        if bus.read(LBA_MID) != 0 || bus.read(LBA_HIGH) != 0 {
+            return None;
+        }
+        bus.wait_data().ok()?;
+
+        let mut id = [0u8; SECTOR_SIZE];
+        bus.read_words(&mut id);
+        let word = |index: usize| u16::from_le_bytes([id[2 * index], id[2 * index + 1]]);
+
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0, |total, i| total | u64::from(word(100 + i)) << (16 * i))
+        } else {
+            u64::from(word(60)) | u64::from(word(61)) << 16
+        };
+        if sectors == 0 {
+            return None;
+        }
+
+        // The model is 40 characters with each word's bytes swapped
+        let model: String = (27..47)
+            .flat_map(|index| word(index).to_be_bytes())
+            .map(char::from)
+            .collect();
+
+        Some(AtaDrive {
+            name: format!("ata{}", u8::from(slave)),
+            model: String::from(model.trim_end()),
+            bus,
+            slave,
+            sectors,
+            lba48,
+        })
+    }
+
+    /// Issue a read or write of `count` sectors at `lba`
+    fn start(&self, lba: u64, count: usize, write: bool) -> Result<(), &'static str> {
+        let bus = self.bus;
+        let extended = lba + count as u64 > LBA28_LIMIT;
+        if extended && !self.lba48 {
+            return Err("Sector out of range");
+        }
+
+        if extended {
+            bus.select(self.slave, 0);
+            bus.wait_idle()?;
+            // High bytes first; each register holds two bytes in turn
+            bus.write(SECTOR_COUNT, (count >> 8) as u8);
+            bus.write(LBA_LOW, (lba >> 24) as u8);
+            bus.write(LBA_MID, (lba >> 32) as u8);
+            bus.write(LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            bus.select(self.slave, (lba >> 24) as u8);
+            bus.wait_idle()?;
+        }
+        bus.write(SECTOR_COUNT, count as u8);
+        bus.write(LBA_LOW, lba as u8);
+        bus.write(LBA_MID, (lba >> 8) as u8);
+        bus.write(LBA_HIGH, (lba >> 16) as u8);
+
+        let command = match (write, extended) {
+            (false, false) => CMD_READ,
+            (false, true) => CMD_READ_EXT,
+            (true, false) => CMD_WRITE,
+            (true, true) => CMD_WRITE_EXT,
+        };
+        bus.write(COMMAND, command);
+        bus.delay();
+        Ok(())
+    }
+
+    /// Wait for written sectors to reach the medium
+    fn flush(&self) -> Result<(), &'static str> {
+        self.bus.write(COMMAND, if self.lba48 { CMD_FLUSH_EXT } else { CMD_FLUSH });
+        self.bus.delay();
+        if self.bus.wait_idle()? & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err("I/O error");
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        for (index, chunk) in buf.chunks_mut(MAX_TRANSFER * SECTOR_SIZE).enumerate() {
+            self.start(lba + (index * MAX_TRANSFER) as u64, chunk.len() / SECTOR_SIZE, false)?;
+            for sector in chunk.as_chunks_mut::<SECTOR_SIZE>().0 {
+                self.bus.wait_data()?;
+                self.bus.read_words(sector);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        for (index, chunk) in buf.chunks(MAX_TRANSFER * SECTOR_SIZE).enumerate() {
+            self.start(lba + (index * MAX_TRANSFER) as u64, chunk.len() / SECTOR_SIZE, true)?;
+            for sector in chunk.as_chunks::<SECTOR_SIZE>().0 {
+                self.bus.wait_data()?;
+                self.bus.write_words(sector);
+            }
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the drives on the primary bus
+pub fn probe() -> Vec<Box<dyn BlockDevice>> {
+    let bus = Bus { io: PRIMARY_IO, control: PRIMARY_CONTROL };
+    let mut drives: Vec<Box<dyn BlockDevice>> = Vec::new();
+    // A bus with no controller floats high
+    if bus.read(STATUS) == 0xFF {
+        return drives;
+    }
+    unsafe { Port::<u8>::new(bus.control).write(CONTROL_NIEN) };
+
+    for slave in [false, true] {
+        if let Some(drive) = AtaDrive::identify(bus, slave) {
+            drives.push(Box::new(drive));
+        }
+    }
+    drives
+}
@@ -0,0 +1,170 @@
+//! Block device layer
+//!
+//! Drivers implement [`BlockDevice`] and are registered at boot; everything
+//! above them addresses a disk by its index in the registry and reads or
+//! writes whole 512-byte sectors through [`read_sectors`] and
+//! [`write_sectors`], which check the request against the disk's size.
+//! Single sectors go through the [`cache`](crate::cache); longer requests
+//! go to the device and see the cached sectors they cover.
+use crate::cache::{self, Cache, Stats};
+use crate::{ata, time, virtio};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Bytes per sector on every device
+pub const SECTOR_SIZE: usize = 512;
+
+/// A disk addressed in sectors
+pub trait BlockDevice: Send {
+    /// Short name, e.g. `ata0`
+    fn name(&self) -> &str;
+
+    /// Product name reported by the device
+    fn model(&self) -> &str;
+
+    /// Capacity in sectors
+    fn sector_count(&self) -> u64;
+
+    /// Read `buf.len() / SECTOR_SIZE` sectors starting at `lba`
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+    /// Write `buf.len() / SECTOR_SIZE` sectors starting at `lba`
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), &'static str>;
+}
+
+/// Summary of a registered disk
+pub struct DiskInfo {
+    pub name: String,
+    pub model: String,
+    pub sectors: u64,
+}
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<Box<dyn BlockDevice>>> = Mutex::new(Vec::new());
+}
+
+/// Locked before `DEVICES` wherever both are needed
+static CACHE: Mutex<Cache> = Mutex::new(Cache::new());
+
+/// How often the timer asks for expired sectors to be written back
+const WRITE_BACK_INTERVAL_MS: u64 = 1000;
+
+/// Set by the timer, cleared by the next `write_back_expired`
+static WRITE_BACK_DUE: AtomicBool = AtomicBool::new(false);
+
+/// Add a disk to the registry, returning its index
+pub fn register(device: Box<dyn BlockDevice>) -> usize {
+    let mut devices = DEVICES.lock();
+    devices.push(device);
+    devices.len() - 1
+}
+
+/// Probe for disks and register the ones found
+pub fn init() {
+    for device in ata::probe().into_iter().chain(virtio::probe()) {
+        register(device);
+    }
+}
+
+/// Registered disks, in index order
+pub fn disks() -> Vec<DiskInfo> {
+    DEVICES.lock().iter()
+        .map(|device| DiskInfo {
+            name: device.name().into(),
+            model: device.model().into(),
+            sectors: device.sector_count(),
+        })
+        .collect()
+}
+
+/// Index of the disk called `name`
+pub fn find(name: &str) -> Option<usize> {
+    DEVICES.lock().iter().position(|device| device.name() == name)
+}
+
+/// Capacity of disk `disk` in sectors
+pub fn sector_count(disk: usize) -> Option<u64> {
+    DEVICES.lock().get(disk).map(|device| device.sector_count())
+}
+
+/// Check that `len` bytes at `lba` are whole sectors within `device`
+fn check(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<(), &'static str> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err("Invalid argument");
+    }
+    let end = lba.checked_add((len / SECTOR_SIZE) as u64).ok_or("Sector out of range")?;
+    if end > device.sector_count() {
+        return Err("Sector out of range");
+    }
+    Ok(())
+}
+
+/// Read whole sectors from disk `disk`
+pub fn read_sectors(disk: usize, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+    let mut cache = CACHE.lock();
+    let mut devices = DEVICES.lock();
+    let device = devices.get_mut(disk).ok_or("No such device")?;
+    check(device.as_ref(), lba, buf.len())?;
+    if buf.len() == SECTOR_SIZE {
+        return cache.read(&mut devices, disk, lba, buf);
+    }
+    device.read_sectors(lba, buf)?;
+    cache.overlay(disk, lba, buf);
+    Ok(())
+}
+
+/// Write whole sectors to disk `disk`. A single sector is only cached;
+/// longer writes go straight to the device.
+pub fn write_sectors(disk: usize, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+    let mut cache = CACHE.lock();
+    let mut devices = DEVICES.lock();
+    let device = devices.get_mut(disk).ok_or("No such device")?;
+    check(device.as_ref(), lba, buf.len())?;
+    if buf.len() == SECTOR_SIZE {
+        return cache.write(&mut devices, disk, lba, buf);
+    }
+    device.write_sectors(lba, buf)?;
+    cache.update(disk, lba, buf);
+    Ok(())
+}
+
+/// Write every cached sector that differs from its disk
+pub fn sync() -> Result<(), &'static str> {
+    let mut cache = CACHE.lock();
+    cache.flush(&mut DEVICES.lock(), None)
+}
+
+/// Called from the timer interrupt. The handler must not wait for a
+/// disk, so it only marks a write-back as due; the next safe point does
+/// it.
+pub fn timer_tick(uptime_ms: u64) {
+    if uptime_ms.is_multiple_of(WRITE_BACK_INTERVAL_MS) {
+        WRITE_BACK_DUE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Write back the sectors that have been dirty for `cache::WRITE_BACK_MS`,
+/// if the timer has asked for it since the last call. Safe points call
+/// this outside interrupt handlers and without locks held: the shell
+/// while it waits for input, `scheduler::wait` between slices and every
+/// system call. A failed write is retried next time.
+pub fn write_back_expired() {
+    if !WRITE_BACK_DUE.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let before = match time::uptime_ms().checked_sub(cache::WRITE_BACK_MS) {
+        Some(before) => before,
+        None => return,
+    };
+    let mut cache = CACHE.lock();
+    let _ = cache.flush(&mut DEVICES.lock(), Some(before));
+}
+
+/// Cache occupancy and hit counts
+pub fn cache_stats() -> Stats {
+    CACHE.lock().stats()
+}
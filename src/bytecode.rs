@@ -0,0 +1,196 @@
+//! Bytecode executable container format
+//!
+//! All fields are little-endian. Section offsets are from the start of the
+//! file and the CRC32 covers the whole file with the checksum field itself
+//! read as zero.
+//!
+//! | Offset | Size | Field                                     |
+//! |--------|------|-------------------------------------------|
+//! | 0      | 4    | magic `7f 'B' 'B' 'X'`                    |
+//! | 4      | 2    | format version                            |
+//! | 6      | 2    | flags (none defined, must be zero)        |
+//! | 8      | 4    | entry point, as an offset into `code`     |
+//! | 12     | 8    | code section offset, size                 |
+//! | 20     | 8    | data section offset, size                 |
+//! | 28     | 8    | rodata section offset, size               |
+//! | 36     | 4    | data memory size in bytes                 |
+//! | 40     | 4    | CRC32 (IEEE)                              |
+//!
+//! Version history:
+//! - 1: initial container
+//! - 2: scan-based `Loop` (0x03) removed in favour of relative jumps
+//! - 3: reserved field became the data memory size
+//!
+//! A program's data memory is zero-filled and starts with a copy of the
+//! data section, so it must be at least as large as that section.
+use alloc::vec::Vec;
+
+/// Magic number at the start of a bytecode executable
+pub const MAGIC: [u8; 4] = [0x7f, b'B', b'B', b'X'];
+
+/// Format version understood by this kernel. Bump it whenever the
+/// meaning of existing opcodes or header fields changes.
+pub const FORMAT_VERSION: u16 = 3;
+
+pub const HEADER_SIZE: usize = 44;
+
+const CRC_OFFSET: usize = 40;
+
+/// Largest data memory a program may ask for
+pub const MAX_MEMORY: usize = 64 * 1024;
+
+/// A validated bytecode program
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub entry: usize,
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,
+    pub rodata: Vec<u8>,
+    /// Size of the data memory
+    pub memory: usize,
+}
+
+/// Check whether a file starts with the bytecode magic
+pub fn has_magic(image: &[u8]) -> bool {
+    image.starts_with(&MAGIC)
+}
+
+fn read_u16(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([image[offset], image[offset + 1]])
+}
+
+fn read_u32(image: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&image[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/// Return the bytes of the section described at `field` in the header
+fn section(image: &[u8], field: usize) -> Result<&[u8], &'static str> {
+    let offset = read_u32(image, field) as usize;
+    let size = read_u32(image, field + 4) as usize;
+    match offset.checked_add(size) {
+        Some(end) if offset >= HEADER_SIZE && end <= image.len() => Ok(&image[offset..end]),
+        _ => Err("Section outside of file"),
+    }
+}
+
+/// Validate a container and extract its sections
+pub fn parse(image: &[u8]) -> Result<Program, &'static str> {
+    if image.len() < HEADER_SIZE || !has_magic(image) {
+        return Err("Missing bytecode header");
+    }
+
+    let version = read_u16(image, 4);
+    if version != FORMAT_VERSION {
+        return Err("Unsupported bytecode version");
+    }
+
+    let flags = read_u16(image, 6);
+    if flags != 0 {
+        return Err("Unsupported bytecode flags");
+    }
+
+    let stored_crc = read_u32(image, CRC_OFFSET);
+    if checksum(image) != stored_crc {
+        return Err("Bytecode checksum mismatch");
+    }
+
+    let code = section(image, 12)?;
+    let data = section(image, 20)?;
+    let rodata = section(image, 28)?;
+
+    let entry = read_u32(image, 8) as usize;
+    if entry >= code.len() {
+        return Err("Entry point outside of code");
+    }
+
+    let memory = read_u32(image, 36) as usize;
+    if memory > MAX_MEMORY {
+        return Err("Data memory too large");
+    }
+    if memory < data.len() {
+        return Err("Data section larger than data memory");
+    }
+
+    Ok(Program {
+        entry,
+        code: code.to_vec(),
+        data: data.to_vec(),
+        rodata: rodata.to_vec(),
+        memory,
+    })
+}
+
+/// Serialize a program into a container, laying the sections out after
+/// the header in code, data, rodata order
+pub fn build(program: &Program) -> Vec<u8> {
+    let code_offset = HEADER_SIZE;
+    let data_offset = code_offset + program.code.len();
+    let rodata_offset = data_offset + program.data.len();
+
+    let mut image = Vec::with_capacity(rodata_offset + program.rodata.len());
+    image.extend_from_slice(&MAGIC);
+    image.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    image.extend_from_slice(&0u16.to_le_bytes());
+    for field in [
+        program.entry,
+        code_offset,
+        program.code.len(),
+        data_offset,
+        program.data.len(),
+        rodata_offset,
+        program.rodata.len(),
+        program.memory,
+        0,
+    ] {
+        image.extend_from_slice(&(field as u32).to_le_bytes());
+    }
+    image.extend_from_slice(&program.code);
+    image.extend_from_slice(&program.data);
+    image.extend_from_slice(&program.rodata);
+
+    let crc = checksum(&image);
+    image[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+    image
+}
+
+/// CRC32 of a container with its checksum field read as zero
+pub fn checksum(image: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(&image[..CRC_OFFSET]);
+    crc.update(&[0; 4]);
+    crc.update(&image[CRC_OFFSET + 4..]);
+    crc.finish()
+}
+
+/// Bitwise CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320)
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.state ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (self.state & 1).wrapping_neg();
+                self.state = (self.state >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,197 @@
+//! Block buffer cache
+//!
+//! Keeps recently used sectors in memory so that filesystems, which read
+//! the same bitmap, inode and directory sectors over and over, do not go
+//! to the disk each time. [`block`](crate::block) sends single-sector
+//! requests through the cache: reads are served from a buffer when one
+//! holds the sector, and writes only fill a buffer and mark it dirty. A
+//! dirty buffer reaches the disk when it is evicted to make room, once it
+//! has been dirty for [`WRITE_BACK_MS`], or on `sync`.
+//!
+//! The buffers are a fixed pool in static memory, evicted least recently
+//! used first.
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::time;
+use alloc::boxed::Box;
+
+/// Sectors the cache holds
+pub const BUFFERS: usize = 256;
+
+/// How long a written sector may stay only in memory
+pub const WRITE_BACK_MS: u64 = 2000;
+
+#[derive(Clone, Copy)]
+struct Buffer {
+    /// Disk index and sector held, `None` for a free buffer
+    sector: Option<(usize, u64)>,
+    data: [u8; SECTOR_SIZE],
+    /// Uptime when the buffer first differed from the disk
+    dirty_since: Option<u64>,
+    /// Value of the access counter at the last use
+    last_used: u64,
+}
+
+const FREE: Buffer = Buffer { sector: None, data: [0; SECTOR_SIZE], dirty_since: None, last_used: 0 };
+
+/// Cache occupancy and counters since boot
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    pub used: usize,
+    pub dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Dirty sectors written to their disk
+    pub write_backs: u64,
+}
+
+pub struct Cache {
+    buffers: [Buffer; BUFFERS],
+    accesses: u64,
+    hits: u64,
+    misses: u64,
+    write_backs: u64,
+}
+
+impl Cache {
+    pub const fn new() -> Self {
+        Cache { buffers: [FREE; BUFFERS], accesses: 0, hits: 0, misses: 0, write_backs: 0 }
+    }
+
+    /// Read sector `lba` of disk `disk`, which the caller has checked
+    pub fn read(
+        &mut self,
+        devices: &mut [Box<dyn BlockDevice>],
+        disk: usize,
+        lba: u64,
+        buf: &mut [u8],
+    ) -> Result<(), &'static str> {
+        let index = match self.find(disk, lba) {
+            Some(index) => {
+                self.hits += 1;
+                index
+            }
+            None => {
+                self.misses += 1;
+                let index = self.evict(devices)?;
+                let buffer = &mut self.buffers[index];
+                devices[disk].read_sectors(lba, &mut buffer.data)?;
+                buffer.sector = Some((disk, lba));
+                index
+            }
+        };
+        buf.copy_from_slice(&self.buffers[index].data);
+        self.touch(index);
+        Ok(())
+    }
+
+    /// Replace sector `lba` of disk `disk` in memory, leaving the disk
+    /// write for later
+    pub fn write(
+        &mut self,
+        devices: &mut [Box<dyn BlockDevice>],
+        disk: usize,
+        lba: u64,
+        buf: &[u8],
+    ) -> Result<(), &'static str> {
+        let index = match self.find(disk, lba) {
+            Some(index) => index,
+            None => {
+                let index = self.evict(devices)?;
+                self.buffers[index].sector = Some((disk, lba));
+                index
+            }
+        };
+        let buffer = &mut self.buffers[index];
+        buffer.data.copy_from_slice(buf);
+        buffer.dirty_since.get_or_insert_with(time::uptime_ms);
+        self.touch(index);
+        Ok(())
+    }
+
+    /// Copy the cached sectors within `buf`, read from the disk at `lba`,
+    /// over it, since they may be newer than the disk
+    pub fn overlay(&self, disk: usize, lba: u64, buf: &mut [u8]) {
+        for buffer in &self.buffers {
+            if let Some(sector) = buffer.sector.and_then(|sector| offset(sector, disk, lba, buf.len())) {
+                buf[sector * SECTOR_SIZE..][..SECTOR_SIZE].copy_from_slice(&buffer.data);
+            }
+        }
+    }
+
+    /// Bring cached sectors in line with `buf`, just written to the disk
+    /// at `lba`
+    pub fn update(&mut self, disk: usize, lba: u64, buf: &[u8]) {
+        for buffer in &mut self.buffers {
+            if let Some(sector) = buffer.sector.and_then(|sector| offset(sector, disk, lba, buf.len())) {
+                buffer.data.copy_from_slice(&buf[sector * SECTOR_SIZE..][..SECTOR_SIZE]);
+                buffer.dirty_since = None;
+            }
+        }
+    }
+
+    /// Write back the buffers dirty since `before` or earlier, or all
+    /// dirty buffers. Every buffer is tried; the first error is returned.
+    pub fn flush(&mut self, devices: &mut [Box<dyn BlockDevice>], before: Option<u64>) -> Result<(), &'static str> {
+        let mut result = Ok(());
+        for index in 0..BUFFERS {
+            let due = match self.buffers[index].dirty_since {
+                Some(since) => before.is_none_or(|before| since <= before),
+                None => false,
+            };
+            if due {
+                if let Err(e) = self.write_back(devices, index) {
+                    result = result.and(Err(e));
+                }
+            }
+        }
+        result
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            used: self.buffers.iter().filter(|buffer| buffer.sector.is_some()).count(),
+            dirty: self.buffers.iter().filter(|buffer| buffer.dirty_since.is_some()).count(),
+            hits: self.hits,
+            misses: self.misses,
+            write_backs: self.write_backs,
+        }
+    }
+
+    fn find(&self, disk: usize, lba: u64) -> Option<usize> {
+        self.buffers.iter().position(|buffer| buffer.sector == Some((disk, lba)))
+    }
+
+    fn touch(&mut self, index: usize) {
+        self.accesses += 1;
+        self.buffers[index].last_used = self.accesses;
+    }
+
+    /// Free the least recently used buffer, writing it back first if it
+    /// is dirty, and return its index
+    fn evict(&mut self, devices: &mut [Box<dyn BlockDevice>]) -> Result<usize, &'static str> {
+        let index = match self.buffers.iter().position(|buffer| buffer.sector.is_none()) {
+            Some(index) => index,
+            None => (0..BUFFERS).min_by_key(|&index| self.buffers[index].last_used).unwrap_or(0),
+        };
+        self.write_back(devices, index)?;
+        self.buffers[index].sector = None;
+        Ok(index)
+    }
+
+    fn write_back(&mut self, devices: &mut [Box<dyn BlockDevice>], index: usize) -> Result<(), &'static str> {
+        let buffer = &mut self.buffers[index];
+        if let (Some((disk, lba)), Some(_)) = (buffer.sector, buffer.dirty_since) {
+            devices[disk].write_sectors(lba, &buffer.data)?;
+            buffer.dirty_since = None;
+            self.write_backs += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Position of `sector` within the `len` bytes at `start` on `disk`
+fn offset(sector: (usize, u64), disk: usize, start: u64, len: usize) -> Option<usize> {
+    let (sector_disk, lba) = sector;
+    let index = lba.checked_sub(start)?;
+    (sector_disk == disk && index < (len / SECTOR_SIZE) as u64).then_some(index as usize)
+}
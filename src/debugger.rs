@@ -0,0 +1,240 @@
+//! Interactive bytecode debugger
+//!
+//! Wraps an [`Executor`] with breakpoints and inspection commands. Each
+//! command line produces the lines to show; program output still goes
+//! through the executor's environment. The kernel's `debug` shell command
+//! and `bbx debug` both drive this.
+//!
+//! ```text
+//! break <offset>     stop before the instruction at <offset>
+//! delete <offset>    remove a breakpoint
+//! step [count]       execute one or <count> instructions
+//! continue           run until a breakpoint or the program stops
+//! regs               instruction pointer, stack and call depth
+//! stack              operand stack (top first) and return addresses
+//! mem <addr> <len>   hex dump of data memory
+//! ```
+use crate::asm::{format_instruction, parse_number};
+use crate::isa::decode;
+use crate::vm::{Environment, Executor, Step};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Instructions `continue` runs between checks for an interrupt
+pub const CONTINUE_SLICE: usize = 1000;
+
+/// Bytes shown per row by `mem`
+const ROW_LEN: usize = 16;
+
+pub struct Debugger<E: Environment> {
+    vm: Executor<E>,
+    /// Offsets of every instruction, for validating breakpoints
+    boundaries: Vec<usize>,
+    breakpoints: Vec<usize>,
+    /// How the program stopped, once it has
+    finished: Option<Step>,
+}
+
+impl<E: Environment> Debugger<E> {
+    pub fn new(vm: Executor<E>) -> Self {
+        let mut boundaries = Vec::new();
+        let mut offset = 0;
+        while let Ok(decoded) = decode(vm.code(), offset) {
+            boundaries.push(offset);
+            offset += decoded.len;
+        }
+        Debugger {
+            vm,
+            boundaries,
+            breakpoints: Vec::new(),
+            finished: None,
+        }
+    }
+
+    /// How the program stopped, or `None` while it can still run
+    pub fn finished(&self) -> Option<Step> {
+        self.finished
+    }
+
+    /// Status the program passed to `exit`
+    pub fn exit_status(&self) -> Option<i32> {
+        self.vm.exit_status()
+    }
+
+    pub fn set_breakpoint(&mut self, offset: usize) -> Result<(), &'static str> {
+        if !self.boundaries.contains(&offset) {
+            return Err("Not the start of an instruction");
+        }
+        if let Err(index) = self.breakpoints.binary_search(&offset) {
+            self.breakpoints.insert(index, offset);
+        }
+        Ok(())
+    }
+
+    pub fn clear_breakpoint(&mut self, offset: usize) -> Result<(), &'static str> {
+        let index = self.breakpoints.binary_search(&offset).map_err(|_| "No breakpoint there")?;
+        self.breakpoints.remove(index);
+        Ok(())
+    }
+
+    /// Execute up to `count` instructions, stopping early if the program
+    /// ends
+    pub fn step(&mut self, count: usize) {
+        if self.finished.is_some() {
+            return;
+        }
+        match self.vm.step(count) {
+            Step::Yielded => {}
+            stopped => self.finished = Some(stopped),
+        }
+    }
+
+    /// Run at most `budget` instructions, stopping before any breakpoint
+    /// other than the one at the current instruction. Returns whether a
+    /// breakpoint was reached.
+    pub fn resume(&mut self, budget: usize) -> bool {
+        for _ in 0..budget {
+            self.step(1);
+            if self.finished.is_some() {
+                return false;
+            }
+            if self.breakpoints.contains(&self.vm.ip()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Disassembly of the next instruction, marked `*` if it has a
+    /// breakpoint
+    pub fn current(&self) -> String {
+        let ip = self.vm.ip();
+        let marker = if self.breakpoints.contains(&ip) { "*" } else { " " };
+        match decode(self.vm.code(), ip) {
+            Ok(decoded) => format!("{:04x}{} {}", ip, marker, format_instruction(self.vm.code(), ip, &decoded)),
+            Err(reason) => format!("{:04x}{} ; {}", ip, marker, reason),
+        }
+    }
+
+    pub fn registers(&self) -> String {
+        let stack = self.vm.stack();
+        let top = match stack.last() {
+            Some(value) => format!("{}", value),
+            None => String::from("-"),
+        };
+        format!(
+            "ip {:#06x}  sp {}  top {}  calls {}  memory {} bytes",
+            self.vm.ip(),
+            stack.len(),
+            top,
+            self.vm.call_stack().len(),
+            self.vm.memory().len()
+        )
+    }
+
+    pub fn stack(&self) -> Vec<String> {
+        let stack = self.vm.stack();
+        let mut lines: Vec<String> = stack.iter().enumerate().rev()
+            .map(|(depth, value)| format!("  [{}] {} ({:#x})", depth, value, value))
+            .collect();
+        if lines.is_empty() {
+            lines.push(String::from("  (empty)"));
+        }
+        if !self.vm.call_stack().is_empty() {
+            let mut returns = String::from("  returns to");
+            for address in self.vm.call_stack().iter().rev() {
+                returns.push_str(&format!(" {:#06x}", address));
+            }
+            lines.push(returns);
+        }
+        lines
+    }
+
+    /// Hex dump of `len` bytes of data memory starting at `address`
+    pub fn memory(&self, address: usize, len: usize) -> Result<Vec<String>, &'static str> {
+        let memory = self.vm.memory();
+        let end = address.checked_add(len).filter(|&end| end <= memory.len())
+            .ok_or("Memory range out of bounds")?;
+        Ok(memory[address..end].chunks(ROW_LEN).enumerate().map(|(i, chunk)| {
+            let mut row = format!("{:04x} ", address + i * ROW_LEN);
+            for byte in chunk {
+                row.push_str(&format!(" {:02x}", byte));
+            }
+            for _ in chunk.len()..ROW_LEN {
+                row.push_str("   ");
+            }
+            row.push_str("  ");
+            row.extend(chunk.iter().map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' }));
+            row
+        }).collect())
+    }
+
+    /// Run one debugger command. `interrupted` is polled while `continue`
+    /// runs, so the caller can stop a program that never reaches a
+    /// breakpoint.
+    pub fn command(&mut self, line: &str, interrupted: &mut dyn FnMut() -> bool) -> Vec<String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match self.execute(&args, interrupted) {
+            Ok(lines) => lines,
+            Err(e) => vec![format!("Error: {}", e)],
+        }
+    }
+
+    fn execute(&mut self, args: &[&str], interrupted: &mut dyn FnMut() -> bool) -> Result<Vec<String>, &'static str> {
+        match args {
+            [] => Ok(Vec::new()),
+            ["break" | "b"] if self.breakpoints.is_empty() => Ok(vec![String::from("No breakpoints")]),
+            ["break" | "b"] => Ok(self.breakpoints.iter().map(|b| format!("Breakpoint at {:#06x}", b)).collect()),
+            ["break" | "b", offset] => {
+                let offset = number(offset)?;
+                self.set_breakpoint(offset)?;
+                Ok(vec![format!("Breakpoint at {:#06x}", offset)])
+            }
+            ["delete" | "d", offset] => {
+                let offset = number(offset)?;
+                self.clear_breakpoint(offset)?;
+                Ok(vec![format!("Deleted breakpoint at {:#06x}", offset)])
+            }
+            ["step" | "s"] => self.run(|debugger| debugger.step(1)),
+            ["step" | "s", count] => {
+                let count = number(count)?;
+                self.run(|debugger| debugger.step(count))
+            }
+            ["continue" | "c"] => self.run(|debugger| {
+                while !debugger.resume(CONTINUE_SLICE) && debugger.finished.is_none() {
+                    if interrupted() {
+                        break;
+                    }
+                }
+            }),
+            ["regs" | "r"] => Ok(vec![self.registers()]),
+            ["stack"] => Ok(self.stack()),
+            ["mem" | "m", address, len] => self.memory(number(address)?, number(len)?),
+            ["list" | "l"] => Ok(vec![self.current()]),
+            _ => Err("Unknown command or wrong arguments"),
+        }
+    }
+
+    /// Run `f` if the program can still execute, then report where it
+    /// stopped
+    fn run(&mut self, f: impl FnOnce(&mut Self)) -> Result<Vec<String>, &'static str> {
+        if self.finished.is_some() {
+            return Err("The program has finished");
+        }
+        f(self);
+        Ok(vec![match self.finished {
+            Some(Step::Fault(e)) => format!("Execution error: {}", e),
+            Some(_) => match self.vm.exit_status() {
+                Some(status) => format!("Program exited with status {}", status),
+                None => String::from("Program finished"),
+            },
+            None => self.current(),
+        }])
+    }
+}
+
+fn number(text: &str) -> Result<usize, &'static str> {
+    usize::try_from(parse_number(text)?).map_err(|_| "Invalid number")
+}
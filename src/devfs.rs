@@ -0,0 +1,143 @@
+//! Device filesystem mounted at `/dev`
+//!
+//! A fixed directory of character devices. Writing to `console` prints on
+//! the screen and writing to `serial` goes to the serial port; `null`
+//! discards writes. All of them read as empty.
+//!
+//! Each registered disk follows them as a block device named after it,
+//! whose bytes are the disk's sectors.
+use crate::block::{self, SECTOR_SIZE};
+use crate::vfs::{DirEntry, FileSystem, FileType, Ino, Stat};
+use crate::{print, rtc, serial_print};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const ROOT: Ino = 0;
+
+/// Device names; a device's node number is its index plus one
+const DEVICES: [&str; 3] = ["null", "console", "serial"];
+
+pub struct DevFs;
+
+/// Node number of disk `disk`, after the character devices
+fn disk_ino(disk: usize) -> Ino {
+    DEVICES.len() + 1 + disk
+}
+
+/// The disk node `ino` refers to, with its size in bytes
+fn disk_of(ino: Ino) -> Option<(usize, usize)> {
+    let disk = ino.checked_sub(DEVICES.len() + 1)?;
+    block::sector_count(disk).map(|sectors| (disk, sectors as usize * SECTOR_SIZE))
+}
+
+/// Read bytes of a disk, a sector at a time
+fn read_disk(disk: usize, size: usize, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+    let end = size.min(offset.saturating_add(buf.len()));
+    let mut sector = [0u8; SECTOR_SIZE];
+    let mut position = offset;
+    while position < end {
+        let within = position % SECTOR_SIZE;
+        let count = (SECTOR_SIZE - within).min(end - position);
+        block::read_sectors(disk, (position / SECTOR_SIZE) as u64, &mut sector)?;
+        buf[position - offset..][..count].copy_from_slice(&sector[within..within + count]);
+        position += count;
+    }
+    Ok(end.saturating_sub(offset))
+}
+
+/// Write bytes of a disk, reading back partly covered sectors first
+fn write_disk(disk: usize, size: usize, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+    if offset >= size && !data.is_empty() {
+        return Err("Filesystem full");
+    }
+    let end = size.min(offset.saturating_add(data.len()));
+    let mut sector = [0u8; SECTOR_SIZE];
+    let mut position = offset;
+    while position < end {
+        let within = position % SECTOR_SIZE;
+        let count = (SECTOR_SIZE - within).min(end - position);
+        let lba = (position / SECTOR_SIZE) as u64;
+        if count < SECTOR_SIZE {
+            block::read_sectors(disk, lba, &mut sector)?;
+        }
+        sector[within..within + count].copy_from_slice(&data[position - offset..][..count]);
+        block::write_sectors(disk, lba, &sector)?;
+        position += count;
+    }
+    Ok(end - offset)
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, &'static str> {
+        if dir != ROOT {
+            return Err("Not a directory");
+        }
+        DEVICES.iter().position(|&device| device == name)
+            .map(|index| index + 1)
+            .or_else(|| block::find(name).map(disk_ino))
+            .ok_or("File not found")
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat, &'static str> {
+        let (file_type, mode, nlink, size) = match ino {
+            ROOT => (FileType::Directory, 0o755, 2, DEVICES.len() + block::disks().len()),
+            _ if ino <= DEVICES.len() => (FileType::Device, 0o666, 1, 0),
+            _ => match disk_of(ino) {
+                Some((_, size)) => (FileType::Device, 0o660, 1, size),
+                None => return Err("File not found"),
+            },
+        };
+        // Devices exist from boot and have no recorded modification
+        let boot = rtc::boot_time();
+        Ok(Stat { ino, file_type, mode, uid: 0, gid: 0, nlink, size, created: boot, modified: boot })
+    }
+
+    fn read_at(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if let Some((disk, size)) = disk_of(ino) {
+            return read_disk(disk, size, offset, buf);
+        }
+        self.stat(ino)?;
+        Ok(0)
+    }
+
+    /// Character devices are streams, so they ignore the offset
+    fn write_at(&mut self, ino: Ino, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        if let Some((disk, size)) = disk_of(ino) {
+            return write_disk(disk, size, offset, data);
+        }
+        let text = String::from_utf8_lossy(data);
+        match DEVICES.get(ino.wrapping_sub(1)) {
+            Some(&"null") => {}
+            Some(&"console") => print!("{}", text),
+            Some(&"serial") => {
+                serial_print!("{}", text);
+            }
+            _ => return Err("Is a directory"),
+        }
+        Ok(data.len())
+    }
+
+    /// Devices have a fixed size, so truncating one is a no-op
+    fn truncate(&mut self, ino: Ino, _size: usize) -> Result<(), &'static str> {
+        self.stat(ino).map(|_| ())
+    }
+
+    fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, &'static str> {
+        if dir != ROOT {
+            return Err("Not a directory");
+        }
+        let devices = DEVICES.iter().enumerate()
+            .map(|(index, name)| DirEntry { name: String::from(*name), ino: index + 1 });
+        let disks = block::disks().into_iter().enumerate()
+            .map(|(disk, info)| DirEntry { name: info.name, ino: disk_ino(disk) });
+        Ok(devices.chain(disks).collect())
+    }
+}
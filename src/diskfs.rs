@@ -0,0 +1,139 @@
+//! Native filesystem volumes on disks
+//!
+//! Connects [`Sfs`] to the block layer, one filesystem block per sector,
+//! and to the VFS. The first disk holding a volume made with `bbx mkfs`
+//! is mounted on [`MOUNT_POINT`] at boot; `mount sfs <disk> <dir>` mounts
+//! others, and `mkfs <disk>` formats a disk from the shell.
+use crate::block::{self, SECTOR_SIZE};
+use crate::rtc;
+use crate::sfs::{self, BlockIo, Kind, Sfs};
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Ino, Stat};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const _: () = assert!(sfs::BLOCK_SIZE == SECTOR_SIZE);
+
+/// Where the volume found at boot is mounted
+pub const MOUNT_POINT: &str = "/disk";
+
+/// A registered disk, addressed in filesystem blocks
+pub struct Disk(usize);
+
+impl BlockIo for Disk {
+    fn block_count(&self) -> u32 {
+        block::sector_count(self.0).unwrap_or(0).min(u64::from(u32::MAX)) as u32
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8; sfs::BLOCK_SIZE]) -> Result<(), &'static str> {
+        block::read_sectors(self.0, u64::from(block), buf)
+    }
+
+    fn write_block(&mut self, block: u32, buf: &[u8; sfs::BLOCK_SIZE]) -> Result<(), &'static str> {
+        block::write_sectors(self.0, u64::from(block), buf)
+    }
+}
+
+/// Open the volume on disk `disk`
+pub fn mount(disk: usize) -> Result<Sfs<Disk>, &'static str> {
+    Sfs::open(Disk(disk), rtc::now)
+}
+
+/// Write an empty volume over disk `disk`
+pub fn format(disk: usize) -> Result<Sfs<Disk>, &'static str> {
+    Sfs::format(Disk(disk), rtc::now)
+}
+
+/// Mount the first volume found on a disk, returning the disk's name
+pub fn init() -> Option<String> {
+    let (disk, fs) = block::disks().into_iter().enumerate()
+        .find_map(|(index, disk)| Some((disk, mount(index).ok()?)))?;
+    let mut vfs = vfs::VFS.lock();
+    let _ = vfs.create_dir(MOUNT_POINT);
+    vfs.mount(MOUNT_POINT, Box::new(fs)).ok()?;
+    Some(disk.name)
+}
+
+fn file_type(kind: Kind) -> FileType {
+    match kind {
+        Kind::File => FileType::Regular,
+        Kind::Directory => FileType::Directory,
+        Kind::Symlink => FileType::Symlink,
+    }
+}
+
+impl FileSystem for Sfs<Disk> {
+    fn name(&self) -> &'static str {
+        "sfs"
+    }
+
+    fn root(&self) -> Ino {
+        sfs::ROOT as Ino
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, &'static str> {
+        Sfs::lookup(self, dir as u32, name).map(|ino| ino as Ino)
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat, &'static str> {
+        let metadata = self.metadata(ino as u32)?;
+        Ok(Stat {
+            ino,
+            file_type: file_type(metadata.kind),
+            mode: metadata.mode,
+            uid: 0,
+            gid: 0,
+            nlink: metadata.nlink as usize,
+            size: metadata.size,
+            created: metadata.created,
+            modified: metadata.modified,
+        })
+    }
+
+    fn read_at(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        Sfs::read_at(self, ino as u32, offset, buf)
+    }
+
+    fn write_at(&mut self, ino: Ino, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        Sfs::write_at(self, ino as u32, offset, data)
+    }
+
+    fn truncate(&mut self, ino: Ino, size: usize) -> Result<(), &'static str> {
+        Sfs::truncate(self, ino as u32, size)
+    }
+
+    fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, &'static str> {
+        Ok(Sfs::readdir(self, dir as u32)?.into_iter()
+            .map(|(name, ino)| DirEntry { name, ino: ino as Ino })
+            .collect())
+    }
+
+    fn create(&mut self, dir: Ino, name: &str, file_type: FileType) -> Result<Ino, &'static str> {
+        let kind = match file_type {
+            FileType::Regular => Kind::File,
+            FileType::Directory => Kind::Directory,
+            FileType::Symlink | FileType::Device => return Err("Operation not supported"),
+        };
+        Sfs::create(self, dir as u32, name, kind).map(|ino| ino as Ino)
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), &'static str> {
+        Sfs::unlink(self, dir as u32, name)
+    }
+
+    fn link(&mut self, ino: Ino, dir: Ino, name: &str) -> Result<(), &'static str> {
+        Sfs::link(self, ino as u32, dir as u32, name)
+    }
+
+    fn symlink(&mut self, dir: Ino, name: &str, target: &str) -> Result<Ino, &'static str> {
+        Sfs::symlink(self, dir as u32, name, target).map(|ino| ino as Ino)
+    }
+
+    fn read_link(&self, ino: Ino) -> Result<String, &'static str> {
+        Sfs::read_link(self, ino as u32)
+    }
+
+    fn set_mode(&mut self, ino: Ino, mode: u16) -> Result<(), &'static str> {
+        Sfs::set_mode(self, ino as u32, mode)
+    }
+}
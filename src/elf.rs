@@ -0,0 +1,432 @@
+//! ELF64 loader for native x86_64 executables
+//!
+//! Executables are loaded into a per-process `AddressSpace`: every PT_LOAD
+//! segment gets its own page-aligned, zero-filled region with the
+//! permissions from `p_flags`, and a user stack is laid out the way the
+//! System V ABI expects (argc, argv, envp, auxv) so that a normal
+//! toolchain's startup code can find its arguments. `usermode.rs` maps
+//! the image and runs it in ring 3.
+use crate::process::{self, Pid};
+use crate::usermode::UserImage;
+use crate::{serial_println, vfs};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// First four bytes of every ELF file
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 0x3e;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Position-independent executables are loaded at this base address
+const PIE_LOAD_BIAS: u64 = 0x40_0000;
+
+/// End of the lower, user half of the address space
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Top of the user stack (exclusive) and its size
+const USER_STACK_TOP: u64 = 0x7fff_ffff_f000;
+const USER_STACK_SIZE: usize = 8 * 1024;
+
+/// Upper bound on the memory a single image may occupy
+const MAX_IMAGE_SIZE: u64 = 512 * 1024;
+
+/// Parsed ELF file header (the fields the loader needs)
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub elf_type: u16,
+    pub entry: u64,
+    pub phoff: u64,
+    pub phentsize: u16,
+    pub phnum: u16,
+}
+
+/// Parsed program header
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// Access permissions of a mapped segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    fn from_flags(flags: u32) -> Self {
+        Permissions {
+            read: flags & PF_R != 0,
+            write: flags & PF_W != 0,
+            execute: flags & PF_X != 0,
+        }
+    }
+}
+
+/// A contiguous, page-aligned region of a process address space
+#[derive(Clone)]
+pub struct Segment {
+    pub start: u64,
+    pub perms: Permissions,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+}
+
+/// The memory image of a native process
+#[derive(Clone, Default)]
+pub struct AddressSpace {
+    segments: Vec<Segment>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        AddressSpace { segments: Vec::new() }
+    }
+
+    /// Map a zeroed region covering `[vaddr, vaddr + len)`, rounded out to
+    /// page boundaries
+    pub fn map(&mut self, vaddr: u64, len: u64, perms: Permissions) -> Result<&mut Segment, &'static str> {
+        let start = align_down(vaddr, PAGE_SIZE);
+        let end = vaddr
+            .checked_add(len)
+            .and_then(|end| align_up(end, PAGE_SIZE))
+            .ok_or("Invalid ELF")?;
+        if end > USER_END {
+            return Err("Segment outside user memory");
+        }
+
+        if self.segments.iter().any(|s| start < s.end() && s.start < end) {
+            return Err("Overlapping segments");
+        }
+        if self.size() + (end - start) > MAX_IMAGE_SIZE {
+            return Err("Image too large");
+        }
+
+        self.segments.push(Segment {
+            start,
+            perms,
+            data: vec![0; (end - start) as usize],
+        });
+        Ok(self.segments.last_mut().unwrap())
+    }
+
+    /// Copy bytes into the address space, ignoring permissions (used by
+    /// the loader itself)
+    pub fn write(&mut self, vaddr: u64, bytes: &[u8]) -> Result<(), &'static str> {
+        let end = vaddr.checked_add(bytes.len() as u64).ok_or("Write outside mapped memory")?;
+        let segment = self.segments.iter_mut()
+            .find(|s| vaddr >= s.start && end <= s.end())
+            .ok_or("Write outside mapped memory")?;
+        let offset = (vaddr - segment.start) as usize;
+        segment.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Total bytes mapped
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|s| s.data.len() as u64).sum()
+    }
+}
+
+/// A loaded executable ready to start
+pub struct Image {
+    pub space: AddressSpace,
+    pub entry: u64,
+    pub stack_pointer: u64,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn align_down(value: u64, align: u64) -> u64 {
+    value & !(align - 1)
+}
+
+fn align_up(value: u64, align: u64) -> Option<u64> {
+    Some(align_down(value.checked_add(align - 1)?, align))
+}
+
+/// Check whether a file starts with the ELF magic
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(&ELF_MAGIC)
+}
+
+/// Parse and validate the ELF file header
+pub fn parse_header(data: &[u8]) -> Result<ElfHeader, &'static str> {
+    if data.len() < ELF_HEADER_SIZE || !is_elf(data) {
+        return Err("Not an ELF file");
+    }
+    if data[4] != ELFCLASS64 {
+        return Err("Not a 64-bit ELF file");
+    }
+    if data[5] != ELFDATA2LSB {
+        return Err("Not a little-endian ELF file");
+    }
+    if data[6] != EV_CURRENT {
+        return Err("Unsupported ELF version");
+    }
+
+    let header = ElfHeader {
+        elf_type: read_u16(data, 16),
+        entry: read_u64(data, 24),
+        phoff: read_u64(data, 32),
+        phentsize: read_u16(data, 54),
+        phnum: read_u16(data, 56),
+    };
+
+    if read_u16(data, 18) != EM_X86_64 {
+        return Err("Not an x86_64 executable");
+    }
+    if header.elf_type != ET_EXEC && header.elf_type != ET_DYN {
+        return Err("Not an executable ELF file");
+    }
+    if header.phentsize as usize != PROGRAM_HEADER_SIZE {
+        return Err("Unexpected program header size");
+    }
+    Ok(header)
+}
+
+/// Parse the program header table
+pub fn program_headers(data: &[u8], header: &ElfHeader) -> Result<Vec<ProgramHeader>, &'static str> {
+    let table_size = header.phnum as u64 * PROGRAM_HEADER_SIZE as u64;
+    match header.phoff.checked_add(table_size) {
+        Some(end) if end <= data.len() as u64 => {}
+        _ => return Err("Program headers past end of file"),
+    }
+
+    let mut headers = Vec::new();
+    for i in 0..header.phnum as usize {
+        let base = header.phoff as usize + i * PROGRAM_HEADER_SIZE;
+        headers.push(ProgramHeader {
+            p_type: read_u32(data, base),
+            flags: read_u32(data, base + 4),
+            offset: read_u64(data, base + 8),
+            vaddr: read_u64(data, base + 16),
+            filesz: read_u64(data, base + 32),
+            memsz: read_u64(data, base + 40),
+        });
+    }
+    Ok(headers)
+}
+
+/// Load an ELF executable into a fresh address space and build its
+/// initial stack
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Image, &'static str> {
+    let header = parse_header(data)?;
+    let phdrs = program_headers(data, &header)?;
+    let bias = if header.elf_type == ET_DYN { PIE_LOAD_BIAS } else { 0 };
+
+    let mut space = AddressSpace::new();
+    let mut phdr_vaddr = None;
+
+    for ph in &phdrs {
+        match ph.p_type {
+            PT_LOAD => {
+                if ph.filesz > ph.memsz {
+                    return Err("Segment file size exceeds memory size");
+                }
+                match ph.offset.checked_add(ph.filesz) {
+                    Some(end) if end <= data.len() as u64 => {}
+                    _ => return Err("Segment past end of file"),
+                }
+
+                let vaddr = ph.vaddr.checked_add(bias).ok_or("Invalid ELF")?;
+                space.map(vaddr, ph.memsz, Permissions::from_flags(ph.flags))?;
+
+                // Everything past filesz is BSS and stays zeroed
+                let file_bytes = &data[ph.offset as usize..(ph.offset + ph.filesz) as usize];
+                space.write(vaddr, file_bytes)?;
+
+                // The program headers are usually part of the first segment
+                if phdr_vaddr.is_none() && header.phoff >= ph.offset && header.phoff < ph.offset + ph.filesz {
+                    phdr_vaddr = Some(vaddr + (header.phoff - ph.offset));
+                }
+            }
+            PT_PHDR => phdr_vaddr = Some(ph.vaddr.checked_add(bias).ok_or("Invalid ELF")?),
+            _ => {}
+        }
+    }
+
+    if space.segments().is_empty() {
+        return Err("No loadable segments");
+    }
+
+    let entry = header.entry.checked_add(bias).ok_or("Invalid ELF")?;
+    if !space.segments().iter().any(|s| s.perms.execute && entry >= s.start && entry < s.end()) {
+        return Err("Entry point not in an executable segment");
+    }
+
+    let auxv = [
+        (AT_PHDR, phdr_vaddr.unwrap_or(0)),
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, header.phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, entry),
+    ];
+    let stack_pointer = setup_stack(&mut space, argv, envp, &auxv)?;
+
+    Ok(Image { space, entry, stack_pointer })
+}
+
+/// Map the user stack and lay out the System V initial process stack:
+///
+/// ```text
+/// rsp ->  argc
+///         argv[0..argc], NULL
+///         envp[..], NULL
+///         auxv pairs, AT_NULL
+///         ... string data ...
+/// ```
+fn setup_stack(space: &mut AddressSpace, argv: &[&str], envp: &[&str], auxv: &[(u64, u64)]) -> Result<u64, &'static str> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE as u64;
+    space.map(stack_bottom, USER_STACK_SIZE as u64, Permissions { read: true, write: true, execute: false })?;
+
+    // Copy the strings to the top of the stack
+    let mut top = USER_STACK_TOP;
+    let mut push_str = |space: &mut AddressSpace, s: &str| -> Result<u64, &'static str> {
+        let len = s.len() as u64 + 1;
+        if top - len < stack_bottom {
+            return Err("Arguments do not fit on the stack");
+        }
+        top -= len;
+        space.write(top, s.as_bytes())?;
+        space.write(top + s.len() as u64, &[0])?;
+        Ok(top)
+    };
+
+    let mut argv_ptrs = Vec::new();
+    for arg in argv {
+        argv_ptrs.push(push_str(space, arg)?);
+    }
+    let mut envp_ptrs = Vec::new();
+    for var in envp {
+        envp_ptrs.push(push_str(space, var)?);
+    }
+
+    // argc + argv + NULL + envp + NULL + auxv pairs + AT_NULL pair
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv_ptrs.len() as u64);
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.push(0);
+    for &(key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+    words.push(AT_NULL);
+    words.push(0);
+
+    // rsp must be 16-byte aligned at process entry
+    let table_size = words.len() as u64 * 8;
+    let rsp = align_down(top - table_size, 16);
+    if rsp < stack_bottom {
+        return Err("Arguments do not fit on the stack");
+    }
+
+    for (i, word) in words.iter().enumerate() {
+        space.write(rsp + i as u64 * 8, &word.to_le_bytes())?;
+    }
+    Ok(rsp)
+}
+
+/// Load an ELF executable from the filesystem into a new child process of
+/// the current one and run it in ring 3 from `e_entry` until it ends
+pub fn spawn(path: &str, argv: &[&str], envp: &[&str]) -> Result<Pid, &'static str> {
+    let content = vfs::VFS.lock().read_file(path)?;
+    let image = load(&content, argv, envp)?;
+
+    serial_println!("elf: {} entry={:#x} rsp={:#x}", path, image.entry, image.stack_pointer);
+    for segment in image.space.segments() {
+        serial_println!(
+            "elf:   {:#x}-{:#x} {}{}{}",
+            segment.start,
+            segment.end(),
+            if segment.perms.read { 'r' } else { '-' },
+            if segment.perms.write { 'w' } else { '-' },
+            if segment.perms.execute { 'x' } else { '-' },
+        );
+    }
+
+    let user = UserImage::map(&image.space)?;
+    let (pid, previous) = {
+        let mut pm = process::PROCESS_MANAGER.lock();
+        let pid = pm.create_process(String::from(path), content)?;
+        let process = pm.get_process_mut(pid).ok_or("Process not found")?;
+        process.entry_point = image.entry as usize;
+        process.instruction_pointer = image.entry as usize;
+        process.stack_pointer = image.stack_pointer as usize;
+        process.address_space = Some(image.space);
+        let previous = pm.get_current();
+        pm.set_current(pid);
+        (pid, previous)
+    };
+
+    // Exit, faults and Ctrl+C have already ended the process by now
+    let status = user.enter(image.entry, image.stack_pointer);
+    drop(user);
+
+    let mut pm = process::PROCESS_MANAGER.lock();
+    if pm.get_process(pid).is_some_and(|p| p.state != process::ProcessState::Terminated) {
+        let _ = pm.exit_process(pid, status);
+    }
+    pm.restore_current(previous);
+    Ok(pid)
+}
@@ -0,0 +1,38 @@
+//! Error numbers, returned negated by system calls
+pub const ENOENT: isize = 2;
+pub const EIO: isize = 5;
+pub const EBADF: isize = 9;
+pub const ECHILD: isize = 10;
+pub const ENOMEM: isize = 12;
+pub const EFAULT: isize = 14;
+pub const EEXIST: isize = 17;
+pub const ENOTDIR: isize = 20;
+pub const EISDIR: isize = 21;
+pub const EINVAL: isize = 22;
+pub const EMFILE: isize = 24;
+pub const EFBIG: isize = 27;
+pub const ENOSPC: isize = 28;
+pub const EROFS: isize = 30;
+pub const ENOSYS: isize = 38;
+
+/// Symbolic name of an error number
+pub fn name(errno: isize) -> &'static str {
+    match errno {
+        ENOENT => "ENOENT",
+        EIO => "EIO",
+        EBADF => "EBADF",
+        ECHILD => "ECHILD",
+        ENOMEM => "ENOMEM",
+        EFAULT => "EFAULT",
+        EEXIST => "EEXIST",
+        ENOTDIR => "ENOTDIR",
+        EISDIR => "EISDIR",
+        EINVAL => "EINVAL",
+        EMFILE => "EMFILE",
+        EFBIG => "EFBIG",
+        ENOSPC => "ENOSPC",
+        EROFS => "EROFS",
+        ENOSYS => "ENOSYS",
+        _ => "E?",
+    }
+}
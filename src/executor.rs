@@ -1,172 +1,228 @@
-use crate::{print, println};
+use crate::{println, serial_println};
+use crate::{process, scheduler, vfs};
+use crate::bytecode;
+use crate::errno::{EFAULT, ENOSPC};
+use crate::verifier;
+use crate::process::Pid;
+use crate::syscall::{self, SyscallNumber};
+use crate::vm::{Environment, Executor};
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
 
-/// Simple bytecode instruction set
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum Instruction {
-    // Basic operations
-    Halt = 0x00,
-    PrintChar = 0x01,      // Print character from next byte
-    PrintString = 0x02,    // Print null-terminated string starting at next byte
-    Loop = 0x03,           // Loop: next 2 bytes are count (little-endian), followed by instructions
-    
-    // Math operations
-    Add = 0x10,            // Not implemented yet
-    Sub = 0x11,            // Not implemented yet
-    
-    // System calls
-    Syscall = 0xFF,        // System call with next byte as syscall number
+/// Size of the executable area the JIT places compiled blocks in
+const CODE_AREA_SIZE: usize = 256 * 1024;
+
+// The kernel has no page table access, so the code area is a section the
+// bootloader maps writable and executable
+core::arch::global_asm!(
+    ".pushsection .jit, \"awx\", @nobits",
+    ".balign 4096",
+    ".global jit_code_area",
+    "jit_code_area:",
+    ".zero {size}",
+    ".popsection",
+    size = const CODE_AREA_SIZE,
+);
+
+extern "C" {
+    static mut jit_code_area: [u8; CODE_AREA_SIZE];
+}
+
+/// Bytes of the code area in use
+static CODE_AREA_USED: Mutex<usize> = Mutex::new(0);
+
+/// Copy machine code into the code area, returning its address
+fn install_code(code: &[u8]) -> Option<usize> {
+    let mut used = CODE_AREA_USED.lock();
+    let start = used.next_multiple_of(16);
+    if start + code.len() > CODE_AREA_SIZE {
+        return None;
+    }
+    let base = core::ptr::addr_of_mut!(jit_code_area) as *mut u8;
+    // SAFETY: the range is within the area and past all installed code
+    unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), base.add(start), code.len()) };
+    *used = start + code.len();
+    Some(base as usize + start)
+}
+
+/// Executors that may run code from the code area, whether parked in
+/// the process table or in the middle of a slice
+static CODE_AREA_USERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Held by the environment of an executor with the JIT enabled, so the
+/// code area outlives every executor that might jump into it
+struct CodeAreaUser;
+
+impl CodeAreaUser {
+    fn new() -> Self {
+        CODE_AREA_USERS.fetch_add(1, Ordering::Relaxed);
+        CodeAreaUser
+    }
+}
+
+impl Clone for CodeAreaUser {
+    fn clone(&self) -> Self {
+        CodeAreaUser::new()
+    }
+}
+
+impl Drop for CodeAreaUser {
+    fn drop(&mut self) {
+        CODE_AREA_USERS.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
-/// System call numbers
-#[repr(u8)]
-pub enum Syscall {
-    Exit = 0,
-    Print = 1,
-    Newline = 2,
+/// Free the code area if no executor is using compiled code
+fn reclaim_code_area() {
+    if CODE_AREA_USERS.load(Ordering::Relaxed) == 0 {
+        *CODE_AREA_USED.lock() = 0;
+    }
 }
 
-/// Simple bytecode executor
-pub struct Executor {
-    code: Vec<u8>,
-    ip: usize,  // instruction pointer
+/// Environment for bytecode running in the kernel. Console and file I/O
+/// and the clock go through the system call layer, so `strace` sees them.
+#[derive(Clone)]
+pub struct KernelEnv {
+    code_area: Option<CodeAreaUser>,
 }
 
-impl Executor {
-    pub fn new(code: Vec<u8>) -> Self {
-        Executor { code, ip: 0 }
-    }
-
-    /// Execute the bytecode program
-    pub fn execute(&mut self) -> Result<(), &'static str> {
-        while self.ip < self.code.len() {
-            let instruction = self.code[self.ip];
-            
-            match instruction {
-                0x00 => { // Halt
-                    return Ok(());
-                }
-                
-                0x01 => { // PrintChar
-                    self.ip += 1;
-                    if self.ip >= self.code.len() {
-                        return Err("Unexpected end of program");
-                    }
-                    let ch = self.code[self.ip] as char;
-                    print!("{}", ch);
-                    self.ip += 1;
-                }
-                
-                0x02 => { // PrintString
-                    self.ip += 1;
-                    let start = self.ip;
-                    
-                    // Find null terminator
-                    while self.ip < self.code.len() && self.code[self.ip] != 0 {
-                        self.ip += 1;
-                    }
-                    
-                    if self.ip >= self.code.len() {
-                        return Err("String not null-terminated");
-                    }
-                    
-                    // Print string
-                    let string_bytes = &self.code[start..self.ip];
-                    if let Ok(s) = core::str::from_utf8(string_bytes) {
-                        print!("{}", s);
-                    }
-                    
-                    self.ip += 1; // Skip null terminator
-                }
-                
-                0x03 => { // Loop
-                    self.ip += 1;
-                    if self.ip + 1 >= self.code.len() {
-                        return Err("Unexpected end of program");
-                    }
-                    
-                    // Read loop count (little-endian 16-bit)
-                    let count = self.code[self.ip] as u16 | ((self.code[self.ip + 1] as u16) << 8);
-                    self.ip += 2;
-                    
-                    let loop_start = self.ip;
-                    
-                    // Find end of loop (next Halt or end of program)
-                    let mut depth = 1;
-                    let mut loop_end = self.ip;
-                    while loop_end < self.code.len() && depth > 0 {
-                        if self.code[loop_end] == 0x03 {
-                            depth += 1;
-                        } else if self.code[loop_end] == 0x00 {
-                            depth -= 1;
-                        }
-                        loop_end += 1;
-                    }
-                    
-                    // Execute loop body
-                    for _ in 0..count {
-                        self.ip = loop_start;
-                        while self.ip < loop_end && self.code[self.ip] != 0x00 {
-                            let inner_instruction = self.code[self.ip];
-                            match inner_instruction {
-                                0x01 => { // PrintChar in loop
-                                    self.ip += 1;
-                                    if self.ip < self.code.len() {
-                                        print!("{}", self.code[self.ip] as char);
-                                    }
-                                    self.ip += 1;
-                                }
-                                _ => {
-                                    self.ip += 1;
-                                }
-                            }
-                        }
-                    }
-                    
-                    self.ip = loop_end;
-                }
-                
-                0xFF => { // Syscall
-                    self.ip += 1;
-                    if self.ip >= self.code.len() {
-                        return Err("Unexpected end of program");
-                    }
-                    
-                    let syscall_num = self.code[self.ip];
-                    match syscall_num {
-                        0 => { // Exit
-                            return Ok(());
-                        }
-                        1 => { // Print (next byte is character)
-                            self.ip += 1;
-                            if self.ip < self.code.len() {
-                                print!("{}", self.code[self.ip] as char);
-                            }
-                            self.ip += 1;
-                        }
-                        2 => { // Newline
-                            println!("");
-                            self.ip += 1;
-                        }
-                        _ => {
-                            return Err("Unknown syscall");
-                        }
-                    }
-                }
-                
-                _ => {
-                    return Err("Unknown instruction");
-                }
+impl Environment for KernelEnv {
+    fn write(&mut self, bytes: &[u8]) {
+        unsafe { syscall::syscall(SyscallNumber::Write as usize, 1, bytes.as_ptr() as usize, bytes.len()) };
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let mut byte = [0u8];
+        let count = unsafe { syscall::syscall(SyscallNumber::Read as usize, 0, byte.as_mut_ptr() as usize, 1) };
+        if count == 1 { Some(byte[0]) } else { None }
+    }
+
+    fn load(&mut self, path: &str) -> Option<Vec<u8>> {
+        let fd = unsafe { syscall::syscall(SyscallNumber::Open as usize, path.as_ptr() as usize, path.len(), 0) };
+        if fd < 0 {
+            return None;
+        }
+
+        let mut content = Vec::new();
+        let mut chunk = [0u8; 512];
+        let result = loop {
+            let count = unsafe {
+                syscall::syscall(SyscallNumber::Read as usize, fd as usize, chunk.as_mut_ptr() as usize, chunk.len())
+            };
+            if count <= 0 {
+                break count;
             }
+            content.extend_from_slice(&chunk[..count as usize]);
+        };
+        unsafe { syscall::syscall(SyscallNumber::Close as usize, fd as usize, 0, 0) };
+        (result == 0).then_some(content)
+    }
+
+    fn store(&mut self, path: &str, content: Vec<u8>) -> Result<(), isize> {
+        let flags = syscall::O_WRITE | syscall::O_CREATE | syscall::O_TRUNCATE;
+        let fd = unsafe { syscall::syscall(SyscallNumber::Open as usize, path.as_ptr() as usize, path.len(), flags) };
+        if fd < 0 {
+            return Err(-fd);
+        }
+
+        let written = unsafe {
+            syscall::syscall(SyscallNumber::Write as usize, fd as usize, content.as_ptr() as usize, content.len())
+        };
+        unsafe { syscall::syscall(SyscallNumber::Close as usize, fd as usize, 0, 0) };
+        match written {
+            e if e < 0 => Err(-e),
+            count if count as usize != content.len() => Err(ENOSPC),
+            _ => Ok(()),
+        }
+    }
+
+    fn uptime_ms(&mut self) -> u64 {
+        unsafe { syscall::syscall(SyscallNumber::Uptime as usize, 0, 0, 0) as u64 }
+    }
+
+    fn exit(&mut self, code: i32) {
+        unsafe { syscall::syscall(SyscallNumber::Exit as usize, code as usize, 0, 0) };
+    }
+
+    /// Pointer arguments are data memory offsets and are turned into
+    /// addresses here; a negative `waitpid` status offset stores nothing
+    fn syscall(&mut self, number: i64, args: [i64; 3], memory: &mut [u8]) -> i64 {
+        let mut words = args.map(|arg| arg as usize);
+        if let Some(buffer) = syscall::buffer_arg(number as usize, words) {
+            let offset = args[buffer.arg];
+            let in_memory = usize::try_from(offset).ok()
+                .filter(|&start| start.checked_add(buffer.len).is_some_and(|end| end <= memory.len()));
+            words[buffer.arg] = match in_memory {
+                Some(start) => memory.as_mut_ptr() as usize + start,
+                None if buffer.optional && offset < 0 => 0,
+                None => return -(EFAULT as i64),
+            };
         }
-        
-        Ok(())
+        // SAFETY: every pointer argument was checked to lie in `memory`
+        unsafe { syscall::syscall(number as usize, words[0], words[1], words[2]) as i64 }
     }
+
+    /// Only an executor counted in `CODE_AREA_USERS` may place code, or
+    /// the area could be reclaimed under it
+    fn install_code(&mut self, code: &[u8]) -> Option<usize> {
+        self.code_area.as_ref()?;
+        install_code(code)
+    }
+}
+
+/// Validate and verify a bytecode executable and set up an interpreter
+/// for it. With `jit`, its blocks are compiled to native code as they
+/// are reached.
+pub fn prepare(image: &[u8], jit: bool) -> Result<Executor<KernelEnv>, &'static str> {
+    let program = bytecode::parse(image)?;
+    if let Err(e) = verifier::verify(&program) {
+        println!("Verification failed at {}", e);
+        return Err("Program rejected by verifier");
+    }
+    serial_println!(
+        "bytecode: entry={} code={} data={} rodata={} memory={}",
+        program.entry,
+        program.code.len(),
+        program.data.len(),
+        program.rodata.len(),
+        program.memory
+    );
+    if !jit {
+        return Ok(Executor::new(program, KernelEnv { code_area: None }));
+    }
+    reclaim_code_area();
+    Ok(Executor::new(program, KernelEnv { code_area: Some(CodeAreaUser::new()) }).with_jit())
+}
+
+/// Load a file from the filesystem as a new child of the current process.
+/// It does not run until the scheduler gives it a slice. With `jit`, its
+/// blocks are compiled to native code as they are reached.
+pub fn start(path: &str, argv: &[&str], envp: &[&str], jit: bool) -> Result<Pid, &'static str> {
+    let content = vfs::VFS.lock().read_file(path)?;
+    let vm = prepare(&content, jit)?.with_args(strings(argv), strings(envp));
+
+    let mut pm = process::PROCESS_MANAGER.lock();
+    let pid = pm.create_process(String::from(path), content)?;
+    if let Some(process) = pm.get_process_mut(pid) {
+        process.entry_point = vm.ip();
+        process.instruction_pointer = vm.ip();
+        process.vm = Some(vm);
+    }
+    Ok(pid)
+}
+
+/// Run a file from the filesystem as a child of the current process.
+///
+/// Returns once the child has finished or been interrupted; its exit status
+/// stays in the process table until the parent collects it with `reap`.
+pub fn spawn(path: &str, argv: &[&str], envp: &[&str], jit: bool) -> Result<Pid, &'static str> {
+    let pid = start(path, argv, envp, jit)?;
+    scheduler::wait(pid);
+    Ok(pid)
 }
 
-/// Execute a program from bytecode
-pub fn execute_program(code: Vec<u8>) -> Result<(), &'static str> {
-    let mut executor = Executor::new(code);
-    executor.execute()
+fn strings(list: &[&str]) -> Vec<String> {
+    list.iter().map(|s| String::from(*s)).collect()
 }
@@ -0,0 +1,1143 @@
+//! FAT12/16/32 filesystem on a block device
+//!
+//! Mounted with `mount fat <disk> <dir>`, from a whole disk or the first
+//! FAT partition of an MBR-partitioned one, such as an image made with
+//! `mkfs.fat` or `mformat`. FAT has no inodes, so a file's node number is
+//! the position of its directory entry: the entry's byte offset in the
+//! volume divided by 32, which stays put for the life of the file. The
+//! root directory has no entry and is node 0, where the boot sector lies.
+//!
+//! Long file names are read and written; names that fit 8.3 get a short
+//! entry alone. Timestamps are taken as UTC, and the only permission FAT
+//! records is the read-only attribute, which clears the write bits.
+use crate::block::{self, SECTOR_SIZE};
+use crate::rtc::{self, DateTime};
+use crate::vfs::{DirEntry, FileSystem, FileType, Ino, Stat};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+const ROOT: Ino = 0;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_SIZE;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Attribute combination marking a long name entry
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First name byte of a free entry, and of the first entry past the end
+/// of a directory
+const DELETED: u8 = 0xE5;
+const END: u8 = 0x00;
+
+/// Ordinal flag on the long name entry stored first, which holds the end
+/// of the name
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Byte offsets of the 13 UCS-2 characters in a long name entry
+const LONG_NAME_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const CHARS_PER_LONG_ENTRY: usize = LONG_NAME_OFFSETS.len();
+const MAX_NAME: usize = 255;
+const MAX_LONG_ENTRIES: usize = MAX_NAME.div_ceil(CHARS_PER_LONG_ENTRY);
+
+/// Case flags of short names that are all lowercase
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+/// MBR partition types holding a FAT volume
+const FAT_PARTITIONS: [u8; 6] = [0x01, 0x04, 0x06, 0x0B, 0x0C, 0x0E];
+
+/// Punctuation allowed in short names besides letters and digits
+const SHORT_NAME_PUNCTUATION: &[u8] = b"!#$%&'()-@^_`{}~";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Smallest FAT entry marking the end of a chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+}
+
+/// Where a directory's entries are
+#[derive(Debug, Clone, Copy)]
+enum Dir {
+    /// The fixed root directory area of FAT12 and FAT16
+    FixedRoot,
+    Chain(u32),
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn first_cluster(entry: &[u8]) -> u32 {
+    u32::from(read_u16(entry, 26)) | u32::from(read_u16(entry, 20)) << 16
+}
+
+fn set_first_cluster(entry: &mut [u8], cluster: u32) {
+    write_u16(entry, 26, cluster as u16);
+    write_u16(entry, 20, (cluster >> 16) as u16);
+}
+
+fn is_directory(entry: &[u8]) -> bool {
+    entry[11] & ATTR_DIRECTORY != 0
+}
+
+/// Checksum of a short name, repeated in each of its long name entries
+fn checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// FAT date and time of a Unix timestamp
+fn fat_timestamp(seconds: u64) -> (u16, u16) {
+    let time = DateTime::from_unix(seconds);
+    let year = time.year.clamp(1980, 2107) - 1980;
+    let date = (year << 9 | time.month << 5 | time.day) as u16;
+    let clock = (time.hour << 11 | time.minute << 5 | (time.second / 2)) as u16;
+    (date, clock)
+}
+
+/// Unix timestamp of a FAT date and time
+fn unix_time(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+    DateTime {
+        year: 1980 + u32::from(date >> 9),
+        month: u32::from(date >> 5 & 0x0F).max(1),
+        day: u32::from(date & 0x1F).max(1),
+        hour: u32::from(time >> 11),
+        minute: u32::from(time >> 5 & 0x3F),
+        second: u32::from(time & 0x1F) * 2,
+    }
+    .to_unix()
+}
+
+/// Whether `name` may be stored in a directory
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.encode_utf16().count() <= MAX_NAME
+        && !name.ends_with(['.', ' '])
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+}
+
+/// The 8.3 entry name and case flags of `name`, if it has that form
+fn short_form(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.split_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut flags = 0;
+    let (base_field, ext_field) = short.split_at_mut(8);
+    for (part, field, lower_flag) in [(base, base_field, LOWER_BASE), (ext, ext_field, LOWER_EXT)] {
+        let bytes = part.as_bytes();
+        if !bytes.iter().all(|b| b.is_ascii_alphanumeric() || SHORT_NAME_PUNCTUATION.contains(b)) {
+            return None;
+        }
+        // Short names are stored in capitals; a part in lowercase is
+        // flagged, and one mixing both needs a long name
+        let upper = bytes.iter().any(u8::is_ascii_uppercase);
+        let lower = bytes.iter().any(u8::is_ascii_lowercase);
+        if upper && lower {
+            return None;
+        }
+        if lower {
+            flags |= lower_flag;
+        }
+        field[..bytes.len()].copy_from_slice(&part.to_ascii_uppercase().into_bytes());
+    }
+    Some((short, flags))
+}
+
+/// Letters and digits of `part` for a short alias, with anything else
+/// unrepresentable replaced by `_`
+fn alias_chars(part: &str) -> impl Iterator<Item = u8> + '_ {
+    part.chars()
+        .filter(|&c| c != ' ' && c != '.')
+        .map(|c| match c.to_ascii_uppercase() {
+            c if c.is_ascii_alphanumeric() || (c.is_ascii() && SHORT_NAME_PUNCTUATION.contains(&(c as u8))) => c as u8,
+            _ => b'_',
+        })
+}
+
+/// A directory entry with its name decoded
+#[derive(Clone)]
+struct Entry {
+    /// Slot of the short entry: the file's node number
+    ino: Ino,
+    raw: [u8; ENTRY_SIZE],
+    /// Slots of the long name entries before it
+    long_slots: [Ino; MAX_LONG_ENTRIES],
+    long_count: usize,
+    name: [u16; MAX_NAME],
+    name_len: usize,
+}
+
+impl Entry {
+    fn is_dot(&self) -> bool {
+        self.raw[0] == b'.'
+    }
+
+    fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.name[..self.name_len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    /// Names compare without regard to ASCII case, as FAT does
+    fn name_matches(&self, name: &str) -> bool {
+        self.chars().map(|c| c.to_ascii_lowercase()).eq(name.chars().map(|c| c.to_ascii_lowercase()))
+    }
+
+    fn name(&self) -> String {
+        self.chars().collect()
+    }
+}
+
+/// Long name entries collected while scanning towards their short entry
+struct LongName {
+    units: [u16; MAX_LONG_ENTRIES * CHARS_PER_LONG_ENTRY],
+    slots: [Ino; MAX_LONG_ENTRIES],
+    count: usize,
+    /// Ordinal the next entry must have; 0 once the name is complete
+    next: u8,
+    checksum: u8,
+    active: bool,
+}
+
+impl LongName {
+    fn new() -> Self {
+        LongName {
+            units: [0; MAX_LONG_ENTRIES * CHARS_PER_LONG_ENTRY],
+            slots: [0; MAX_LONG_ENTRIES],
+            count: 0,
+            next: 0,
+            checksum: 0,
+            active: false,
+        }
+    }
+
+    fn add(&mut self, slot: Ino, raw: &[u8]) {
+        let ordinal = raw[0] & 0x1F;
+        if raw[0] & LAST_LONG_ENTRY != 0 {
+            self.active = (1..=MAX_LONG_ENTRIES as u8).contains(&ordinal);
+            self.checksum = raw[13];
+            self.count = 0;
+            self.units.fill(0xFFFF);
+        } else if !self.active || ordinal != self.next || raw[13] != self.checksum {
+            self.active = false;
+        }
+        if !self.active {
+            return;
+        }
+        let start = usize::from(ordinal - 1) * CHARS_PER_LONG_ENTRY;
+        for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+            self.units[start + i] = read_u16(raw, offset);
+        }
+        self.slots[self.count] = slot;
+        self.count += 1;
+        self.next = ordinal - 1;
+    }
+
+    /// Finish an entry with the short entry that follows the long ones,
+    /// using the long name if it belongs to it
+    fn finish(&mut self, slot: Ino, raw: &[u8]) -> Entry {
+        let mut entry = Entry {
+            ino: slot,
+            raw: [0; ENTRY_SIZE],
+            long_slots: [0; MAX_LONG_ENTRIES],
+            long_count: 0,
+            name: [0; MAX_NAME],
+            name_len: 0,
+        };
+        entry.raw.copy_from_slice(raw);
+
+        if self.active && self.next == 0 && self.checksum == checksum(raw) {
+            let len = self.units.iter().position(|&unit| unit == 0 || unit == 0xFFFF).unwrap_or(self.units.len());
+            entry.name_len = len.min(MAX_NAME);
+            entry.name[..entry.name_len].copy_from_slice(&self.units[..entry.name_len]);
+            entry.long_slots = self.slots;
+            entry.long_count = self.count;
+        } else {
+            let mut short = [0u8; 11];
+            short.copy_from_slice(&raw[..11]);
+            // A name starting with 0xE5 is stored with 0x05
+            if short[0] == 0x05 {
+                short[0] = DELETED;
+            }
+            let (base, ext) = short.split_at(8);
+            let base_len = base.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+            let ext_len = ext.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+            let mut push = |byte: u8, lower: bool| {
+                let byte = if lower { byte.to_ascii_lowercase() } else { byte };
+                entry.name[entry.name_len] = u16::from(byte);
+                entry.name_len += 1;
+            };
+            for &byte in &base[..base_len] {
+                push(byte, raw[12] & LOWER_BASE != 0);
+            }
+            if ext_len > 0 {
+                push(b'.', false);
+                for &byte in &ext[..ext_len] {
+                    push(byte, raw[12] & LOWER_EXT != 0);
+                }
+            }
+        }
+        self.active = false;
+        entry
+    }
+}
+
+pub struct FatFs {
+    disk: usize,
+    /// First sector of the volume on the disk
+    start: u64,
+    fat_type: FatType,
+    sectors_per_cluster: u64,
+    /// Sectors within the volume
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    root_dir_start: u64,
+    root_dir_sectors: u64,
+    data_start: u64,
+    /// Root directory cluster on FAT32
+    root_cluster: u32,
+    cluster_count: u32,
+    /// Sector of the FAT32 free cluster hints, if there is one
+    fs_info: Option<u64>,
+    /// Whether the free cluster hints have been marked unknown since
+    /// clusters started being allocated and freed
+    fs_info_cleared: bool,
+    /// Where to start looking for a free cluster
+    next_free: u32,
+    /// The last cluster looked up in a file: node, index in the chain and
+    /// cluster, so sequential access does not walk the chain from the start
+    cursor: Option<(Ino, usize, u32)>,
+    /// Time of mounting, shown for the root directory
+    mounted: u64,
+}
+
+impl FatFs {
+    /// Mount the FAT volume on a disk or in its first FAT partition
+    pub fn mount(disk: usize) -> Result<Self, &'static str> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        block::read_sectors(disk, 0, &mut sector)?;
+        if let Some(fs) = Self::from_boot_sector(disk, 0, &sector) {
+            return Ok(fs);
+        }
+        if read_u16(&sector, 510) != 0xAA55 {
+            return Err("Not a FAT filesystem");
+        }
+        let partitions = sector;
+        for entry in partitions[446..510].as_chunks::<16>().0 {
+            if !FAT_PARTITIONS.contains(&entry[4]) {
+                continue;
+            }
+            let start = u64::from(read_u32(entry, 8));
+            block::read_sectors(disk, start, &mut sector)?;
+            if let Some(fs) = Self::from_boot_sector(disk, start, &sector) {
+                return Ok(fs);
+            }
+        }
+        Err("Not a FAT filesystem")
+    }
+
+    /// Read the volume geometry from a boot sector, if it is a FAT one
+    fn from_boot_sector(disk: usize, start: u64, boot: &[u8]) -> Option<Self> {
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved = u64::from(read_u16(boot, 14));
+        let fat_count = u64::from(boot[16]);
+        let root_entries = u64::from(read_u16(boot, 17));
+        let total = match read_u16(boot, 19) {
+            0 => u64::from(read_u32(boot, 32)),
+            total => u64::from(total),
+        };
+        let fat_sectors = match read_u16(boot, 22) {
+            0 => u64::from(read_u32(boot, 36)),
+            size => u64::from(size),
+        };
+        if read_u16(boot, 510) != 0xAA55
+            || usize::from(read_u16(boot, 11)) != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return None;
+        }
+
+        let root_dir_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(SECTOR_SIZE as u64);
+        let fat_start = reserved;
+        let root_dir_start = fat_start + fat_count * fat_sectors;
+        let data_start = root_dir_start + root_dir_sectors;
+        let cluster_count = total.checked_sub(data_start)? / sectors_per_cluster;
+        // The type follows from the number of clusters alone
+        let fat_type = match cluster_count {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let fs_info = match read_u16(boot, 48) {
+            sector if fat_type == FatType::Fat32 && sector != 0 && u64::from(sector) < reserved => Some(u64::from(sector)),
+            _ => None,
+        };
+
+        Some(FatFs {
+            disk,
+            start,
+            fat_type,
+            sectors_per_cluster,
+            fat_start,
+            fat_sectors,
+            fat_count,
+            root_dir_start,
+            root_dir_sectors,
+            data_start,
+            root_cluster: if fat_type == FatType::Fat32 { read_u32(boot, 44) } else { 0 },
+            cluster_count: cluster_count as u32,
+            fs_info,
+            fs_info_cleared: false,
+            next_free: 2,
+            cursor: None,
+            mounted: rtc::now(),
+        })
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    /// Whether `cluster` names a cluster in the data area. Cluster numbers
+    /// read from disk are checked with this before they are followed.
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    /// Byte offset of a cluster in the volume. `cluster` must be a data
+    /// cluster.
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        (self.data_start + u64::from(cluster - 2) * self.sectors_per_cluster) * SECTOR_SIZE as u64
+    }
+
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let within = (position % SECTOR_SIZE as u64) as usize;
+            let count = (SECTOR_SIZE - within).min(buf.len() - done);
+            block::read_sectors(self.disk, self.start + position / SECTOR_SIZE as u64, &mut sector)?;
+            buf[done..done + count].copy_from_slice(&sector[within..within + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Write bytes, reading back the sectors they only partly cover
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let lba = self.start + position / SECTOR_SIZE as u64;
+            let within = (position % SECTOR_SIZE as u64) as usize;
+            let count = (SECTOR_SIZE - within).min(data.len() - done);
+            if count < SECTOR_SIZE {
+                block::read_sectors(self.disk, lba, &mut sector)?;
+            }
+            sector[within..within + count].copy_from_slice(&data[done..done + count]);
+            block::write_sectors(self.disk, lba, &sector)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn read_entry(&self, ino: Ino) -> Result<[u8; ENTRY_SIZE], &'static str> {
+        let mut entry = [0u8; ENTRY_SIZE];
+        self.read_bytes((ino * ENTRY_SIZE) as u64, &mut entry)?;
+        Ok(entry)
+    }
+
+    fn write_entry(&self, ino: Ino, entry: &[u8; ENTRY_SIZE]) -> Result<(), &'static str> {
+        self.write_bytes((ino * ENTRY_SIZE) as u64, entry)
+    }
+
+    /// The short entry of a live file or directory
+    fn live_entry(&self, ino: Ino) -> Result<[u8; ENTRY_SIZE], &'static str> {
+        let entry = self.read_entry(ino)?;
+        if matches!(entry[0], END | DELETED) || entry[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            return Err("File not found");
+        }
+        Ok(entry)
+    }
+
+    /// Byte offset of cluster `cluster`'s entry within a FAT
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        let cluster = u64::from(cluster);
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, &'static str> {
+        let offset = self.fat_start * SECTOR_SIZE as u64 + self.fat_offset(cluster);
+        let mut bytes = [0u8; 4];
+        let len = if self.fat_type == FatType::Fat32 { 4 } else { 2 };
+        self.read_bytes(offset, &mut bytes[..len])?;
+        let value = u32::from_le_bytes(bytes);
+        Ok(match self.fat_type {
+            FatType::Fat12 if cluster & 1 == 1 => value >> 4,
+            FatType::Fat12 => value & 0xFFF,
+            FatType::Fat16 => value,
+            FatType::Fat32 => value & 0x0FFF_FFFF,
+        })
+    }
+
+    /// Set a cluster's entry in every copy of the FAT
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), &'static str> {
+        self.clear_fs_info()?;
+        for copy in 0..self.fat_count {
+            let offset = (self.fat_start + copy * self.fat_sectors) * SECTOR_SIZE as u64 + self.fat_offset(cluster);
+            let mut bytes = [0u8; 4];
+            let len = if self.fat_type == FatType::Fat32 { 4 } else { 2 };
+            self.read_bytes(offset, &mut bytes[..len])?;
+            let old = u32::from_le_bytes(bytes);
+            let new = match self.fat_type {
+                // Twelve-bit entries share a byte with their neighbour
+                FatType::Fat12 if cluster & 1 == 1 => (old & 0x000F) | (value << 4),
+                FatType::Fat12 => (old & 0xF000) | (value & 0xFFF),
+                FatType::Fat16 => value,
+                // The top four bits are reserved and kept
+                FatType::Fat32 => (old & 0xF000_0000) | (value & 0x0FFF_FFFF),
+            };
+            self.write_bytes(offset, &new.to_le_bytes()[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Mark the FAT32 free cluster count and hint unknown, since they are
+    /// not kept up to date
+    fn clear_fs_info(&mut self) -> Result<(), &'static str> {
+        let sector = match self.fs_info {
+            Some(sector) if !self.fs_info_cleared => sector,
+            _ => return Ok(()),
+        };
+        self.fs_info_cleared = true;
+        let offset = sector * SECTOR_SIZE as u64;
+        let mut signature = [0u8; 4];
+        self.read_bytes(offset, &mut signature)?;
+        if u32::from_le_bytes(signature) == 0x4161_5252 {
+            self.write_bytes(offset + 488, &[0xFF; 8])?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, &'static str> {
+        let next = self.fat_entry(cluster)?;
+        if next >= self.fat_type.end_of_chain() {
+            return Ok(None);
+        }
+        if !self.is_data_cluster(next) {
+            return Err("Corrupt filesystem");
+        }
+        Ok(Some(next))
+    }
+
+    /// Take a free cluster, zero it and append it to the chain ending at
+    /// `previous`
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, &'static str> {
+        let first = 2;
+        let end = self.cluster_count + 2;
+        let start = self.next_free.clamp(first, end - 1);
+        let cluster = (start..end).chain(first..start)
+            .find(|&cluster| self.fat_entry(cluster) == Ok(0))
+            .ok_or("Filesystem full")?;
+
+        self.set_fat_entry(cluster, 0x0FFF_FFFF)?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        let zeros = [0u8; SECTOR_SIZE];
+        let first_sector = self.cluster_offset(cluster) / SECTOR_SIZE as u64;
+        for sector in 0..self.sectors_per_cluster {
+            block::write_sectors(self.disk, self.start + first_sector + sector, &zeros)?;
+        }
+        self.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    /// Return a chain of clusters to the free pool
+    fn free_chain(&mut self, first: u32) -> Result<(), &'static str> {
+        let mut cluster = Some(first);
+        let mut remaining = self.cluster_count;
+        while let Some(current) = cluster {
+            if !self.is_data_cluster(current) || remaining == 0 {
+                return Err("Corrupt filesystem");
+            }
+            remaining -= 1;
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(current, 0)?;
+        }
+        self.cursor = None;
+        Ok(())
+    }
+
+    /// Cluster number `index` of the chain starting at `first`
+    fn cluster_at(&mut self, ino: Ino, first: u32, index: usize) -> Result<u32, &'static str> {
+        let (mut position, mut cluster) = match self.cursor {
+            Some((cursor_ino, position, cluster)) if cursor_ino == ino && position <= index => (position, cluster),
+            _ => (0, first),
+        };
+        if !self.is_data_cluster(cluster) {
+            return Err("Corrupt filesystem");
+        }
+        while position < index {
+            cluster = self.next_cluster(cluster)?.ok_or("Corrupt filesystem")?;
+            position += 1;
+        }
+        self.cursor = Some((ino, index, cluster));
+        Ok(cluster)
+    }
+
+    /// Make the chain of `entry` at least `count` clusters long
+    fn ensure_clusters(&mut self, entry: &mut [u8; ENTRY_SIZE], count: usize) -> Result<(), &'static str> {
+        if count == 0 {
+            return Ok(());
+        }
+        let mut last = match first_cluster(entry) {
+            0 => {
+                let cluster = self.allocate_cluster(None)?;
+                set_first_cluster(entry, cluster);
+                cluster
+            }
+            first if self.is_data_cluster(first) => first,
+            _ => return Err("Corrupt filesystem"),
+        };
+        let mut length = 1;
+        while let Some(next) = self.next_cluster(last)? {
+            last = next;
+            length += 1;
+        }
+        while length < count {
+            last = self.allocate_cluster(Some(last))?;
+            length += 1;
+        }
+        Ok(())
+    }
+
+    /// Volume byte offset of file position `position`, and how many bytes
+    /// follow it in the same cluster
+    fn locate(&mut self, ino: Ino, first: u32, position: usize) -> Result<(u64, usize), &'static str> {
+        let cluster_size = self.cluster_size();
+        let cluster = self.cluster_at(ino, first, position / cluster_size)?;
+        let within = position % cluster_size;
+        Ok((self.cluster_offset(cluster) + within as u64, cluster_size - within))
+    }
+
+    /// Overwrite part of a file with zeros
+    fn zero_range(&mut self, ino: Ino, first: u32, from: usize, to: usize) -> Result<(), &'static str> {
+        let zeros = [0u8; SECTOR_SIZE];
+        let mut position = from;
+        while position < to {
+            let (offset, available) = self.locate(ino, first, position)?;
+            let count = available.min(to - position).min(SECTOR_SIZE);
+            self.write_bytes(offset, &zeros[..count])?;
+            position += count;
+        }
+        Ok(())
+    }
+
+    /// Update the size and modification time of a file
+    fn touch(&self, ino: Ino, entry: &mut [u8; ENTRY_SIZE], size: usize) -> Result<(), &'static str> {
+        let (date, time) = fat_timestamp(rtc::now());
+        write_u16(entry, 22, time);
+        write_u16(entry, 24, date);
+        write_u16(entry, 18, date);
+        write_u32(entry, 28, size as u32);
+        entry[11] |= ATTR_ARCHIVE;
+        self.write_entry(ino, entry)
+    }
+
+    fn dir_of(&self, ino: Ino) -> Result<Dir, &'static str> {
+        if ino == ROOT {
+            return Ok(match self.fat_type {
+                FatType::Fat32 => Dir::Chain(self.root_cluster),
+                _ => Dir::FixedRoot,
+            });
+        }
+        let entry = self.live_entry(ino)?;
+        if !is_directory(&entry) {
+            return Err("Not a directory");
+        }
+        Ok(Dir::Chain(first_cluster(&entry)))
+    }
+
+    /// Call `visit` with each sector of a directory until it returns
+    /// `Some`
+    fn for_each_sector<T>(
+        &self,
+        dir: Dir,
+        mut visit: impl FnMut(u64) -> Result<Option<T>, &'static str>,
+    ) -> Result<Option<T>, &'static str> {
+        match dir {
+            Dir::FixedRoot => {
+                for sector in self.root_dir_start..self.root_dir_start + self.root_dir_sectors {
+                    if let Some(found) = visit(sector)? {
+                        return Ok(Some(found));
+                    }
+                }
+            }
+            Dir::Chain(first) => {
+                let mut cluster = Some(first);
+                let mut remaining = self.cluster_count;
+                while let Some(current) = cluster {
+                    if !self.is_data_cluster(current) || remaining == 0 {
+                        return Err("Corrupt filesystem");
+                    }
+                    remaining -= 1;
+                    let first_sector = self.cluster_offset(current) / SECTOR_SIZE as u64;
+                    for sector in first_sector..first_sector + self.sectors_per_cluster {
+                        if let Some(found) = visit(sector)? {
+                            return Ok(Some(found));
+                        }
+                    }
+                    cluster = self.next_cluster(current)?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Call `visit` with each slot of a directory and its raw entry, in
+    /// order, until it returns `Some`
+    fn scan<T>(&self, dir: Dir, mut visit: impl FnMut(Ino, &[u8]) -> Option<T>) -> Result<Option<T>, &'static str> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.for_each_sector(dir, |sector| {
+            block::read_sectors(self.disk, self.start + sector, &mut buf)?;
+            for (index, raw) in buf.as_chunks::<ENTRY_SIZE>().0.iter().enumerate() {
+                if let Some(found) = visit(sector as usize * ENTRIES_PER_SECTOR + index, raw) {
+                    return Ok(Some(found));
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    /// Call `visit` with each file and subdirectory, including `.` and
+    /// `..`, until it returns `Some`
+    fn entries<T>(&self, dir: Dir, mut visit: impl FnMut(&Entry) -> Option<T>) -> Result<Option<T>, &'static str> {
+        let mut long = LongName::new();
+        self.scan(dir, |slot, raw| {
+            if matches!(raw[0], END | DELETED) {
+                long.active = false;
+                return None;
+            }
+            if raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                long.add(slot, raw);
+                return None;
+            }
+            let entry = long.finish(slot, raw);
+            if raw[11] & ATTR_VOLUME_ID != 0 {
+                return None;
+            }
+            visit(&entry)
+        })
+    }
+
+    fn find(&self, dir: Ino, name: &str) -> Result<Entry, &'static str> {
+        let mut found = None;
+        self.entries(self.dir_of(dir)?, |entry| {
+            if !entry.is_dot() && entry.name_matches(name) {
+                found = Some(entry.clone());
+                return Some(());
+            }
+            None
+        })?;
+        found.ok_or("File not found")
+    }
+
+    /// A short name not yet used in `dir`, of the form `BASE~N.EXT`
+    fn alias(&self, dir: Dir, name: &str) -> Result<[u8; 11], &'static str> {
+        let (stem, ext) = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.trim_start_matches('.').is_empty() => (stem, ext),
+            _ => (name, ""),
+        };
+        let mut short = [b' '; 11];
+        for (slot, byte) in short[8..].iter_mut().zip(alias_chars(ext)) {
+            *slot = byte;
+        }
+        let base: Vec<u8> = alias_chars(stem).take(6).collect();
+
+        for number in 1..1_000_000u32 {
+            let mut tail = [0u8; 7];
+            let mut digits = number;
+            let mut len = 0;
+            while digits > 0 {
+                tail[6 - len] = b'0' + (digits % 10) as u8;
+                digits /= 10;
+                len += 1;
+            }
+            tail[6 - len] = b'~';
+            let tail = &tail[6 - len..];
+            let keep = base.len().min(8 - tail.len());
+            short[..8].fill(b' ');
+            short[..keep].copy_from_slice(&base[..keep]);
+            short[keep..keep + tail.len()].copy_from_slice(tail);
+
+            let taken = self.scan(dir, |_, raw| {
+                (!matches!(raw[0], END | DELETED) && raw[11] & ATTR_LONG_NAME != ATTR_LONG_NAME && raw[..11] == short)
+                    .then_some(())
+            })?;
+            if taken.is_none() {
+                return Ok(short);
+            }
+        }
+        Err("Directory full")
+    }
+
+    /// `count` free slots in a row, growing the directory if needed
+    fn free_slots(&mut self, dir: Dir, count: usize) -> Result<Vec<Ino>, &'static str> {
+        loop {
+            let mut run = Vec::new();
+            let found = self.scan(dir, |slot, raw| {
+                if matches!(raw[0], END | DELETED) {
+                    run.push(slot);
+                } else {
+                    run.clear();
+                }
+                (run.len() == count).then_some(())
+            })?;
+            if found.is_some() {
+                return Ok(run);
+            }
+            let first = match dir {
+                Dir::FixedRoot => return Err("Directory full"),
+                Dir::Chain(first) => first,
+            };
+            let mut last = first;
+            while let Some(next) = self.next_cluster(last)? {
+                last = next;
+            }
+            self.allocate_cluster(Some(last))?;
+        }
+    }
+
+    /// Count the entries of a directory, leaving out `.` and `..`
+    fn entry_count(&self, dir: Dir) -> Result<usize, &'static str> {
+        let mut count = 0;
+        self.entries(dir, |entry| {
+            if !entry.is_dot() {
+                count += 1;
+            }
+            None::<()>
+        })?;
+        Ok(count)
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        match self.fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, &'static str> {
+        self.find(dir, name).map(|entry| entry.ino)
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat, &'static str> {
+        if ino == ROOT {
+            let size = self.entry_count(self.dir_of(ROOT)?)?;
+            return Ok(Stat {
+                ino,
+                file_type: FileType::Directory,
+                mode: 0o755,
+                uid: 0,
+                gid: 0,
+                nlink: 2,
+                size,
+                created: self.mounted,
+                modified: self.mounted,
+            });
+        }
+        let entry = self.live_entry(ino)?;
+        let (file_type, mut mode, nlink, size) = if is_directory(&entry) {
+            (FileType::Directory, 0o755, 2, self.entry_count(Dir::Chain(first_cluster(&entry)))?)
+        } else {
+            (FileType::Regular, 0o644, 1, read_u32(&entry, 28) as usize)
+        };
+        if entry[11] & ATTR_READ_ONLY != 0 {
+            mode &= !0o222;
+        }
+        Ok(Stat {
+            ino,
+            file_type,
+            mode,
+            uid: 0,
+            gid: 0,
+            nlink,
+            size,
+            created: unix_time(read_u16(&entry, 16), read_u16(&entry, 14)),
+            modified: unix_time(read_u16(&entry, 24), read_u16(&entry, 22)),
+        })
+    }
+
+    fn read_at(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if ino == ROOT {
+            return Err("Is a directory");
+        }
+        let entry = self.live_entry(ino)?;
+        if is_directory(&entry) {
+            return Err("Is a directory");
+        }
+        let size = read_u32(&entry, 28) as usize;
+        let end = size.min(offset.saturating_add(buf.len()));
+        let mut position = offset;
+        while position < end {
+            let (disk_offset, available) = self.locate(ino, first_cluster(&entry), position)?;
+            let count = available.min(end - position);
+            self.read_bytes(disk_offset, &mut buf[position - offset..][..count])?;
+            position += count;
+        }
+        Ok(end.saturating_sub(offset))
+    }
+
+    fn write_at(&mut self, ino: Ino, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        if ino == ROOT {
+            return Err("Is a directory");
+        }
+        let mut entry = self.live_entry(ino)?;
+        if is_directory(&entry) {
+            return Err("Is a directory");
+        }
+        if entry[11] & ATTR_READ_ONLY != 0 {
+            return Err("Permission denied");
+        }
+        let end = offset.checked_add(data.len()).filter(|&end| end <= u32::MAX as usize).ok_or("File too large")?;
+        let size = read_u32(&entry, 28) as usize;
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        self.ensure_clusters(&mut entry, end.div_ceil(self.cluster_size()))?;
+        let first = first_cluster(&entry);
+        // Bytes past the old end of the last cluster are whatever was left
+        // there, so a gap before `offset` is cleared
+        if offset > size {
+            self.zero_range(ino, first, size, offset.min(size.next_multiple_of(self.cluster_size())))?;
+        }
+        let mut position = offset;
+        while position < end {
+            let (disk_offset, available) = self.locate(ino, first, position)?;
+            let count = available.min(end - position);
+            self.write_bytes(disk_offset, &data[position - offset..][..count])?;
+            position += count;
+        }
+        self.touch(ino, &mut entry, size.max(end))?;
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: Ino, new_size: usize) -> Result<(), &'static str> {
+        if ino == ROOT {
+            return Err("Is a directory");
+        }
+        let mut entry = self.live_entry(ino)?;
+        if is_directory(&entry) {
+            return Err("Is a directory");
+        }
+        if new_size > u32::MAX as usize {
+            return Err("File too large");
+        }
+        let size = read_u32(&entry, 28) as usize;
+        let cluster_size = self.cluster_size();
+        match new_size.cmp(&size) {
+            Ordering::Greater => {
+                self.ensure_clusters(&mut entry, new_size.div_ceil(cluster_size))?;
+                let first = first_cluster(&entry);
+                self.zero_range(ino, first, size, new_size.min(size.next_multiple_of(cluster_size)))?;
+            }
+            Ordering::Less => {
+                let first = first_cluster(&entry);
+                let keep = new_size.div_ceil(cluster_size);
+                if keep == 0 {
+                    if first != 0 {
+                        self.free_chain(first)?;
+                    }
+                    set_first_cluster(&mut entry, 0);
+                } else {
+                    let last = self.cluster_at(ino, first, keep - 1)?;
+                    if let Some(rest) = self.next_cluster(last)? {
+                        self.set_fat_entry(last, 0x0FFF_FFFF)?;
+                        self.free_chain(rest)?;
+                    }
+                }
+                self.cursor = None;
+            }
+            Ordering::Equal => {}
+        }
+        self.touch(ino, &mut entry, new_size)
+    }
+
+    fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, &'static str> {
+        let mut entries = Vec::new();
+        self.entries(self.dir_of(dir)?, |entry| {
+            if !entry.is_dot() {
+                entries.push(DirEntry { name: entry.name(), ino: entry.ino });
+            }
+            None::<()>
+        })?;
+        Ok(entries)
+    }
+
+    fn create(&mut self, dir: Ino, name: &str, file_type: FileType) -> Result<Ino, &'static str> {
+        let location = self.dir_of(dir)?;
+        if !valid_name(name) {
+            return Err("Invalid argument");
+        }
+        if !matches!(file_type, FileType::Regular | FileType::Directory) {
+            return Err("Operation not supported");
+        }
+        if self.find(dir, name).is_ok() {
+            return Err("File exists");
+        }
+
+        let (short, case, long_name) = match short_form(name) {
+            Some((short, case)) => (short, case, None),
+            None => (self.alias(location, name)?, 0, Some(name)),
+        };
+        let units: Vec<u16> = long_name.map_or(Vec::new(), |name| name.encode_utf16().collect());
+        let long_count = units.len().div_ceil(CHARS_PER_LONG_ENTRY);
+        let slots = self.free_slots(location, long_count + 1)?;
+
+        let (date, time) = fat_timestamp(rtc::now());
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[..11].copy_from_slice(&short);
+        entry[12] = case;
+        for (offset, value) in [(14, time), (16, date), (18, date), (22, time), (24, date)] {
+            write_u16(&mut entry, offset, value);
+        }
+
+        if file_type == FileType::Directory {
+            entry[11] = ATTR_DIRECTORY;
+            let cluster = self.allocate_cluster(None)?;
+            set_first_cluster(&mut entry, cluster);
+            // `..` of a directory in the root refers to cluster 0
+            let parent = match location {
+                Dir::Chain(parent) if dir != ROOT => parent,
+                _ => 0,
+            };
+            let mut dot = entry;
+            dot[..11].copy_from_slice(b".          ");
+            dot[12] = 0;
+            let mut dot_dot = dot;
+            dot_dot[..11].copy_from_slice(b"..         ");
+            set_first_cluster(&mut dot_dot, parent);
+            let offset = self.cluster_offset(cluster);
+            self.write_bytes(offset, &dot)?;
+            self.write_bytes(offset + ENTRY_SIZE as u64, &dot_dot)?;
+        } else {
+            entry[11] = ATTR_ARCHIVE;
+        }
+
+        // Long name entries go first, the end of the name first
+        let sum = checksum(&short);
+        for (index, &slot) in slots[..long_count].iter().enumerate() {
+            let ordinal = long_count - index;
+            let mut long = [0u8; ENTRY_SIZE];
+            long[0] = ordinal as u8 | if index == 0 { LAST_LONG_ENTRY } else { 0 };
+            long[11] = ATTR_LONG_NAME;
+            long[13] = sum;
+            for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let position = (ordinal - 1) * CHARS_PER_LONG_ENTRY + i;
+                // The name ends with a 0 and the rest is padded with 0xFFFF
+                let unit = match position.cmp(&units.len()) {
+                    Ordering::Less => units[position],
+                    Ordering::Equal => 0,
+                    Ordering::Greater => 0xFFFF,
+                };
+                write_u16(&mut long, offset, unit);
+            }
+            self.write_entry(slot, &long)?;
+        }
+        let ino = slots[long_count];
+        self.write_entry(ino, &entry)?;
+        Ok(ino)
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), &'static str> {
+        let found = self.find(dir, name)?;
+        if is_directory(&found.raw) && self.entry_count(Dir::Chain(first_cluster(&found.raw)))? > 0 {
+            return Err("Directory not empty");
+        }
+        for &slot in found.long_slots[..found.long_count].iter().chain([&found.ino]) {
+            let mut entry = self.read_entry(slot)?;
+            entry[0] = DELETED;
+            self.write_entry(slot, &entry)?;
+        }
+        match first_cluster(&found.raw) {
+            0 => Ok(()),
+            first => self.free_chain(first),
+        }
+    }
+
+    fn link(&mut self, _ino: Ino, _dir: Ino, _name: &str) -> Result<(), &'static str> {
+        Err("Operation not supported")
+    }
+
+    fn symlink(&mut self, _dir: Ino, _name: &str, _target: &str) -> Result<Ino, &'static str> {
+        Err("Operation not supported")
+    }
+
+    /// FAT only records whether a file may be written
+    fn set_mode(&mut self, ino: Ino, mode: u16) -> Result<(), &'static str> {
+        if ino == ROOT {
+            return Err("Operation not supported");
+        }
+        let mut entry = self.live_entry(ino)?;
+        if mode & 0o200 == 0 {
+            entry[11] |= ATTR_READ_ONLY;
+        } else {
+            entry[11] &= !ATTR_READ_ONLY;
+        }
+        self.write_entry(ino, &entry)
+    }
+}
@@ -0,0 +1,71 @@
+//! Global descriptor table and task state segment
+//!
+//! The bootloader's GDT only describes the kernel. Native programs run in
+//! ring 3, which needs user code and data segments, and a TSS naming the
+//! kernel stack the CPU switches to when an interrupt or `int 0x80`
+//! arrives from user mode.
+use core::ptr::{addr_of, addr_of_mut};
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Only changed through `set_kernel_stack`; the CPU reads it on every
+/// entry from ring 3
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Segment selectors of the kernel's GDT
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub user_data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        // The descriptor only records the TSS's address and size
+        let tss = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
+        (gdt, Selectors { kernel_code, kernel_data, user_code, user_data, tss })
+    };
+}
+
+/// Load the GDT and TSS. Must run before the IDT is built, since its
+/// entries take the current code segment.
+pub fn init() {
+    GDT.0.load();
+    unsafe {
+        CS::set_reg(GDT.1.kernel_code);
+        SS::set_reg(GDT.1.kernel_data);
+        DS::set_reg(GDT.1.kernel_data);
+        ES::set_reg(GDT.1.kernel_data);
+        load_tss(GDT.1.tss);
+    }
+}
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Set the stack used for interrupts and system calls from ring 3,
+/// returning the previous one
+pub fn set_kernel_stack(top: u64) -> u64 {
+    // SAFETY: the TSS is only written here, with interrupts from ring 3
+    // impossible since the kernel is running
+    unsafe {
+        let tss = &mut *addr_of_mut!(TSS);
+        let mut stacks = tss.privilege_stack_table;
+        let previous = stacks[0];
+        stacks[0] = VirtAddr::new(top);
+        tss.privilege_stack_table = stacks;
+        previous.as_u64()
+    }
+}
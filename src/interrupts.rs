@@ -1,12 +1,17 @@
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use crate::println;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
+use crate::{println, usermode};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = 40;
 
+/// Vector native programs make system calls through
+pub const SYSCALL_VECTOR: usize = 0x80;
+
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
@@ -31,16 +36,27 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
         idt[InterruptIndex::Timer.as_usize()]
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
+        // Reachable with `int 0x80` from ring 3
+        unsafe {
+            idt[SYSCALL_VECTOR]
+                .set_handler_addr(VirtAddr::new(usermode::syscall_entry()))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
         idt
     };
 }
 
 pub fn init() {
     IDT.load();
+    crate::time::init();
     unsafe { PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
 }
@@ -49,11 +65,51 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+// Exceptions in a native program end it; in the kernel they are bugs
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    if usermode::from_user(&stack_frame) {
+        usermode::fault("Division by zero", &stack_frame);
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    if usermode::from_user(&stack_frame) {
+        usermode::fault("Invalid instruction", &stack_frame);
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    if usermode::from_user(&stack_frame) {
+        usermode::fault("General protection fault", &stack_frame);
+    }
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}", error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let address = Cr2::read();
+    if usermode::from_user(&stack_frame) {
+        println!(
+            "Segmentation fault at {:#x} accessing {:#x}",
+            stack_frame.instruction_pointer.as_u64(),
+            address.as_u64()
+        );
+        usermode::terminate(-1);
+    }
+    panic!("EXCEPTION: PAGE FAULT at {:#x} ({:?})\n{:#?}", address.as_u64(), error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    crate::time::tick();
+    crate::block::timer_tick(crate::time::uptime_ms());
+
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    usermode::check_interrupt(&stack_frame);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -0,0 +1,439 @@
+//! Bytecode instruction set
+//!
+//! Opcode definitions, operand decoding, arithmetic and VM limits. This
+//! module only depends on `core`, so the host toolchain in `tools/bbx`
+//! builds it too.
+
+/// Simple bytecode instruction set
+///
+/// Stack operations work on 64-bit signed integers. Binary operations pop
+/// the right-hand operand first: `push a; push b; sub` leaves `a - b`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Instruction {
+    // Basic operations
+    Halt = 0x00,
+    PrintChar = 0x01,      // Print character from next byte
+    PrintString = 0x02,    // Print null-terminated string starting at next byte
+    Exit = 0x03,           // Pop an exit status and stop the program
+    PrintInt = 0x04,       // Pop a value and print it in decimal
+    
+    // Stack operations
+    Push = 0x08,           // Push next 4 bytes (little-endian, sign-extended)
+    Pop = 0x09,            // Discard the top value
+    Dup = 0x0A,            // Duplicate the top value
+    Swap = 0x0B,           // Exchange the top two values
+    
+    // Math operations
+    Add = 0x10,
+    Sub = 0x11,
+    Mul = 0x12,
+    Div = 0x13,            // Signed division, faults on zero divisor
+    Mod = 0x14,            // Signed remainder, faults on zero divisor
+    Neg = 0x15,
+    
+    // Bitwise operations
+    And = 0x18,
+    Or = 0x19,
+    Xor = 0x1A,
+    Not = 0x1B,
+    Shl = 0x1C,            // Shift count is taken modulo 64
+    Shr = 0x1D,            // Logical shift right
+    Sar = 0x1E,            // Arithmetic shift right
+    
+    // Comparisons (signed, push 1 if true and 0 otherwise)
+    Eq = 0x20,
+    Ne = 0x21,
+    Lt = 0x22,
+    Le = 0x23,
+    Gt = 0x24,
+    Ge = 0x25,
+    
+    // Control flow: next 2 bytes are a signed little-endian offset
+    // relative to the following instruction
+    Jmp = 0x30,
+    Jz = 0x31,             // Pop a value and jump if it is zero
+    Jnz = 0x32,            // Pop a value and jump if it is not zero
+    Call = 0x33,           // Push the return address on the call stack and jump
+    Ret = 0x34,            // Return to the address on top of the call stack
+    
+    // Data memory: loads pop an address and push the value, zero-extended;
+    // stores pop a value, then an address, and write its low bytes.
+    // Values are little-endian and need not be aligned.
+    Load8 = 0x40,
+    Load16 = 0x41,
+    Load32 = 0x42,
+    Load64 = 0x43,
+    Store8 = 0x44,
+    Store16 = 0x45,
+    Store32 = 0x46,
+    Store64 = 0x47,
+    
+    // System calls
+    Syscall = 0xFF,        // System call; next byte selects a `Syscall`
+}
+
+impl Instruction {
+    /// Every instruction, in opcode order
+    pub const ALL: [Instruction; 42] = {
+        use Instruction::*;
+        [
+            Halt, PrintChar, PrintString, Exit, PrintInt, Push, Pop, Dup, Swap,
+            Add, Sub, Mul, Div, Mod, Neg, And, Or, Xor, Not, Shl, Shr, Sar,
+            Eq, Ne, Lt, Le, Gt, Ge, Jmp, Jz, Jnz, Call, Ret,
+            Load8, Load16, Load32, Load64, Store8, Store16, Store32, Store64, Syscall,
+        ]
+    };
+
+    /// Width in bytes of a load or store
+    pub fn access_width(self) -> Option<usize> {
+        use Instruction::*;
+        match self {
+            Load8 | Store8 => Some(1),
+            Load16 | Store16 => Some(2),
+            Load32 | Store32 => Some(4),
+            Load64 | Store64 => Some(8),
+            _ => None,
+        }
+    }
+
+    /// Assembly mnemonic
+    pub fn mnemonic(self) -> &'static str {
+        use Instruction::*;
+        match self {
+            Halt => "halt",
+            PrintChar => "print_char",
+            PrintString => "print_string",
+            Exit => "exit",
+            PrintInt => "print_int",
+            Push => "push",
+            Pop => "pop",
+            Dup => "dup",
+            Swap => "swap",
+            Add => "add",
+            Sub => "sub",
+            Mul => "mul",
+            Div => "div",
+            Mod => "mod",
+            Neg => "neg",
+            And => "and",
+            Or => "or",
+            Xor => "xor",
+            Not => "not",
+            Shl => "shl",
+            Shr => "shr",
+            Sar => "sar",
+            Eq => "eq",
+            Ne => "ne",
+            Lt => "lt",
+            Le => "le",
+            Gt => "gt",
+            Ge => "ge",
+            Jmp => "jmp",
+            Jz => "jz",
+            Jnz => "jnz",
+            Call => "call",
+            Ret => "ret",
+            Load8 => "load8",
+            Load16 => "load16",
+            Load32 => "load32",
+            Load64 => "load64",
+            Store8 => "store8",
+            Store16 => "store16",
+            Store32 => "store32",
+            Store64 => "store64",
+            Syscall => "syscall",
+        }
+    }
+
+    /// Look up an instruction by its assembly mnemonic
+    pub fn from_mnemonic(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|i| i.mnemonic() == name)
+    }
+
+    /// Decode an opcode byte
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        use Instruction::*;
+        let instruction = match byte {
+            0x00 => Halt,
+            0x01 => PrintChar,
+            0x02 => PrintString,
+            0x03 => Exit,
+            0x04 => PrintInt,
+            0x08 => Push,
+            0x09 => Pop,
+            0x0A => Dup,
+            0x0B => Swap,
+            0x10 => Add,
+            0x11 => Sub,
+            0x12 => Mul,
+            0x13 => Div,
+            0x14 => Mod,
+            0x15 => Neg,
+            0x18 => And,
+            0x19 => Or,
+            0x1A => Xor,
+            0x1B => Not,
+            0x1C => Shl,
+            0x1D => Shr,
+            0x1E => Sar,
+            0x20 => Eq,
+            0x21 => Ne,
+            0x22 => Lt,
+            0x23 => Le,
+            0x24 => Gt,
+            0x25 => Ge,
+            0x30 => Jmp,
+            0x31 => Jz,
+            0x32 => Jnz,
+            0x33 => Call,
+            0x34 => Ret,
+            0x40 => Load8,
+            0x41 => Load16,
+            0x42 => Load32,
+            0x43 => Load64,
+            0x44 => Store8,
+            0x45 => Store16,
+            0x46 => Store32,
+            0x47 => Store64,
+            0xFF => Syscall,
+            _ => return None,
+        };
+        Some(instruction)
+    }
+}
+
+/// Maximum depth of the operand stack
+pub const STACK_SIZE: usize = 256;
+
+/// Maximum nesting of `Call`
+pub const CALL_DEPTH: usize = 64;
+
+/// Instruction operand as decoded from the code stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    None,
+    /// `PrintChar` character
+    Byte(u8),
+    /// `PrintString` text, as a range of the code (terminator excluded)
+    Str { start: usize, len: usize },
+    /// `Push` immediate
+    Imm(i32),
+    /// Relative offset of a jump or call
+    Offset(i16),
+    /// `Syscall` service and its inline argument byte, if it takes one
+    Syscall { call: Syscall, arg: Option<u8> },
+}
+
+/// Services a program can request with `Syscall`, named by the byte after
+/// the opcode. Arguments are popped from the operand stack, last argument
+/// on top, and most calls push a result; failures push `-errno`.
+///
+/// | No | Name    | Pops                 | Pushes                            |
+/// |----|---------|----------------------|-----------------------------------|
+/// | 0  | exit    |                      |                                   |
+/// | 1  | putc    | (inline byte)        |                                   |
+/// | 2  | newline |                      |                                   |
+/// | 3  | getc    |                      | input byte, or -1 at end of input |
+/// | 4  | clock   |                      | milliseconds since boot           |
+/// | 5  | open    | path, len, mode      | fd                                |
+/// | 6  | fgetc   | fd                   | byte, or -1 at end of file        |
+/// | 7  | fputc   | fd, byte             | 0                                 |
+/// | 8  | close   | fd                   | 0                                 |
+/// | 9  | write   | buf, len             | bytes written                     |
+/// | 10 | read    | buf, len             | bytes read, 0 at end of input     |
+/// | 11 | fread   | fd, buf, len         | bytes read, 0 at end of file      |
+/// | 12 | fwrite  | fd, buf, len         | bytes written                     |
+/// | 13 | argc    |                      | number of arguments               |
+/// | 14 | argv    | index, buf, len      | length of the argument            |
+/// | 15 | environ | index, buf, len      | length of the `NAME=value` entry  |
+/// | 16 | getenv  | name, nlen, buf, len | length of the value               |
+/// | 17 | sys     | number, a1, a2, a3   | result of the kernel system call  |
+///
+/// Paths and `getenv` names are strings in the rodata section, given as
+/// an offset and length. `mode` is one of the `OPEN_*` constants. Buffers are ranges of
+/// data memory; `read` stops after a newline, like a terminal delivering a
+/// line. `argv`, `environ` and `getenv` copy as much of the string as fits
+/// and return its full length, or `-EINVAL` past the last entry and
+/// `-ENOENT` for an unset variable. `argv` 0 is the program's path.
+///
+/// `sys` makes any call in the kernel's system call table (see
+/// `syscall.rs`) by number. Arguments that are buffers or paths there are
+/// ranges of data memory here, and `sys 0` exits like the `exit`
+/// instruction. Hosts without that table push `-ENOSYS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Syscall {
+    Exit = 0,
+    PutChar = 1,
+    Newline = 2,
+    GetChar = 3,
+    Clock = 4,
+    Open = 5,
+    ReadByte = 6,
+    WriteByte = 7,
+    Close = 8,
+    Write = 9,
+    Read = 10,
+    FileRead = 11,
+    FileWrite = 12,
+    ArgCount = 13,
+    Arg = 14,
+    EnvEntry = 15,
+    GetEnv = 16,
+    Sys = 17,
+}
+
+/// `open` modes
+pub const OPEN_READ: i64 = 0;
+pub const OPEN_WRITE: i64 = 1;
+pub const OPEN_APPEND: i64 = 2;
+
+/// Kernel system call number of `exit`, which `sys` handles itself
+pub const SYS_EXIT: i64 = 0;
+
+impl Syscall {
+    pub const ALL: [Syscall; 18] = {
+        use Syscall::*;
+        [
+            Exit, PutChar, Newline, GetChar, Clock, Open, ReadByte, WriteByte, Close,
+            Write, Read, FileRead, FileWrite, ArgCount, Arg, EnvEntry, GetEnv, Sys,
+        ]
+    };
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    /// Assembly name, as in `syscall getc`
+    pub fn name(self) -> &'static str {
+        match self {
+            Syscall::Exit => "exit",
+            Syscall::PutChar => "putc",
+            Syscall::Newline => "newline",
+            Syscall::GetChar => "getc",
+            Syscall::Clock => "clock",
+            Syscall::Open => "open",
+            Syscall::ReadByte => "fgetc",
+            Syscall::WriteByte => "fputc",
+            Syscall::Close => "close",
+            Syscall::Write => "write",
+            Syscall::Read => "read",
+            Syscall::FileRead => "fread",
+            Syscall::FileWrite => "fwrite",
+            Syscall::ArgCount => "argc",
+            Syscall::Arg => "argv",
+            Syscall::EnvEntry => "environ",
+            Syscall::GetEnv => "getenv",
+            Syscall::Sys => "sys",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|call| call.name() == name)
+    }
+
+    /// Values popped and pushed
+    pub fn stack_effect(self) -> (i32, i32) {
+        match self {
+            Syscall::Exit | Syscall::PutChar | Syscall::Newline => (0, 0),
+            Syscall::GetChar | Syscall::Clock | Syscall::ArgCount => (0, 1),
+            Syscall::Open => (3, 1),
+            Syscall::ReadByte | Syscall::Close => (1, 1),
+            Syscall::WriteByte | Syscall::Write | Syscall::Read => (2, 1),
+            Syscall::FileRead | Syscall::FileWrite | Syscall::Arg | Syscall::EnvEntry => (3, 1),
+            Syscall::GetEnv | Syscall::Sys => (4, 1),
+        }
+    }
+}
+
+/// A single decoded instruction
+#[derive(Debug, Clone, Copy)]
+pub struct Decoded {
+    pub instruction: Instruction,
+    pub operand: Operand,
+    /// Encoded length including the opcode byte
+    pub len: usize,
+}
+
+impl Decoded {
+    /// Absolute target of a jump or call located at `offset`
+    pub fn target(&self, offset: usize) -> Option<isize> {
+        match self.operand {
+            Operand::Offset(rel) => Some((offset + self.len) as isize + rel as isize),
+            _ => None,
+        }
+    }
+}
+
+/// Decode the instruction at `offset`
+pub fn decode(code: &[u8], offset: usize) -> Result<Decoded, &'static str> {
+    let byte = |i: usize| code.get(offset + i).copied().ok_or("Unexpected end of program");
+
+    let opcode = byte(0)?;
+    let instruction = Instruction::from_byte(opcode).ok_or("Unknown instruction")?;
+
+    let (operand, len) = match instruction {
+        Instruction::PrintChar => (Operand::Byte(byte(1)?), 2),
+        Instruction::PrintString => {
+            let start = offset + 1;
+            let len = code[start.min(code.len())..].iter()
+                .position(|&b| b == 0)
+                .ok_or("String not null-terminated")?;
+            (Operand::Str { start, len }, len + 2)
+        }
+        Instruction::Push => {
+            let imm = i32::from_le_bytes([byte(1)?, byte(2)?, byte(3)?, byte(4)?]);
+            (Operand::Imm(imm), 5)
+        }
+        Instruction::Jmp | Instruction::Jz | Instruction::Jnz | Instruction::Call => {
+            let rel = i16::from_le_bytes([byte(1)?, byte(2)?]);
+            (Operand::Offset(rel), 3)
+        }
+        Instruction::Syscall => {
+            let call = Syscall::from_byte(byte(1)?).ok_or("Unknown syscall")?;
+            match call {
+                Syscall::PutChar => (Operand::Syscall { call, arg: Some(byte(2)?) }, 3),
+                _ => (Operand::Syscall { call, arg: None }, 2),
+            }
+        }
+        _ => (Operand::None, 1),
+    };
+
+    Ok(Decoded { instruction, operand, len })
+}
+
+/// Evaluate a two-operand arithmetic, bitwise or comparison instruction
+pub fn binary_op(instruction: Instruction, a: i64, b: i64) -> Result<i64, &'static str> {
+    let result = match instruction {
+        Instruction::Add => a.wrapping_add(b),
+        Instruction::Sub => a.wrapping_sub(b),
+        Instruction::Mul => a.wrapping_mul(b),
+        Instruction::Div => {
+            if b == 0 {
+                return Err("Division by zero");
+            }
+            a.wrapping_div(b)
+        }
+        Instruction::Mod => {
+            if b == 0 {
+                return Err("Division by zero");
+            }
+            a.wrapping_rem(b)
+        }
+        Instruction::And => a & b,
+        Instruction::Or => a | b,
+        Instruction::Xor => a ^ b,
+        Instruction::Shl => a.wrapping_shl(b as u32),
+        Instruction::Shr => ((a as u64).wrapping_shr(b as u32)) as i64,
+        Instruction::Sar => a.wrapping_shr(b as u32),
+        Instruction::Eq => (a == b) as i64,
+        Instruction::Ne => (a != b) as i64,
+        Instruction::Lt => (a < b) as i64,
+        Instruction::Le => (a <= b) as i64,
+        Instruction::Gt => (a > b) as i64,
+        Instruction::Ge => (a >= b) as i64,
+        _ => return Err("Not a binary operation"),
+    };
+    Ok(result)
+}
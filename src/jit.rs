@@ -0,0 +1,442 @@
+//! Bytecode to x86_64 compiler
+//!
+//! Straight-line runs of bytecode ("blocks") are translated into native
+//! code the first time execution reaches them. A block ends at a jump, at
+//! an instruction the compiler does not handle (printing, calls, syscalls),
+//! or after `MAX_BLOCK` instructions; the interpreter runs whatever cannot
+//! be compiled.
+//!
+//! Compiled blocks work on the interpreter's own operand stack and data
+//! memory, so control can pass back and forth at any block boundary. When
+//! a run-time check fails (a bad memory address, a zero divisor) the block
+//! returns to the interpreter just before the failing instruction, which
+//! then reports the fault exactly as it would have without the compiler.
+//!
+//! Register use inside a block:
+//!
+//! | Register | Holds                                      |
+//! |----------|--------------------------------------------|
+//! | rdi      | operand stack base                         |
+//! | rsi      | stack depth on entry (adjusted at exits)   |
+//! | r9       | data memory base                           |
+//! | r8       | data memory size                           |
+//! | rax, rcx, rdx | scratch                               |
+//!
+//! The code only touches caller-saved registers and is position
+//! independent, so it can be copied anywhere executable.
+use crate::isa::{decode, Instruction, Operand, STACK_SIZE};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Longest block the compiler builds, in bytecode instructions
+pub const MAX_BLOCK: usize = 64;
+
+/// Where a block left off: the bytecode offset to continue at, with the
+/// number of instructions run above `RAN_SHIFT`, and the new stack depth
+#[repr(C)]
+struct BlockExit {
+    ip: u64,
+    sp: u64,
+}
+
+/// Set in `BlockExit::ip` when a check failed and the interpreter has to
+/// run the instruction at `ip`
+const FAILED_CHECK: u64 = 1 << 31;
+
+/// Position in `BlockExit::ip` of the count of instructions the block ran
+/// before leaving, which is less than its length if it left early
+const RAN_SHIFT: u32 = 32;
+
+type BlockFn = unsafe extern "sysv64" fn(stack: *mut i64, sp: usize, memory: *mut u8, memory_len: usize) -> BlockExit;
+
+/// Machine code for the bytecode starting at one offset
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub code: Vec<u8>,
+    /// Stack depth the block needs on entry
+    pub needs: usize,
+    /// How far the block can grow the stack above its entry depth
+    pub grows: usize,
+    /// Bytecode instructions covered
+    pub instructions: usize,
+}
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+
+/// Condition codes for `jcc` and `setcc`
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_A: u8 = 0x7;
+const CC_L: u8 = 0xC;
+const CC_GE: u8 = 0xD;
+const CC_LE: u8 = 0xE;
+const CC_G: u8 = 0xF;
+
+struct Emitter {
+    code: Vec<u8>,
+    /// Stack depth relative to rsi
+    depth: i32,
+    min: i32,
+    max: i32,
+    /// Bytecode instructions run on reaching the code being emitted
+    ran: u32,
+    /// Out-of-line exits: position of the rel32 to patch, value to return
+    /// in rax, stack depth and instructions run
+    exits: Vec<(usize, u32, i32, u32)>,
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, value: i32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Displacement of the stack slot `from_top` entries below the top
+    /// (1 is the top)
+    fn slot(&self, from_top: i32) -> i32 {
+        (self.depth - from_top) * 8
+    }
+
+    /// `opcode reg, [rdi + rsi*8 + disp]`
+    fn stack_op(&mut self, opcode: &[u8], reg: u8, disp: i32) {
+        self.bytes(&[0x48]);
+        self.bytes(opcode);
+        self.bytes(&[0x84 | ((reg & 7) << 3), 0xF7]);
+        self.imm32(disp);
+    }
+
+    fn load(&mut self, reg: u8, from_top: i32) {
+        let disp = self.slot(from_top);
+        self.stack_op(&[0x8B], reg, disp);
+    }
+
+    fn store(&mut self, reg: u8, from_top: i32) {
+        let disp = self.slot(from_top);
+        self.stack_op(&[0x89], reg, disp);
+    }
+
+    /// Record a change of stack depth: `pops` values are consumed, then
+    /// `pushes` produced
+    fn adjust(&mut self, pops: i32, pushes: i32) {
+        self.depth -= pops;
+        self.min = self.min.min(self.depth);
+        self.depth += pushes;
+        self.max = self.max.max(self.depth);
+    }
+
+    /// Return `ip` (possibly flagged), the instructions run and the
+    /// current stack depth to the caller
+    fn exit(&mut self, ip: u32) {
+        if self.depth != 0 {
+            self.bytes(&[0x48, 0x81, 0xC6]); // add rsi, depth
+            self.imm32(self.depth);
+        }
+        let value = u64::from(self.ran) << RAN_SHIFT | u64::from(ip);
+        self.bytes(&[0x48, 0xB8]); // mov rax, value
+        self.bytes(&value.to_le_bytes());
+        self.bytes(&[0x48, 0x89, 0xF2, 0xC3]); // mov rdx, rsi; ret
+    }
+
+    /// Conditional jump to an exit returning `ip` at the current depth
+    fn exit_if(&mut self, cc: u8, ip: u32) {
+        self.bytes(&[0x0F, 0x80 | cc]);
+        self.exits.push((self.code.len(), ip, self.depth, self.ran));
+        self.imm32(0);
+    }
+
+    /// Conditional jump to an exit handing the instruction at `ip` to the
+    /// interpreter
+    fn fail_if(&mut self, cc: u8, ip: usize) {
+        self.exit_if(cc, ip as u32 | FAILED_CHECK as u32);
+    }
+
+    /// Emit the out-of-line exits and patch the jumps to them
+    fn finish(mut self) -> Vec<u8> {
+        for (patch, ip, depth, ran) in core::mem::take(&mut self.exits) {
+            let rel = (self.code.len() - (patch + 4)) as i32;
+            self.code[patch..patch + 4].copy_from_slice(&rel.to_le_bytes());
+            self.depth = depth;
+            self.ran = ran;
+            self.exit(ip);
+        }
+        self.code
+    }
+
+    /// Fail over to the interpreter at `ip` unless `width` bytes at the
+    /// address in rax lie within data memory
+    fn check_address(&mut self, ip: usize, width: u8) {
+        self.bytes(&[0x4C, 0x39, 0xC0]); // cmp rax, r8
+        self.fail_if(CC_A, ip);
+        self.bytes(&[0x48, 0x8D, 0x50, width]); // lea rdx, [rax + width]
+        self.bytes(&[0x4C, 0x39, 0xC2]); // cmp rdx, r8
+        self.fail_if(CC_A, ip);
+    }
+}
+
+/// Compile the block starting at `start`, or return `None` if the
+/// instruction there has to be interpreted
+pub fn compile(code: &[u8], start: usize) -> Option<Block> {
+    let mut e = Emitter { code: Vec::new(), depth: 0, min: 0, max: 0, ran: 0, exits: Vec::new() };
+    // mov r9, rdx; mov r8, rcx
+    e.bytes(&[0x49, 0x89, 0xD1, 0x49, 0x89, 0xC8]);
+
+    let mut ip = start;
+    let mut instructions = 0;
+    let mut ended = false;
+    while !ended && instructions < MAX_BLOCK {
+        let Ok(decoded) = decode(code, ip) else { break };
+        let next = ip + decoded.len;
+        let target = decoded.target(ip).filter(|&t| t >= 0 && (t as usize) < code.len()).map(|t| t as usize);
+        e.ran = instructions as u32;
+
+        use Instruction::*;
+        match (decoded.instruction, decoded.operand) {
+            (Push, Operand::Imm(value)) => {
+                let disp = e.slot(0);
+                e.stack_op(&[0xC7], 0, disp);
+                e.imm32(value);
+                e.adjust(0, 1);
+            }
+            (Pop, _) => e.adjust(1, 0),
+            (Dup, _) => {
+                e.load(RAX, 1);
+                e.store(RAX, 0);
+                e.adjust(1, 2);
+            }
+            (Swap, _) => {
+                e.load(RAX, 1);
+                e.load(RCX, 2);
+                e.store(RCX, 1);
+                e.store(RAX, 2);
+                e.adjust(2, 2);
+            }
+            (Add | Sub | And | Or | Xor, _) => {
+                let opcode = match decoded.instruction {
+                    Add => 0x01,
+                    Sub => 0x29,
+                    And => 0x21,
+                    Or => 0x09,
+                    _ => 0x31,
+                };
+                e.load(RAX, 1);
+                let disp = e.slot(2);
+                e.stack_op(&[opcode], RAX, disp);
+                e.adjust(2, 1);
+            }
+            (Mul, _) => {
+                e.load(RAX, 2);
+                let disp = e.slot(1);
+                e.stack_op(&[0x0F, 0xAF], RAX, disp);
+                e.store(RAX, 2);
+                e.adjust(2, 1);
+            }
+            (Div | Mod, _) => {
+                // Zero faults and i64::MIN / -1 overflows; the interpreter
+                // handles both
+                e.load(RCX, 1);
+                e.bytes(&[0x48, 0x85, 0xC9]); // test rcx, rcx
+                e.fail_if(CC_E, ip);
+                e.bytes(&[0x48, 0x83, 0xF9, 0xFF]); // cmp rcx, -1
+                e.fail_if(CC_E, ip);
+                e.load(RAX, 2);
+                e.bytes(&[0x48, 0x99, 0x48, 0xF7, 0xF9]); // cqo; idiv rcx
+                let result = if decoded.instruction == Div { RAX } else { RDX };
+                e.store(result, 2);
+                e.adjust(2, 1);
+            }
+            (Neg | Not, _) => {
+                let ext = if decoded.instruction == Neg { 3 } else { 2 };
+                let disp = e.slot(1);
+                e.stack_op(&[0xF7], ext, disp);
+                e.adjust(1, 1);
+            }
+            (Shl | Shr | Sar, _) => {
+                let ext = match decoded.instruction {
+                    Shl => 4,
+                    Shr => 5,
+                    _ => 7,
+                };
+                e.load(RCX, 1);
+                let disp = e.slot(2);
+                e.stack_op(&[0xD3], ext, disp); // shift by cl, masked to 6 bits
+                e.adjust(2, 1);
+            }
+            (Eq | Ne | Lt | Le | Gt | Ge, _) => {
+                let cc = match decoded.instruction {
+                    Eq => CC_E,
+                    Ne => CC_NE,
+                    Lt => CC_L,
+                    Le => CC_LE,
+                    Gt => CC_G,
+                    _ => CC_GE,
+                };
+                e.load(RAX, 2);
+                let disp = e.slot(1);
+                e.stack_op(&[0x3B], RAX, disp); // cmp rax, [top]
+                e.bytes(&[0x0F, 0x90 | cc, 0xC0]); // setcc al
+                e.bytes(&[0x0F, 0xB6, 0xC0]); // movzx eax, al
+                e.store(RAX, 2);
+                e.adjust(2, 1);
+            }
+            (Load8 | Load16 | Load32 | Load64, _) => {
+                let width = decoded.instruction.access_width().unwrap_or(8) as u8;
+                e.load(RAX, 1);
+                e.check_address(ip, width);
+                // Zero-extending load of [r9 + rax] into rcx
+                e.bytes(match width {
+                    1 => &[0x41, 0x0F, 0xB6, 0x0C, 0x01],
+                    2 => &[0x41, 0x0F, 0xB7, 0x0C, 0x01],
+                    4 => &[0x41, 0x8B, 0x0C, 0x01],
+                    _ => &[0x49, 0x8B, 0x0C, 0x01],
+                });
+                e.store(RCX, 1);
+                e.adjust(1, 1);
+            }
+            (Store8 | Store16 | Store32 | Store64, _) => {
+                let width = decoded.instruction.access_width().unwrap_or(8) as u8;
+                e.load(RAX, 2);
+                e.check_address(ip, width);
+                e.load(RCX, 1);
+                // Store the low bytes of rcx to [r9 + rax]
+                e.bytes(match width {
+                    1 => &[0x41, 0x88, 0x0C, 0x01],
+                    2 => &[0x66, 0x41, 0x89, 0x0C, 0x01],
+                    4 => &[0x41, 0x89, 0x0C, 0x01],
+                    _ => &[0x49, 0x89, 0x0C, 0x01],
+                });
+                e.adjust(2, 0);
+            }
+            (Jmp, _) if target.is_some() => {
+                // Jumps leave the block having run
+                e.ran += 1;
+                e.exit(target.unwrap_or(0) as u32);
+                ended = true;
+            }
+            (Jz | Jnz, _) if target.is_some() => {
+                e.load(RAX, 1);
+                e.adjust(1, 0);
+                e.bytes(&[0x48, 0x85, 0xC0]); // test rax, rax
+                e.ran += 1;
+                let cc = if decoded.instruction == Jz { CC_E } else { CC_NE };
+                e.exit_if(cc, target.unwrap_or(0) as u32);
+                e.exit(next as u32);
+                ended = true;
+            }
+            _ => break,
+        }
+        ip = next;
+        instructions += 1;
+    }
+
+    if instructions == 0 {
+        return None;
+    }
+    if !ended {
+        e.ran = instructions as u32;
+        e.exit(ip as u32);
+    }
+    let (needs, grows) = (-e.min as usize, e.max as usize);
+    Some(Block { code: e.finish(), needs, grows, instructions })
+}
+
+/// Counters describing how a program ran under the JIT
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JitStats {
+    /// Blocks compiled
+    pub blocks: usize,
+    /// Bytes of machine code generated
+    pub code_bytes: usize,
+    /// Bytecode instructions run as native code
+    pub native: usize,
+    /// Bytecode instructions run by the interpreter
+    pub interpreted: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Unknown,
+    Native { entry: usize, needs: usize, grows: usize, instructions: usize },
+    Interpreted,
+}
+
+/// Compiled blocks of one program, by bytecode offset
+#[derive(Debug, Clone)]
+pub struct Jit {
+    slots: Vec<Slot>,
+    /// Offset of an instruction whose check failed in native code
+    failed_at: Option<usize>,
+    pub stats: JitStats,
+}
+
+impl Jit {
+    pub fn new(code_len: usize) -> Self {
+        Jit { slots: vec![Slot::Unknown; code_len], failed_at: None, stats: JitStats::default() }
+    }
+
+    /// Run the block at `ip` natively, compiling it on first use. `install`
+    /// copies machine code into executable memory and returns its address.
+    ///
+    /// Returns the next offset and the instructions run, which are fewer
+    /// than the block's if a check failed, or `None` if the interpreter
+    /// has to execute the instruction at `ip`: it cannot be compiled, a check for it failed in native code, the block would
+    /// exceed `budget`, or the stack is too shallow or too deep for the
+    /// block to run without checks.
+    pub fn run(
+        &mut self,
+        code: &[u8],
+        ip: usize,
+        budget: usize,
+        stack: &mut Vec<i64>,
+        memory: &mut [u8],
+        install: impl FnOnce(&[u8]) -> Option<usize>,
+    ) -> Option<(usize, usize)> {
+        if self.failed_at.take() == Some(ip) {
+            return None;
+        }
+        let slot = self.slots.get_mut(ip)?;
+        if let Slot::Unknown = slot {
+            *slot = match compile(code, ip) {
+                Some(block) => match install(&block.code) {
+                    Some(entry) => {
+                        self.stats.blocks += 1;
+                        self.stats.code_bytes += block.code.len();
+                        Slot::Native { entry, needs: block.needs, grows: block.grows, instructions: block.instructions }
+                    }
+                    None => Slot::Interpreted,
+                },
+                None => Slot::Interpreted,
+            };
+        }
+
+        let Slot::Native { entry, needs, grows, instructions } = *slot else { return None };
+        let sp = stack.len();
+        if instructions > budget || sp < needs || sp + grows > STACK_SIZE {
+            return None;
+        }
+
+        stack.reserve(STACK_SIZE - sp);
+        // SAFETY: `entry` holds code produced by `compile`, which only
+        // touches the stack between `sp - needs` and `sp + grows` (checked
+        // above to lie within the reserved capacity) and memory it has
+        // bounds-checked. Every slot below the returned depth that was
+        // beyond the old length has been written by the block.
+        let exit = unsafe {
+            let block: BlockFn = core::mem::transmute(entry);
+            let exit = block(stack.as_mut_ptr(), sp, memory.as_mut_ptr(), memory.len());
+            stack.set_len(exit.sp as usize);
+            exit
+        };
+        let ran = (exit.ip >> RAN_SHIFT) as usize;
+        let next = (exit.ip as u32 & !(FAILED_CHECK as u32)) as usize;
+        if exit.ip & FAILED_CHECK != 0 {
+            self.failed_at = Some(next);
+        }
+        self.stats.native += ran;
+        Some((next, ran))
+    }
+}
@@ -1,19 +1,26 @@
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use spin::Mutex;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 lazy_static! {
     static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
         Keyboard::new(
             ScancodeSet1::new(),
             layouts::Us104Key,
-            HandleControl::Ignore
+            HandleControl::MapLettersToUnicode
         )
     );
     
     static ref KEY_BUFFER: Mutex<KeyBuffer> = Mutex::new(KeyBuffer::new());
 }
 
+/// Set by Ctrl+C until someone acts on it
+static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+/// Ctrl+C as delivered with `HandleControl::MapLettersToUnicode`
+const CTRL_C: char = '\u{3}';
+
 struct KeyBuffer {
     buffer: [u8; 256],
     read_pos: usize,
@@ -54,6 +61,9 @@ pub fn handle_scancode(scancode: u8) {
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
+                DecodedKey::Unicode(CTRL_C) => {
+                    INTERRUPT.store(true, Ordering::SeqCst);
+                }
                 DecodedKey::Unicode(character) => {
                     let mut buffer = KEY_BUFFER.lock();
                     buffer.push(character as u8);
@@ -70,3 +80,13 @@ pub fn read_key() -> Option<u8> {
     let mut buffer = KEY_BUFFER.lock();
     buffer.pop()
 }
+
+/// Whether Ctrl+C has been pressed and not yet handled
+pub fn interrupt_pending() -> bool {
+    INTERRUPT.load(Ordering::SeqCst)
+}
+
+/// Consume a pending Ctrl+C
+pub fn take_interrupt() -> bool {
+    INTERRUPT.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,156 @@
+//! Executable format detection and loader dispatch
+//!
+//! `run` and the `spawn` syscall hand files here, and the first bytes of
+//! the file decide which loader runs it. Every program gets the shell's
+//! environment variables.
+use crate::process::{self, Pid};
+use crate::{bytecode, elf, executor, vfs};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Executable formats the kernel can run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Bytecode container
+    Bytecode,
+    /// Native ELF64 executable
+    Elf,
+    /// Text file starting with `#!`
+    Script,
+}
+
+/// Maximum nesting of scripts running scripts
+const MAX_SCRIPT_DEPTH: usize = 4;
+
+static SCRIPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// Environment passed to programs, as `NAME=value` entries
+    static ref ENVIRONMENT: Mutex<Vec<String>> = Mutex::new(vec![
+        String::from("SHELL=/bin/sh"),
+        String::from("TERM=vga"),
+    ]);
+}
+
+/// Current environment, as `NAME=value` entries
+pub fn environment() -> Vec<String> {
+    ENVIRONMENT.lock().clone()
+}
+
+/// Set or replace an environment variable
+pub fn set_env(name: &str, value: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.contains('=') {
+        return Err("Invalid variable name");
+    }
+    let mut env = ENVIRONMENT.lock();
+    env.retain(|entry| !is_entry_for(entry, name));
+    env.push(format!("{}={}", name, value));
+    Ok(())
+}
+
+/// Remove an environment variable, returning whether it was set
+pub fn unset_env(name: &str) -> bool {
+    let mut env = ENVIRONMENT.lock();
+    let before = env.len();
+    env.retain(|entry| !is_entry_for(entry, name));
+    env.len() != before
+}
+
+fn is_entry_for(entry: &str, name: &str) -> bool {
+    entry.strip_prefix(name).is_some_and(|rest| rest.starts_with('='))
+}
+
+/// Identify a file's executable format from its magic number
+pub fn detect(content: &[u8]) -> Option<Format> {
+    if bytecode::has_magic(content) {
+        Some(Format::Bytecode)
+    } else if elf::is_elf(content) {
+        Some(Format::Elf)
+    } else if content.starts_with(b"#!") {
+        Some(Format::Script)
+    } else {
+        None
+    }
+}
+
+/// Identify a file's executable format from its first bytes, without
+/// reading the rest of it
+pub fn detect_file(path: &str) -> Result<Option<Format>, &'static str> {
+    let mut fs = vfs::VFS.lock();
+    let mut handle = fs.open(path, vfs::OpenFlags::default())?;
+    let mut header = [0u8; bytecode::MAGIC.len()];
+    let count = fs.read(&mut handle, &mut header)?;
+    Ok(detect(&header[..count]))
+}
+
+/// Run a file with the loader matching its format, as a child of the
+/// current process. `argv[0]` is the path.
+pub fn spawn(path: &str, argv: &[&str]) -> Result<Pid, &'static str> {
+    let environment = environment();
+    let envp: Vec<&str> = environment.iter().map(String::as_str).collect();
+    let format = detect_file(path)?.ok_or("Not an executable")?;
+
+    match format {
+        Format::Bytecode => executor::spawn(path, argv, &envp, false),
+        Format::Elf => elf::spawn(path, argv, &envp),
+        Format::Script => run_script(path),
+    }
+}
+
+/// Run a `#!` script. The shell is the only interpreter: each following
+/// line (or `;`-separated command, since `write` cannot store newlines) is
+/// run as a shell command, with the script as the current process.
+fn run_script(path: &str) -> Result<Pid, &'static str> {
+    let content = vfs::VFS.lock().read_file(path)?;
+    let text = core::str::from_utf8(&content).map_err(|_| "Script is not valid UTF-8")?;
+    let mut lines = text.split(['\n', ';']);
+
+    let interpreter = lines.next().unwrap_or("")[2..].trim();
+    if !matches!(interpreter, "" | "sh" | "/bin/sh") {
+        return Err("Unsupported script interpreter");
+    }
+
+    let _depth = ScriptDepth::enter()?;
+
+    let pid = {
+        let mut pm = process::PROCESS_MANAGER.lock();
+        let pid = pm.create_process(String::from(path), content.clone())?;
+        pm.set_current(pid);
+        pid
+    };
+
+    for line in lines {
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            crate::shell::process_command(line);
+        }
+    }
+
+    let _ = process::PROCESS_MANAGER.lock().exit_process(pid, 0);
+    Ok(pid)
+}
+
+/// One level of script nesting, counted in `SCRIPT_DEPTH` until dropped,
+/// so every way out of `run_script` gives it back
+struct ScriptDepth;
+
+impl ScriptDepth {
+    fn enter() -> Result<Self, &'static str> {
+        if SCRIPT_DEPTH.fetch_add(1, Ordering::SeqCst) >= MAX_SCRIPT_DEPTH {
+            SCRIPT_DEPTH.fetch_sub(1, Ordering::SeqCst);
+            return Err("Scripts nested too deeply");
+        }
+        Ok(ScriptDepth)
+    }
+}
+
+impl Drop for ScriptDepth {
+    fn drop(&mut self) {
+        SCRIPT_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
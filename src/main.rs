@@ -8,42 +8,90 @@
 
 extern crate alloc;
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
 mod vga_buffer;
 mod keyboard;
 mod interrupts;
+mod gdt;
+mod usermode;
 mod serial;
 mod allocator;
-mod filesystem;
+mod memory;
+mod pci;
+mod block;
+mod cache;
+mod ata;
+mod virtio;
+mod vfs;
+mod ramfs;
+mod devfs;
+mod procfs;
+mod fat;
+mod sfs;
+mod diskfs;
 mod process;
 mod executor;
+mod isa;
+mod vm;
+mod jit;
+mod scheduler;
+mod errno;
+mod bytecode;
+mod verifier;
 mod syscall;
+mod elf;
+mod loader;
+mod time;
+mod rtc;
+mod asm;
+mod debugger;
+
+entry_point!(kernel_main);
 
 /// Entry point for the kernel
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     serial_println!("Bare Bones OS Phase 2 - Starting...");
     println!("Welcome to Bare Bones OS - Phase 2!");
     serial_println!("VGA buffer initialized");
     
+    // Drivers doing DMA need physical addresses
+    memory::init(boot_info.physical_memory_offset);
+    
     // Initialize memory allocator
     serial_println!("Initializing heap allocator...");
     allocator::init_heap();
     serial_println!("Heap allocator initialized");
     
+    // The filesystem timestamps files with the wall clock
+    rtc::init();
+    serial_println!("RTC: {}", rtc::DateTime::from_unix(rtc::now()));
+    
     // Initialize filesystem
     serial_println!("Initializing filesystem...");
-    filesystem::init_filesystem();
-    filesystem::load_builtin_programs();
+    vfs::init();
     serial_println!("Filesystem initialized");
     
+    // Probe disks
+    block::init();
+    for disk in block::disks() {
+        serial_println!("Disk {}: {} ({} sectors)", disk.name, disk.model, disk.sectors);
+    }
+    if let Some(disk) = diskfs::init() {
+        serial_println!("Mounted sfs from {} on {}", disk, diskfs::MOUNT_POINT);
+        // Start there, so files written with relative paths persist
+        let _ = vfs::VFS.lock().change_dir(diskfs::MOUNT_POINT);
+    }
+    
     println!("Type 'help' for available commands");
     println!("");
     
     // Initialize interrupts and keyboard
     serial_println!("Initializing interrupts...");
+    gdt::init();
     interrupts::init();
+    time::calibrate();
     serial_println!("Interrupts initialized");
     
     // Start the command line interface
@@ -81,7 +129,10 @@ fn test_runner(tests: &[&dyn Fn()]) {
 }
 
 mod shell {
-    use crate::{print, println, filesystem, executor, process};
+    use crate::{print, println, block, cache, vfs, process, loader, scheduler};
+    use crate::debugger::Debugger;
+    use crate::rtc::DateTime;
+    use crate::vm::Step;
     use crate::keyboard;
     use alloc::string::String;
     use alloc::vec::Vec;
@@ -129,11 +180,262 @@ mod shell {
                 }
             }
             
-            x86_64::instructions::hlt();
+            if keyboard::take_interrupt() {
+                println!("^C");
+                input_len = 0;
+                print!("> ");
+            }
+            
+            // Background jobs run and the disk cache is written back
+            // while the shell waits for input
+            scheduler::reap_jobs();
+            block::write_back_expired();
+            if !scheduler::run_slice() {
+                x86_64::instructions::hlt();
+            }
         }
     }
     
-    fn process_command(cmd: &str) {
+    /// Read a line of input with echo and backspace. Returns `None` if
+    /// Ctrl+C is pressed.
+    fn read_line(prompt: &str) -> Option<String> {
+        let mut line = String::new();
+        print!("{}", prompt);
+        loop {
+            if keyboard::take_interrupt() {
+                println!("^C");
+                return None;
+            }
+            match keyboard::read_key() {
+                Some(b'\n') => {
+                    println!("");
+                    return Some(line);
+                }
+                Some(8) => {
+                    if line.pop().is_some() {
+                        print!("{}", 8 as char);
+                    }
+                }
+                Some(c) if (32..127).contains(&c) && line.len() < MAX_INPUT_LEN - 1 => {
+                    line.push(c as char);
+                    print!("{}", c as char);
+                }
+                Some(_) => {}
+                None => {
+                    scheduler::reap_jobs();
+                    block::write_back_expired();
+                    if !scheduler::run_slice() {
+                        x86_64::instructions::hlt();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Interactive debugger session for a bytecode program. The program
+    /// runs as its own process so its syscalls are attributed to it.
+    fn debug(filename: &str, argv: &[&str]) {
+        let content = match vfs::VFS.lock().read_file(filename) {
+            Ok(content) => content,
+            Err(e) => {
+                println!("{}: {}", filename, e);
+                return;
+            }
+        };
+        let vm = match crate::executor::prepare(&content, false) {
+            Ok(vm) => vm.with_args(
+                argv.iter().map(|arg| String::from(*arg)).collect(),
+                loader::environment(),
+            ),
+            Err(e) => {
+                println!("{}: {}", filename, e);
+                return;
+            }
+        };
+        let pid = match process::PROCESS_MANAGER.lock().create_process(String::from(filename), content) {
+            Ok(pid) => pid,
+            Err(e) => {
+                println!("Failed to create process: {}", e);
+                return;
+            }
+        };
+
+        let mut debugger = Debugger::new(vm);
+        println!("Debugging {} as PID {}; 'quit' to stop", filename, pid);
+        println!("{}", debugger.current());
+        while let Some(line) = read_line("(debug) ") {
+            match line.trim() {
+                "quit" | "q" => break,
+                "help" | "h" => {
+                    println!("break <offset>, delete <offset>, step [count], continue,");
+                    println!("regs, stack, mem <addr> <len>, list, quit");
+                    continue;
+                }
+                _ => {}
+            }
+            let previous = {
+                let mut pm = process::PROCESS_MANAGER.lock();
+                let previous = pm.get_current();
+                pm.set_current(pid);
+                previous
+            };
+            let output = debugger.command(&line, &mut keyboard::take_interrupt);
+            process::PROCESS_MANAGER.lock().restore_current(previous);
+            for line in output {
+                println!("{}", line);
+            }
+        }
+
+        let mut pm = process::PROCESS_MANAGER.lock();
+        if pm.get_process(pid).is_some_and(|p| p.state != process::ProcessState::Terminated) {
+            let code = match debugger.finished() {
+                Some(Step::Fault(_)) => -1,
+                Some(_) => debugger.exit_status().unwrap_or(0),
+                None => scheduler::INTERRUPTED,
+            };
+            let _ = pm.exit_process(pid, code);
+        }
+        let parent = pm.get_current();
+        if let Ok((pid, code)) = pm.reap(parent, Some(pid)) {
+            println!("PID {} exited ({})", pid, code);
+        }
+    }
+
+    /// Run a bytecode program with the interpreter and then with the JIT,
+    /// and compare the times
+    fn bench(filename: &str, argv: &[&str]) {
+        let environment = loader::environment();
+        let envp: Vec<&str> = environment.iter().map(String::as_str).collect();
+        let mut times = [0u64; 2];
+        for (i, jit) in [false, true].into_iter().enumerate() {
+            let start = crate::time::tsc();
+            let pid = match crate::executor::spawn(filename, argv, &envp, jit) {
+                Ok(pid) => pid,
+                Err(e) => {
+                    println!("{}: {}", filename, e);
+                    return;
+                }
+            };
+            times[i] = crate::time::tsc_to_us(crate::time::tsc() - start);
+
+            let mut pm = process::PROCESS_MANAGER.lock();
+            let stats = pm.get_process(pid).and_then(|p| p.jit_stats);
+            let parent = pm.get_current();
+            let code = pm.reap(parent, Some(pid)).map_or(-1, |(_, code)| code);
+            println!("");
+            match stats {
+                Some(stats) => println!(
+                    "jit:         {} us, status {} ({} blocks, {} bytes; {} instructions native, {} interpreted)",
+                    times[i], code, stats.blocks, stats.code_bytes, stats.native, stats.interpreted
+                ),
+                None => println!("interpreter: {} us, status {}", times[i], code),
+            }
+        }
+        if let Some(tenths) = (times[0] * 10).checked_div(times[1]) {
+            println!("speedup:     {}.{}x", tenths / 10, tenths % 10);
+        }
+    }
+
+    /// Print the inode details of a file, not following a final symlink
+    fn stat(path: &str) {
+        let vfs = vfs::VFS.lock();
+        let stat = match vfs.lstat(path) {
+            Ok(stat) => stat,
+            Err(e) => {
+                println!("{}: {}", path, e);
+                return;
+            }
+        };
+        let kind = match stat.file_type {
+            vfs::FileType::Regular => "regular file",
+            vfs::FileType::Directory => "directory",
+            vfs::FileType::Symlink => "symbolic link",
+            vfs::FileType::Device => "character device",
+        };
+        match vfs.read_link(path) {
+            Ok(target) => println!("    File: {} -> {}", path, target),
+            Err(_) => println!("    File: {}", path),
+        }
+        println!("    Type: {}  Size: {}", kind, stat.size);
+        println!("   Inode: {}  Links: {}", stat.ino, stat.nlink);
+        println!("    Mode: {:04o} ({})  Uid: {}  Gid: {}", stat.mode, stat.mode_string(), stat.uid, stat.gid);
+        println!(" Created: {}", DateTime::from_unix(stat.created));
+        println!("Modified: {}", DateTime::from_unix(stat.modified));
+    }
+
+    /// Check that a disk reads back what is written to it, using its last
+    /// sectors and restoring them afterwards, then time a sequential read
+    fn disk_test(name: &str) -> Result<(), &'static str> {
+        const SECTORS: usize = 16;
+        const LEN: usize = SECTORS * block::SECTOR_SIZE;
+        let disk = block::find(name).ok_or("No such device")?;
+        let total = block::sector_count(disk).unwrap_or(0);
+        if total < SECTORS as u64 {
+            return Err("Disk too small");
+        }
+        let lba = total - SECTORS as u64;
+        let report = |what: &str, ok: bool| println!("{:<28} {}", what, if ok { "ok" } else { "FAILED" });
+
+        let mut saved = [0u8; LEN];
+        let mut pattern = [0u8; LEN];
+        let mut readback = [0u8; LEN];
+        block::read_sectors(disk, lba, &mut saved)?;
+        for (i, byte) in pattern.iter_mut().enumerate() {
+            *byte = (i * 7 + i / block::SECTOR_SIZE) as u8;
+        }
+
+        block::write_sectors(disk, lba, &pattern)?;
+        block::read_sectors(disk, lba, &mut readback)?;
+        report("multi-sector write and read", readback == pattern);
+        let middle = SECTORS / 2 * block::SECTOR_SIZE;
+        block::read_sectors(disk, lba + (SECTORS / 2) as u64, &mut readback[..block::SECTOR_SIZE])?;
+        report("single sector read", readback[..block::SECTOR_SIZE] == pattern[middle..][..block::SECTOR_SIZE]);
+        report("read past the end fails", block::read_sectors(disk, total, &mut readback[..block::SECTOR_SIZE]).is_err());
+
+        block::write_sectors(disk, lba, &saved)?;
+        block::read_sectors(disk, lba, &mut readback)?;
+        report("original data restored", readback == saved);
+
+        // Up to 1 MiB from the start of the disk
+        let runs = (total / SECTORS as u64).min(128);
+        let start = crate::time::tsc();
+        for run in 0..runs {
+            block::read_sectors(disk, run * SECTORS as u64, &mut readback)?;
+        }
+        let us = crate::time::tsc_to_us(crate::time::tsc() - start).max(1);
+        let bytes = runs * LEN as u64;
+        println!("read {} KiB in {} us ({} KiB/s)", bytes / 1024, us, bytes * 1_000_000 / 1024 / us);
+        Ok(())
+    }
+
+    /// Hex dump a file, reading it a chunk at a time
+    fn cat(path: &str) -> Result<(), &'static str> {
+        let mut fs = vfs::VFS.lock();
+        let size = fs.stat(path)?.size;
+        let mut handle = fs.open(path, vfs::OpenFlags::default())?;
+        println!("File: {} ({} bytes)", path, size);
+        print!("Content (hex): ");
+        let mut chunk = [0u8; 512];
+        let mut offset = 0;
+        loop {
+            let count = fs.read(&mut handle, &mut chunk)?;
+            if count == 0 {
+                break;
+            }
+            for byte in &chunk[..count] {
+                if offset > 0 && offset % 16 == 0 {
+                    println!("");
+                    print!("               ");
+                }
+                print!("{:02x} ", byte);
+                offset += 1;
+            }
+        }
+        println!("");
+        Ok(())
+    }
+
+    pub fn process_command(cmd: &str) {
         let mut parts_vec: [&str; 16] = [""; 16];
         let mut count = 0;
         
@@ -156,12 +458,44 @@ mod shell {
                 println!("  help       - Show this help message");
                 println!("  echo       - Echo back the arguments");
                 println!("  clear      - Clear the screen");
-                println!("  ls         - List files in filesystem");
+                println!("  ls [dir]   - List a directory");
+                println!("  mkdir <dir> / rmdir <dir> - Create or remove a directory");
+                println!("  rm <file>  - Remove a file or link");
+                println!("  cd [dir]   - Change directory (default /)");
+                println!("  pwd        - Print the working directory");
+                println!("  ls -l [dir] - List with type, mode, links, owner, size and time");
+                println!("  stat <file> - Show a file's inode details");
+                println!("  ln [-s] <target> <name> - Create a hard or symbolic link");
+                println!("  chmod <mode> <file> - Change permission bits (octal)");
+                println!("  date       - Show the date and time");
+                println!("  mount [ramfs <dir>] - List mounts or mount a new ramfs");
+                println!("  mount fat <disk> <dir> - Mount a FAT filesystem from a disk");
+                println!("  mount sfs <disk> <dir> - Mount a native filesystem made with bbx mkfs");
+                println!("  umount <dir> - Unmount a filesystem");
                 println!("  cat <file> - Display file contents (hex)");
-                println!("  run <file> - Execute a binary program");
+                println!("  run <file> - Execute a program (bytecode, ELF or #! script)");
+                println!("  run <file> & - Run a bytecode program in the background");
+                println!("  run --jit <file> - Run a bytecode program compiled to native code");
+                println!("  bench <file> - Time a bytecode program interpreted and compiled");
+                println!("  kill <pid> - Stop a bytecode program (Ctrl+C stops the foreground one)");
                 println!("  ps         - List running processes");
                 println!("  mem        - Show memory info");
-                println!("  write <file> <text> - Create a text file");
+                println!("  disks      - List detected disks and their sizes");
+                println!("  disktest <disk> - Check a disk's reads and writes and time it");
+                println!("  mkfs <disk> - Erase a disk and write an empty native filesystem");
+                println!("  sync       - Write cached disk sectors back to their disks");
+                println!("  cache      - Show block cache usage and hit rate");
+                println!("  write <file> <text> - Create a text file (only files under /disk persist)");
+                println!("  append <file> <text> - Append a line to a text file");
+                println!("  truncate <file> <size> - Cut or zero-extend a file");
+                println!("  asm <src> <out>     - Assemble a bytecode source file");
+                println!("  disasm <file>       - Disassemble a bytecode program");
+                println!("  debug <file> [args] - Step through a bytecode program");
+                println!("  env                 - List environment variables");
+                println!("  export <name>=<value> - Set an environment variable");
+                println!("  unset <name>        - Remove an environment variable");
+                println!("  strace <command>    - Run a command, logging syscalls to serial");
+                println!("  trace <pid> <on|off> - Toggle syscall tracing for a process");
             }
             "echo" => {
                 // Echo all arguments after the command
@@ -177,89 +511,213 @@ mod shell {
                 crate::vga_buffer::clear_screen();
             }
             "ls" => {
-                let fs = filesystem::FILESYSTEM.lock();
-                let files = fs.list_files();
-                if files.is_empty() {
-                    println!("No files in filesystem");
+                let long = parts_vec[1] == "-l";
+                let first = if long { 2 } else { 1 };
+                let path = if count > first { parts_vec[first] } else { "." };
+                let vfs = vfs::VFS.lock();
+                match vfs.list_dir(path) {
+                    Ok(entries) if entries.is_empty() => println!("Empty directory"),
+                    Ok(entries) => {
+                        for (name, stat) in entries {
+                            if long {
+                                print!("{} {:>2} {} {} {:>6} {} {}", stat.mode_string(), stat.nlink,
+                                    stat.uid, stat.gid, stat.size, DateTime::from_unix(stat.modified), name);
+                                match vfs.read_link(&alloc::format!("{}/{}", path, name)) {
+                                    Ok(target) => println!(" -> {}", target),
+                                    Err(_) => println!(""),
+                                }
+                                continue;
+                            }
+                            match stat.file_type {
+                                vfs::FileType::Directory => println!("  {}/", name),
+                                vfs::FileType::Symlink => println!("  {}@", name),
+                                vfs::FileType::Device => println!("  {} (device)", name),
+                                vfs::FileType::Regular => println!("  {} ({} bytes)", name, stat.size),
+                            }
+                        }
+                    }
+                    Err(e) => println!("{}: {}", path, e),
+                }
+            }
+            "stat" => {
+                if count != 2 {
+                    println!("Usage: stat <file>");
                 } else {
-                    println!("Files:");
-                    for file in files {
-                        println!("  {} ({} bytes)", file.name, file.size);
+                    stat(parts_vec[1]);
+                }
+            }
+            "ln" => {
+                let result = match (count, parts_vec[1]) {
+                    (4, "-s") => vfs::VFS.lock().symlink(parts_vec[2], parts_vec[3]),
+                    (3, _) => vfs::VFS.lock().link(parts_vec[1], parts_vec[2]),
+                    _ => {
+                        println!("Usage: ln [-s] <target> <name>");
+                        return;
                     }
+                };
+                if let Err(e) = result {
+                    println!("{}: {}", parts_vec[count - 1], e);
                 }
             }
+            "chmod" => {
+                match u16::from_str_radix(parts_vec[1], 8) {
+                    Ok(mode) if count == 3 && mode <= 0o777 => {
+                        if let Err(e) = vfs::VFS.lock().set_mode(parts_vec[2], mode) {
+                            println!("{}: {}", parts_vec[2], e);
+                        }
+                    }
+                    _ => println!("Usage: chmod <octal mode> <file>"),
+                }
+            }
+            "date" => {
+                println!("{} UTC", DateTime::from_unix(crate::rtc::now()));
+            }
+            "mkdir" => {
+                if count != 2 {
+                    println!("Usage: mkdir <directory>");
+                } else if let Err(e) = vfs::VFS.lock().create_dir(parts_vec[1]) {
+                    println!("{}: {}", parts_vec[1], e);
+                }
+            }
+            "rmdir" => {
+                if count != 2 {
+                    println!("Usage: rmdir <directory>");
+                } else if let Err(e) = vfs::VFS.lock().remove_dir(parts_vec[1]) {
+                    println!("{}: {}", parts_vec[1], e);
+                }
+            }
+            "rm" => {
+                if count != 2 {
+                    println!("Usage: rm <file>");
+                } else if let Err(e) = vfs::VFS.lock().delete_file(parts_vec[1]) {
+                    println!("{}: {}", parts_vec[1], e);
+                }
+            }
+            "cd" => {
+                let path = if count > 1 { parts_vec[1] } else { "/" };
+                if let Err(e) = vfs::VFS.lock().change_dir(path) {
+                    println!("{}: {}", path, e);
+                }
+            }
+            "mount" => {
+                let mut vfs = vfs::VFS.lock();
+                match count {
+                    1 => {
+                        for (path, name) in vfs.mounts() {
+                            println!("{} on {}", name, path);
+                        }
+                    }
+                    3 if parts_vec[1] == "ramfs" => {
+                        if let Err(e) = vfs.mount(parts_vec[2], alloc::boxed::Box::new(crate::ramfs::RamFs::new())) {
+                            println!("{}: {}", parts_vec[2], e);
+                        }
+                    }
+                    4 if matches!(parts_vec[1], "fat" | "sfs") => {
+                        let result = match crate::block::find(parts_vec[2]) {
+                            Some(disk) if parts_vec[1] == "fat" => crate::fat::FatFs::mount(disk)
+                                .map(|fs| alloc::boxed::Box::new(fs) as alloc::boxed::Box<dyn vfs::FileSystem>),
+                            Some(disk) => crate::diskfs::mount(disk)
+                                .map(|fs| alloc::boxed::Box::new(fs) as alloc::boxed::Box<dyn vfs::FileSystem>),
+                            None => Err("No such device"),
+                        };
+                        if let Err(e) = result.and_then(|fs| vfs.mount(parts_vec[3], fs)) {
+                            println!("{}: {}", parts_vec[2], e);
+                        }
+                    }
+                    _ => println!("Usage: mount [ramfs <directory> | fat|sfs <disk> <directory>]"),
+                }
+            }
+            "umount" => {
+                if count != 2 {
+                    println!("Usage: umount <directory>");
+                } else if let Err(e) = vfs::VFS.lock().unmount(parts_vec[1]) {
+                    println!("{}: {}", parts_vec[1], e);
+                }
+            }
+            "pwd" => {
+                println!("{}", vfs::VFS.lock().cwd());
+            }
             "cat" => {
                 if count < 2 {
                     println!("Usage: cat <filename>");
-                } else {
-                    let filename = parts_vec[1];
-                    let fs = filesystem::FILESYSTEM.lock();
-                    if let Some(content) = fs.read_file(filename) {
-                        println!("File: {} ({} bytes)", filename, content.len());
-                        print!("Content (hex): ");
-                        for (i, byte) in content.iter().enumerate() {
-                            if i > 0 && i % 16 == 0 {
-                                println!("");
-                                print!("               ");
-                            }
-                            print!("{:02x} ", byte);
-                        }
-                        println!("");
-                    } else {
-                        println!("File not found: {}", filename);
-                    }
+                } else if let Err(e) = cat(parts_vec[1]) {
+                    println!("{}: {}", parts_vec[1], e);
                 }
             }
             "run" => {
-                if count < 2 {
-                    println!("Usage: run <filename>");
+                let jit = parts_vec[1] == "--jit";
+                let first = if jit { 2 } else { 1 };
+                if count <= first {
+                    println!("Usage: run [--jit] <filename> [args...] [&]");
                 } else {
-                    let filename = parts_vec[1];
+                    let filename = parts_vec[first];
+                    let background = parts_vec[count - 1] == "&";
+                    if background {
+                        count -= 1;
+                    }
+                    let argv = &parts_vec[first..count];
                     
-                    // Clone the content before releasing the lock
-                    let content_option = {
-                        let fs = filesystem::FILESYSTEM.lock();
-                        fs.read_file(filename).map(|c| c.clone())
+                    let format = match loader::detect_file(filename) {
+                        Ok(format) => format,
+                        Err(e) => {
+                            println!("{}: {}", filename, e);
+                            return;
+                        }
                     };
+                    if format.is_none() {
+                        println!("{}: not an executable", filename);
+                        return;
+                    }
+                    if jit && format != Some(loader::Format::Bytecode) {
+                        println!("{}: --jit only applies to bytecode programs", filename);
+                        return;
+                    }
                     
-                    if let Some(content) = content_option {
-                        println!("Executing: {}", filename);
-                        
-                        // Create a process
-                        let mut pm = process::PROCESS_MANAGER.lock();
-                        let mut name = String::new();
-                        for c in filename.chars() {
-                            name.push(c);
+                    let environment = loader::environment();
+                    let envp: Vec<&str> = environment.iter().map(String::as_str).collect();
+                    if background {
+                        if format != Some(loader::Format::Bytecode) {
+                            println!("{}: only bytecode programs can run in the background", filename);
+                            return;
                         }
-                        match pm.create_process(name, content.clone()) {
+                        match crate::executor::start(filename, argv, &envp, jit) {
                             Ok(pid) => {
-                                pm.set_current(pid);
-                                drop(pm);
-                                
-                                // Execute the program
-                                match executor::execute_program(content.clone()) {
-                                    Ok(_) => {
-                                        println!("");
-                                        let mut pm = process::PROCESS_MANAGER.lock();
-                                        let _ = pm.terminate_process(pid);
-                                    }
-                                    Err(e) => {
-                                        println!("");
-                                        println!("Execution error: {}", e);
-                                        let mut pm = process::PROCESS_MANAGER.lock();
-                                        let _ = pm.terminate_process(pid);
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                println!("Failed to create process: {}", e);
+                                scheduler::add_job(pid);
+                                println!("[{}] {}", pid, filename);
                             }
+                            Err(e) => println!("Failed to create process: {}", e),
                         }
+                        return;
+                    }
+                    
+                    println!("Executing: {}", filename);
+                    let result = if jit {
+                        crate::executor::spawn(filename, argv, &envp, true)
                     } else {
-                        println!("File not found: {}", filename);
+                        loader::spawn(filename, argv)
+                    };
+                    match result {
+                        Ok(pid) => {
+                            println!("");
+                            let mut pm = process::PROCESS_MANAGER.lock();
+                            let parent = pm.get_current();
+                            if let Ok((_, code)) = pm.reap(parent, Some(pid)) {
+                                println!("Exited with status {}", code);
+                            }
+                        }
+                        Err(e) => {
+                            println!("Failed to create process: {}", e);
+                        }
                     }
                 }
             }
+            "bench" => {
+                if count < 2 {
+                    println!("Usage: bench <filename> [args...]");
+                } else {
+                    bench(parts_vec[1], &parts_vec[1..count]);
+                }
+            }
             "ps" => {
                 let pm = process::PROCESS_MANAGER.lock();
                 let processes = pm.list_processes();
@@ -268,10 +726,69 @@ mod shell {
                 } else {
                     println!("Active processes:");
                     for proc in processes {
-                        println!("  PID {} - {} ({:?})", proc.pid, proc.name, proc.state);
+                        match proc.vm {
+                            Some(_) => println!("  PID {} - {} ({:?}, ip {:#06x})",
+                                proc.pid, proc.name, proc.state, proc.instruction_pointer),
+                            None => println!("  PID {} - {} ({:?})", proc.pid, proc.name, proc.state),
+                        }
+                    }
+                }
+            }
+            "disks" => {
+                let disks = block::disks();
+                if disks.is_empty() {
+                    println!("No disks found");
+                }
+                for disk in disks {
+                    let bytes = disk.sectors * block::SECTOR_SIZE as u64;
+                    let size = if bytes < 1024 * 1024 {
+                        alloc::format!("{} KiB", bytes / 1024)
+                    } else {
+                        alloc::format!("{} MiB", bytes / (1024 * 1024))
+                    };
+                    println!("{:<6} {:>9} {:>10} sectors  {}", disk.name, size, disk.sectors, disk.model);
+                }
+            }
+            "mkfs" => {
+                if count != 2 {
+                    println!("Usage: mkfs <disk>");
+                } else {
+                    let result = crate::block::find(parts_vec[1]).ok_or("No such device")
+                        .and_then(crate::diskfs::format)
+                        .and_then(|fs| fs.usage());
+                    match result {
+                        Ok((blocks, free_blocks, inodes, _)) => println!(
+                            "{}: {} blocks ({} free), {} inodes",
+                            parts_vec[1], blocks, free_blocks, inodes
+                        ),
+                        Err(e) => println!("{}: {}", parts_vec[1], e),
                     }
                 }
             }
+            "disktest" => {
+                if count != 2 {
+                    println!("Usage: disktest <disk>");
+                } else if let Err(e) = disk_test(parts_vec[1]) {
+                    println!("{}: {}", parts_vec[1], e);
+                }
+            }
+            "sync" => {
+                if let Err(e) = block::sync() {
+                    println!("sync: {}", e);
+                }
+            }
+            "cache" => {
+                let stats = block::cache_stats();
+                let lookups = stats.hits + stats.misses;
+                println!("Block cache: {} buffers of {} bytes", cache::BUFFERS, block::SECTOR_SIZE);
+                println!("  In use:       {} ({} dirty)", stats.used, stats.dirty);
+                println!("  Hits:         {}", stats.hits);
+                println!("  Misses:       {}", stats.misses);
+                if let Some(rate) = (stats.hits * 100).checked_div(lookups) {
+                    println!("  Hit rate:     {}%", rate);
+                }
+                println!("  Written back: {}", stats.write_backs);
+            }
             "mem" => {
                 println!("Memory Information:");
                 println!("  Heap start: 0x{:x}", crate::allocator::get_heap_start());
@@ -290,21 +807,155 @@ mod shell {
                         text.push_str(parts_vec[i]);
                     }
                     
-                    let mut fs = filesystem::FILESYSTEM.lock();
-                    let mut fname = String::new();
-                    for c in filename.chars() {
-                        fname.push(c);
-                    }
+                    let mut fs = vfs::VFS.lock();
                     let mut content_vec = Vec::new();
                     for &byte in text.as_bytes() {
                         content_vec.push(byte);
                     }
-                    match fs.create_file(fname, content_vec) {
+                    match fs.write_file(filename, content_vec) {
                         Ok(_) => println!("File created: {}", filename),
                         Err(e) => println!("Error: {}", e),
                     }
                 }
             }
+            "append" => {
+                if count < 3 {
+                    println!("Usage: append <filename> <text>");
+                } else {
+                    let filename = parts_vec[1];
+                    let mut line = parts_vec[2..count].join(" ");
+                    line.push('\n');
+                    let mut fs = vfs::VFS.lock();
+                    let flags = vfs::OpenFlags { write: true, create: true, append: true, truncate: false };
+                    match fs.open(filename, flags).and_then(|mut handle| fs.write(&mut handle, line.as_bytes())) {
+                        Ok(_) => println!("Appended to: {}", filename),
+                        Err(e) => println!("Error: {}", e),
+                    }
+                }
+            }
+            "truncate" => {
+                match parts_vec[2].parse::<usize>() {
+                    Ok(size) if count == 3 => {
+                        let filename = parts_vec[1];
+                        let mut fs = vfs::VFS.lock();
+                        let flags = vfs::OpenFlags { write: true, ..Default::default() };
+                        if let Err(e) = fs.open(filename, flags).and_then(|handle| fs.truncate(&handle, size)) {
+                            println!("{}: {}", filename, e);
+                        }
+                    }
+                    _ => println!("Usage: truncate <filename> <size>"),
+                }
+            }
+            "asm" => {
+                if count != 3 {
+                    println!("Usage: asm <source> <output>");
+                } else {
+                    let mut fs = vfs::VFS.lock();
+                    let source = match fs.read_file(parts_vec[1]) {
+                        Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+                        Err(e) => {
+                            println!("{}: {}", parts_vec[1], e);
+                            return;
+                        }
+                    };
+                    match crate::asm::assemble(&source) {
+                        Ok(program) => {
+                            let image = crate::bytecode::build(&program);
+                            let size = image.len();
+                            match fs.write_file(parts_vec[2], image).and_then(|()| fs.set_mode(parts_vec[2], 0o755)) {
+                                Ok(_) => println!("Assembled {} ({} bytes)", parts_vec[2], size),
+                                Err(e) => println!("Error: {}", e),
+                            }
+                        }
+                        Err(e) => println!("{}: {}", parts_vec[1], e),
+                    }
+                }
+            }
+            "disasm" => {
+                if count != 2 {
+                    println!("Usage: disasm <filename>");
+                } else {
+                    let program = match vfs::VFS.lock().read_file(parts_vec[1]) {
+                        Ok(content) => crate::bytecode::parse(&content),
+                        Err(e) => {
+                            println!("{}: {}", parts_vec[1], e);
+                            return;
+                        }
+                    };
+                    match program {
+                        Ok(program) => {
+                            for line in crate::asm::disassemble(&program) {
+                                println!("{}", line);
+                            }
+                        }
+                        Err(e) => println!("{}: {}", parts_vec[1], e),
+                    }
+                }
+            }
+            "debug" => {
+                if count < 2 {
+                    println!("Usage: debug <filename> [args...]");
+                } else {
+                    debug(parts_vec[1], &parts_vec[1..count]);
+                }
+            }
+            "env" => {
+                for entry in loader::environment() {
+                    println!("{}", entry);
+                }
+            }
+            "export" => {
+                match parts_vec[1].split_once('=') {
+                    Some((name, value)) if count == 2 => {
+                        if let Err(e) = loader::set_env(name, value) {
+                            println!("Error: {}", e);
+                        }
+                    }
+                    _ => println!("Usage: export <name>=<value>"),
+                }
+            }
+            "unset" => {
+                if count != 2 {
+                    println!("Usage: unset <name>");
+                } else if !loader::unset_env(parts_vec[1]) {
+                    println!("Not set: {}", parts_vec[1]);
+                }
+            }
+            "kill" => {
+                match parts_vec[1].parse::<process::Pid>() {
+                    Ok(pid) if count == 2 => match scheduler::kill(pid) {
+                        Ok(()) => println!("Killed PID {}", pid),
+                        Err(e) => println!("Error: {}", e),
+                    },
+                    _ => println!("Usage: kill <pid>"),
+                }
+            }
+            "strace" => {
+                if count < 2 {
+                    println!("Usage: strace <command> [args...]");
+                } else {
+                    let inner = parts_vec[1..count].join(" ");
+                    process::PROCESS_MANAGER.lock().set_trace_new(true);
+                    process_command(&inner);
+                    process::PROCESS_MANAGER.lock().set_trace_new(false);
+                }
+            }
+            "trace" => {
+                let enable = match parts_vec[2] {
+                    "on" => Some(true),
+                    "off" => Some(false),
+                    _ => None,
+                };
+                match (parts_vec[1].parse::<process::Pid>(), enable) {
+                    (Ok(pid), Some(enable)) if count == 3 => {
+                        match process::PROCESS_MANAGER.lock().set_traced(pid, enable) {
+                            Ok(()) => println!("Tracing {} for PID {}", parts_vec[2], pid),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    }
+                    _ => println!("Usage: trace <pid> <on|off>"),
+                }
+            }
             "" => {}
             _ => {
                 println!("Unknown command: {}", command);
@@ -0,0 +1,120 @@
+//! Virtual to physical address translation
+//!
+//! The bootloader maps all of physical memory at a fixed offset, which is
+//! how the page tables themselves are reached. Devices that read and
+//! write memory directly (DMA) need the physical address of a buffer, and
+//! native programs need their pages mapped at the addresses they were
+//! linked for, with user access.
+use alloc::alloc::{alloc_zeroed, Layout};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::{Cr3, Efer, EferFlags};
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Size of a page and of the frames backing user memory
+pub const PAGE_SIZE: usize = 4096;
+
+/// Virtual address at which physical memory starts
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Record where the bootloader mapped physical memory
+pub fn init(physical_memory_offset: u64) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+}
+
+/// The active page tables, reached through the physical memory mapping
+fn page_table() -> OffsetPageTable<'static> {
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table = (offset + level_4_frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+    // The kernel runs on one CPU and never holds two of these at once
+    unsafe { OffsetPageTable::new(&mut *level_4_table, offset) }
+}
+
+/// Physical address backing a kernel virtual address, if it is mapped
+pub fn physical_address(addr: usize) -> Option<u64> {
+    let addr = VirtAddr::try_new(addr as u64).ok()?;
+    page_table().translate_addr(addr).map(|phys| phys.as_u64())
+}
+
+/// Allocate a zeroed, page-aligned page of kernel heap memory
+pub fn alloc_page() -> Option<*mut u8> {
+    let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).ok()?;
+    let page = unsafe { alloc_zeroed(layout) };
+    (!page.is_null()).then_some(page)
+}
+
+/// Page tables created for user mappings come from the kernel heap and
+/// are kept for later mappings
+struct HeapFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for HeapFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let page = alloc_page()?;
+        PhysFrame::from_start_address(PhysAddr::new(physical_address(page as usize)?)).ok()
+    }
+}
+
+/// Map the user page at `vaddr` to the frame behind the kernel page at
+/// `backing`, which must be page-aligned heap memory from `alloc_page`
+pub fn map_user_page(vaddr: u64, backing: *mut u8, writable: bool, executable: bool) -> Result<(), &'static str> {
+    let page = VirtAddr::try_new(vaddr).ok()
+        .and_then(|addr| Page::<Size4KiB>::from_start_address(addr).ok())
+        .ok_or("Invalid user address")?;
+    let frame = physical_address(backing as usize)
+        .and_then(|phys| PhysFrame::from_start_address(PhysAddr::new(phys)).ok())
+        .ok_or("Invalid backing page")?;
+
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    // Without NXE the bit is reserved and would fault every access
+    if !executable && Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    let result = unsafe { page_table().map_to_with_table_flags(page, frame, flags, table_flags, &mut HeapFrames) };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::FrameAllocationFailed) => Err("Out of memory"),
+        Err(_) => Err("Address already in use"),
+    }
+}
+
+/// Remove a mapping made by `map_user_page`
+pub fn unmap_user_page(vaddr: u64) {
+    if let Ok(page) = Page::<Size4KiB>::from_start_address(VirtAddr::new(vaddr)) {
+        if let Ok((_, flush)) = page_table().unmap(page) {
+            flush.flush();
+        }
+    }
+}
+
+/// Whether ring 3 may read `[addr, addr + len)`, and write it if `write`
+pub fn user_accessible(addr: usize, len: usize, write: bool) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let table = page_table();
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        let mapped = VirtAddr::try_new(page as u64).ok().map(|page| table.translate(page));
+        match mapped {
+            Some(TranslateResult::Mapped { flags, .. })
+                if flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                    && (!write || flags.contains(PageTableFlags::WRITABLE)) => {}
+            _ => return false,
+        }
+        page += PAGE_SIZE;
+    }
+    true
+}
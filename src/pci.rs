@@ -0,0 +1,101 @@
+//! PCI configuration space access through the legacy I/O ports
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// Configuration space registers
+const VENDOR_DEVICE: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const HEADER_TYPE: u8 = 0x0C;
+const BAR0: u8 = 0x10;
+
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+/// Header type bit marking a device with more than one function
+const MULTI_FUNCTION: u32 = 0x80 << 16;
+
+/// One function of a device on the PCI bus
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+}
+
+/// Value selecting a register in `CONFIG_ADDRESS`
+fn config_address(bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+    0x8000_0000 | u32::from(bus) << 16 | u32::from(slot) << 11 | u32::from(function) << 8 | u32::from(offset & 0xFC)
+}
+
+fn read_config(bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+    unsafe {
+        Port::<u32>::new(CONFIG_ADDRESS).write(config_address(bus, slot, function, offset));
+        Port::<u32>::new(CONFIG_DATA).read()
+    }
+}
+
+impl PciDevice {
+    /// Read a 32-bit configuration register
+    pub fn read(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.slot, self.function, offset)
+    }
+
+    /// Write a 32-bit configuration register
+    pub fn write(&self, offset: u8, value: u32) {
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(config_address(self.bus, self.slot, self.function, offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    /// Port base of base address register `index`, if it maps I/O ports
+    pub fn io_bar(&self, index: u8) -> Option<u16> {
+        let bar = self.read(BAR0 + 4 * index);
+        (bar & 1 == 1).then_some((bar & !0x3) as u16)
+    }
+
+    /// Let the device answer on its I/O ports and access memory itself
+    pub fn enable_io_and_dma(&self) {
+        let command = self.read(COMMAND) & 0xFFFF;
+        self.write(COMMAND, command | COMMAND_IO_SPACE | COMMAND_BUS_MASTER);
+    }
+}
+
+/// Every function present on every bus
+pub fn devices() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for slot in 0..32 {
+            for function in 0..8 {
+                let id = read_config(bus, slot, function, VENDOR_DEVICE);
+                if id & 0xFFFF == 0xFFFF {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                devices.push(PciDevice {
+                    bus,
+                    slot,
+                    function,
+                    vendor_id: id as u16,
+                    device_id: (id >> 16) as u16,
+                });
+                if function == 0 && read_config(bus, slot, 0, HEADER_TYPE) & MULTI_FUNCTION == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    devices
+}
+
+/// The first device with the given vendor and device IDs
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    devices().into_iter().find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
@@ -1,6 +1,11 @@
 use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use crate::elf::AddressSpace;
+use crate::executor::KernelEnv;
+use crate::jit::JitStats;
+use crate::vfs::FileHandle;
+use crate::vm::Executor;
 
 /// Process ID type
 pub type Pid = usize;
@@ -8,6 +13,16 @@ pub type Pid = usize;
 /// Maximum number of processes
 const MAX_PROCESSES: usize = 16;
 
+/// Maximum size of a process's sbrk-managed heap
+pub const PROCESS_HEAP_LIMIT: usize = 4 * 1024;
+
+/// Descriptor of the first file a process opens, after stdin, stdout
+/// and stderr
+pub const FIRST_FILE_FD: usize = 3;
+
+/// Maximum number of files one process may have open
+pub const MAX_OPEN_FILES: usize = 16;
+
 /// Process states
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
@@ -26,10 +41,27 @@ pub struct Process {
     pub entry_point: usize,
     pub stack_pointer: usize,
     pub instruction_pointer: usize,
+    pub parent: Option<Pid>,
+    pub exit_code: i32,
+    /// Log every syscall made by this process to the serial port
+    pub traced: bool,
+    /// Data segment grown and shrunk by `sbrk`
+    pub heap: Vec<u8>,
+    /// Anonymous regions handed out by `mmap`
+    pub mappings: Vec<Vec<u8>>,
+    /// Files opened with `open`; descriptor `FIRST_FILE_FD + i` is slot `i`
+    pub files: Vec<Option<FileHandle>>,
+    /// Memory image of a native (ELF) process
+    pub address_space: Option<AddressSpace>,
+    /// Interpreter state of a bytecode process between scheduler slices
+    pub vm: Option<Executor<KernelEnv>>,
+    /// JIT activity of a bytecode process started with `run --jit`, kept
+    /// after it exits
+    pub jit_stats: Option<JitStats>,
 }
 
 impl Process {
-    pub fn new(pid: Pid, name: alloc::string::String, code: Vec<u8>) -> Self {
+    pub fn new(pid: Pid, name: alloc::string::String, code: Vec<u8>, parent: Option<Pid>) -> Self {
         Process {
             pid,
             name,
@@ -38,6 +70,15 @@ impl Process {
             entry_point: 0,
             stack_pointer: 0,
             instruction_pointer: 0,
+            parent,
+            exit_code: 0,
+            traced: false,
+            heap: Vec::new(),
+            mappings: Vec::new(),
+            files: Vec::new(),
+            address_space: None,
+            vm: None,
+            jit_stats: None,
         }
     }
 }
@@ -47,6 +88,8 @@ pub struct ProcessManager {
     processes: Vec<Process>,
     next_pid: Pid,
     current_pid: Option<Pid>,
+    /// Trace processes started directly by the shell (`strace`)
+    trace_new: bool,
 }
 
 impl ProcessManager {
@@ -55,10 +98,11 @@ impl ProcessManager {
             processes: Vec::new(),
             next_pid: 1,
             current_pid: None,
+            trace_new: false,
         }
     }
 
-    /// Create a new process
+    /// Create a new process as a child of the current one
     pub fn create_process(&mut self, name: alloc::string::String, code: Vec<u8>) -> Result<Pid, &'static str> {
         if self.processes.len() >= MAX_PROCESSES {
             return Err("Process table full");
@@ -67,7 +111,13 @@ impl ProcessManager {
         let pid = self.next_pid;
         self.next_pid += 1;
 
-        let process = Process::new(pid, name, code);
+        let mut process = Process::new(pid, name, code, self.current_pid);
+
+        // Children of traced processes are traced as well
+        process.traced = match self.current_pid {
+            Some(parent) => self.get_process(parent).is_some_and(|p| p.traced),
+            None => self.trace_new,
+        };
         self.processes.push(process);
 
         Ok(pid)
@@ -83,14 +133,40 @@ impl ProcessManager {
         self.processes.iter_mut().find(|p| p.pid == pid)
     }
 
-    /// Terminate a process
-    pub fn terminate_process(&mut self, pid: Pid) -> Result<(), &'static str> {
-        if let Some(process) = self.get_process_mut(pid) {
-            process.state = ProcessState::Terminated;
-            Ok(())
-        } else {
-            Err("Process not found")
+    /// Terminate a process with an exit status, keeping it around until
+    /// its parent collects the status with `reap`
+    pub fn exit_process(&mut self, pid: Pid, code: i32) -> Result<(), &'static str> {
+        let process = self.get_process_mut(pid).ok_or("Process not found")?;
+        process.state = ProcessState::Terminated;
+        process.exit_code = code;
+        process.heap = Vec::new();
+        process.mappings = Vec::new();
+        process.files = Vec::new();
+        process.address_space = None;
+        process.vm = None;
+        let parent = process.parent;
+
+        // Control returns to whoever started the process
+        if self.current_pid == Some(pid) {
+            self.current_pid = parent;
         }
+        Ok(())
+    }
+
+    /// Remove a terminated child of `parent` and return its pid and exit status.
+    /// `pid` of `None` accepts any terminated child.
+    pub fn reap(&mut self, parent: Option<Pid>, pid: Option<Pid>) -> Result<(Pid, i32), &'static str> {
+        let is_child = |p: &Process| p.parent == parent && pid.is_none_or(|pid| p.pid == pid);
+
+        if !self.processes.iter().any(&is_child) {
+            return Err("No such child");
+        }
+
+        let index = self.processes.iter()
+            .position(|p| is_child(p) && p.state == ProcessState::Terminated)
+            .ok_or("Child still running")?;
+        let process = self.processes.remove(index);
+        Ok((process.pid, process.exit_code))
     }
 
     /// List all active processes
@@ -113,6 +189,32 @@ impl ProcessManager {
         self.current_pid
     }
 
+    /// Make `pid` current again after running another process, without
+    /// changing any process state
+    pub fn restore_current(&mut self, pid: Option<Pid>) {
+        self.current_pid = pid;
+    }
+
+    /// Bytecode processes waiting for a scheduler slice, in pid order
+    pub fn runnable(&self) -> Vec<Pid> {
+        self.processes.iter()
+            .filter(|p| p.state == ProcessState::Ready && p.vm.is_some())
+            .map(|p| p.pid)
+            .collect()
+    }
+
+    /// Enable or disable syscall tracing for a process
+    pub fn set_traced(&mut self, pid: Pid, traced: bool) -> Result<(), &'static str> {
+        let process = self.get_process_mut(pid).ok_or("Process not found")?;
+        process.traced = traced;
+        Ok(())
+    }
+
+    /// Trace every process the shell starts until turned off again
+    pub fn set_trace_new(&mut self, traced: bool) {
+        self.trace_new = traced;
+    }
+
     /// Clean up terminated processes
     pub fn cleanup(&mut self) {
         self.processes.retain(|p| p.state != ProcessState::Terminated);
@@ -0,0 +1,126 @@
+//! Process information filesystem mounted at `/proc`
+//!
+//! Contents are generated on every read: `uptime` and `meminfo` at the
+//! top, and a directory per process holding its `status`.
+use crate::vfs::{DirEntry, FileSystem, FileType, Ino, Stat};
+use crate::process::{self, Pid};
+use crate::{allocator, rtc, time};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+const ROOT: Ino = 0;
+const UPTIME: Ino = 1;
+const MEMINFO: Ino = 2;
+
+/// Node numbers of per-process entries: `(pid + 1) << 4` is the
+/// directory, with its files in the low bits
+const PID_SHIFT: usize = 4;
+const STATUS: Ino = 1;
+
+pub struct ProcFs;
+
+/// Directory node of `pid`, or `None` if the pid is too large to have one
+fn pid_dir(pid: Pid) -> Option<Ino> {
+    pid.checked_add(1)?.checked_mul(1 << PID_SHIFT)
+}
+
+/// The process whose directory or file `ino` is, if it still exists
+fn process_of(ino: Ino) -> Option<Pid> {
+    let pid = (ino >> PID_SHIFT).checked_sub(1)?;
+    process::PROCESS_MANAGER.lock().get_process(pid).map(|_| pid)
+}
+
+fn status(pid: Pid) -> Option<String> {
+    let pm = process::PROCESS_MANAGER.lock();
+    let process = pm.get_process(pid)?;
+    let parent = process.parent.map_or(String::from("-"), |parent| parent.to_string());
+    Some(format!(
+        "Name: {}\nPid: {}\nPPid: {}\nState: {:?}\nExitCode: {}\n",
+        process.name, pid, parent, process.state, process.exit_code
+    ))
+}
+
+/// Text of a file, generated afresh on each read
+fn contents(ino: Ino) -> Result<String, &'static str> {
+    let text = match ino {
+        UPTIME => format!("{}\n", time::uptime_ms()),
+        MEMINFO => format!(
+            "HeapStart: {:#x}\nHeapSize: {} kB\n",
+            allocator::get_heap_start(),
+            allocator::HEAP_SIZE / 1024
+        ),
+        _ if ino & ((1 << PID_SHIFT) - 1) == STATUS => {
+            process_of(ino).and_then(status).ok_or("File not found")?
+        }
+        _ => return Err("Is a directory"),
+    };
+    Ok(text)
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, &'static str> {
+        let ino = match (dir, name) {
+            (ROOT, "uptime") => UPTIME,
+            (ROOT, "meminfo") => MEMINFO,
+            (ROOT, _) => name.parse().ok().and_then(pid_dir).ok_or("File not found")?,
+            (_, "status") if dir & ((1 << PID_SHIFT) - 1) == 0 => dir | STATUS,
+            _ => return Err("File not found"),
+        };
+        self.stat(ino).map(|_| ino)
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat, &'static str> {
+        let (file_type, size) = match ino {
+            ROOT => (FileType::Directory, self.readdir(ROOT)?.len()),
+            UPTIME | MEMINFO => (FileType::Regular, 0),
+            _ => {
+                process_of(ino).ok_or("File not found")?;
+                match ino & ((1 << PID_SHIFT) - 1) {
+                    0 => (FileType::Directory, 1),
+                    STATUS => (FileType::Regular, 0),
+                    _ => return Err("File not found"),
+                }
+            }
+        };
+        // Everything is generated on demand, so it is always new
+        let (mode, nlink) = if file_type == FileType::Directory { (0o555, 2) } else { (0o444, 1) };
+        let now = rtc::now();
+        Ok(Stat { ino, file_type, mode, uid: 0, gid: 0, nlink, size, created: now, modified: now })
+    }
+
+    fn read_at(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let text = contents(ino)?;
+        let rest = text.as_bytes().get(offset..).unwrap_or(&[]);
+        let count = rest.len().min(buf.len());
+        buf[..count].copy_from_slice(&rest[..count]);
+        Ok(count)
+    }
+
+    fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, &'static str> {
+        if dir == ROOT {
+            let mut entries = alloc::vec![
+                DirEntry { name: String::from("uptime"), ino: UPTIME },
+                DirEntry { name: String::from("meminfo"), ino: MEMINFO },
+            ];
+            for process in process::PROCESS_MANAGER.lock().list_processes() {
+                if let Some(ino) = pid_dir(process.pid) {
+                    entries.push(DirEntry { name: process.pid.to_string(), ino });
+                }
+            }
+            return Ok(entries);
+        }
+        match self.stat(dir)?.file_type {
+            FileType::Directory => Ok(alloc::vec![DirEntry { name: String::from("status"), ino: dir | STATUS }]),
+            _ => Err("Not a directory"),
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! In-memory filesystem
+//!
+//! File content is kept in fixed-size blocks allocated as they are first
+//! written, so growing a file never copies it and a sparse file only
+//! costs the blocks that hold data.
+use crate::rtc;
+use crate::vfs::{DirEntry, FileSystem, FileType, Ino, Stat};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Maximum number of inodes in one ramfs
+const MAX_NODES: usize = 64;
+
+/// Maximum file size (64 KB)
+const MAX_FILE_SIZE: usize = 64 * 1024;
+
+/// Bytes per block of file content
+const BLOCK_SIZE: usize = 512;
+
+type Block = Box<[u8; BLOCK_SIZE]>;
+
+/// Inode number of the root directory
+const ROOT: Ino = 0;
+
+enum Data {
+    /// Content blocks, where `None` is a hole that reads as zeros
+    File { blocks: Vec<Option<Block>>, size: usize },
+    /// Names and inode numbers of the entries, in creation order
+    Directory(Vec<(String, Ino)>),
+    Symlink(String),
+}
+
+struct Inode {
+    data: Data,
+    mode: u16,
+    /// Directory entries naming this inode
+    links: usize,
+    created: u64,
+    modified: u64,
+}
+
+impl Inode {
+    fn new(data: Data, mode: u16) -> Self {
+        let now = rtc::now();
+        Inode { data, mode, links: 1, created: now, modified: now }
+    }
+}
+
+pub struct RamFs {
+    /// Inode table indexed by inode number, with holes left by deletions
+    nodes: Vec<Option<Inode>>,
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        RamFs {
+            nodes: alloc::vec![Some(Inode::new(Data::Directory(Vec::new()), 0o755))],
+        }
+    }
+
+    fn node(&self, ino: Ino) -> Result<&Inode, &'static str> {
+        self.nodes.get(ino).and_then(Option::as_ref).ok_or("File not found")
+    }
+
+    fn node_mut(&mut self, ino: Ino) -> Result<&mut Inode, &'static str> {
+        self.nodes.get_mut(ino).and_then(Option::as_mut).ok_or("File not found")
+    }
+
+    /// Add `name` for `ino` to directory `dir`
+    fn add_entry(&mut self, dir: Ino, name: &str, ino: Ino) -> Result<(), &'static str> {
+        let directory = self.node_mut(dir)?;
+        match &mut directory.data {
+            Data::Directory(entries) => entries.push((String::from(name), ino)),
+            _ => return Err("Not a directory"),
+        }
+        directory.modified = rtc::now();
+        Ok(())
+    }
+
+    /// Store a new inode and link it into `dir` as `name`
+    fn insert(&mut self, dir: Ino, name: &str, inode: Inode) -> Result<Ino, &'static str> {
+        if !matches!(self.node(dir)?.data, Data::Directory(_)) {
+            return Err("Not a directory");
+        }
+        if self.lookup(dir, name).is_ok() {
+            return Err("File exists");
+        }
+        if self.nodes.iter().flatten().count() >= MAX_NODES {
+            return Err("Filesystem full");
+        }
+        let ino = match self.nodes.iter().position(Option::is_none) {
+            Some(ino) => {
+                self.nodes[ino] = Some(inode);
+                ino
+            }
+            None => {
+                self.nodes.push(Some(inode));
+                self.nodes.len() - 1
+            }
+        };
+        self.add_entry(dir, name, ino)?;
+        Ok(ino)
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Ino {
+        ROOT
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, &'static str> {
+        match &self.node(dir)?.data {
+            Data::Directory(entries) => entries.iter()
+                .find(|(entry, _)| entry == name)
+                .map(|&(_, ino)| ino)
+                .ok_or("File not found"),
+            _ => Err("Not a directory"),
+        }
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat, &'static str> {
+        let node = self.node(ino)?;
+        let (file_type, size, nlink) = match &node.data {
+            Data::File { size, .. } => (FileType::Regular, *size, node.links),
+            Data::Symlink(target) => (FileType::Symlink, target.len(), node.links),
+            // A directory is named by its parent's entry and its own `.`,
+            // and by the `..` of each subdirectory
+            Data::Directory(entries) => {
+                let subdirs = entries.iter()
+                    .filter(|&&(_, entry)| matches!(self.node(entry).map(|n| &n.data), Ok(Data::Directory(_))))
+                    .count();
+                (FileType::Directory, entries.len(), 2 + subdirs)
+            }
+        };
+        Ok(Stat {
+            ino,
+            file_type,
+            mode: node.mode,
+            uid: 0,
+            gid: 0,
+            nlink,
+            size,
+            created: node.created,
+            modified: node.modified,
+        })
+    }
+
+    fn read_at(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let (blocks, size) = match &self.node(ino)?.data {
+            Data::File { blocks, size } => (blocks, *size),
+            Data::Directory(_) => return Err("Is a directory"),
+            Data::Symlink(_) => return Err("Invalid argument"),
+        };
+        let end = size.min(offset.saturating_add(buf.len()));
+        let mut position = offset;
+        while position < end {
+            let within = position % BLOCK_SIZE;
+            let count = (BLOCK_SIZE - within).min(end - position);
+            let target = &mut buf[position - offset..][..count];
+            match &blocks[position / BLOCK_SIZE] {
+                Some(block) => target.copy_from_slice(&block[within..within + count]),
+                None => target.fill(0),
+            }
+            position += count;
+        }
+        Ok(end.saturating_sub(offset))
+    }
+
+    fn write_at(&mut self, ino: Ino, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        let end = offset.checked_add(data.len()).ok_or("File too large")?;
+        if end > MAX_FILE_SIZE {
+            return Err("File too large");
+        }
+        let node = self.node_mut(ino)?;
+        let (blocks, size) = match &mut node.data {
+            Data::File { blocks, size } => (blocks, size),
+            Data::Directory(_) => return Err("Is a directory"),
+            Data::Symlink(_) => return Err("Invalid argument"),
+        };
+        if blocks.len() < end.div_ceil(BLOCK_SIZE) {
+            blocks.resize_with(end.div_ceil(BLOCK_SIZE), || None);
+        }
+        let mut position = offset;
+        while position < end {
+            let within = position % BLOCK_SIZE;
+            let count = (BLOCK_SIZE - within).min(end - position);
+            let block = blocks[position / BLOCK_SIZE].get_or_insert_with(|| Box::new([0; BLOCK_SIZE]));
+            block[within..within + count].copy_from_slice(&data[position - offset..][..count]);
+            position += count;
+        }
+        *size = (*size).max(end);
+        node.modified = rtc::now();
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: Ino, new_size: usize) -> Result<(), &'static str> {
+        if new_size > MAX_FILE_SIZE {
+            return Err("File too large");
+        }
+        let node = self.node_mut(ino)?;
+        let (blocks, size) = match &mut node.data {
+            Data::File { blocks, size } => (blocks, size),
+            Data::Directory(_) => return Err("Is a directory"),
+            Data::Symlink(_) => return Err("Invalid argument"),
+        };
+        // Zero the cut-off tail of the last block so growing the file
+        // again reads zeros rather than the old bytes
+        blocks.resize_with(new_size.div_ceil(BLOCK_SIZE), || None);
+        if let Some(Some(last)) = blocks.last_mut() {
+            let within = new_size % BLOCK_SIZE;
+            if within != 0 {
+                last[within..].fill(0);
+            }
+        }
+        *size = new_size;
+        node.modified = rtc::now();
+        Ok(())
+    }
+
+    fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, &'static str> {
+        match &self.node(dir)?.data {
+            Data::Directory(entries) => Ok(entries.iter()
+                .map(|(name, ino)| DirEntry { name: name.clone(), ino: *ino })
+                .collect()),
+            _ => Err("Not a directory"),
+        }
+    }
+
+    fn create(&mut self, dir: Ino, name: &str, file_type: FileType) -> Result<Ino, &'static str> {
+        let inode = match file_type {
+            FileType::Regular => Inode::new(Data::File { blocks: Vec::new(), size: 0 }, 0o644),
+            FileType::Directory => Inode::new(Data::Directory(Vec::new()), 0o755),
+            FileType::Symlink | FileType::Device => return Err("Operation not supported"),
+        };
+        self.insert(dir, name, inode)
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), &'static str> {
+        let ino = self.lookup(dir, name)?;
+        if matches!(&self.node(ino)?.data, Data::Directory(entries) if !entries.is_empty()) {
+            return Err("Directory not empty");
+        }
+
+        let directory = self.node_mut(dir)?;
+        if let Data::Directory(entries) = &mut directory.data {
+            entries.retain(|(entry, _)| entry != name);
+        }
+        directory.modified = rtc::now();
+
+        let node = self.node_mut(ino)?;
+        node.links -= 1;
+        if node.links == 0 {
+            self.nodes[ino] = None;
+        }
+        Ok(())
+    }
+
+    fn link(&mut self, ino: Ino, dir: Ino, name: &str) -> Result<(), &'static str> {
+        if matches!(self.node(ino)?.data, Data::Directory(_)) {
+            return Err("Is a directory");
+        }
+        if self.lookup(dir, name).is_ok() {
+            return Err("File exists");
+        }
+        self.add_entry(dir, name, ino)?;
+        self.node_mut(ino)?.links += 1;
+        Ok(())
+    }
+
+    fn symlink(&mut self, dir: Ino, name: &str, target: &str) -> Result<Ino, &'static str> {
+        self.insert(dir, name, Inode::new(Data::Symlink(String::from(target)), 0o777))
+    }
+
+    fn read_link(&self, ino: Ino) -> Result<String, &'static str> {
+        match &self.node(ino)?.data {
+            Data::Symlink(target) => Ok(target.clone()),
+            _ => Err("Not a symbolic link"),
+        }
+    }
+
+    fn set_mode(&mut self, ino: Ino, mode: u16) -> Result<(), &'static str> {
+        self.node_mut(ino)?.mode = mode;
+        Ok(())
+    }
+}
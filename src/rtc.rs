@@ -0,0 +1,128 @@
+//! CMOS real-time clock and calendar conversion
+//!
+//! The RTC is read once at boot; after that the wall clock advances with
+//! the timer, so reading it costs no port I/O.
+use crate::time;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Seconds since the Unix epoch when the kernel started
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// A calendar date and time of day (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// Date and time `seconds` after 1970-01-01 00:00:00
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / 86400) as i64;
+        let time = (seconds % 86400) as u32;
+
+        // Days to civil date, counting in 400-year eras from 0000-03-01
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+        let month = (if month_index < 10 { month_index + 3 } else { month_index - 9 }) as u32;
+        let year = (year_of_era + era * 400 + i64::from(month <= 2)) as u32;
+
+        DateTime { year, month, day, hour: time / 3600, minute: time / 60 % 60, second: time % 60 }
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn to_unix(self) -> u64 {
+        let year = i64::from(self.year) - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let month = i64::from(self.month);
+        let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days as u64 * 86400 + u64::from(self.hour * 3600 + self.minute * 60 + self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn cmos(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(0x70);
+    let mut data: Port<u8> = Port::new(0x71);
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+/// Read the date and time from the RTC
+pub fn read() -> DateTime {
+    // Read until two passes agree, each started outside an update
+    let sample = || {
+        while cmos(0x0A) & 0x80 != 0 {}
+        [0x00, 0x02, 0x04, 0x07, 0x08, 0x09].map(cmos)
+    };
+    let mut values = sample();
+    loop {
+        let again = sample();
+        if again == values {
+            break;
+        }
+        values = again;
+    }
+
+    let status_b = cmos(0x0B);
+    let binary = status_b & 0x04 != 0;
+    let decode = |value: u8| if binary { u32::from(value) } else { u32::from((value >> 4) * 10 + (value & 0x0F)) };
+    let [second, minute, hour, day, month, year] = values;
+
+    // In 12-hour mode the top bit of the hour marks PM
+    let pm = status_b & 0x02 == 0 && hour & 0x80 != 0;
+    let mut hour = decode(hour & 0x7F);
+    if status_b & 0x02 == 0 {
+        hour = (hour % 12) + if pm { 12 } else { 0 };
+    }
+
+    DateTime {
+        year: 2000 + decode(year),
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Record the boot time from the RTC
+pub fn init() {
+    let uptime = time::uptime_ms() / 1000;
+    BOOT_TIME.store(read().to_unix().saturating_sub(uptime), Ordering::Relaxed);
+}
+
+/// Seconds since the Unix epoch at boot
+pub fn boot_time() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed)
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + time::uptime_ms() / 1000
+}
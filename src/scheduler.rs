@@ -0,0 +1,134 @@
+//! Round-robin scheduling of bytecode processes
+//!
+//! Bytecode runs in slices of `TIME_SLICE` instructions. Between slices
+//! the interpreter state is parked in the process table, so a foreground
+//! program, background jobs started with `run <file> &` and the shell all
+//! make progress. Ctrl+C interrupts the foreground program and `kill`
+//! stops any bytecode process. Console input still blocks everything until
+//! a key arrives.
+use crate::{block, println};
+use crate::keyboard;
+use crate::process::{self, Pid, ProcessState};
+use crate::vm::Step;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Instructions a process runs before the next one gets a turn
+pub const TIME_SLICE: usize = 1000;
+
+/// Exit status of a process stopped by Ctrl+C or `kill`
+pub const INTERRUPTED: i32 = 130;
+
+lazy_static! {
+    /// Background jobs whose completion the shell has not reported yet
+    static ref JOBS: Mutex<Vec<Pid>> = Mutex::new(Vec::new());
+}
+
+/// Give every runnable bytecode process one slice. Returns whether any
+/// process ran.
+pub fn run_slice() -> bool {
+    let runnable = process::PROCESS_MANAGER.lock().runnable();
+    for &pid in &runnable {
+        run_process(pid);
+    }
+    !runnable.is_empty()
+}
+
+fn run_process(pid: Pid) {
+    let (mut vm, previous) = {
+        let mut pm = process::PROCESS_MANAGER.lock();
+        let vm = match pm.get_process_mut(pid).and_then(|p| p.vm.take()) {
+            Some(vm) => vm,
+            None => return,
+        };
+        let previous = pm.get_current();
+        pm.set_current(pid);
+        (vm, previous)
+    };
+
+    // The lock is released while the slice runs, since syscalls take it
+    let result = vm.step(TIME_SLICE);
+
+    let mut pm = process::PROCESS_MANAGER.lock();
+    pm.restore_current(previous);
+    if let Some(process) = pm.get_process_mut(pid) {
+        process.jit_stats = vm.jit_stats();
+    }
+    let process = match pm.get_process_mut(pid) {
+        Some(process) if process.state != ProcessState::Terminated => process,
+        // Exited through the exit syscall
+        _ => return,
+    };
+    process.instruction_pointer = vm.ip();
+
+    match result {
+        Step::Yielded => {
+            process.state = ProcessState::Ready;
+            process.vm = Some(vm);
+        }
+        Step::Halted => {
+            let _ = pm.exit_process(pid, 0);
+        }
+        Step::Fault(e) => {
+            println!("");
+            println!("Execution error: {}", e);
+            let _ = pm.exit_process(pid, -1);
+        }
+    }
+}
+
+/// Run the scheduler until `pid` terminates or Ctrl+C interrupts it
+pub fn wait(pid: Pid) {
+    loop {
+        let state = process::PROCESS_MANAGER.lock().get_process(pid).map(|p| p.state);
+        if state.is_none() || state == Some(ProcessState::Terminated) {
+            return;
+        }
+        if keyboard::take_interrupt() {
+            println!("^C");
+            let _ = kill(pid);
+            return;
+        }
+        block::write_back_expired();
+        if !run_slice() {
+            // Nothing left that could finish it
+            return;
+        }
+    }
+}
+
+/// Stop a bytecode process with the `INTERRUPTED` status
+pub fn kill(pid: Pid) -> Result<(), &'static str> {
+    let mut pm = process::PROCESS_MANAGER.lock();
+    let process = pm.get_process(pid).ok_or("Process not found")?;
+    if process.state == ProcessState::Terminated {
+        return Err("Process already terminated");
+    }
+    if process.vm.is_none() {
+        return Err("Not a bytecode process");
+    }
+    pm.exit_process(pid, INTERRUPTED)
+}
+
+/// Let a process run in the background; the shell reports when it ends
+pub fn add_job(pid: Pid) {
+    JOBS.lock().push(pid);
+}
+
+/// Reap finished background jobs and report their exit status
+pub fn reap_jobs() {
+    let mut jobs = JOBS.lock();
+    let mut pm = process::PROCESS_MANAGER.lock();
+    jobs.retain(|&pid| {
+        let parent = match pm.get_process(pid) {
+            Some(p) if p.state == ProcessState::Terminated => p.parent,
+            Some(_) => return true,
+            None => return false,
+        };
+        if let Ok((pid, code)) = pm.reap(parent, Some(pid)) {
+            println!("[{}] Done ({})", pid, code);
+        }
+        false
+    });
+}
@@ -0,0 +1,882 @@
+//! Native on-disk filesystem
+//!
+//! A small Unix-style layout in 512-byte blocks: a superblock, bitmaps of
+//! the inodes and blocks in use, an inode table, then data. Each inode
+//! has twelve direct block pointers, an indirect and a double indirect
+//! block; a pointer of 0 is a hole that reads as zeros. Directories are
+//! files of fixed-size entries, without `.` and `..`, which the VFS
+//! resolves itself. Hard links, symbolic links, permission bits and
+//! timestamps behave as in the ramfs.
+//!
+//! The disk is reached only through [`BlockIo`], so the same code runs in
+//! the kernel (see `diskfs.rs`) and in `bbx mkfs`, which builds images on
+//! the host.
+//!
+//! ```text
+//! block 0              superblock
+//! inode_bitmap         one bit per inode, set while in use
+//! block_bitmap         one bit per block, set while in use
+//! inode_table          128-byte inodes, four per block
+//! data_start           file content, directories and pointer blocks
+//! ```
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Bytes per filesystem block
+pub const BLOCK_SIZE: usize = 512;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SFS1");
+const VERSION: u32 = 1;
+
+const INODE_SIZE: usize = 128;
+const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32;
+const BITS_PER_BLOCK: u32 = BLOCK_SIZE as u32 * 8;
+
+/// Inode number of the root directory; 0 is never used
+pub const ROOT: u32 = 1;
+
+/// Inodes created by [`Sfs::format`] per data block
+const BLOCKS_PER_INODE: u32 = 8;
+
+const DIRECT_BLOCKS: usize = 12;
+const POINTERS_PER_BLOCK: usize = BLOCK_SIZE / 4;
+
+/// Largest file the direct, indirect and double indirect blocks can map
+pub const MAX_FILE_SIZE: usize =
+    (DIRECT_BLOCKS + POINTERS_PER_BLOCK + POINTERS_PER_BLOCK * POINTERS_PER_BLOCK) * BLOCK_SIZE;
+
+/// Longest symbolic link target, which fits in the direct blocks
+const MAX_LINK: usize = DIRECT_BLOCKS * BLOCK_SIZE;
+
+/// A directory entry: the inode number, then the name padded with zeros
+const ENTRY_SIZE: usize = 64;
+
+/// Longest name of a directory entry, in bytes
+pub const MAX_NAME: usize = ENTRY_SIZE - 4;
+
+type Block = [u8; BLOCK_SIZE];
+
+/// Storage addressed in filesystem blocks
+pub trait BlockIo {
+    /// Capacity in blocks
+    fn block_count(&self) -> u32;
+
+    fn read_block(&self, block: u32, buf: &mut Block) -> Result<(), &'static str>;
+
+    fn write_block(&mut self, block: u32, buf: &Block) -> Result<(), &'static str>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl Kind {
+    fn code(self) -> u8 {
+        match self {
+            Kind::File => 1,
+            Kind::Directory => 2,
+            Kind::Symlink => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Kind::File),
+            2 => Some(Kind::Directory),
+            3 => Some(Kind::Symlink),
+            _ => None,
+        }
+    }
+}
+
+/// What `stat` reports about an inode
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub kind: Kind,
+    pub mode: u16,
+    pub nlink: u32,
+    /// Content length for files and symlinks, number of entries for
+    /// directories
+    pub size: usize,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    pub modified: u64,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(bytes, offset)) | u64::from(read_u32(bytes, offset + 4)) << 32
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Where everything is, as recorded in block 0
+#[derive(Debug, Clone, Copy)]
+struct Superblock {
+    block_count: u32,
+    inode_count: u32,
+    inode_bitmap: u32,
+    block_bitmap: u32,
+    inode_table: u32,
+    data_start: u32,
+}
+
+impl Superblock {
+    /// Lay out a filesystem of `block_count` blocks and `inode_count`
+    /// inodes
+    fn new(block_count: u32, inode_count: u32) -> Self {
+        let inode_bitmap = 1;
+        let block_bitmap = inode_bitmap + inode_count.div_ceil(BITS_PER_BLOCK);
+        let inode_table = block_bitmap + block_count.div_ceil(BITS_PER_BLOCK);
+        let data_start = inode_table + inode_count / INODES_PER_BLOCK;
+        Superblock { block_count, inode_count, inode_bitmap, block_bitmap, inode_table, data_start }
+    }
+
+    fn encode(&self) -> Block {
+        let mut block = [0; BLOCK_SIZE];
+        for (index, value) in [
+            MAGIC,
+            VERSION,
+            self.block_count,
+            self.inode_count,
+            self.inode_bitmap,
+            self.block_bitmap,
+            self.inode_table,
+            self.data_start,
+        ].into_iter().enumerate() {
+            write_u32(&mut block, index * 4, value);
+        }
+        block
+    }
+
+    fn decode(block: &Block) -> Result<Self, &'static str> {
+        if read_u32(block, 0) != MAGIC {
+            return Err("Not an sfs filesystem");
+        }
+        if read_u32(block, 4) != VERSION {
+            return Err("Unsupported sfs version");
+        }
+        let superblock = Superblock::new(read_u32(block, 8), read_u32(block, 12));
+        let recorded = [read_u32(block, 16), read_u32(block, 20), read_u32(block, 24), read_u32(block, 28)];
+        let expected = [
+            superblock.inode_bitmap,
+            superblock.block_bitmap,
+            superblock.inode_table,
+            superblock.data_start,
+        ];
+        if recorded != expected || superblock.data_start >= superblock.block_count {
+            return Err("Corrupt filesystem");
+        }
+        Ok(superblock)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Inode {
+    kind: Kind,
+    mode: u16,
+    nlink: u32,
+    /// Length in bytes, including a directory's free entries
+    size: usize,
+    created: u64,
+    modified: u64,
+    direct: [u32; DIRECT_BLOCKS],
+    indirect: u32,
+    double_indirect: u32,
+}
+
+impl Inode {
+    fn new(kind: Kind, mode: u16, now: u64) -> Self {
+        Inode {
+            kind,
+            mode,
+            // A directory is also named by its own `.`
+            nlink: if kind == Kind::Directory { 2 } else { 1 },
+            size: 0,
+            created: now,
+            modified: now,
+            direct: [0; DIRECT_BLOCKS],
+            indirect: 0,
+            double_indirect: 0,
+        }
+    }
+
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes.fill(0);
+        bytes[0] = self.kind.code();
+        write_u16(bytes, 2, self.mode);
+        write_u32(bytes, 4, self.nlink);
+        write_u64(bytes, 8, self.size as u64);
+        write_u64(bytes, 16, self.created);
+        write_u64(bytes, 24, self.modified);
+        for (index, &block) in self.direct.iter().enumerate() {
+            write_u32(bytes, 32 + index * 4, block);
+        }
+        write_u32(bytes, 80, self.indirect);
+        write_u32(bytes, 84, self.double_indirect);
+    }
+
+    /// The inode stored in `bytes`, or `None` if the slot is free
+    fn decode(bytes: &[u8]) -> Result<Option<Self>, &'static str> {
+        let Some(kind) = Kind::from_code(bytes[0]) else {
+            return Ok(None);
+        };
+        let size = read_u64(bytes, 8);
+        if size > MAX_FILE_SIZE as u64 {
+            return Err("Corrupt filesystem");
+        }
+        let mut direct = [0; DIRECT_BLOCKS];
+        for (index, block) in direct.iter_mut().enumerate() {
+            *block = read_u32(bytes, 32 + index * 4);
+        }
+        Ok(Some(Inode {
+            kind,
+            mode: read_u16(bytes, 2),
+            nlink: read_u32(bytes, 4),
+            size: size as usize,
+            created: read_u64(bytes, 16),
+            modified: read_u64(bytes, 24),
+            direct,
+            indirect: read_u32(bytes, 80),
+            double_indirect: read_u32(bytes, 84),
+        }))
+    }
+}
+
+/// Check that `name` fits in a directory entry
+fn check_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.contains('\0') {
+        return Err("Invalid argument");
+    }
+    if name.len() > MAX_NAME {
+        return Err("File name too long");
+    }
+    Ok(())
+}
+
+/// A filesystem on a [`BlockIo`] device
+pub struct Sfs<D: BlockIo> {
+    device: D,
+    superblock: Superblock,
+    /// Source of timestamps, in seconds since the Unix epoch
+    clock: fn() -> u64,
+    /// Where to start looking for a free block or inode
+    next_block: u32,
+    next_inode: u32,
+}
+
+impl<D: BlockIo> Sfs<D> {
+    /// Write an empty filesystem over the whole device
+    pub fn format(mut device: D, clock: fn() -> u64) -> Result<Self, &'static str> {
+        let block_count = device.block_count();
+        let inode_count = (block_count / BLOCKS_PER_INODE).next_multiple_of(INODES_PER_BLOCK).max(INODES_PER_BLOCK);
+        let superblock = Superblock::new(block_count, inode_count);
+        if superblock.data_start >= block_count {
+            return Err("Disk too small");
+        }
+
+        let zeros = [0; BLOCK_SIZE];
+        for block in superblock.inode_bitmap..superblock.data_start {
+            device.write_block(block, &zeros)?;
+        }
+        device.write_block(0, &superblock.encode())?;
+
+        let mut fs = Sfs { device, superblock, clock, next_block: 0, next_inode: 0 };
+        // Inode 0 stands for a free directory entry, and the blocks up
+        // to the data area hold the metadata
+        for _ in 0..=ROOT {
+            fs.allocate_bit(superblock.inode_bitmap, inode_count, 0)?;
+        }
+        for _ in 0..superblock.data_start {
+            fs.allocate_bit(superblock.block_bitmap, block_count, 0)?;
+        }
+        fs.write_inode(ROOT, &Inode::new(Kind::Directory, 0o755, clock()))?;
+        Ok(fs)
+    }
+
+    /// Open the filesystem on a device
+    pub fn open(device: D, clock: fn() -> u64) -> Result<Self, &'static str> {
+        let mut block = [0; BLOCK_SIZE];
+        device.read_block(0, &mut block)?;
+        let superblock = Superblock::decode(&block)?;
+        if superblock.block_count > device.block_count() {
+            return Err("Corrupt filesystem");
+        }
+        Ok(Sfs { device, superblock, clock, next_block: 0, next_inode: 0 })
+    }
+
+    /// Total and free blocks and inodes, as
+    /// `(blocks, free blocks, inodes, free inodes)`
+    pub fn usage(&self) -> Result<(u32, u32, u32, u32), &'static str> {
+        let Superblock { block_count, inode_count, .. } = self.superblock;
+        let free_blocks = self.count_free(self.superblock.block_bitmap, block_count)?;
+        let free_inodes = self.count_free(self.superblock.inode_bitmap, inode_count)?;
+        Ok((block_count, free_blocks, inode_count, free_inodes))
+    }
+
+    fn count_free(&self, bitmap: u32, bits: u32) -> Result<u32, &'static str> {
+        let mut block = [0; BLOCK_SIZE];
+        let mut used = 0;
+        for index in 0..bits.div_ceil(BITS_PER_BLOCK) {
+            self.device.read_block(bitmap + index, &mut block)?;
+            used += block.iter().map(|byte| byte.count_ones()).sum::<u32>();
+        }
+        Ok(bits - used)
+    }
+
+    /// Claim the first clear bit of a bitmap from the block holding bit
+    /// `hint` on, returning its number
+    fn allocate_bit(&mut self, bitmap: u32, bits: u32, hint: u32) -> Result<u32, &'static str> {
+        let blocks = bits.div_ceil(BITS_PER_BLOCK);
+        let first = (hint / BITS_PER_BLOCK).min(blocks - 1);
+        let mut block = [0; BLOCK_SIZE];
+        for index in (first..blocks).chain(0..first) {
+            self.device.read_block(bitmap + index, &mut block)?;
+            let Some(byte) = block.iter().position(|&byte| byte != 0xFF) else {
+                continue;
+            };
+            let bit = block[byte].trailing_ones();
+            let number = index * BITS_PER_BLOCK + byte as u32 * 8 + bit;
+            // Bits are taken in order, so one past the end means the rest
+            // are in use
+            if number >= bits {
+                continue;
+            }
+            block[byte] |= 1 << bit;
+            self.device.write_block(bitmap + index, &block)?;
+            return Ok(number);
+        }
+        Err("Filesystem full")
+    }
+
+    fn free_bit(&mut self, bitmap: u32, number: u32) -> Result<(), &'static str> {
+        let mut block = [0; BLOCK_SIZE];
+        let index = bitmap + number / BITS_PER_BLOCK;
+        let within = (number % BITS_PER_BLOCK) as usize;
+        self.device.read_block(index, &mut block)?;
+        block[within / 8] &= !(1 << (within % 8));
+        self.device.write_block(index, &block)
+    }
+
+    /// Take a free block and fill it with zeros
+    fn allocate_block(&mut self) -> Result<u32, &'static str> {
+        let block = self.allocate_bit(self.superblock.block_bitmap, self.superblock.block_count, self.next_block)?;
+        self.next_block = block + 1;
+        self.device.write_block(block, &[0; BLOCK_SIZE])?;
+        Ok(block)
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<(), &'static str> {
+        if block < self.superblock.data_start || block >= self.superblock.block_count {
+            return Err("Corrupt filesystem");
+        }
+        self.free_bit(self.superblock.block_bitmap, block)
+    }
+
+    /// Check a block pointer read from disk: 0 for a hole, or a block in
+    /// the data area
+    fn check_block(&self, block: u32) -> Result<u32, &'static str> {
+        if block != 0 && (block < self.superblock.data_start || block >= self.superblock.block_count) {
+            return Err("Corrupt filesystem");
+        }
+        Ok(block)
+    }
+
+    fn inode_location(&self, ino: u32) -> Result<(u32, usize), &'static str> {
+        if ino == 0 || ino >= self.superblock.inode_count {
+            return Err("File not found");
+        }
+        let block = self.superblock.inode_table + ino / INODES_PER_BLOCK;
+        Ok((block, (ino % INODES_PER_BLOCK) as usize * INODE_SIZE))
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode, &'static str> {
+        let (block, offset) = self.inode_location(ino)?;
+        let mut buf = [0; BLOCK_SIZE];
+        self.device.read_block(block, &mut buf)?;
+        Inode::decode(&buf[offset..offset + INODE_SIZE])?.ok_or("File not found")
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &Inode) -> Result<(), &'static str> {
+        let (block, offset) = self.inode_location(ino)?;
+        let mut buf = [0; BLOCK_SIZE];
+        self.device.read_block(block, &mut buf)?;
+        inode.encode(&mut buf[offset..offset + INODE_SIZE]);
+        self.device.write_block(block, &buf)
+    }
+
+    /// Free an inode and everything it holds
+    fn release(&mut self, ino: u32, inode: &mut Inode) -> Result<(), &'static str> {
+        self.free_blocks(inode, 0)?;
+        let (block, offset) = self.inode_location(ino)?;
+        let mut buf = [0; BLOCK_SIZE];
+        self.device.read_block(block, &mut buf)?;
+        buf[offset..offset + INODE_SIZE].fill(0);
+        self.device.write_block(block, &buf)?;
+        self.free_bit(self.superblock.inode_bitmap, ino)
+    }
+
+    /// Entry `index` of pointer block `table`, 0 for a hole
+    fn pointer(&self, table: u32, index: usize) -> Result<u32, &'static str> {
+        if self.check_block(table)? == 0 {
+            return Ok(0);
+        }
+        let mut buf = [0; BLOCK_SIZE];
+        self.device.read_block(table, &mut buf)?;
+        self.check_block(read_u32(&buf, index * 4))
+    }
+
+    /// Block holding block `index` of a file's content, 0 for a hole
+    fn block_at(&self, inode: &Inode, index: usize) -> Result<u32, &'static str> {
+        if index < DIRECT_BLOCKS {
+            return self.check_block(inode.direct[index]);
+        }
+        let index = index - DIRECT_BLOCKS;
+        if index < POINTERS_PER_BLOCK {
+            return self.pointer(inode.indirect, index);
+        }
+        let index = index - POINTERS_PER_BLOCK;
+        if index >= POINTERS_PER_BLOCK * POINTERS_PER_BLOCK {
+            return Err("File too large");
+        }
+        let table = self.pointer(inode.double_indirect, index / POINTERS_PER_BLOCK)?;
+        self.pointer(table, index % POINTERS_PER_BLOCK)
+    }
+
+    /// Entry `index` of pointer block `table`, allocating a block for it
+    /// if it is a hole
+    fn map_pointer(&mut self, table: u32, index: usize) -> Result<u32, &'static str> {
+        let mut buf = [0; BLOCK_SIZE];
+        self.device.read_block(self.check_block(table)?, &mut buf)?;
+        match self.check_block(read_u32(&buf, index * 4))? {
+            0 => {
+                let block = self.allocate_block()?;
+                write_u32(&mut buf, index * 4, block);
+                self.device.write_block(table, &buf)?;
+                Ok(block)
+            }
+            block => Ok(block),
+        }
+    }
+
+    /// Like [`Self::block_at`], allocating the block and any pointer
+    /// blocks on the way that are missing
+    fn map_block(&mut self, inode: &mut Inode, index: usize) -> Result<u32, &'static str> {
+        fn ensure<D: BlockIo>(fs: &mut Sfs<D>, pointer: &mut u32) -> Result<u32, &'static str> {
+            if fs.check_block(*pointer)? == 0 {
+                *pointer = fs.allocate_block()?;
+            }
+            Ok(*pointer)
+        }
+
+        if index < DIRECT_BLOCKS {
+            return ensure(self, &mut inode.direct[index]);
+        }
+        let index = index - DIRECT_BLOCKS;
+        if index < POINTERS_PER_BLOCK {
+            let table = ensure(self, &mut inode.indirect)?;
+            return self.map_pointer(table, index);
+        }
+        let index = index - POINTERS_PER_BLOCK;
+        if index >= POINTERS_PER_BLOCK * POINTERS_PER_BLOCK {
+            return Err("File too large");
+        }
+        let top = ensure(self, &mut inode.double_indirect)?;
+        let table = self.map_pointer(top, index / POINTERS_PER_BLOCK)?;
+        self.map_pointer(table, index % POINTERS_PER_BLOCK)
+    }
+
+    /// Free the content blocks of a file from block `keep` on, with the
+    /// pointer blocks left empty
+    fn free_blocks(&mut self, inode: &mut Inode, keep: usize) -> Result<(), &'static str> {
+        for block in inode.direct.iter_mut().skip(keep) {
+            if *block != 0 {
+                self.free_block(*block)?;
+                *block = 0;
+            }
+        }
+        let keep = keep.saturating_sub(DIRECT_BLOCKS);
+        inode.indirect = self.free_table(inode.indirect, keep, 1)?;
+        let keep = keep.saturating_sub(POINTERS_PER_BLOCK);
+        inode.double_indirect = self.free_table(inode.double_indirect, keep, 2)?;
+        Ok(())
+    }
+
+    /// Free the blocks a pointer block `depth` levels above the content
+    /// maps from block `keep` on, returning the pointer block, or 0 if it
+    /// was freed too
+    fn free_table(&mut self, table: u32, keep: usize, depth: u32) -> Result<u32, &'static str> {
+        if table == 0 {
+            return Ok(0);
+        }
+        // Content blocks under each entry
+        let span = POINTERS_PER_BLOCK.pow(depth - 1);
+        let mut buf = [0; BLOCK_SIZE];
+        self.device.read_block(self.check_block(table)?, &mut buf)?;
+        let mut changed = false;
+        for index in 0..POINTERS_PER_BLOCK {
+            let block = read_u32(&buf, index * 4);
+            let start = index * span;
+            if block == 0 || start + span <= keep {
+                continue;
+            }
+            let remaining = if depth == 1 {
+                self.free_block(block)?;
+                0
+            } else {
+                self.free_table(block, keep.saturating_sub(start), depth - 1)?
+            };
+            if remaining != block {
+                write_u32(&mut buf, index * 4, remaining);
+                changed = true;
+            }
+        }
+        if keep == 0 {
+            self.free_block(table)?;
+            return Ok(0);
+        }
+        if changed {
+            self.device.write_block(table, &buf)?;
+        }
+        Ok(table)
+    }
+
+    fn read_data(&self, inode: &Inode, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let end = inode.size.min(offset.saturating_add(buf.len()));
+        let mut block = [0; BLOCK_SIZE];
+        let mut position = offset;
+        while position < end {
+            let within = position % BLOCK_SIZE;
+            let count = (BLOCK_SIZE - within).min(end - position);
+            let target = &mut buf[position - offset..][..count];
+            match self.block_at(inode, position / BLOCK_SIZE)? {
+                0 => target.fill(0),
+                number => {
+                    self.device.read_block(number, &mut block)?;
+                    target.copy_from_slice(&block[within..within + count]);
+                }
+            }
+            position += count;
+        }
+        Ok(end.saturating_sub(offset))
+    }
+
+    /// Copy `data` into a file's blocks at `offset`, counting the bytes
+    /// stored in `written`
+    fn write_data(&mut self, inode: &mut Inode, offset: usize, data: &[u8], written: &mut usize) -> Result<(), &'static str> {
+        let mut block = [0; BLOCK_SIZE];
+        while *written < data.len() {
+            let position = offset + *written;
+            let within = position % BLOCK_SIZE;
+            let count = (BLOCK_SIZE - within).min(data.len() - *written);
+            let number = self.map_block(inode, position / BLOCK_SIZE)?;
+            if count < BLOCK_SIZE {
+                self.device.read_block(number, &mut block)?;
+            }
+            block[within..within + count].copy_from_slice(&data[*written..][..count]);
+            self.device.write_block(number, &block)?;
+            *written += count;
+        }
+        Ok(())
+    }
+
+    /// Write to inode `ino` and store it back with its new size and
+    /// blocks. A write that fails part way returns what it stored.
+    fn write_inode_data(&mut self, ino: u32, inode: &mut Inode, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        let end = offset.checked_add(data.len()).ok_or("File too large")?;
+        if end > MAX_FILE_SIZE {
+            return Err("File too large");
+        }
+        let mut written = 0;
+        let result = self.write_data(inode, offset, data, &mut written);
+        if written > 0 {
+            inode.size = inode.size.max(offset + written);
+            inode.modified = (self.clock)();
+        }
+        // Blocks may have been allocated even if nothing was written
+        self.write_inode(ino, inode)?;
+        match result {
+            Err(error) if written == 0 => Err(error),
+            _ => Ok(written),
+        }
+    }
+
+    fn directory(&self, ino: u32) -> Result<Inode, &'static str> {
+        let inode = self.read_inode(ino)?;
+        if inode.kind != Kind::Directory {
+            return Err("Not a directory");
+        }
+        Ok(inode)
+    }
+
+    /// Call `visit` with the slot, inode number and name of each entry in
+    /// a directory until it returns `Some`
+    fn scan<T>(&self, dir: &Inode, mut visit: impl FnMut(usize, u32, &str) -> Option<T>) -> Result<Option<T>, &'static str> {
+        let mut block = [0; BLOCK_SIZE];
+        for index in 0..dir.size.div_ceil(BLOCK_SIZE) {
+            match self.block_at(dir, index)? {
+                0 => continue,
+                number => self.device.read_block(number, &mut block)?,
+            }
+            let entries = (dir.size - index * BLOCK_SIZE).min(BLOCK_SIZE) / ENTRY_SIZE;
+            for (within, entry) in block.as_chunks::<ENTRY_SIZE>().0.iter().take(entries).enumerate() {
+                let ino = read_u32(entry, 0);
+                if ino == 0 {
+                    continue;
+                }
+                let name = &entry[4..];
+                let len = name.iter().position(|&byte| byte == 0).unwrap_or(MAX_NAME);
+                let Ok(name) = core::str::from_utf8(&name[..len]) else {
+                    continue;
+                };
+                let slot = index * (BLOCK_SIZE / ENTRY_SIZE) + within;
+                if let Some(found) = visit(slot, ino, name) {
+                    return Ok(Some(found));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Slot and inode number of the entry called `name`
+    fn find(&self, dir: &Inode, name: &str) -> Result<Option<(usize, u32)>, &'static str> {
+        self.scan(dir, |slot, ino, entry| (entry == name).then_some((slot, ino)))
+    }
+
+    fn entry_count(&self, dir: &Inode) -> Result<usize, &'static str> {
+        let mut count = 0;
+        self.scan(dir, |_, _, _| {
+            count += 1;
+            None::<()>
+        })?;
+        Ok(count)
+    }
+
+    /// Store an entry naming `ino` in the first free slot of a directory
+    fn add_entry(&mut self, dir_ino: u32, dir: &mut Inode, name: &str, ino: u32) -> Result<(), &'static str> {
+        let mut entry = [0; ENTRY_SIZE];
+        write_u32(&mut entry, 0, ino);
+        entry[4..4 + name.len()].copy_from_slice(name.as_bytes());
+
+        let mut slot = dir.size / ENTRY_SIZE;
+        let mut block = [0; BLOCK_SIZE];
+        for index in 0..dir.size.div_ceil(BLOCK_SIZE) {
+            let len = self.read_data(dir, index * BLOCK_SIZE, &mut block)?;
+            if let Some(free) = block[..len].as_chunks::<ENTRY_SIZE>().0.iter().position(|entry| read_u32(entry, 0) == 0) {
+                slot = index * (BLOCK_SIZE / ENTRY_SIZE) + free;
+                break;
+            }
+        }
+        if self.write_inode_data(dir_ino, dir, slot * ENTRY_SIZE, &entry)? < ENTRY_SIZE {
+            return Err("Filesystem full");
+        }
+        Ok(())
+    }
+
+    /// Allocate an inode with the given content and link it into `dir`
+    fn insert(&mut self, dir: u32, name: &str, kind: Kind, mode: u16, content: &[u8]) -> Result<u32, &'static str> {
+        check_name(name)?;
+        let mut parent = self.directory(dir)?;
+        if self.find(&parent, name)?.is_some() {
+            return Err("File exists");
+        }
+
+        let ino = self.allocate_bit(self.superblock.inode_bitmap, self.superblock.inode_count, self.next_inode)?;
+        self.next_inode = ino + 1;
+        let mut inode = Inode::new(kind, mode, (self.clock)());
+        self.write_inode(ino, &inode)?;
+        let linked = match self.write_inode_data(ino, &mut inode, 0, content) {
+            Ok(written) if written < content.len() => Err("Filesystem full"),
+            Ok(_) => self.add_entry(dir, &mut parent, name, ino),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = linked {
+            self.release(ino, &mut inode)?;
+            return Err(error);
+        }
+
+        // The new directory's `..` names its parent
+        if kind == Kind::Directory {
+            parent.nlink += 1;
+            self.write_inode(dir, &parent)?;
+        }
+        Ok(ino)
+    }
+
+    pub fn lookup(&self, dir: u32, name: &str) -> Result<u32, &'static str> {
+        let dir = self.directory(dir)?;
+        self.find(&dir, name)?.map(|(_, ino)| ino).ok_or("File not found")
+    }
+
+    pub fn metadata(&self, ino: u32) -> Result<Metadata, &'static str> {
+        let inode = self.read_inode(ino)?;
+        let size = match inode.kind {
+            Kind::Directory => self.entry_count(&inode)?,
+            _ => inode.size,
+        };
+        Ok(Metadata {
+            kind: inode.kind,
+            mode: inode.mode,
+            nlink: inode.nlink,
+            size,
+            created: inode.created,
+            modified: inode.modified,
+        })
+    }
+
+    pub fn read_at(&self, ino: u32, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let inode = self.read_inode(ino)?;
+        match inode.kind {
+            Kind::File => self.read_data(&inode, offset, buf),
+            Kind::Directory => Err("Is a directory"),
+            Kind::Symlink => Err("Invalid argument"),
+        }
+    }
+
+    pub fn write_at(&mut self, ino: u32, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        let mut inode = self.read_inode(ino)?;
+        match inode.kind {
+            Kind::File => self.write_inode_data(ino, &mut inode, offset, data),
+            Kind::Directory => Err("Is a directory"),
+            Kind::Symlink => Err("Invalid argument"),
+        }
+    }
+
+    /// Cut a file to `size` bytes or extend it with a hole
+    pub fn truncate(&mut self, ino: u32, size: usize) -> Result<(), &'static str> {
+        let mut inode = self.read_inode(ino)?;
+        match inode.kind {
+            Kind::File => {}
+            Kind::Directory => return Err("Is a directory"),
+            Kind::Symlink => return Err("Invalid argument"),
+        }
+        if size > MAX_FILE_SIZE {
+            return Err("File too large");
+        }
+        if size < inode.size {
+            self.free_blocks(&mut inode, size.div_ceil(BLOCK_SIZE))?;
+            // Zero the cut-off tail of the last block so growing the file
+            // again reads zeros rather than the old bytes
+            let within = size % BLOCK_SIZE;
+            let last = self.block_at(&inode, size / BLOCK_SIZE)?;
+            if within != 0 && last != 0 {
+                let mut block = [0; BLOCK_SIZE];
+                self.device.read_block(last, &mut block)?;
+                block[within..].fill(0);
+                self.device.write_block(last, &block)?;
+            }
+        }
+        inode.size = size;
+        inode.modified = (self.clock)();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Names and inode numbers of a directory's entries
+    pub fn readdir(&self, dir: u32) -> Result<Vec<(String, u32)>, &'static str> {
+        let dir = self.directory(dir)?;
+        let mut entries = Vec::new();
+        self.scan(&dir, |_, ino, name| {
+            entries.push((String::from(name), ino));
+            None::<()>
+        })?;
+        Ok(entries)
+    }
+
+    /// Create an empty file or directory
+    pub fn create(&mut self, dir: u32, name: &str, kind: Kind) -> Result<u32, &'static str> {
+        match kind {
+            Kind::File => self.insert(dir, name, kind, 0o644, &[]),
+            Kind::Directory => self.insert(dir, name, kind, 0o755, &[]),
+            Kind::Symlink => Err("Operation not supported"),
+        }
+    }
+
+    /// Remove a directory entry, freeing the inode with its last link.
+    /// Directories must be empty.
+    pub fn unlink(&mut self, dir: u32, name: &str) -> Result<(), &'static str> {
+        let mut parent = self.directory(dir)?;
+        let (slot, ino) = self.find(&parent, name)?.ok_or("File not found")?;
+        let mut inode = self.read_inode(ino)?;
+        if inode.kind == Kind::Directory && self.entry_count(&inode)? > 0 {
+            return Err("Directory not empty");
+        }
+
+        // Removing a directory drops its `..` link to the parent
+        let counted = if inode.kind == Kind::Directory { &mut parent } else { &mut inode };
+        counted.nlink = counted.nlink.checked_sub(1).ok_or("Corrupt filesystem")?;
+
+        self.write_inode_data(dir, &mut parent, slot * ENTRY_SIZE, &[0; ENTRY_SIZE])?;
+        if inode.kind == Kind::Directory {
+            self.write_inode(dir, &parent)?;
+            return self.release(ino, &mut inode);
+        }
+        if inode.nlink == 0 {
+            return self.release(ino, &mut inode);
+        }
+        self.write_inode(ino, &inode)
+    }
+
+    /// Add another name for a file
+    pub fn link(&mut self, ino: u32, dir: u32, name: &str) -> Result<(), &'static str> {
+        check_name(name)?;
+        let mut inode = self.read_inode(ino)?;
+        if inode.kind == Kind::Directory {
+            return Err("Is a directory");
+        }
+        let mut parent = self.directory(dir)?;
+        if self.find(&parent, name)?.is_some() {
+            return Err("File exists");
+        }
+        self.add_entry(dir, &mut parent, name, ino)?;
+        inode.nlink += 1;
+        self.write_inode(ino, &inode)
+    }
+
+    pub fn symlink(&mut self, dir: u32, name: &str, target: &str) -> Result<u32, &'static str> {
+        if target.len() > MAX_LINK {
+            return Err("File name too long");
+        }
+        self.insert(dir, name, Kind::Symlink, 0o777, target.as_bytes())
+    }
+
+    /// Path a symbolic link points to
+    pub fn read_link(&self, ino: u32) -> Result<String, &'static str> {
+        let inode = self.read_inode(ino)?;
+        if inode.kind != Kind::Symlink {
+            return Err("Not a symbolic link");
+        }
+        if inode.size > MAX_LINK {
+            return Err("Corrupt filesystem");
+        }
+        let mut target = vec![0; inode.size];
+        self.read_data(&inode, 0, &mut target)?;
+        String::from_utf8(target).map_err(|_| "Corrupt filesystem")
+    }
+
+    pub fn set_mode(&mut self, ino: u32, mode: u16) -> Result<(), &'static str> {
+        let mut inode = self.read_inode(ino)?;
+        inode.mode = mode;
+        self.write_inode(ino, &inode)
+    }
+}
@@ -1,5 +1,45 @@
-/// System call interface for user programs
-use crate::{print, println};
+//! System call interface for user programs
+//!
+//! Arguments are passed as up to three machine words and the result is a
+//! single signed word. Negative results are `-errno` values. Pointer
+//! arguments are plain kernel addresses, since programs currently share
+//! the kernel's address space. Bytecode programs reach every call with
+//! `syscall sys`, passing data memory offsets for the pointers. Native
+//! programs use `int 0x80` (see `usermode.rs`), and their pointers must
+//! lie in their own mapped memory.
+//!
+//! | No | Name     | arg1                 | arg2       | arg3 | Result                         |
+//! |----|----------|----------------------|------------|------|--------------------------------|
+//! | 0  | exit     | status               |            |      | 0 (the process is terminated)  |
+//! | 1  | write    | fd (1 = stdout)      | buf        | len  | bytes written                  |
+//! | 2  | read     | fd (0 = stdin)       | buf        | len  | bytes read (0 at end of file)  |
+//! | 3  | open     | path                 | path len   | flags| fd                             |
+//! | 4  | close    | fd                   |            |      | 0                              |
+//! | 5  | getpid   |                      |            |      | pid (0 for the kernel shell)   |
+//! | 6  | getppid  |                      |            |      | parent pid (0 for the shell)   |
+//! | 7  | sleep_ms | milliseconds         |            |      | 0                              |
+//! | 8  | yield    |                      |            |      | 0                              |
+//! | 9  | uptime   |                      |            |      | milliseconds since boot        |
+//! | 10 | spawn    | path                 | path len   |      | child pid                      |
+//! | 11 | waitpid  | pid (-1 = any child) | status ptr |      | reaped pid                     |
+//! | 12 | sbrk     | increment (signed)   |            |      | previous program break         |
+//! | 13 | mmap     | len                  |            |      | address of zeroed region       |
+//! | 14 | unlink   | path                 | path len   |      | 0                              |
+//! | 15 | lseek    | fd                   | offset     |whence| new offset                     |
+//! | 16 | ftruncate| fd                   | len        |      | 0                              |
+//! | 17 | sync     |                      |            |      | 0                              |
+//!
+//! Reading stdin blocks until at least one key is available. Descriptors
+//! from 3 up are files: `open` takes the `O_*` flag bits below, and
+//! `lseek` measures from the start (0), the current offset (1) or the end
+//! of the file (2). `sync` writes cached disk sectors back to the disks.
+
+use crate::{print, println, serial_println};
+use crate::{block, keyboard, loader, process, scheduler, time, vfs};
+use crate::errno::{self, EBADF, ECHILD, EEXIST, EFAULT, EFBIG, EINVAL, EIO, EISDIR, EMFILE, ENOENT, ENOMEM, ENOSPC, ENOSYS, ENOTDIR, EROFS};
+use crate::vfs::{FileHandle, OpenFlags, SeekFrom, Vfs};
+use alloc::format;
+use alloc::string::String;
 
 /// System call numbers
 #[derive(Debug, Clone, Copy)]
@@ -10,47 +50,560 @@ pub enum SyscallNumber {
     Read = 2,
     Open = 3,
     Close = 4,
+    GetPid = 5,
+    GetPpid = 6,
+    SleepMs = 7,
+    Yield = 8,
+    Uptime = 9,
+    Spawn = 10,
+    WaitPid = 11,
+    Sbrk = 12,
+    Mmap = 13,
+    Unlink = 14,
+    Lseek = 15,
+    Ftruncate = 16,
+    Sync = 17,
+}
+
+impl SyscallNumber {
+    pub fn from_usize(number: usize) -> Option<Self> {
+        use SyscallNumber::*;
+        let syscall = match number {
+            0 => Exit,
+            1 => Write,
+            2 => Read,
+            3 => Open,
+            4 => Close,
+            5 => GetPid,
+            6 => GetPpid,
+            7 => SleepMs,
+            8 => Yield,
+            9 => Uptime,
+            10 => Spawn,
+            11 => WaitPid,
+            12 => Sbrk,
+            13 => Mmap,
+            14 => Unlink,
+            15 => Lseek,
+            16 => Ftruncate,
+            17 => Sync,
+            _ => return None,
+        };
+        Some(syscall)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SyscallNumber::Exit => "exit",
+            SyscallNumber::Write => "write",
+            SyscallNumber::Read => "read",
+            SyscallNumber::Open => "open",
+            SyscallNumber::Close => "close",
+            SyscallNumber::GetPid => "getpid",
+            SyscallNumber::GetPpid => "getppid",
+            SyscallNumber::SleepMs => "sleep_ms",
+            SyscallNumber::Yield => "yield",
+            SyscallNumber::Uptime => "uptime",
+            SyscallNumber::Spawn => "spawn",
+            SyscallNumber::WaitPid => "waitpid",
+            SyscallNumber::Sbrk => "sbrk",
+            SyscallNumber::Mmap => "mmap",
+            SyscallNumber::Unlink => "unlink",
+            SyscallNumber::Lseek => "lseek",
+            SyscallNumber::Ftruncate => "ftruncate",
+            SyscallNumber::Sync => "sync",
+        }
+    }
+}
+
+/// `open` flag: allow writing
+pub const O_WRITE: usize = 1;
+/// `open` flag: create the file if it does not exist
+pub const O_CREATE: usize = 2;
+/// `open` flag: empty the file
+pub const O_TRUNCATE: usize = 4;
+/// `open` flag: write at the end of the file
+pub const O_APPEND: usize = 8;
+
+/// Memory a call reads or writes through one of its arguments
+pub struct Buffer {
+    /// Index of the pointer argument
+    pub arg: usize,
+    pub len: usize,
+    /// The kernel writes to it
+    pub written: bool,
+    /// A null pointer means the call does without it
+    pub optional: bool,
+}
+
+/// The buffer or path a call takes, if any, so callers that do not share
+/// the kernel's view of memory can check or translate the pointer
+pub fn buffer_arg(number: usize, args: [usize; 3]) -> Option<Buffer> {
+    let buffer = |arg, len, written, optional| Some(Buffer { arg, len, written, optional });
+    match SyscallNumber::from_usize(number)? {
+        SyscallNumber::Write => buffer(1, args[2], false, false),
+        SyscallNumber::Read => buffer(1, args[2], true, false),
+        SyscallNumber::Open | SyscallNumber::Spawn | SyscallNumber::Unlink => buffer(0, args[1], false, false),
+        SyscallNumber::WaitPid => buffer(1, 4, true, true),
+        _ => None,
+    }
+}
+
+/// Largest region a single `mmap` call may request
+const MMAP_LIMIT: usize = 16 * 1024;
+
+/// Execute a system call, logging it to the serial port if the calling
+/// process is being traced
+///
+/// # Safety
+///
+/// Pointer arguments are dereferenced as-is, so the caller must ensure
+/// they describe valid memory for the requested length.
+pub unsafe fn syscall(number: usize, arg1: usize, arg2: usize, arg3: usize) -> isize {
+    // Programs that never let the shell wait still get the cache written
+    // back
+    block::write_back_expired();
+
+    let traced_pid = {
+        let pm = process::PROCESS_MANAGER.lock();
+        pm.get_current().filter(|&pid| pm.get_process(pid).is_some_and(|p| p.traced))
+    };
+
+    let pid = match traced_pid {
+        Some(pid) => pid,
+        None => return dispatch(number, arg1, arg2, arg3),
+    };
+
+    let start = time::tsc();
+    let result = dispatch(number, arg1, arg2, arg3);
+    let elapsed = time::tsc() - start;
+
+    let call = trace_call(number, arg1, arg2, arg3);
+    let returns_address = matches!(
+        SyscallNumber::from_usize(number),
+        Some(SyscallNumber::Sbrk | SyscallNumber::Mmap)
+    );
+    let us = time::tsc_to_us(elapsed);
+    if result < 0 {
+        serial_println!("[pid {}] {} = -1 {} ({} us)", pid, call, errno::name(-result), us);
+    } else if returns_address {
+        serial_println!("[pid {}] {} = {:#x} ({} us)", pid, call, result, us);
+    } else {
+        serial_println!("[pid {}] {} = {} ({} us)", pid, call, result, us);
+    }
+    result
+}
+
+unsafe fn dispatch(number: usize, arg1: usize, arg2: usize, arg3: usize) -> isize {
+    let syscall = match SyscallNumber::from_usize(number) {
+        Some(syscall) => syscall,
+        None => return -ENOSYS,
+    };
+
+    match syscall {
+        SyscallNumber::Exit => sys_exit(arg1 as i32),
+        SyscallNumber::Write => match user_slice(arg2, arg3) {
+            Ok(buf) => sys_write(arg1, buf),
+            Err(e) => e,
+        },
+        SyscallNumber::Read => match user_slice_mut(arg2, arg3) {
+            Ok(buf) => sys_read(arg1, buf),
+            Err(e) => e,
+        },
+        SyscallNumber::Open => match user_str(arg1, arg2) {
+            Ok(path) => sys_open(path, arg3),
+            Err(e) => e,
+        },
+        SyscallNumber::Close => sys_close(arg1),
+        SyscallNumber::GetPid => sys_getpid(),
+        SyscallNumber::GetPpid => sys_getppid(),
+        SyscallNumber::SleepMs => sys_sleep_ms(arg1 as u64),
+        SyscallNumber::Yield => sys_yield(),
+        SyscallNumber::Uptime => sys_uptime(),
+        SyscallNumber::Spawn => match user_str(arg1, arg2) {
+            Ok(path) => sys_spawn(path),
+            Err(e) => e,
+        },
+        SyscallNumber::WaitPid => {
+            let mut status = 0;
+            let result = sys_waitpid(arg1 as isize, &mut status);
+            if result >= 0 && arg2 != 0 {
+                (arg2 as *mut i32).write_unaligned(status);
+            }
+            result
+        }
+        SyscallNumber::Sbrk => sys_sbrk(arg1 as isize),
+        SyscallNumber::Mmap => sys_mmap(arg1),
+        SyscallNumber::Unlink => match user_str(arg1, arg2) {
+            Ok(path) => sys_unlink(path),
+            Err(e) => e,
+        },
+        SyscallNumber::Lseek => sys_lseek(arg1, arg2 as isize, arg3),
+        SyscallNumber::Ftruncate => sys_ftruncate(arg1, arg2),
+        SyscallNumber::Sync => sys_sync(),
+    }
+}
+
+/// Render a call with its decoded arguments, e.g. `write(1, "hi", 2)`
+unsafe fn trace_call(number: usize, arg1: usize, arg2: usize, arg3: usize) -> String {
+    let syscall = match SyscallNumber::from_usize(number) {
+        Some(syscall) => syscall,
+        None => return format!("syscall_{}({:#x}, {:#x}, {:#x})", number, arg1, arg2, arg3),
+    };
+
+    let name = syscall.name();
+    match syscall {
+        SyscallNumber::Exit => format!("{}({})", name, arg1 as i32),
+        SyscallNumber::Write => {
+            format!("{}({}, {}, {})", name, arg1, quote(user_slice(arg2, arg3).unwrap_or(&[])), arg3)
+        }
+        SyscallNumber::Read => {
+            format!("{}({}, {:#x}, {})", name, arg1, arg2, arg3)
+        }
+        SyscallNumber::Open => {
+            format!("{}({}, {:#x})", name, quote(user_slice(arg1, arg2).unwrap_or(&[])), arg3)
+        }
+        SyscallNumber::Spawn | SyscallNumber::Unlink => {
+            format!("{}({})", name, quote(user_slice(arg1, arg2).unwrap_or(&[])))
+        }
+        SyscallNumber::Close | SyscallNumber::SleepMs | SyscallNumber::Mmap => {
+            format!("{}({})", name, arg1)
+        }
+        SyscallNumber::GetPid | SyscallNumber::GetPpid | SyscallNumber::Yield | SyscallNumber::Uptime
+        | SyscallNumber::Sync => {
+            format!("{}()", name)
+        }
+        SyscallNumber::WaitPid => format!("{}({}, {:#x})", name, arg1 as isize, arg2),
+        SyscallNumber::Sbrk => format!("{}({})", name, arg1 as isize),
+        SyscallNumber::Lseek => format!("{}({}, {}, {})", name, arg1, arg2 as isize, arg3),
+        SyscallNumber::Ftruncate => format!("{}({}, {})", name, arg1, arg2),
+    }
+}
+
+/// Quote a buffer for the trace log, escaping control bytes and
+/// truncating long buffers
+fn quote(bytes: &[u8]) -> String {
+    const MAX_QUOTED: usize = 32;
+
+    let mut out = String::from("\"");
+    for &byte in bytes.iter().take(MAX_QUOTED) {
+        match byte {
+            b'\n' => out.push_str("\\n"),
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    out.push('"');
+    if bytes.len() > MAX_QUOTED {
+        out.push_str("...");
+    }
+    out
+}
+
+unsafe fn user_slice<'a>(ptr: usize, len: usize) -> Result<&'a [u8], isize> {
+    if ptr == 0 && len != 0 {
+        return Err(-EFAULT);
+    }
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(core::slice::from_raw_parts(ptr as *const u8, len))
+}
+
+unsafe fn user_slice_mut<'a>(ptr: usize, len: usize) -> Result<&'a mut [u8], isize> {
+    if ptr == 0 && len != 0 {
+        return Err(-EFAULT);
+    }
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    Ok(core::slice::from_raw_parts_mut(ptr as *mut u8, len))
+}
+
+unsafe fn user_str<'a>(ptr: usize, len: usize) -> Result<&'a str, isize> {
+    let bytes = user_slice(ptr, len)?;
+    core::str::from_utf8(bytes).map_err(|_| -EINVAL)
+}
+
+/// Error number for a filesystem error
+fn vfs_errno(error: &str) -> isize {
+    match error {
+        "File not found" => ENOENT,
+        "File exists" => EEXIST,
+        "Not a directory" => ENOTDIR,
+        "Is a directory" => EISDIR,
+        "File too large" => EFBIG,
+        "Filesystem full" => ENOSPC,
+        "Read-only filesystem" => EROFS,
+        "Bad file descriptor" | "Stale file handle" => EBADF,
+        _ => EINVAL,
+    }
+}
+
+/// Apply `op` to the current process's open file `fd` and store the
+/// handle back with its new offset. The process table is not held during
+/// the operation, since reading `/proc` locks it.
+fn with_file(fd: usize, op: impl FnOnce(&mut Vfs, &mut FileHandle) -> Result<usize, &'static str>) -> isize {
+    let slot = match fd.checked_sub(process::FIRST_FILE_FD) {
+        Some(slot) => slot,
+        None => return -EBADF,
+    };
+    let (pid, mut handle) = {
+        let pm = process::PROCESS_MANAGER.lock();
+        let pid = match pm.get_current() {
+            Some(pid) => pid,
+            None => return -EBADF,
+        };
+        match pm.get_process(pid).and_then(|p| p.files.get(slot).cloned().flatten()) {
+            Some(handle) => (pid, handle),
+            None => return -EBADF,
+        }
+    };
+
+    let result = op(&mut vfs::VFS.lock(), &mut handle);
+
+    let mut pm = process::PROCESS_MANAGER.lock();
+    if let Some(Some(file)) = pm.get_process_mut(pid).and_then(|p| p.files.get_mut(slot)) {
+        *file = handle;
+    }
+    match result {
+        Ok(value) => value as isize,
+        Err(e) => -vfs_errno(e),
+    }
+}
+
+/// Terminate the current process with `status`
+pub fn sys_exit(status: i32) -> isize {
+    let mut pm = process::PROCESS_MANAGER.lock();
+    if let Some(pid) = pm.get_current() {
+        let _ = pm.exit_process(pid, status);
+    }
+    0
 }
 
-/// Execute a system call
-pub fn syscall(number: usize, arg1: usize, arg2: usize, _arg3: usize) -> isize {
-    match number {
-        0 => { // Exit
-            // In a real OS, this would terminate the process
-            0
-        }
-        1 => { // Write
-            // arg1: file descriptor (1 = stdout)
-            // arg2: pointer to data
-            // In simplified version, we just print
-            if arg1 == 1 {
-                if arg2 < 128 {
-                    print!("{}", arg2 as u8 as char);
-                    1
-                } else {
-                    -1
+/// Write a buffer to a file descriptor
+pub fn sys_write(fd: usize, buf: &[u8]) -> isize {
+    if fd != 1 {
+        return with_file(fd, |vfs, handle| vfs.write(handle, buf));
+    }
+    for &byte in buf {
+        print!("{}", byte as char);
+    }
+    buf.len() as isize
+}
+
+/// Read from a file, or from the keyboard waiting until at least one
+/// byte is available
+pub fn sys_read(fd: usize, buf: &mut [u8]) -> isize {
+    if fd != 0 {
+        return with_file(fd, |vfs, handle| vfs.read(handle, buf));
+    }
+    if buf.is_empty() {
+        return 0;
+    }
+
+    let mut count = 0;
+    loop {
+        while count < buf.len() {
+            match keyboard::read_key() {
+                Some(key) => {
+                    buf[count] = key;
+                    count += 1;
                 }
-            } else {
-                -1
+                None => break,
             }
         }
-        2 => { // Read
-            // Not implemented yet
-            -1
+        // Ctrl+C ends the read so the scheduler can stop the reader
+        if count > 0 || keyboard::interrupt_pending() {
+            return count as isize;
         }
-        _ => {
-            -1
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Open a file for the current process
+pub fn sys_open(path: &str, flags: usize) -> isize {
+    let flags = OpenFlags {
+        write: flags & O_WRITE != 0,
+        create: flags & O_CREATE != 0,
+        truncate: flags & O_TRUNCATE != 0,
+        append: flags & O_APPEND != 0,
+    };
+    let handle = match vfs::VFS.lock().open(path, flags) {
+        Ok(handle) => handle,
+        Err(e) => return -vfs_errno(e),
+    };
+
+    let mut pm = process::PROCESS_MANAGER.lock();
+    let pid = match pm.get_current() {
+        Some(pid) => pid,
+        None => return -EBADF,
+    };
+    let files = match pm.get_process_mut(pid) {
+        Some(process) => &mut process.files,
+        None => return -EBADF,
+    };
+    let slot = match files.iter().position(Option::is_none) {
+        Some(slot) => slot,
+        None if files.len() < process::MAX_OPEN_FILES => {
+            files.push(None);
+            files.len() - 1
         }
+        None => return -EMFILE,
+    };
+    files[slot] = Some(handle);
+    (process::FIRST_FILE_FD + slot) as isize
+}
+
+/// Close a file opened by the current process
+pub fn sys_close(fd: usize) -> isize {
+    let mut pm = process::PROCESS_MANAGER.lock();
+    let pid = match pm.get_current() {
+        Some(pid) => pid,
+        None => return -EBADF,
+    };
+    let file = fd.checked_sub(process::FIRST_FILE_FD)
+        .and_then(|slot| pm.get_process_mut(pid)?.files.get_mut(slot));
+    match file.and_then(Option::take) {
+        Some(_) => 0,
+        None => -EBADF,
     }
 }
 
-/// Helper function to print a string via syscall interface
-pub fn sys_write(fd: usize, data: &str) -> isize {
-    if fd == 1 {
-        print!("{}", data);
-        data.len() as isize
-    } else {
-        -1
+/// Move the offset of an open file
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    let from = match whence {
+        0 if offset >= 0 => SeekFrom::Start(offset as usize),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return -EINVAL,
+    };
+    with_file(fd, |vfs, handle| vfs.seek(handle, from))
+}
+
+/// Set the size of an open file
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+    with_file(fd, |vfs, handle| vfs.truncate(handle, len).map(|()| 0))
+}
+
+pub fn sys_sync() -> isize {
+    match block::sync() {
+        Ok(()) => 0,
+        Err(_) => -EIO,
+    }
+}
+
+pub fn sys_getpid() -> isize {
+    process::PROCESS_MANAGER.lock().get_current().unwrap_or(0) as isize
+}
+
+pub fn sys_getppid() -> isize {
+    let pm = process::PROCESS_MANAGER.lock();
+    pm.get_current()
+        .and_then(|pid| pm.get_process(pid))
+        .and_then(|p| p.parent)
+        .unwrap_or(0) as isize
+}
+
+pub fn sys_sleep_ms(ms: u64) -> isize {
+    time::sleep_ms(ms);
+    0
+}
+
+/// Give up the CPU: every other runnable bytecode process gets one
+/// scheduler slice before the caller continues. With nothing else to run,
+/// wait for the next interrupt instead.
+pub fn sys_yield() -> isize {
+    if !scheduler::run_slice() {
+        x86_64::instructions::hlt();
+    }
+    0
+}
+
+pub fn sys_uptime() -> isize {
+    time::uptime_ms() as isize
+}
+
+/// Run a program from the filesystem as a child of the current process.
+/// The child runs to completion before this returns.
+pub fn sys_spawn(path: &str) -> isize {
+    match loader::spawn(path, &[path]) {
+        Ok(pid) => pid as isize,
+        Err(e) => -vfs_errno(e),
+    }
+}
+
+/// Collect the exit status of a terminated child
+pub fn sys_waitpid(pid: isize, status: &mut i32) -> isize {
+    let mut pm = process::PROCESS_MANAGER.lock();
+    let parent = pm.get_current();
+    let target = if pid < 0 { None } else { Some(pid as usize) };
+    match pm.reap(parent, target) {
+        Ok((pid, code)) => {
+            *status = code;
+            pid as isize
+        }
+        Err(_) => -ECHILD,
+    }
+}
+
+/// Grow or shrink the current process's heap, returning the old break
+pub fn sys_sbrk(increment: isize) -> isize {
+    let mut pm = process::PROCESS_MANAGER.lock();
+    let pid = match pm.get_current() {
+        Some(pid) => pid,
+        None => return -EINVAL,
+    };
+    let process = match pm.get_process_mut(pid) {
+        Some(process) => process,
+        None => return -EINVAL,
+    };
+
+    // Reserve the whole heap up front so the break address stays stable
+    if process.heap.capacity() == 0 {
+        process.heap.reserve_exact(process::PROCESS_HEAP_LIMIT);
+    }
+
+    let old_len = process.heap.len();
+    let new_len = match (old_len as isize).checked_add(increment) {
+        Some(len) if len >= 0 && len as usize <= process::PROCESS_HEAP_LIMIT => len as usize,
+        _ => return -ENOMEM,
+    };
+
+    let old_break = process.heap.as_ptr() as usize + old_len;
+    process.heap.resize(new_len, 0);
+    old_break as isize
+}
+
+/// Map a zeroed anonymous region for the current process
+pub fn sys_mmap(len: usize) -> isize {
+    if len == 0 || len > MMAP_LIMIT {
+        return -EINVAL;
+    }
+
+    let mut pm = process::PROCESS_MANAGER.lock();
+    let pid = match pm.get_current() {
+        Some(pid) => pid,
+        None => return -EINVAL,
+    };
+    match pm.get_process_mut(pid) {
+        Some(process) => {
+            let region = alloc::vec![0u8; len];
+            let addr = region.as_ptr() as usize;
+            process.mappings.push(region);
+            addr as isize
+        }
+        None => -EINVAL,
+    }
+}
+
+/// Remove a file from the filesystem
+pub fn sys_unlink(path: &str) -> isize {
+    match vfs::VFS.lock().delete_file(path) {
+        Ok(()) => 0,
+        Err(e) => -vfs_errno(e),
     }
 }
 
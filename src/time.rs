@@ -0,0 +1,77 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Input clock of the 8253/8254 programmable interval timer
+const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// Timer interrupt rate (one tick per millisecond)
+pub const TICK_HZ: u32 = 1000;
+
+/// Number of timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Time stamp counter increments per millisecond, measured by `calibrate`
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Program PIT channel 0 to fire at `TICK_HZ`
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICK_HZ) as u16;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel0: Port<u8> = Port::new(0x40);
+
+    unsafe {
+        // Channel 0, lobyte/hibyte access, mode 3 (square wave)
+        command.write(0x36);
+        channel0.write((divisor & 0xFF) as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Called from the timer interrupt handler
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Timer ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since boot
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICK_HZ as u64
+}
+
+/// Block the CPU until at least `ms` milliseconds have passed
+pub fn sleep_ms(ms: u64) {
+    let target = uptime_ms().saturating_add(ms);
+    while uptime_ms() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Read the CPU time stamp counter
+pub fn tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Measure the TSC rate against the PIT. Needs interrupts enabled.
+pub fn calibrate() {
+    const SAMPLE_MS: u64 = 10;
+
+    // Start on a tick boundary so the sample covers whole ticks
+    let first = ticks();
+    while ticks() == first {
+        x86_64::instructions::hlt();
+    }
+
+    let start = tsc();
+    sleep_ms(SAMPLE_MS);
+    let elapsed = tsc() - start;
+    TSC_PER_MS.store(elapsed / SAMPLE_MS, Ordering::Relaxed);
+}
+
+/// Convert a TSC delta to microseconds (0 before calibration)
+pub fn tsc_to_us(cycles: u64) -> u64 {
+    (cycles * 1000).checked_div(TSC_PER_MS.load(Ordering::Relaxed)).unwrap_or(0)
+}
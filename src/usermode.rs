@@ -0,0 +1,236 @@
+//! Running native code in ring 3
+//!
+//! A loaded `AddressSpace` is copied into page-aligned frames from the
+//! kernel heap, which are mapped at the segments' addresses with user
+//! access, and the CPU drops to ring 3 at the entry point with `iretq`.
+//! The program calls back into the kernel with `int 0x80`: the call
+//! number goes in `rax`, the arguments in `rdi`, `rsi` and `rdx`, and the
+//! result comes back in `rax`. Interrupts and faults from ring 3 arrive on
+//! a kernel stack named in the TSS.
+//!
+//! Native programs run on their caller's behalf: `UserImage::enter`
+//! returns once the program exits, faults or is stopped with Ctrl+C. An
+//! image mapped over addresses already in use (the kernel's, or those of
+//! a native program that spawned this one) is refused.
+use crate::elf::AddressSpace;
+use crate::errno::EFAULT;
+use crate::syscall::{self, SyscallNumber};
+use crate::{gdt, keyboard, memory, println, process, scheduler};
+use alloc::alloc::{dealloc, Layout};
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Kernel stack for interrupts and system calls from one native program
+const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+/// Kernel stack pointer to return to when the innermost native program
+/// stops. Each `user_enter` saves the previous value on its own stack.
+static mut USER_RETURN_RSP: u64 = 0;
+
+core::arch::global_asm!(
+    // user_enter(entry, stack, code selector, data selector) -> status
+    ".global user_enter",
+    "user_enter:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "push qword ptr [rip + {saved}]",
+    "mov [rip + {saved}], rsp",
+    // iretq frame: ss, rsp, rflags (interrupts enabled), cs, rip
+    "push rcx",
+    "push rsi",
+    "push 0x202",
+    "push rdx",
+    "push rdi",
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+
+    // user_leave(status): return from the innermost user_enter, from
+    // whatever kernel stack the program's trap is running on
+    ".global user_leave",
+    "user_leave:",
+    "mov rsp, [rip + {saved}]",
+    "pop qword ptr [rip + {saved}]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "mov rax, rdi",
+    "sti",
+    "ret",
+
+    // int 0x80: rax = number, rdi, rsi, rdx = arguments
+    ".global syscall_trap",
+    "syscall_trap:",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    // The CPU pushed five words; keep the stack 16-byte aligned
+    "sub rsp, 8",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "call {handler}",
+    "add rsp, 8",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "iretq",
+    saved = sym USER_RETURN_RSP,
+    handler = sym trap_syscall,
+);
+
+extern "C" {
+    fn user_enter(entry: u64, stack: u64, code: u64, data: u64) -> i64;
+    fn user_leave(status: i64) -> !;
+    fn syscall_trap();
+}
+
+/// Address of the `int 0x80` entry point, for the IDT
+pub fn syscall_entry() -> u64 {
+    syscall_trap as *const () as u64
+}
+
+/// Handle `int 0x80` from a native program
+extern "C" fn trap_syscall(number: usize, arg1: usize, arg2: usize, arg3: usize) -> isize {
+    // The gate turned interrupts off; sleeping and reading keys need them
+    x86_64::instructions::interrupts::enable();
+
+    let args = [arg1, arg2, arg3];
+    if let Some(buffer) = syscall::buffer_arg(number, args) {
+        let addr = args[buffer.arg];
+        let optional = buffer.optional && addr == 0;
+        if !optional && !memory::user_accessible(addr, buffer.len, buffer.written) {
+            return -EFAULT;
+        }
+    }
+
+    let result = unsafe { syscall::syscall(number, arg1, arg2, arg3) };
+    if number == SyscallNumber::Exit as usize {
+        unsafe { user_leave(arg1 as i32 as i64) }
+    }
+    result
+}
+
+/// Whether an interrupt arrived while ring 3 code was running
+pub fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Terminate the running native program with `status` and return to the
+/// kernel code that entered it. Only valid in a trap from ring 3.
+pub fn terminate(status: i32) -> ! {
+    {
+        let mut pm = process::PROCESS_MANAGER.lock();
+        if let Some(pid) = pm.get_current() {
+            let _ = pm.exit_process(pid, status);
+        }
+    }
+    unsafe { user_leave(status as i64) }
+}
+
+/// A CPU exception in ring 3 ends the program
+pub fn fault(reason: &str, stack_frame: &InterruptStackFrame) -> ! {
+    println!("{} at {:#x}", reason, stack_frame.instruction_pointer.as_u64());
+    terminate(-1)
+}
+
+/// Called from the timer interrupt after end of interrupt has been sent:
+/// Ctrl+C stops a native program the way the scheduler stops bytecode
+pub fn check_interrupt(stack_frame: &InterruptStackFrame) {
+    if from_user(stack_frame) && keyboard::take_interrupt() {
+        println!("^C");
+        terminate(scheduler::INTERRUPTED);
+    }
+}
+
+/// A native program's memory, mapped into the page tables until dropped
+pub struct UserImage {
+    /// User address and backing kernel page of every mapped page
+    pages: Vec<(u64, *mut u8)>,
+}
+
+impl UserImage {
+    /// Copy an address space into fresh frames and map them at its
+    /// addresses
+    pub fn map(space: &AddressSpace) -> Result<Self, &'static str> {
+        let mut image = UserImage { pages: Vec::new() };
+        for segment in space.segments() {
+            for (i, chunk) in segment.data.chunks(memory::PAGE_SIZE).enumerate() {
+                let backing = memory::alloc_page().ok_or("Out of memory")?;
+                // SAFETY: a fresh page, and chunks are at most a page long
+                unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), backing, chunk.len()) };
+                let vaddr = segment.start + (i * memory::PAGE_SIZE) as u64;
+                if let Err(e) = memory::map_user_page(vaddr, backing, segment.perms.write, segment.perms.execute) {
+                    free_page(backing);
+                    return Err(e);
+                }
+                image.pages.push((vaddr, backing));
+            }
+        }
+        Ok(image)
+    }
+
+    /// Run the image in ring 3 from `entry` with the stack at
+    /// `stack_pointer`, returning its exit status
+    pub fn enter(&self, entry: u64, stack_pointer: u64) -> i32 {
+        let kernel_stack = vec![0u8; KERNEL_STACK_SIZE];
+        let top = (kernel_stack.as_ptr() as u64 + KERNEL_STACK_SIZE as u64) & !0xf;
+        let previous = gdt::set_kernel_stack(top);
+
+        let selectors = gdt::selectors();
+        let status = unsafe {
+            user_enter(entry, stack_pointer, selectors.user_code.0 as u64, selectors.user_data.0 as u64)
+        };
+
+        gdt::set_kernel_stack(previous);
+        drop(kernel_stack);
+        status as i32
+    }
+}
+
+impl Drop for UserImage {
+    fn drop(&mut self) {
+        for &(vaddr, backing) in &self.pages {
+            memory::unmap_user_page(vaddr);
+            free_page(backing);
+        }
+    }
+}
+
+fn free_page(page: *mut u8) {
+    let layout = Layout::from_size_align(memory::PAGE_SIZE, memory::PAGE_SIZE).unwrap();
+    // SAFETY: allocated by `memory::alloc_page` with this layout
+    unsafe { dealloc(page, layout) };
+}
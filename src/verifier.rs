@@ -0,0 +1,230 @@
+//! Static bytecode verifier
+//!
+//! Runs before a program starts so that malformed bytecode is rejected up
+//! front instead of faulting halfway through its output. The verifier
+//! decodes the whole code section, checks that the entry point and every
+//! jump or call target land on an instruction boundary, and tracks the
+//! operand stack depth along every path.
+//!
+//! Stack depth is checked per function (the entry point and every `Call`
+//! target). A function's effect on the stack is the lowest depth it
+//! reaches relative to its start and the depth at which it returns; every
+//! `Ret` of a function must agree. Call sites apply the callee's effect,
+//! and effects are resolved iteratively so recursive functions work as
+//! long as some path returns. The upper bound is only checked within each
+//! function, since recursion depth is not known statically; the executor
+//! still catches overflow at run time.
+use crate::bytecode::Program;
+use crate::isa::{decode, Decoded, Instruction, Operand, Syscall, STACK_SIZE};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Why a program was rejected, and where
+#[derive(Debug, Clone, Copy)]
+pub struct VerifyError {
+    pub offset: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "offset {:#06x}: {}", self.offset, self.reason)
+    }
+}
+
+fn error(offset: usize, reason: &'static str) -> VerifyError {
+    VerifyError { offset, reason }
+}
+
+/// Stack behaviour of a function, relative to its depth on entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Effect {
+    /// Lowest depth reached (zero or negative: values taken from the caller)
+    min: i32,
+    /// Depth at `Ret`
+    net: i32,
+}
+
+/// Depths are clamped to this magnitude so recursive effects converge
+const DEPTH_LIMIT: i32 = STACK_SIZE as i32 + 1;
+
+/// Result of analysing one function with the effects known so far
+struct Analysis {
+    min: i32,
+    max: i32,
+    /// Offsets where `min` and `max` were reached, for error reporting
+    min_at: usize,
+    max_at: usize,
+    /// Depth at `Ret`, if a return was reached
+    net: Option<i32>,
+    ret_at: Option<usize>,
+}
+
+impl Analysis {
+    /// Note the lowest and highest depth an instruction at `offset` reaches
+    fn record(&mut self, offset: usize, low: i32, high: i32) {
+        let low = low.max(-DEPTH_LIMIT);
+        if low < self.min {
+            self.min = low;
+            self.min_at = offset;
+        }
+        if high > self.max {
+            self.max = high;
+            self.max_at = offset;
+        }
+    }
+}
+
+/// Verify a program, returning the first problem found
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    let code = &program.code;
+    let instructions = decode_all(code)?;
+
+    let boundary = |target: isize| target >= 0 && instructions.contains_key(&(target as usize));
+
+    if !instructions.contains_key(&program.entry) {
+        return Err(error(program.entry, "Entry point is not an instruction boundary"));
+    }
+
+    // Every jump and call must land on an instruction
+    let mut functions = vec![program.entry];
+    for (&offset, decoded) in &instructions {
+        if let Some(target) = decoded.target(offset) {
+            if !boundary(target) {
+                return Err(error(offset, "Jump target is not an instruction boundary"));
+            }
+            if decoded.instruction == Instruction::Call && !functions.contains(&(target as usize)) {
+                functions.push(target as usize);
+            }
+        }
+    }
+
+    // Resolve function effects until nothing changes
+    let mut effects: BTreeMap<usize, Effect> = BTreeMap::new();
+    loop {
+        let mut changed = false;
+        for &function in &functions {
+            let analysis = analyse(&instructions, function, &effects)?;
+            if analysis.max > STACK_SIZE as i32 {
+                return Err(error(analysis.max_at, "Stack overflow"));
+            }
+            if let Some(net) = analysis.net {
+                let effect = Effect { min: analysis.min, net };
+                if effects.insert(function, effect) != Some(effect) {
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let main = analyse(&instructions, program.entry, &effects)?;
+    if let Some(ret) = main.ret_at {
+        return Err(error(ret, "Return outside of a function"));
+    }
+    if main.min < 0 {
+        return Err(error(main.min_at, "Stack underflow"));
+    }
+    Ok(())
+}
+
+/// Decode the code section linearly into a map of offset to instruction
+fn decode_all(code: &[u8]) -> Result<BTreeMap<usize, Decoded>, VerifyError> {
+    let mut instructions = BTreeMap::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let decoded = decode(code, offset).map_err(|reason| error(offset, reason))?;
+        instructions.insert(offset, decoded);
+        offset += decoded.len;
+    }
+    Ok(instructions)
+}
+
+/// Values popped and pushed by a straight-line instruction
+fn stack_effect(decoded: &Decoded) -> (i32, i32) {
+    use Instruction::*;
+    if let Operand::Syscall { call, .. } = decoded.operand {
+        return call.stack_effect();
+    }
+    match decoded.instruction {
+        Halt | PrintChar | PrintString | Jmp | Call | Ret | Syscall => (0, 0),
+        PrintInt | Pop | Jz | Jnz | Exit => (1, 0),
+        Push => (0, 1),
+        Dup => (1, 2),
+        Swap => (2, 2),
+        Neg | Not | Load8 | Load16 | Load32 | Load64 => (1, 1),
+        Store8 | Store16 | Store32 | Store64 => (2, 0),
+        _ => (2, 1),
+    }
+}
+
+/// Walk every path of the function starting at `start`, tracking the stack
+/// depth relative to its entry. Fall-through after a call to a function
+/// whose effect is not yet known is left unexplored for a later round.
+fn analyse(
+    instructions: &BTreeMap<usize, Decoded>,
+    start: usize,
+    effects: &BTreeMap<usize, Effect>,
+) -> Result<Analysis, VerifyError> {
+    let mut depth_at: BTreeMap<usize, i32> = BTreeMap::new();
+    let mut worklist: Vec<(usize, i32)> = vec![(start, 0)];
+    let mut result = Analysis { min: 0, max: 0, min_at: start, max_at: start, net: None, ret_at: None };
+
+    while let Some((offset, depth)) = worklist.pop() {
+        let decoded = match instructions.get(&offset) {
+            Some(decoded) => decoded,
+            // Running off the end of the code halts the program
+            None => continue,
+        };
+
+        match depth_at.get(&offset) {
+            Some(&known) if known == depth => continue,
+            Some(_) => return Err(error(offset, "Inconsistent stack depth")),
+            None => {
+                depth_at.insert(offset, depth);
+            }
+        }
+
+        let next = offset + decoded.len;
+        let instruction = decoded.instruction;
+        let (pops, pushes) = stack_effect(decoded);
+        let after = (depth - pops + pushes).clamp(-DEPTH_LIMIT, DEPTH_LIMIT);
+        result.record(offset, depth - pops, after);
+
+        match instruction {
+            Instruction::Halt | Instruction::Exit => {}
+            Instruction::Syscall if decoded.operand == (Operand::Syscall { call: Syscall::Exit, arg: None }) => {}
+            Instruction::Ret => match result.net {
+                Some(net) if net != depth => {
+                    return Err(error(offset, "Inconsistent stack depth at return"));
+                }
+                _ => {
+                    result.net = Some(depth);
+                    result.ret_at = Some(offset);
+                }
+            },
+            Instruction::Jmp => {
+                worklist.push((decoded.target(offset).unwrap() as usize, after));
+            }
+            Instruction::Jz | Instruction::Jnz => {
+                worklist.push((decoded.target(offset).unwrap() as usize, after));
+                worklist.push((next, after));
+            }
+            Instruction::Call => {
+                let target = decoded.target(offset).unwrap() as usize;
+                if let Some(effect) = effects.get(&target) {
+                    let returned = (depth + effect.net).clamp(-DEPTH_LIMIT, DEPTH_LIMIT);
+                    result.record(offset, depth + effect.min, returned);
+                    worklist.push((next, returned));
+                }
+            }
+            _ => worklist.push((next, after)),
+        }
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,610 @@
+//! Virtual filesystem: a mount table of filesystem backends behind one
+//! path namespace
+//!
+//! Backends implement [`FileSystem`] in terms of node numbers within
+//! themselves; [`Vfs`] resolves paths, crossing into a mounted filesystem
+//! whenever a walk reaches its mount point. The ramfs holding the built-in
+//! programs is mounted at `/`, devices at `/dev` and process information
+//! at `/proc`.
+use crate::{devfs, procfs, ramfs};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Node number within one filesystem
+pub type Ino = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    Device,
+}
+
+/// Inode metadata
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub ino: Ino,
+    pub file_type: FileType,
+    /// Permission bits (`0o777`)
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    /// Number of directory entries referring to the inode
+    pub nlink: usize,
+    /// Content length for files and symlinks, number of entries for
+    /// directories
+    pub size: usize,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    pub modified: u64,
+}
+
+impl Stat {
+    /// `ls -l` style type and permissions, e.g. `drwxr-xr-x`
+    pub fn mode_string(&self) -> String {
+        let mut text = String::from(match self.file_type {
+            FileType::Regular => "-",
+            FileType::Directory => "d",
+            FileType::Symlink => "l",
+            FileType::Device => "c",
+        });
+        for shift in [6, 3, 0] {
+            let bits = self.mode >> shift;
+            text.push(if bits & 4 != 0 { 'r' } else { '-' });
+            text.push(if bits & 2 != 0 { 'w' } else { '-' });
+            text.push(if bits & 1 != 0 { 'x' } else { '-' });
+        }
+        text
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: Ino,
+}
+
+/// A filesystem backend. Directory operations take the inode of the
+/// directory and a single path component.
+pub trait FileSystem: Send {
+    /// Type name shown by `mount`
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Ino;
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, &'static str>;
+
+    fn stat(&self, ino: Ino) -> Result<Stat, &'static str>;
+
+    /// Read from `offset` into `buf`, returning the number of bytes read:
+    /// fewer than asked for only at the end of the file
+    fn read_at(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str>;
+
+    /// Write `data` at `offset`, extending the file if needed, and return
+    /// the number of bytes written
+    fn write_at(&mut self, _ino: Ino, _offset: usize, _data: &[u8]) -> Result<usize, &'static str> {
+        Err("Read-only filesystem")
+    }
+
+    /// Cut a file to `size` bytes or extend it with zeros
+    fn truncate(&mut self, _ino: Ino, _size: usize) -> Result<(), &'static str> {
+        Err("Read-only filesystem")
+    }
+
+    fn readdir(&self, dir: Ino) -> Result<Vec<DirEntry>, &'static str>;
+
+    /// Create an empty file or directory
+    fn create(&mut self, _dir: Ino, _name: &str, _file_type: FileType) -> Result<Ino, &'static str> {
+        Err("Read-only filesystem")
+    }
+
+    /// Remove a directory entry, freeing the inode with its last link.
+    /// Directories must be empty.
+    fn unlink(&mut self, _dir: Ino, _name: &str) -> Result<(), &'static str> {
+        Err("Read-only filesystem")
+    }
+
+    /// Add another name for a file
+    fn link(&mut self, _ino: Ino, _dir: Ino, _name: &str) -> Result<(), &'static str> {
+        Err("Read-only filesystem")
+    }
+
+    fn symlink(&mut self, _dir: Ino, _name: &str, _target: &str) -> Result<Ino, &'static str> {
+        Err("Read-only filesystem")
+    }
+
+    /// Path a symbolic link points to
+    fn read_link(&self, _ino: Ino) -> Result<String, &'static str> {
+        Err("Not a symbolic link")
+    }
+
+    fn set_mode(&mut self, _ino: Ino, _mode: u16) -> Result<(), &'static str> {
+        Err("Read-only filesystem")
+    }
+}
+
+/// Most symbolic links followed while resolving one path
+const MAX_SYMLINKS: usize = 8;
+
+/// Bytes moved per backend call by the whole-file helpers
+const CHUNK_SIZE: usize = 512;
+
+/// How to open a file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenFlags {
+    pub write: bool,
+    /// Create the file if it does not exist
+    pub create: bool,
+    /// Empty the file on opening
+    pub truncate: bool,
+    /// Every write goes to the end of the file
+    pub append: bool,
+}
+
+/// Where `seek` measures from
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+/// An open file and its offset
+#[derive(Debug, Clone)]
+pub struct FileHandle {
+    /// Identity of the mount, which outlives its index in the table
+    mount_id: usize,
+    ino: Ino,
+    pub offset: usize,
+    flags: OpenFlags,
+}
+
+struct Mount {
+    /// Absolute path of the mount point
+    path: String,
+    fs: Box<dyn FileSystem>,
+    id: usize,
+}
+
+/// A node in a particular mounted filesystem
+#[derive(Clone, Copy)]
+struct Location {
+    mount: usize,
+    ino: Ino,
+}
+
+/// The mounted filesystems and the working directory
+///
+/// Paths are `/`-separated. Absolute paths start at the root, others at
+/// the working directory, and `.` and `..` are followed as they are met.
+pub struct Vfs {
+    /// Mounts, the root filesystem first
+    mounts: Vec<Mount>,
+    /// Absolute path of the working directory
+    cwd: String,
+    next_mount_id: usize,
+}
+
+impl Vfs {
+    pub fn new(root: Box<dyn FileSystem>) -> Self {
+        Vfs {
+            mounts: alloc::vec![Mount { path: String::from("/"), fs: root, id: 0 }],
+            cwd: String::from("/"),
+            next_mount_id: 1,
+        }
+    }
+
+    /// Absolute path of the working directory
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+
+    /// Attach `fs` at `path`, which must be an existing directory with
+    /// nothing mounted on it
+    pub fn mount(&mut self, path: &str, fs: Box<dyn FileSystem>) -> Result<(), &'static str> {
+        let path = self.canonical(path)?;
+        if self.stat(&path)?.file_type != FileType::Directory {
+            return Err("Not a directory");
+        }
+        if self.mounts.iter().any(|mount| mount.path == path) {
+            return Err("Already mounted");
+        }
+        self.mounts.push(Mount { path, fs, id: self.next_mount_id });
+        self.next_mount_id += 1;
+        Ok(())
+    }
+
+    /// Detach the filesystem mounted at `path`
+    pub fn unmount(&mut self, path: &str) -> Result<(), &'static str> {
+        let path = self.canonical(path)?;
+        let index = self.mounts.iter().position(|mount| mount.path == path).ok_or("Not a mount point")?;
+        if index == 0 {
+            return Err("Cannot unmount the root filesystem");
+        }
+        let inside = path.clone() + "/";
+        if self.cwd == path || self.cwd.starts_with(&inside)
+            || self.mounts.iter().any(|mount| mount.path.starts_with(&inside))
+        {
+            return Err("Filesystem in use");
+        }
+        self.mounts.remove(index);
+        Ok(())
+    }
+
+    /// Mount points and the type of filesystem mounted on each
+    pub fn mounts(&self) -> Vec<(String, &'static str)> {
+        self.mounts.iter().map(|mount| (mount.path.clone(), mount.fs.name())).collect()
+    }
+
+    fn root(&self) -> Location {
+        Location { mount: 0, ino: self.mounts[0].fs.root() }
+    }
+
+    /// Follow `path`, returning the name and location of every directory
+    /// entry on the way from the root to what it names. An entry that is
+    /// a mount point stands for the root of the filesystem mounted there.
+    /// Symbolic links are followed, except as the last component when
+    /// `follow` is false.
+    fn walk(&self, path: &str, follow: bool) -> Result<Vec<(String, Location)>, &'static str> {
+        // Components still to visit, the next one last
+        let mut pending: Vec<String> = Vec::new();
+        push_components(&mut pending, path);
+        if !path.starts_with('/') {
+            push_components(&mut pending, &self.cwd);
+        }
+
+        let mut chain: Vec<(String, Location)> = Vec::new();
+        let mut links = 0;
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                chain.pop();
+                continue;
+            }
+            let dir = chain.last().map_or(self.root(), |&(_, location)| location);
+            let fs = &self.mounts[dir.mount].fs;
+            let ino = fs.lookup(dir.ino, &name)?;
+            if fs.stat(ino)?.file_type == FileType::Symlink && (follow || !pending.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err("Too many levels of symbolic links");
+                }
+                let target = fs.read_link(ino)?;
+                if target.starts_with('/') {
+                    chain.clear();
+                }
+                push_components(&mut pending, &target);
+                continue;
+            }
+            chain.push((name, Location { mount: dir.mount, ino }));
+
+            let here = path_of(&chain);
+            if let Some(mount) = self.mounts.iter().position(|mount| mount.path == here) {
+                let ino = self.mounts[mount].fs.root();
+                chain.last_mut().unwrap().1 = Location { mount, ino };
+            }
+        }
+        Ok(chain)
+    }
+
+    fn locate(&self, path: &str, follow: bool) -> Result<Location, &'static str> {
+        Ok(self.walk(path, follow)?.last().map_or(self.root(), |&(_, location)| location))
+    }
+
+    fn find(&self, path: &str) -> Result<Location, &'static str> {
+        self.locate(path, true)
+    }
+
+    /// Absolute path without symbolic links, `.`, `..` or repeated slashes
+    pub fn canonical(&self, path: &str) -> Result<String, &'static str> {
+        Ok(path_of(&self.walk(path, true)?))
+    }
+
+    /// Split `path` into the directory that would contain it and its last
+    /// component
+    fn parent<'a>(&self, path: &'a str) -> Result<(Location, &'a str), &'static str> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = match path.rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((dir, name)) => (dir, name),
+            None => (".", path),
+        };
+        if matches!(name, "" | "." | "..") {
+            return Err("Invalid path");
+        }
+        let dir = self.find(dir)?;
+        if self.mounts[dir.mount].fs.stat(dir.ino)?.file_type != FileType::Directory {
+            return Err("Not a directory");
+        }
+        Ok((dir, name))
+    }
+
+    /// Metadata of what `path` names, following symbolic links
+    pub fn stat(&self, path: &str) -> Result<Stat, &'static str> {
+        let location = self.find(path)?;
+        self.mounts[location.mount].fs.stat(location.ino)
+    }
+
+    /// Metadata of `path` itself, even if it is a symbolic link
+    pub fn lstat(&self, path: &str) -> Result<Stat, &'static str> {
+        let location = self.locate(path, false)?;
+        self.mounts[location.mount].fs.stat(location.ino)
+    }
+
+    /// Open a file, creating it first if `flags.create` allows
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<FileHandle, &'static str> {
+        let location = match self.find(path) {
+            Ok(location) => location,
+            Err("File not found") if flags.create => {
+                let (dir, name) = self.parent(path)?;
+                let ino = self.mounts[dir.mount].fs.create(dir.ino, name, FileType::Regular)?;
+                Location { mount: dir.mount, ino }
+            }
+            Err(e) => return Err(e),
+        };
+        let fs = &mut self.mounts[location.mount].fs;
+        if fs.stat(location.ino)?.file_type == FileType::Directory {
+            return Err("Is a directory");
+        }
+        if flags.truncate && flags.write {
+            fs.truncate(location.ino, 0)?;
+        }
+        Ok(FileHandle { mount_id: self.mounts[location.mount].id, ino: location.ino, offset: 0, flags })
+    }
+
+    /// Backend a handle refers to
+    fn backend(&mut self, handle: &FileHandle) -> Result<&mut Box<dyn FileSystem>, &'static str> {
+        self.mounts.iter_mut()
+            .find(|mount| mount.id == handle.mount_id)
+            .map(|mount| &mut mount.fs)
+            .ok_or("Stale file handle")
+    }
+
+    /// Read at the handle's offset and advance it
+    pub fn read(&mut self, handle: &mut FileHandle, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let count = self.read_at(handle, handle.offset, buf)?;
+        handle.offset += count;
+        Ok(count)
+    }
+
+    /// Write at the handle's offset, or the end of the file when
+    /// appending, and advance it past the data
+    pub fn write(&mut self, handle: &mut FileHandle, data: &[u8]) -> Result<usize, &'static str> {
+        if handle.flags.append {
+            handle.offset = self.backend(handle)?.stat(handle.ino)?.size;
+        }
+        let count = self.write_at(handle, handle.offset, data)?;
+        handle.offset += count;
+        Ok(count)
+    }
+
+    /// Read at `offset` without moving the handle
+    pub fn read_at(&mut self, handle: &FileHandle, offset: usize, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let ino = handle.ino;
+        self.backend(handle)?.read_at(ino, offset, buf)
+    }
+
+    /// Write at `offset` without moving the handle
+    pub fn write_at(&mut self, handle: &FileHandle, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        if !handle.flags.write {
+            return Err("Bad file descriptor");
+        }
+        let ino = handle.ino;
+        self.backend(handle)?.write_at(ino, offset, data)
+    }
+
+    /// Move the handle's offset, which may go past the end of the file
+    pub fn seek(&mut self, handle: &mut FileHandle, from: SeekFrom) -> Result<usize, &'static str> {
+        let (base, delta) = match from {
+            SeekFrom::Start(offset) => (0, offset as isize),
+            SeekFrom::Current(delta) => (handle.offset, delta),
+            SeekFrom::End(delta) => (self.backend(handle)?.stat(handle.ino)?.size, delta),
+        };
+        handle.offset = base.checked_add_signed(delta).ok_or("Invalid argument")?;
+        Ok(handle.offset)
+    }
+
+    /// Set the size of an open file
+    pub fn truncate(&mut self, handle: &FileHandle, size: usize) -> Result<(), &'static str> {
+        if !handle.flags.write {
+            return Err("Bad file descriptor");
+        }
+        let ino = handle.ino;
+        self.backend(handle)?.truncate(ino, size)
+    }
+
+    /// Read a file's content
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, &'static str> {
+        let mut handle = self.open(path, OpenFlags::default())?;
+        let mut content = Vec::new();
+        let mut chunk = [0u8; CHUNK_SIZE];
+        loop {
+            let count = self.read(&mut handle, &mut chunk)?;
+            if count == 0 {
+                return Ok(content);
+            }
+            content.extend_from_slice(&chunk[..count]);
+        }
+    }
+
+    /// Create a new file or overwrite existing one
+    pub fn write_file(&mut self, path: &str, content: Vec<u8>) -> Result<(), &'static str> {
+        let flags = OpenFlags { write: true, create: true, truncate: true, append: false };
+        let mut handle = self.open(path, flags)?;
+        for chunk in content.chunks(CHUNK_SIZE) {
+            self.write(&mut handle, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Delete a file or symbolic link
+    pub fn delete_file(&mut self, path: &str) -> Result<(), &'static str> {
+        if self.lstat(path)?.file_type == FileType::Directory {
+            return Err("Is a directory");
+        }
+        let (dir, name) = self.parent(path)?;
+        self.mounts[dir.mount].fs.unlink(dir.ino, name)
+    }
+
+    /// Create an empty directory
+    pub fn create_dir(&mut self, path: &str) -> Result<(), &'static str> {
+        let (dir, name) = self.parent(path)?;
+        let fs = &mut self.mounts[dir.mount].fs;
+        if fs.lookup(dir.ino, name).is_ok() {
+            return Err("File exists");
+        }
+        fs.create(dir.ino, name, FileType::Directory)?;
+        Ok(())
+    }
+
+    /// Remove an empty directory that is not a mount point and does not
+    /// contain the working directory
+    pub fn remove_dir(&mut self, path: &str) -> Result<(), &'static str> {
+        if self.lstat(path)?.file_type == FileType::Symlink {
+            return Err("Not a directory");
+        }
+        let canonical = self.canonical(path)?;
+        let stat = self.stat(&canonical)?;
+        if stat.file_type != FileType::Directory {
+            return Err("Not a directory");
+        }
+        if stat.size > 0 {
+            return Err("Directory not empty");
+        }
+        if self.mounts.iter().any(|mount| mount.path == canonical)
+            || self.cwd == canonical || self.cwd.starts_with(&(canonical.clone() + "/"))
+        {
+            return Err("Directory in use");
+        }
+        let (dir, name) = self.parent(&canonical)?;
+        self.mounts[dir.mount].fs.unlink(dir.ino, name)
+    }
+
+    /// Give the file at `existing` the additional name `path`
+    pub fn link(&mut self, existing: &str, path: &str) -> Result<(), &'static str> {
+        let target = self.find(existing)?;
+        let (dir, name) = self.parent(path)?;
+        if dir.mount != target.mount {
+            return Err("Cross-device link");
+        }
+        let fs = &mut self.mounts[dir.mount].fs;
+        if fs.stat(target.ino)?.file_type == FileType::Directory {
+            return Err("Is a directory");
+        }
+        if fs.lookup(dir.ino, name).is_ok() {
+            return Err("File exists");
+        }
+        fs.link(target.ino, dir.ino, name)
+    }
+
+    /// Create a symbolic link at `path` pointing to `target`, which need
+    /// not exist
+    pub fn symlink(&mut self, target: &str, path: &str) -> Result<(), &'static str> {
+        let (dir, name) = self.parent(path)?;
+        let fs = &mut self.mounts[dir.mount].fs;
+        if fs.lookup(dir.ino, name).is_ok() {
+            return Err("File exists");
+        }
+        fs.symlink(dir.ino, name, target)?;
+        Ok(())
+    }
+
+    pub fn read_link(&self, path: &str) -> Result<String, &'static str> {
+        let location = self.locate(path, false)?;
+        self.mounts[location.mount].fs.read_link(location.ino)
+    }
+
+    /// Change the permission bits of what `path` names
+    pub fn set_mode(&mut self, path: &str, mode: u16) -> Result<(), &'static str> {
+        let location = self.find(path)?;
+        self.mounts[location.mount].fs.set_mode(location.ino, mode & 0o777)
+    }
+
+    /// Make `path` the working directory
+    pub fn change_dir(&mut self, path: &str) -> Result<(), &'static str> {
+        let canonical = self.canonical(path)?;
+        if self.stat(&canonical)?.file_type != FileType::Directory {
+            return Err("Not a directory");
+        }
+        self.cwd = canonical;
+        Ok(())
+    }
+
+    /// Names and metadata of a directory's entries. Symbolic links are
+    /// described themselves, not their targets.
+    pub fn list_dir(&self, path: &str) -> Result<Vec<(String, Stat)>, &'static str> {
+        let canonical = self.canonical(path)?;
+        let location = self.find(&canonical)?;
+        let fs = &self.mounts[location.mount].fs;
+        if fs.stat(location.ino)?.file_type != FileType::Directory {
+            return Err("Not a directory");
+        }
+        let base = if canonical == "/" { String::new() } else { canonical };
+        let mut listing = Vec::new();
+        for entry in fs.readdir(location.ino)? {
+            // Mount points show the mounted filesystem's root
+            let stat = match self.mounts.iter().find(|mount| mount.path == base.clone() + "/" + &entry.name) {
+                Some(mount) => mount.fs.stat(mount.fs.root())?,
+                None => fs.stat(entry.ino)?,
+            };
+            listing.push((entry.name, stat));
+        }
+        Ok(listing)
+    }
+}
+
+/// Push the components of `path` onto a stack of components to visit so
+/// that the first is popped first
+fn push_components(pending: &mut Vec<String>, path: &str) {
+    for name in path.split('/').rev().filter(|name| !matches!(*name, "" | ".")) {
+        pending.push(String::from(name));
+    }
+}
+
+/// Absolute path of the last entry of a walk
+fn path_of(chain: &[(String, Location)]) -> String {
+    let mut path = String::new();
+    for (name, _) in chain {
+        path.push('/');
+        path.push_str(name);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+lazy_static! {
+    pub static ref VFS: Mutex<Vfs> = Mutex::new(Vfs::new(Box::new(ramfs::RamFs::new())));
+}
+
+/// Mount the standard filesystems and load the built-in programs
+pub fn init() {
+    let mut vfs = VFS.lock();
+    for (path, fs) in [
+        ("/dev", Box::new(devfs::DevFs) as Box<dyn FileSystem>),
+        ("/proc", Box::new(procfs::ProcFs)),
+    ] {
+        let _ = vfs.create_dir(path);
+        let _ = vfs.mount(path, fs);
+    }
+
+    // Built-in programs and their assembler sources
+    for (name, content) in [
+        ("hello.bin", &include_bytes!("../programs/hello.bin")[..]),
+        ("count.bin", include_bytes!("../programs/count.bin")),
+        // Compute-bound program for the `bench` command
+        ("bench.bin", include_bytes!("../programs/bench.bin")),
+        ("hello.asm", include_bytes!("../programs/hello.asm")),
+        ("count.asm", include_bytes!("../programs/count.asm")),
+        ("bench.asm", include_bytes!("../programs/bench.asm")),
+    ] {
+        let _ = vfs.write_file(name, content.to_vec());
+        if name.ends_with(".bin") {
+            let _ = vfs.set_mode(name, 0o755);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! virtio-blk driver over the legacy PCI interface
+//!
+//! QEMU's `virtio-blk-pci` is a transitional device: besides the modern
+//! interface it has an I/O port BAR with the legacy register layout, used
+//! here. A single virtqueue carries requests of three descriptors
+//! (header, data, status) one at a time, and completion is polled from the
+//! used ring. The rings and buffers live in one static page-aligned region
+//! whose physical addresses are handed to the device, so only the first
+//! virtio-blk device is driven, and data passes through a one-page bounce
+//! buffer because the heap is not physically contiguous.
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::{memory, pci, serial_println};
+use alloc::boxed::Box;
+use core::ptr::{addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+
+const VENDOR_ID: u16 = 0x1AF4;
+/// Transitional block device, which keeps the legacy interface
+const DEVICE_ID: u16 = 0x1001;
+
+// Legacy registers, as offsets from BAR 0
+const DEVICE_FEATURES: u16 = 0x00;
+const GUEST_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0C;
+const QUEUE_SELECT: u16 = 0x0E;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+/// Capacity in sectors, the first field of the block device configuration
+const CAPACITY: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 0x80;
+
+/// Feature bit of a read-only disk
+const FEATURE_READ_ONLY: u32 = 1 << 5;
+
+const DESCRIPTOR_NEXT: u16 = 1;
+/// The device writes the buffer rather than reading it
+const DESCRIPTOR_WRITE: u16 = 2;
+/// Available ring flag asking the device not to interrupt
+const AVAIL_NO_INTERRUPT: u16 = 1;
+
+const REQUEST_READ: u32 = 0;
+const REQUEST_WRITE: u32 = 1;
+
+const REQUEST_OK: u8 = 0;
+const REQUEST_UNSUPPORTED: u8 = 2;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Room for the rings of a queue of up to 1024 entries
+const QUEUE_PAGES: usize = 8;
+
+/// Sectors moved per request, filling the bounce buffer
+const SECTORS_PER_REQUEST: usize = PAGE_SIZE / SECTOR_SIZE;
+
+/// Used ring polls before a request is given up on
+const TIMEOUT: usize = 10_000_000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Memory shared with the device
+#[repr(C, align(4096))]
+struct Dma {
+    /// Descriptor table, available ring and used ring
+    queue: [u8; QUEUE_PAGES * PAGE_SIZE],
+    buffer: [u8; PAGE_SIZE],
+    header: RequestHeader,
+    status: u8,
+}
+
+static mut DMA: Dma = Dma {
+    queue: [0; QUEUE_PAGES * PAGE_SIZE],
+    buffer: [0; PAGE_SIZE],
+    header: RequestHeader { kind: 0, reserved: 0, sector: 0 },
+    status: 0,
+};
+
+/// Whether `DMA` has been handed to a device
+static DMA_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Physical address of `len` bytes starting at the page-aligned `addr`,
+/// if they are contiguous in physical memory
+fn physical_range(addr: usize, len: usize) -> Option<u64> {
+    let start = memory::physical_address(addr)?;
+    (PAGE_SIZE..len).step_by(PAGE_SIZE)
+        .all(|offset| memory::physical_address(addr + offset) == Some(start + offset as u64))
+        .then_some(start)
+}
+
+pub struct VirtioBlk {
+    io: u16,
+    sectors: u64,
+    read_only: bool,
+    dma: &'static mut Dma,
+    queue_size: u16,
+    /// Offsets of the rings within `dma.queue`
+    avail_offset: usize,
+    used_offset: usize,
+    /// Physical addresses of the request buffers
+    buffer_phys: u64,
+    header_phys: u64,
+    status_phys: u64,
+    /// Requests made so far, wrapping like the ring indices
+    requests: u16,
+}
+
+impl VirtioBlk {
+    fn read8(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + register).read() }
+    }
+
+    fn write8(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + register).write(value) }
+    }
+
+    fn read32(&self, register: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.io + register).read() }
+    }
+
+    /// Reset the device and set up its queue
+    fn init(io: u16, dma: &'static mut Dma) -> Result<Self, &'static str> {
+        let mut disk = VirtioBlk {
+            io,
+            sectors: 0,
+            read_only: false,
+            dma,
+            queue_size: 0,
+            avail_offset: 0,
+            used_offset: 0,
+            buffer_phys: 0,
+            header_phys: 0,
+            status_phys: 0,
+            requests: 0,
+        };
+        disk.write8(DEVICE_STATUS, 0);
+        disk.write8(DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+        disk.write8(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let result = disk.setup();
+        if result.is_err() {
+            disk.write8(DEVICE_STATUS, STATUS_FAILED);
+        }
+        result.map(|()| disk)
+    }
+
+    fn setup(&mut self) -> Result<(), &'static str> {
+        // Ask for no optional features; read-only is reported regardless
+        let features = self.read32(DEVICE_FEATURES);
+        self.read_only = features & FEATURE_READ_ONLY != 0;
+        unsafe { Port::<u32>::new(self.io + GUEST_FEATURES).write(0) };
+        self.sectors = u64::from(self.read32(CAPACITY)) | u64::from(self.read32(CAPACITY + 4)) << 32;
+
+        unsafe { Port::<u16>::new(self.io + QUEUE_SELECT).write(0) };
+        let size = unsafe { Port::<u16>::new(self.io + QUEUE_SIZE).read() };
+        if size == 0 {
+            return Err("No request queue");
+        }
+
+        // The legacy layout: descriptors, then the available ring, then
+        // the used ring on the next page boundary
+        let entries = usize::from(size);
+        self.avail_offset = 16 * entries;
+        self.used_offset = (self.avail_offset + 6 + 2 * entries).next_multiple_of(PAGE_SIZE);
+        let queue_len = self.used_offset + (6 + 8 * entries).next_multiple_of(PAGE_SIZE);
+        if queue_len > self.dma.queue.len() {
+            return Err("Request queue too large");
+        }
+        self.queue_size = size;
+
+        let queue_phys = physical_range(self.dma.queue.as_ptr() as usize, queue_len);
+        let buffer_phys = physical_range(self.dma.buffer.as_ptr() as usize, PAGE_SIZE);
+        let header_phys = memory::physical_address(&self.dma.header as *const RequestHeader as usize);
+        let status_phys = memory::physical_address(&self.dma.status as *const u8 as usize);
+        let (Some(queue_phys), Some(buffer_phys), Some(header_phys), Some(status_phys)) =
+            (queue_phys, buffer_phys, header_phys, status_phys)
+        else {
+            return Err("Queue memory is not physically contiguous");
+        };
+        self.buffer_phys = buffer_phys;
+        self.header_phys = header_phys;
+        self.status_phys = status_phys;
+
+        self.dma.queue.fill(0);
+        unsafe {
+            let avail = self.dma.queue.as_mut_ptr().add(self.avail_offset) as *mut u16;
+            write_volatile(avail, AVAIL_NO_INTERRUPT);
+            Port::<u32>::new(self.io + QUEUE_ADDRESS).write((queue_phys / PAGE_SIZE as u64) as u32);
+        }
+        self.write8(DEVICE_STATUS, self.read8(DEVICE_STATUS) | STATUS_DRIVER_OK);
+        Ok(())
+    }
+
+    /// Move `count` sectors between the bounce buffer and the disk and
+    /// wait for the device to finish
+    fn request(&mut self, lba: u64, count: usize, write: bool) -> Result<(), &'static str> {
+        self.dma.header = RequestHeader {
+            kind: if write { REQUEST_WRITE } else { REQUEST_READ },
+            reserved: 0,
+            sector: lba,
+        };
+        self.dma.status = 0xFF;
+        let data_flags = if write { DESCRIPTOR_NEXT } else { DESCRIPTOR_NEXT | DESCRIPTOR_WRITE };
+        let chain = [
+            Descriptor { addr: self.header_phys, len: 16, flags: DESCRIPTOR_NEXT, next: 1 },
+            Descriptor { addr: self.buffer_phys, len: (count * SECTOR_SIZE) as u32, flags: data_flags, next: 2 },
+            Descriptor { addr: self.status_phys, len: 1, flags: DESCRIPTOR_WRITE, next: 0 },
+        ];
+
+        let queue = self.dma.queue.as_mut_ptr();
+        unsafe {
+            // With one request in flight the chain always starts at
+            // descriptor 0
+            let table = queue as *mut Descriptor;
+            for (index, descriptor) in chain.into_iter().enumerate() {
+                write_volatile(table.add(index), descriptor);
+            }
+            let avail = queue.add(self.avail_offset) as *mut u16;
+            write_volatile(avail.add(2 + usize::from(self.requests % self.queue_size)), 0);
+            fence(Ordering::SeqCst);
+            self.requests = self.requests.wrapping_add(1);
+            write_volatile(avail.add(1), self.requests);
+            fence(Ordering::SeqCst);
+            Port::<u16>::new(self.io + QUEUE_NOTIFY).write(0);
+        }
+
+        let used_index = unsafe { queue.add(self.used_offset + 2) as *const u16 };
+        let mut done = false;
+        for _ in 0..TIMEOUT {
+            if unsafe { read_volatile(used_index) } == self.requests {
+                done = true;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        fence(Ordering::SeqCst);
+        if !done {
+            return Err("Device timed out");
+        }
+        match unsafe { read_volatile(&self.dma.status) } {
+            REQUEST_OK => Ok(()),
+            REQUEST_UNSUPPORTED => Err("Operation not supported"),
+            _ => Err("I/O error"),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        "vda"
+    }
+
+    fn model(&self) -> &str {
+        if self.read_only { "virtio-blk (read-only)" } else { "virtio-blk" }
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        for (index, chunk) in buf.chunks_mut(PAGE_SIZE).enumerate() {
+            self.request(lba + (index * SECTORS_PER_REQUEST) as u64, chunk.len() / SECTOR_SIZE, false)?;
+            chunk.copy_from_slice(&self.dma.buffer[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        if self.read_only {
+            return Err("Read-only filesystem");
+        }
+        for (index, chunk) in buf.chunks(PAGE_SIZE).enumerate() {
+            self.dma.buffer[..chunk.len()].copy_from_slice(chunk);
+            self.request(lba + (index * SECTORS_PER_REQUEST) as u64, chunk.len() / SECTOR_SIZE, true)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find and set up the first virtio-blk device
+pub fn probe() -> Option<Box<dyn BlockDevice>> {
+    let device = pci::find(VENDOR_ID, DEVICE_ID)?;
+    let io = device.io_bar(0)?;
+    if DMA_TAKEN.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+    device.enable_io_and_dma();
+    // Only one driver instance ever holds the region, guarded above
+    let dma = unsafe { &mut *addr_of_mut!(DMA) };
+    match VirtioBlk::init(io, dma) {
+        Ok(disk) => Some(Box::new(disk)),
+        Err(e) => {
+            serial_println!("virtio-blk: {}", e);
+            None
+        }
+    }
+}
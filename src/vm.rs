@@ -0,0 +1,592 @@
+//! Bytecode interpreter
+//!
+//! The interpreter reaches the outside world only through an
+//! [`Environment`], so it runs unchanged in the kernel (see `executor.rs`)
+//! and in the host toolchain's tests against a mock environment. Files are
+//! loaded whole when opened and stored back when closed. Arguments and
+//! environment variables are given to the executor when it is created.
+use crate::bytecode::Program;
+use crate::errno::{EBADF, EFAULT, EINVAL, EMFILE, ENOENT, ENOSYS};
+use crate::isa::{binary_op, decode, Decoded, Instruction, Operand, Syscall, CALL_DEPTH, STACK_SIZE};
+use crate::isa::{OPEN_APPEND, OPEN_READ, OPEN_WRITE, SYS_EXIT};
+use crate::jit::{Jit, JitStats};
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Services the interpreter needs from whatever is hosting it
+pub trait Environment {
+    /// Write program output to the console
+    fn write(&mut self, bytes: &[u8]);
+
+    /// Next byte of console input, or `None` at end of input
+    fn read(&mut self) -> Option<u8>;
+
+    /// Contents of a file, or `None` if it does not exist
+    fn load(&mut self, path: &str) -> Option<Vec<u8>>;
+
+    /// Replace a file's contents, creating it if needed. Errors are errno
+    /// values.
+    fn store(&mut self, path: &str, content: Vec<u8>) -> Result<(), isize>;
+
+    /// Milliseconds since boot
+    fn uptime_ms(&mut self) -> u64;
+
+    /// The program asked to exit
+    fn exit(&mut self, code: i32);
+
+    /// Make a kernel system call for `sys`. Pointer arguments are offsets
+    /// into the program's data memory, which is passed along so they can
+    /// be resolved. Returns the result, `-errno` on failure.
+    fn syscall(&mut self, _number: i64, _args: [i64; 3], _memory: &mut [u8]) -> i64 {
+        errno(ENOSYS)
+    }
+
+    /// Copy machine code into executable memory and return its address,
+    /// or `None` if native code cannot run here. Only used by the JIT.
+    fn install_code(&mut self, _code: &[u8]) -> Option<usize> {
+        None
+    }
+}
+
+/// Lets a caller lend an environment and inspect it after the run
+impl<E: Environment + ?Sized> Environment for &mut E {
+    fn write(&mut self, bytes: &[u8]) {
+        (**self).write(bytes)
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        (**self).read()
+    }
+
+    fn load(&mut self, path: &str) -> Option<Vec<u8>> {
+        (**self).load(path)
+    }
+
+    fn store(&mut self, path: &str, content: Vec<u8>) -> Result<(), isize> {
+        (**self).store(path, content)
+    }
+
+    fn uptime_ms(&mut self) -> u64 {
+        (**self).uptime_ms()
+    }
+
+    fn exit(&mut self, code: i32) {
+        (**self).exit(code)
+    }
+
+    fn syscall(&mut self, number: i64, args: [i64; 3], memory: &mut [u8]) -> i64 {
+        (**self).syscall(number, args, memory)
+    }
+
+    fn install_code(&mut self, code: &[u8]) -> Option<usize> {
+        (**self).install_code(code)
+    }
+}
+
+/// Maximum number of files a program may have open at once
+pub const MAX_OPEN_FILES: usize = 8;
+
+#[derive(Clone)]
+struct OpenFile {
+    path: String,
+    data: Vec<u8>,
+    pos: usize,
+    writable: bool,
+}
+
+/// How a slice of execution ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// The instruction budget ran out; call `step` again to continue
+    Yielded,
+    /// The program halted or exited
+    Halted,
+    /// The program hit a runtime error and cannot continue
+    Fault(&'static str),
+}
+
+/// Bytecode interpreter state. Everything needed to resume a program is
+/// in here, so a scheduler can keep it between slices.
+#[derive(Clone)]
+pub struct Executor<E: Environment> {
+    code: Vec<u8>,
+    rodata: Vec<u8>,
+    /// Data memory, starting with a copy of the data section
+    memory: Vec<u8>,
+    ip: usize,  // instruction pointer
+    stack: Vec<i64>,
+    call_stack: Vec<usize>,
+    files: Vec<Option<OpenFile>>,
+    argv: Vec<String>,
+    /// `NAME=value` entries
+    envp: Vec<String>,
+    /// Status passed to `exit`, once the program has exited
+    status: Option<i32>,
+    jit: Option<Jit>,
+    env: E,
+}
+
+impl<E: Environment> Executor<E> {
+    pub fn new(program: Program, env: E) -> Self {
+        let mut memory = vec![0; program.memory.max(program.data.len())];
+        memory[..program.data.len()].copy_from_slice(&program.data);
+        Executor {
+            code: program.code,
+            rodata: program.rodata,
+            memory,
+            ip: program.entry,
+            stack: Vec::with_capacity(STACK_SIZE),
+            call_stack: Vec::new(),
+            files: Vec::new(),
+            argv: Vec::new(),
+            envp: Vec::new(),
+            status: None,
+            jit: None,
+            env,
+        }
+    }
+
+    /// Compile blocks to native code as they are reached, if the
+    /// environment can run it
+    pub fn with_jit(mut self) -> Self {
+        self.jit = Some(Jit::new(self.code.len()));
+        self
+    }
+
+    /// How much of the program ran as native code, if the JIT is enabled
+    pub fn jit_stats(&self) -> Option<JitStats> {
+        self.jit.as_ref().map(|jit| jit.stats)
+    }
+
+    /// Set the arguments and environment the program can read
+    pub fn with_args(mut self, argv: Vec<String>, envp: Vec<String>) -> Self {
+        self.argv = argv;
+        self.envp = envp;
+        self
+    }
+
+    fn push(&mut self, value: i64) -> Result<(), &'static str> {
+        if self.stack.len() >= STACK_SIZE {
+            return Err("Stack overflow");
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i64, &'static str> {
+        self.stack.pop().ok_or("Stack underflow")
+    }
+
+    /// Pop the right and left operands of a binary operation
+    fn pop2(&mut self) -> Result<(i64, i64), &'static str> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        Ok((a, b))
+    }
+
+    /// Resolve the jump target of the instruction at the current ip
+    fn jump_target(&self, decoded: &Decoded) -> Result<usize, &'static str> {
+        match decoded.target(self.ip) {
+            Some(target) if target >= 0 && (target as usize) < self.code.len() => Ok(target as usize),
+            _ => Err("Jump target out of range"),
+        }
+    }
+
+    /// Offset of the next instruction
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Operand stack, bottom first
+    pub fn stack(&self) -> &[i64] {
+        &self.stack
+    }
+
+    /// Return addresses of the active calls, outermost first
+    pub fn call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Status the program passed to `exit`, or `None` if it has not
+    /// exited that way
+    pub fn exit_status(&self) -> Option<i32> {
+        self.status
+    }
+
+    /// Execute at most `budget` instructions. Open files are stored back
+    /// once the program stops.
+    pub fn step(&mut self, budget: usize) -> Step {
+        let mut executed = 0;
+        while executed < budget {
+            if let Some(jit) = &mut self.jit {
+                let env = &mut self.env;
+                let native = jit.run(&self.code, self.ip, budget - executed, &mut self.stack, &mut self.memory, |code| {
+                    env.install_code(code)
+                });
+                if let Some((next, count)) = native {
+                    self.ip = next;
+                    executed += count;
+                    continue;
+                }
+                jit.stats.interpreted += 1;
+            }
+
+            executed += 1;
+            let stopped = match self.step_one() {
+                Ok(true) => continue,
+                Ok(false) => Step::Halted,
+                Err(e) => Step::Fault(e),
+            };
+            self.close_all();
+            return stopped;
+        }
+        Step::Yielded
+    }
+
+    /// Execute one instruction, returning whether the program is still
+    /// running
+    fn step_one(&mut self) -> Result<bool, &'static str> {
+        if self.ip >= self.code.len() {
+            return Ok(false);
+        }
+
+        let decoded = decode(&self.code, self.ip)?;
+        let instruction = decoded.instruction;
+        let mut next = self.ip + decoded.len;
+
+        match (instruction, decoded.operand) {
+            (Instruction::Halt, _) => return Ok(false),
+            (Instruction::Exit, _) => {
+                let status = self.pop()? as i32;
+                self.exit(status);
+                return Ok(false);
+            }
+
+            (Instruction::PrintChar, Operand::Byte(ch)) => self.env.write(&[ch]),
+            (Instruction::PrintString, Operand::Str { start, len }) => {
+                self.env.write(&self.code[start..start + len]);
+            }
+            (Instruction::PrintInt, _) => {
+                let value = self.pop()?;
+                self.env.write(value.to_string().as_bytes());
+            }
+
+            (Instruction::Push, Operand::Imm(value)) => self.push(value as i64)?,
+            (Instruction::Pop, _) => {
+                self.pop()?;
+            }
+            (Instruction::Dup, _) => {
+                let value = *self.stack.last().ok_or("Stack underflow")?;
+                self.push(value)?;
+            }
+            (Instruction::Swap, _) => {
+                let (a, b) = self.pop2()?;
+                self.push(b)?;
+                self.push(a)?;
+            }
+
+            (Instruction::Neg, _) => {
+                let value = self.pop()?;
+                self.push(value.wrapping_neg())?;
+            }
+            (Instruction::Not, _) => {
+                let value = self.pop()?;
+                self.push(!value)?;
+            }
+
+            (Instruction::Jmp, _) => next = self.jump_target(&decoded)?,
+            (Instruction::Jz, _) | (Instruction::Jnz, _) => {
+                let target = self.jump_target(&decoded)?;
+                let value = self.pop()?;
+                if (value == 0) == (instruction == Instruction::Jz) {
+                    next = target;
+                }
+            }
+            (Instruction::Call, _) => {
+                let target = self.jump_target(&decoded)?;
+                if self.call_stack.len() >= CALL_DEPTH {
+                    return Err("Call stack overflow");
+                }
+                self.call_stack.push(next);
+                next = target;
+            }
+            (Instruction::Ret, _) => {
+                next = self.call_stack.pop().ok_or("Return with empty call stack")?;
+            }
+
+            (Instruction::Load8 | Instruction::Load16 | Instruction::Load32 | Instruction::Load64, _) => {
+                let address = self.pop()?;
+                let bytes = self.access(address, instruction)?;
+                let mut value = [0u8; 8];
+                value[..bytes.len()].copy_from_slice(bytes);
+                self.push(i64::from_le_bytes(value))?;
+            }
+            (Instruction::Store8 | Instruction::Store16 | Instruction::Store32 | Instruction::Store64, _) => {
+                let (address, value) = self.pop2()?;
+                let bytes = self.access(address, instruction)?;
+                let width = bytes.len();
+                bytes.copy_from_slice(&value.to_le_bytes()[..width]);
+            }
+
+            (Instruction::Syscall, Operand::Syscall { call, arg }) => {
+                if call == Syscall::Exit {
+                    self.exit(0);
+                    return Ok(false);
+                }
+                if !self.syscall(call, arg)? {
+                    return Ok(false);
+                }
+            }
+
+            _ => {
+                let (a, b) = self.pop2()?;
+                let result = binary_op(instruction, a, b)?;
+                self.push(result)?;
+            }
+        }
+
+        self.ip = next;
+        Ok(true)
+    }
+
+    fn exit(&mut self, status: i32) {
+        self.status = Some(status);
+        self.env.exit(status);
+    }
+
+    /// The bytes of data memory a load or store at `address` touches
+    fn access(&mut self, address: i64, instruction: Instruction) -> Result<&mut [u8], &'static str> {
+        let width = instruction.access_width().unwrap_or(0);
+        let range = span(self.memory.len(), address, width as i64).ok_or("Memory access out of bounds")?;
+        Ok(&mut self.memory[range])
+    }
+
+    /// Carry out a `Syscall`, returning whether the program is still
+    /// running
+    fn syscall(&mut self, call: Syscall, arg: Option<u8>) -> Result<bool, &'static str> {
+        let result = match call {
+            Syscall::Exit => 0,
+            Syscall::PutChar => {
+                self.env.write(&[arg.unwrap_or(0)]);
+                return Ok(true);
+            }
+            Syscall::Newline => {
+                self.env.write(b"\n");
+                return Ok(true);
+            }
+            Syscall::GetChar => self.env.read().map_or(-1, |byte| byte as i64),
+            Syscall::Clock => self.env.uptime_ms() as i64,
+            Syscall::Open => {
+                let mode = self.pop()?;
+                let (path, len) = self.pop2()?;
+                self.open(path, len, mode).unwrap_or_else(errno)
+            }
+            Syscall::ReadByte => {
+                let fd = self.pop()?;
+                match file(&mut self.files, fd) {
+                    Ok(file) => match file.data.get(file.pos) {
+                        Some(&byte) => {
+                            file.pos += 1;
+                            byte as i64
+                        }
+                        None => -1,
+                    },
+                    Err(e) => errno(e),
+                }
+            }
+            Syscall::WriteByte => {
+                let (fd, byte) = self.pop2()?;
+                match file(&mut self.files, fd) {
+                    Ok(file) if file.writable => {
+                        file.data.push(byte as u8);
+                        0
+                    }
+                    Ok(_) => errno(EBADF),
+                    Err(e) => errno(e),
+                }
+            }
+            Syscall::Close => {
+                let fd = self.pop()?;
+                match file(&mut self.files, fd) {
+                    Ok(_) => self.close(fd as usize).map_or_else(errno, |_| 0),
+                    Err(e) => errno(e),
+                }
+            }
+            Syscall::Write => {
+                let (buf, len) = self.pop2()?;
+                match span(self.memory.len(), buf, len) {
+                    Some(range) => {
+                        self.env.write(&self.memory[range]);
+                        len
+                    }
+                    None => errno(EFAULT),
+                }
+            }
+            Syscall::Read => {
+                let (buf, len) = self.pop2()?;
+                match span(self.memory.len(), buf, len) {
+                    Some(range) => {
+                        let mut count = 0;
+                        for slot in &mut self.memory[range] {
+                            let Some(byte) = self.env.read() else { break };
+                            *slot = byte;
+                            count += 1;
+                            if byte == b'\n' {
+                                break;
+                            }
+                        }
+                        count
+                    }
+                    None => errno(EFAULT),
+                }
+            }
+            Syscall::FileRead | Syscall::FileWrite => {
+                let (buf, len) = self.pop2()?;
+                let fd = self.pop()?;
+                let range = span(self.memory.len(), buf, len);
+                match (file(&mut self.files, fd), range) {
+                    (Err(e), _) => errno(e),
+                    (_, None) => errno(EFAULT),
+                    (Ok(file), Some(range)) if call == Syscall::FileRead => {
+                        let available = file.data.get(file.pos..).unwrap_or(&[]);
+                        let count = available.len().min(range.len());
+                        self.memory[range.start..range.start + count].copy_from_slice(&available[..count]);
+                        file.pos += count;
+                        count as i64
+                    }
+                    (Ok(file), Some(range)) if file.writable => {
+                        file.data.extend_from_slice(&self.memory[range]);
+                        len
+                    }
+                    (Ok(_), Some(_)) => errno(EBADF),
+                }
+            }
+            Syscall::ArgCount => self.argv.len() as i64,
+            Syscall::Arg | Syscall::EnvEntry => {
+                let (buf, len) = self.pop2()?;
+                let index = self.pop()?;
+                let list = if call == Syscall::Arg { &self.argv } else { &self.envp };
+                match usize::try_from(index).ok().and_then(|i| list.get(i)) {
+                    Some(entry) => copy_out(&mut self.memory, entry.as_bytes(), buf, len),
+                    None => errno(EINVAL),
+                }
+            }
+            Syscall::GetEnv => {
+                let (buf, len) = self.pop2()?;
+                let (name, name_len) = self.pop2()?;
+                match getenv(&self.envp, &self.rodata, name, name_len) {
+                    Ok(value) => copy_out(&mut self.memory, value.as_bytes(), buf, len),
+                    Err(e) => errno(e),
+                }
+            }
+            Syscall::Sys => {
+                let (arg2, arg3) = self.pop2()?;
+                let (number, arg1) = self.pop2()?;
+                if number == SYS_EXIT {
+                    self.exit(arg1 as i32);
+                    return Ok(false);
+                }
+                self.env.syscall(number, [arg1, arg2, arg3], &mut self.memory)
+            }
+        };
+        self.push(result)?;
+        Ok(true)
+    }
+
+    /// Open the file named by a rodata string, returning its descriptor
+    fn open(&mut self, path: i64, len: i64, mode: i64) -> Result<i64, isize> {
+        let range = span(self.rodata.len(), path, len).ok_or(EFAULT)?;
+        let path = String::from(core::str::from_utf8(&self.rodata[range]).map_err(|_| EINVAL)?);
+
+        let data = match mode {
+            OPEN_READ | OPEN_APPEND => self.env.load(&path),
+            OPEN_WRITE => Some(Vec::new()),
+            _ => return Err(EINVAL),
+        };
+        let data = match (data, mode) {
+            (Some(data), _) => data,
+            (None, OPEN_APPEND) => Vec::new(),
+            (None, _) => return Err(ENOENT),
+        };
+
+        let slot = match self.files.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if self.files.len() < MAX_OPEN_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(EMFILE),
+        };
+        let pos = if mode == OPEN_APPEND { data.len() } else { 0 };
+        self.files[slot] = Some(OpenFile { path, data, pos, writable: mode != OPEN_READ });
+        Ok(slot as i64)
+    }
+
+    /// Close a descriptor, storing the file back if it was written
+    fn close(&mut self, fd: usize) -> Result<(), isize> {
+        match self.files.get_mut(fd).and_then(Option::take) {
+            Some(file) if file.writable => self.env.store(&file.path, file.data),
+            Some(_) => Ok(()),
+            None => Err(EBADF),
+        }
+    }
+
+    fn close_all(&mut self) {
+        for fd in 0..self.files.len() {
+            let _ = self.close(fd);
+        }
+    }
+}
+
+fn file(files: &mut [Option<OpenFile>], fd: i64) -> Result<&mut OpenFile, isize> {
+    usize::try_from(fd).ok()
+        .and_then(|fd| files.get_mut(fd))
+        .and_then(Option::as_mut)
+        .ok_or(EBADF)
+}
+
+/// `len` bytes from `start`, if that lies within a region of `size` bytes
+fn span(size: usize, start: i64, len: i64) -> Option<Range<usize>> {
+    let start = usize::try_from(start).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    (end <= size).then_some(start..end)
+}
+
+/// Value of the variable whose name is a rodata string
+fn getenv<'a>(envp: &'a [String], rodata: &[u8], name: i64, len: i64) -> Result<&'a str, isize> {
+    let range = span(rodata.len(), name, len).ok_or(EFAULT)?;
+    let name = &rodata[range];
+    envp.iter()
+        .find_map(|entry| {
+            let value = entry.as_bytes().strip_prefix(name)?.strip_prefix(b"=")?;
+            Some(&entry[entry.len() - value.len()..])
+        })
+        .ok_or(ENOENT)
+}
+
+/// Copy as much of `bytes` as fits into the `len`-byte buffer at `buf`,
+/// returning the full length
+fn copy_out(memory: &mut [u8], bytes: &[u8], buf: i64, len: i64) -> i64 {
+    match span(memory.len(), buf, len) {
+        Some(range) => {
+            let count = bytes.len().min(range.len());
+            memory[range.start..range.start + count].copy_from_slice(&bytes[..count]);
+            bytes.len() as i64
+        }
+        None => errno(EFAULT),
+    }
+}
+
+fn errno(e: isize) -> i64 {
+    -(e as i64)
+}
@@ -0,0 +1,212 @@
+//! Environments for running programs on the host
+use crate::bytecode::Program;
+use crate::errno::ENOSPC;
+use crate::vm::{Environment, Executor, Step};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+use std::{fs, str};
+
+/// How a run ended, if not by halting
+#[derive(Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The program hit a runtime error
+    Error(&'static str),
+    /// More than the allowed number of instructions ran
+    StepLimit,
+}
+
+/// Run a verified program to completion, executing at most `limit`
+/// instructions. Returns the program's exit status, 0 if it halted.
+pub fn run(program: Program, env: impl Environment, limit: usize) -> Result<i32, Fault> {
+    run_executor(Executor::new(program, env), limit)
+}
+
+/// Like [`run`], for an executor that has already been set up, e.g. with
+/// arguments
+pub fn run_executor<E: Environment>(mut vm: Executor<E>, limit: usize) -> Result<i32, Fault> {
+    match vm.step(limit) {
+        Step::Halted => Ok(vm.exit_status().unwrap_or(0)),
+        Step::Fault(e) => Err(Fault::Error(e)),
+        Step::Yielded => Err(Fault::StepLimit),
+    }
+}
+
+/// Size of the executable mapping each environment places JIT output in
+const CODE_AREA_SIZE: usize = 1024 * 1024;
+
+/// Executable memory for compiled blocks, mapped on first use
+#[derive(Default)]
+pub struct CodeArea {
+    base: Option<*mut u8>,
+    used: usize,
+}
+
+impl CodeArea {
+    /// Copy `code` into the area, returning its address, or `None` if the
+    /// area is full or cannot be mapped
+    pub fn install(&mut self, code: &[u8]) -> Option<usize> {
+        if !cfg!(target_arch = "x86_64") {
+            return None;
+        }
+        let base = match self.base {
+            Some(base) => base,
+            None => {
+                // SAFETY: a fresh anonymous mapping, not aliased by anything
+                let base = unsafe {
+                    libc::mmap(
+                        std::ptr::null_mut(),
+                        CODE_AREA_SIZE,
+                        libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                        -1,
+                        0,
+                    )
+                };
+                if base == libc::MAP_FAILED {
+                    return None;
+                }
+                *self.base.insert(base as *mut u8)
+            }
+        };
+
+        let start = self.used.next_multiple_of(16);
+        if start + code.len() > CODE_AREA_SIZE {
+            return None;
+        }
+        // SAFETY: the range lies within the mapping and past all code
+        // installed so far
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), base.add(start), code.len()) };
+        self.used = start + code.len();
+        Some(base as usize + start)
+    }
+}
+
+impl Drop for CodeArea {
+    fn drop(&mut self) {
+        if let Some(base) = self.base {
+            // SAFETY: the mapping was created in `install`, and the
+            // executors whose blocks live in it are gone along with the
+            // environment that owns it
+            unsafe { libc::munmap(base as *mut libc::c_void, CODE_AREA_SIZE) };
+        }
+    }
+}
+
+/// The real console, clock and working directory
+pub struct HostEnv {
+    start: Instant,
+    code: CodeArea,
+}
+
+impl HostEnv {
+    pub fn new() -> Self {
+        HostEnv { start: Instant::now(), code: CodeArea::default() }
+    }
+}
+
+impl Default for HostEnv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Environment for HostEnv {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(bytes);
+        let _ = stdout.flush();
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let mut byte = [0u8];
+        match io::stdin().read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    fn load(&mut self, path: &str) -> Option<Vec<u8>> {
+        fs::read(path).ok()
+    }
+
+    fn store(&mut self, path: &str, content: Vec<u8>) -> Result<(), isize> {
+        fs::write(path, content).map_err(|_| ENOSPC)
+    }
+
+    fn uptime_ms(&mut self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn exit(&mut self, _code: i32) {}
+
+    fn install_code(&mut self, code: &[u8]) -> Option<usize> {
+        self.code.install(code)
+    }
+}
+
+/// Scripted environment for tests: input comes from a buffer, output and
+/// files are kept in memory, the clock advances one millisecond per
+/// reading, and kernel system calls are recorded and return their number
+#[derive(Default)]
+pub struct MockEnv {
+    pub input: Vec<u8>,
+    pub output: Vec<u8>,
+    pub files: BTreeMap<String, Vec<u8>>,
+    pub clock: u64,
+    pub exited: Option<i32>,
+    pub syscalls: Vec<(i64, [i64; 3])>,
+    pub code: CodeArea,
+}
+
+impl MockEnv {
+    pub fn with_input(input: &[u8]) -> Self {
+        MockEnv { input: input.to_vec(), ..Default::default() }
+    }
+
+    /// Console output as text
+    pub fn output(&self) -> &str {
+        str::from_utf8(&self.output).unwrap_or("<invalid UTF-8>")
+    }
+}
+
+impl Environment for MockEnv {
+    fn write(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        if self.input.is_empty() {
+            None
+        } else {
+            Some(self.input.remove(0))
+        }
+    }
+
+    fn load(&mut self, path: &str) -> Option<Vec<u8>> {
+        self.files.get(path).cloned()
+    }
+
+    fn store(&mut self, path: &str, content: Vec<u8>) -> Result<(), isize> {
+        self.files.insert(String::from(path), content);
+        Ok(())
+    }
+
+    fn uptime_ms(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn exit(&mut self, code: i32) {
+        self.exited = Some(code);
+    }
+
+    fn syscall(&mut self, number: i64, args: [i64; 3], _memory: &mut [u8]) -> i64 {
+        self.syscalls.push((number, args));
+        number
+    }
+
+    fn install_code(&mut self, code: &[u8]) -> Option<usize> {
+        self.code.install(code)
+    }
+}
@@ -0,0 +1,58 @@
+//! Disk images holding the kernel's native filesystem
+use crate::sfs::{BlockIo, Kind, Sfs, BLOCK_SIZE, ROOT};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An image file addressed in filesystem blocks
+pub struct ImageFile {
+    file: File,
+    blocks: u32,
+}
+
+impl ImageFile {
+    /// Open an existing image for reading and writing; a trailing partial
+    /// block is left out
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let blocks = (file.metadata()?.len() / BLOCK_SIZE as u64).min(u64::from(u32::MAX)) as u32;
+        Ok(ImageFile { file, blocks })
+    }
+}
+
+impl BlockIo for ImageFile {
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), &'static str> {
+        self.file.read_exact_at(buf, u64::from(block) * BLOCK_SIZE as u64).map_err(|_| "I/O error")
+    }
+
+    fn write_block(&mut self, block: u32, buf: &[u8; BLOCK_SIZE]) -> Result<(), &'static str> {
+        self.file.write_all_at(buf, u64::from(block) * BLOCK_SIZE as u64).map_err(|_| "I/O error")
+    }
+}
+
+/// Seconds since the Unix epoch, for timestamps
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+/// Format `device` and copy each `(name, content)` into the root
+/// directory. As with the kernel's built-in programs, `.bin` files are
+/// made executable.
+pub fn make_filesystem<D: BlockIo>(device: D, files: &[(&str, &[u8])]) -> Result<Sfs<D>, &'static str> {
+    let mut fs = Sfs::format(device, now)?;
+    for &(name, content) in files {
+        let ino = fs.create(ROOT, name, Kind::File)?;
+        if fs.write_at(ino, 0, content)? < content.len() {
+            return Err("Filesystem full");
+        }
+        if name.ends_with(".bin") {
+            fs.set_mode(ino, 0o755)?;
+        }
+    }
+    Ok(fs)
+}
@@ -0,0 +1,37 @@
+//! Host-side toolchain for the kernel's bytecode executables and disks
+//!
+//! The instruction set, container format, assembler, verifier,
+//! interpreter and native filesystem are the kernel's own source files,
+//! compiled here against `std` so programs and disk images can be built
+//! and tested on the development machine.
+extern crate alloc;
+
+#[path = "../../../src/isa.rs"]
+pub mod isa;
+
+#[path = "../../../src/bytecode.rs"]
+pub mod bytecode;
+
+#[path = "../../../src/asm.rs"]
+pub mod asm;
+
+#[path = "../../../src/verifier.rs"]
+pub mod verifier;
+
+#[path = "../../../src/errno.rs"]
+pub mod errno;
+
+#[path = "../../../src/vm.rs"]
+pub mod vm;
+
+#[path = "../../../src/jit.rs"]
+pub mod jit;
+
+#[path = "../../../src/debugger.rs"]
+pub mod debugger;
+
+#[path = "../../../src/sfs.rs"]
+pub mod sfs;
+
+pub mod host;
+pub mod image;
@@ -0,0 +1,209 @@
+//! `bbx`: assemble, disassemble and run bytecode programs on the host
+//!
+//! ```text
+//! bbx asm <source.asm> <output.bin>
+//! bbx disasm <program.bin>
+//! bbx run [--jit] <program.bin> [args...]
+//! bbx debug <program.bin> [args...]
+//! bbx bench <program.bin> [args...]
+//! bbx mkfs <disk.img> [files...]
+//! ```
+//!
+//! Programs get their arguments, with the path as `argv[0]`, and the
+//! host's environment variables. `run` exits with the program's status.
+//! `bench` runs a program interpreted and then with the JIT, checks both
+//! print the same and compares the times. `mkfs` writes an empty native
+//! filesystem over an existing image, sized to fill it, and copies the
+//! given files into its root directory.
+use bbx::host::{self, Fault, HostEnv, MockEnv};
+use bbx::vm::{Environment, Executor, Step};
+use bbx::debugger::Debugger;
+use bbx::jit::JitStats;
+use bbx::image::{self, ImageFile};
+use bbx::{asm, bytecode, verifier};
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+
+/// Instructions `run` executes before assuming the program never halts
+const STEP_LIMIT: usize = 100_000_000;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["asm", source, output] => assemble(source, output).map(|()| 0),
+        ["disasm", path] => disassemble(path).map(|()| 0),
+        ["run", "--jit", path, ..] => run(path, &args[2..], true),
+        ["run", path, ..] => run(path, &args[1..], false),
+        ["debug", path, ..] => debug(path, &args[1..]).map(|()| 0),
+        ["bench", path, ..] => bench(path, &args[1..]).map(|()| 0),
+        ["mkfs", path, files @ ..] => mkfs(path, files).map(|()| 0),
+        _ => {
+            eprintln!(
+                "Usage: bbx asm <source> <output> | disasm <file> | run [--jit] <file> [args] | debug <file> [args] | bench <file> [args] | mkfs <image> [files]"
+            );
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(status) => ExitCode::from(status as u8),
+        Err(message) => {
+            eprintln!("bbx: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Read and validate a container and run it through the verifier
+fn verified(path: &str) -> Result<bytecode::Program, String> {
+    let program = bytecode::parse(&read(path)?).map_err(|e| format!("{}: {}", path, e))?;
+    verifier::verify(&program).map_err(|e| format!("{}: verification failed at {}", path, e))?;
+    Ok(program)
+}
+
+/// Set up an interpreter for `program` with `argv` and the host
+/// environment
+fn with_args<E: Environment>(program: bytecode::Program, env: E, argv: &[&str]) -> Executor<E> {
+    let argv = argv.iter().map(|arg| arg.to_string()).collect();
+    let envp = env::vars().map(|(name, value)| format!("{}={}", name, value)).collect();
+    Executor::new(program, env).with_args(argv, envp)
+}
+
+fn load(path: &str, argv: &[&str]) -> Result<Executor<HostEnv>, String> {
+    Ok(with_args(verified(path)?, HostEnv::new(), argv))
+}
+
+fn assemble(source: &str, output: &str) -> Result<(), String> {
+    let text = String::from_utf8(read(source)?).map_err(|_| format!("{}: not valid UTF-8", source))?;
+    let program = asm::assemble(&text).map_err(|e| format!("{}: {}", source, e))?;
+    verifier::verify(&program).map_err(|e| format!("{}: verification failed at {}", source, e))?;
+    let image = bytecode::build(&program);
+    fs::write(output, &image).map_err(|e| format!("{}: {}", output, e))?;
+    println!("Assembled {} ({} bytes)", output, image.len());
+    Ok(())
+}
+
+fn disassemble(path: &str) -> Result<(), String> {
+    let program = bytecode::parse(&read(path)?).map_err(|e| format!("{}: {}", path, e))?;
+    for line in asm::disassemble(&program) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn run(path: &str, argv: &[&str], jit: bool) -> Result<i32, String> {
+    let mut vm = load(path, argv)?;
+    if jit {
+        vm = vm.with_jit();
+    }
+    match host::run_executor(vm, STEP_LIMIT) {
+        Ok(status) => Ok(status),
+        Err(Fault::Error(e)) => Err(format!("{}: execution error: {}", path, e)),
+        Err(Fault::StepLimit) => Err(format!("{}: no halt after {} instructions", path, STEP_LIMIT)),
+    }
+}
+
+/// Debugger commands are read from stdin, one per line, until `quit` or
+/// end of input
+fn debug(path: &str, argv: &[&str]) -> Result<(), String> {
+    let mut debugger = Debugger::new(load(path, argv)?);
+    println!("{}", debugger.current());
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("(debug) ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let line = match lines.next() {
+            Some(line) => line.map_err(|e| e.to_string())?,
+            None => return Ok(()),
+        };
+        if matches!(line.trim(), "quit" | "q") {
+            return Ok(());
+        }
+        for output in debugger.command(&line, &mut || false) {
+            println!("{}", output);
+        }
+    }
+}
+
+/// Run with the interpreter and then with the JIT, capturing output so
+/// the two runs can be compared
+fn bench(path: &str, argv: &[&str]) -> Result<(), String> {
+    let program = verified(path)?;
+    let interpreted = timed(path, &program, argv, false)?;
+    let compiled = timed(path, &program, argv, true)?;
+    if (&interpreted.output, interpreted.status) != (&compiled.output, compiled.status) {
+        return Err(format!("{}: the JIT's output or status differs from the interpreter's", path));
+    }
+
+    io::stdout().write_all(&interpreted.output).map_err(|e| e.to_string())?;
+    println!("interpreter: {:>10.3} ms, status {}", millis(interpreted.time), interpreted.status);
+    println!("jit:         {:>10.3} ms, status {}", millis(compiled.time), compiled.status);
+    if let Some(stats) = compiled.stats {
+        println!(
+            "             {} blocks, {} bytes; {} instructions native, {} interpreted",
+            stats.blocks, stats.code_bytes, stats.native, stats.interpreted
+        );
+    }
+    println!("speedup:     {:>10.1}x", interpreted.time.as_secs_f64() / compiled.time.as_secs_f64());
+    Ok(())
+}
+
+/// One timed `bench` run
+struct Timing {
+    output: Vec<u8>,
+    status: i32,
+    time: Duration,
+    stats: Option<JitStats>,
+}
+
+fn timed(path: &str, program: &bytecode::Program, argv: &[&str], jit: bool) -> Result<Timing, String> {
+    let mut env = MockEnv::default();
+    let mut vm = with_args(program.clone(), &mut env, argv);
+    if jit {
+        vm = vm.with_jit();
+    }
+    let start = Instant::now();
+    let result = vm.step(STEP_LIMIT);
+    let time = start.elapsed();
+    let status = match result {
+        Step::Halted => vm.exit_status().unwrap_or(0),
+        Step::Fault(e) => return Err(format!("{}: execution error: {}", path, e)),
+        Step::Yielded => return Err(format!("{}: no halt after {} instructions", path, STEP_LIMIT)),
+    };
+    let stats = vm.jit_stats();
+    drop(vm);
+    Ok(Timing { output: env.output, status, time, stats })
+}
+
+fn mkfs(path: &str, files: &[&str]) -> Result<(), String> {
+    let contents = files.iter().map(|file| read(file)).collect::<Result<Vec<_>, _>>()?;
+    let mut named = Vec::new();
+    for (file, content) in files.iter().zip(&contents) {
+        let name = file.rsplit('/').next().unwrap_or(file);
+        named.push((name, content.as_slice()));
+    }
+
+    let device = ImageFile::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let fs = image::make_filesystem(device, &named).map_err(|e| format!("{}: {}", path, e))?;
+    let (blocks, free_blocks, inodes, free_inodes) = fs.usage().map_err(|e| format!("{}: {}", path, e))?;
+    println!(
+        "Formatted {}: {} blocks ({} free), {} inodes ({} free)",
+        path, blocks, free_blocks, inodes, free_inodes
+    );
+    for (name, content) in named {
+        println!("  {} ({} bytes)", name, content.len());
+    }
+    Ok(())
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
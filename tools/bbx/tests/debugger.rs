@@ -0,0 +1,114 @@
+use bbx::debugger::Debugger;
+use bbx::host::MockEnv;
+use bbx::vm::{Executor, Step};
+use bbx::{asm, verifier};
+
+fn debugger<'e>(source: &str, env: &'e mut MockEnv) -> Debugger<&'e mut MockEnv> {
+    let program = asm::assemble(source).unwrap();
+    verifier::verify(&program).unwrap();
+    Debugger::new(Executor::new(program, env))
+}
+
+fn run(debugger: &mut Debugger<&mut MockEnv>, line: &str) -> Vec<String> {
+    debugger.command(line, &mut || false)
+}
+
+const LOOP: &str = concat!(
+    "    push 3\n",
+    "top:\n",
+    "    dup\n",
+    "    print_int\n",
+    "    push 1\n",
+    "    sub\n",
+    "    dup\n",
+    "    jnz top\n",
+    "    halt\n",
+);
+
+#[test]
+fn step_shows_next_instruction() {
+    let mut env = MockEnv::default();
+    let mut debugger = debugger(LOOP, &mut env);
+    assert_eq!(debugger.current(), "0000  push 3");
+    assert_eq!(run(&mut debugger, "step"), ["0005  dup"]);
+    assert_eq!(run(&mut debugger, "step 2"), ["0007  push 1"]);
+    assert_eq!(run(&mut debugger, "regs"), ["ip 0x0007  sp 1  top 3  calls 0  memory 0 bytes"]);
+    assert_eq!(run(&mut debugger, "stack"), ["  [0] 3 (0x3)"]);
+}
+
+#[test]
+fn continue_stops_at_breakpoints() {
+    let mut env = MockEnv::default();
+    let mut debugger = debugger(LOOP, &mut env);
+    assert_eq!(run(&mut debugger, "break 8"), ["Error: Not the start of an instruction"]);
+    assert_eq!(run(&mut debugger, "break 0x06"), ["Breakpoint at 0x0006"]);
+
+    assert_eq!(run(&mut debugger, "continue"), ["0006* print_int"]);
+    assert_eq!(run(&mut debugger, "continue"), ["0006* print_int"]);
+    assert_eq!(run(&mut debugger, "stack"), ["  [1] 2 (0x2)", "  [0] 2 (0x2)"]);
+
+    assert_eq!(run(&mut debugger, "delete 6"), ["Deleted breakpoint at 0x0006"]);
+    assert_eq!(run(&mut debugger, "continue"), ["Program finished"]);
+    assert_eq!(debugger.finished(), Some(Step::Halted));
+    assert_eq!(run(&mut debugger, "step"), ["Error: The program has finished"]);
+    drop(debugger);
+    assert_eq!(env.output(), "321");
+}
+
+#[test]
+fn continue_can_be_interrupted() {
+    let mut env = MockEnv::default();
+    let mut debugger = debugger("top:\n    jmp top\n", &mut env);
+    let mut polls = 0;
+    let output = debugger.command("continue", &mut || {
+        polls += 1;
+        polls == 3
+    });
+    assert_eq!(output, ["0000  jmp 0x0000"]);
+    assert_eq!(debugger.finished(), None);
+}
+
+#[test]
+fn memory_dump_and_call_stack() {
+    let mut env = MockEnv::default();
+    let mut debugger = debugger(
+        concat!(
+            ".entry main\n",
+            "f:\n",
+            "    ret\n",
+            "main:\n",
+            "    call f\n",
+            "    halt\n",
+            ".data\n",
+            "    .ascii \"Hi!\"\n",
+            "    .byte 0, 0xff\n",
+            ".memory 32\n",
+        ),
+        &mut env,
+    );
+    assert_eq!(run(&mut debugger, "step"), ["0000  ret"]);
+    assert_eq!(run(&mut debugger, "stack"), ["  (empty)", "  returns to 0x0004"]);
+    assert_eq!(
+        run(&mut debugger, "mem 0 5"),
+        ["0000  48 69 21 00 ff                                   Hi!.."]
+    );
+    assert_eq!(run(&mut debugger, "mem 30 4"), ["Error: Memory range out of bounds"]);
+    assert_eq!(run(&mut debugger, "mem 16 16").len(), 1);
+}
+
+#[test]
+fn faults_are_reported() {
+    let mut env = MockEnv::default();
+    let mut debugger = debugger("    push 1\n    push 0\n    div\n    halt\n", &mut env);
+    assert_eq!(run(&mut debugger, "continue"), ["Execution error: Division by zero"]);
+    assert!(matches!(debugger.finished(), Some(Step::Fault(_))));
+}
+
+#[test]
+fn exit_status_is_reported() {
+    let mut env = MockEnv::default();
+    let mut debugger = debugger("    push 7\n    exit\n", &mut env);
+    assert_eq!(run(&mut debugger, "step"), ["0005  exit"]);
+    assert_eq!(run(&mut debugger, "step"), ["Program exited with status 7"]);
+    assert_eq!(debugger.exit_status(), Some(7));
+}
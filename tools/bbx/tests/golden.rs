@@ -0,0 +1,63 @@
+//! Golden-output tests: each `tests/golden/<name>.asm` is assembled,
+//! verified and run with `<name>.in` (if present) as console input and the
+//! words of `<name>.args` (if present) as arguments, and its console
+//! output followed by how it stopped must match `<name>.out`. The
+//! environment is always `GOLDEN_ENV`.
+//! Run with `BLESS=1` to rewrite the expected output.
+use bbx::host::{self, Fault, MockEnv};
+use bbx::vm::Executor;
+use bbx::{asm, verifier};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const GOLDEN_ENV: [&str; 2] = ["HOME=/home/golden", "TERM=dumb"];
+
+fn transcript(source: &Path) -> String {
+    let text = fs::read_to_string(source).unwrap();
+    let program = asm::assemble(&text).unwrap_or_else(|e| panic!("{}: {}", source.display(), e));
+    verifier::verify(&program).unwrap_or_else(|e| panic!("{}: {}", source.display(), e));
+
+    let mut env = MockEnv::with_input(&fs::read(source.with_extension("in")).unwrap_or_default());
+    let mut argv = vec![source.file_name().unwrap().to_string_lossy().replace(".asm", ".bin")];
+    let args = fs::read_to_string(source.with_extension("args")).unwrap_or_default();
+    argv.extend(args.split_whitespace().map(String::from));
+    let envp = GOLDEN_ENV.iter().map(|entry| entry.to_string()).collect();
+
+    let vm = Executor::new(program, &mut env).with_args(argv, envp);
+    let status = match host::run_executor(vm, 1_000_000) {
+        Ok(_) => match env.exited {
+            Some(code) => format!("exited {}", code),
+            None => String::from("halted"),
+        },
+        Err(Fault::Error(e)) => format!("error: {}", e),
+        Err(Fault::StepLimit) => String::from("step limit"),
+    };
+    format!("{}[{}]\n", env.output(), status)
+}
+
+#[test]
+fn golden_outputs() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut sources: Vec<PathBuf> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty());
+
+    let bless = std::env::var_os("BLESS").is_some();
+    let mut failures = Vec::new();
+    for source in &sources {
+        let actual = transcript(source);
+        let expected_path = source.with_extension("out");
+        if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        if actual != expected {
+            failures.push(format!("{}:\n--- expected\n{}--- actual\n{}", source.display(), expected, actual));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
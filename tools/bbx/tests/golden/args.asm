@@ -0,0 +1,92 @@
+; Print the arguments and a variable, then exit with the argument count
+.entry main
+
+; Print argv[index] through the buffer; index on the stack
+print_arg:
+    push buffer
+    push 32
+    syscall argv
+    push buffer
+    swap
+    syscall write
+    pop
+    ret
+
+main:
+    print_string "argc: "
+    syscall argc
+    print_int
+    syscall newline
+
+    push 0
+loop:
+    dup
+    syscall argc
+    lt
+    jz done
+    dup
+    print_char '['
+    call print_arg
+    print_char ']'
+    push 1
+    add
+    jmp loop
+done:
+    pop
+    syscall newline
+
+    print_string "HOME="
+    push home
+    push 4
+    push buffer
+    push 32
+    syscall getenv
+    dup
+    push 0
+    lt
+    jnz unset
+    push buffer
+    swap
+    syscall write
+    pop
+    syscall newline
+
+    push missing
+    push 7
+    push buffer
+    push 32
+    syscall getenv
+    print_int
+    syscall newline
+
+    push 1
+    push buffer
+    push 32
+    syscall environ
+    push buffer
+    swap
+    syscall write
+    pop
+    syscall newline
+
+    push 5
+    push buffer
+    push 32
+    syscall environ
+    print_int
+    syscall newline
+
+    syscall argc
+    exit
+unset:
+    halt
+
+.rodata
+home:
+    .ascii "HOME"
+missing:
+    .ascii "MISSING"
+.data
+buffer:
+    .byte 0
+.memory 32
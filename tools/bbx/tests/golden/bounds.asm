@@ -0,0 +1,13 @@
+; A store that runs past the end of data memory faults
+.memory 16
+    push 14
+    push 0x41
+    store16             ; fine: bytes 14 and 15
+    push 14
+    load8
+    print_int
+    syscall newline
+    push 15
+    push 0x41
+    store16             ; byte 16 is out of bounds
+    halt
@@ -0,0 +1,47 @@
+; Copy a greeting from data memory to a file with fwrite, read it back
+; with fread into another buffer, and print it with write
+.memory 64
+.data
+greeting: .ascii "hello, file\n"
+
+.rodata
+name: .ascii "greeting.txt"
+
+.code
+    push name
+    push 12
+    push 1              ; write
+    syscall open        ; fd
+    dup
+    push greeting
+    push 12
+    syscall fwrite
+    print_int
+    syscall newline
+    syscall close
+    pop
+
+    push name
+    push 12
+    push 0              ; read
+    syscall open        ; fd
+    dup
+    push 32
+    push 32
+    syscall fread       ; fd count
+    push 32
+    swap
+    syscall write
+    pop
+    push 32             ; a second read is at end of file
+    push 32
+    syscall fread
+    print_int
+    syscall newline
+
+    push 200            ; buffers outside memory are refused
+    push 4
+    syscall write
+    print_int
+    syscall newline
+    halt
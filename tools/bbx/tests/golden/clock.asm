@@ -0,0 +1,8 @@
+; Time between two clock readings (the test clock ticks once per read)
+    syscall clock
+    syscall clock
+    swap
+    sub
+    print_int
+    syscall newline
+    halt
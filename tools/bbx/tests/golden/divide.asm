@@ -0,0 +1,8 @@
+; Division by zero faults at run time
+    print_string "before"
+    syscall newline
+    push 1
+    push 0
+    div
+    print_int
+    halt
@@ -0,0 +1,33 @@
+; The first ten Fibonacci numbers, computed recursively
+    push 0              ; i
+loop:
+    dup
+    call fib
+    print_int
+    print_char ' '
+    push 1
+    add
+    dup
+    push 10
+    lt
+    jnz loop
+    pop
+    syscall newline
+    halt
+
+fib:                    ; n -> fib(n)
+    dup
+    push 2
+    lt
+    jnz base
+    dup
+    push 1
+    sub
+    call fib            ; n fib(n-1)
+    swap
+    push 2
+    sub
+    call fib            ; fib(n-1) fib(n-2)
+    add
+base:
+    ret
@@ -0,0 +1,52 @@
+; Write a file, read it back, and try a file that does not exist
+.rodata
+notes:   .ascii "notes.txt"
+missing: .ascii "missing.txt"
+
+.code
+    push notes
+    push 9
+    push 1              ; write
+    syscall open        ; fd
+    dup
+    push 'h'
+    syscall fputc
+    pop
+    dup
+    push 'i'
+    syscall fputc
+    pop
+    syscall close
+    pop
+
+    push notes
+    push 9
+    push 0              ; read
+    syscall open        ; fd
+    push 0              ; fd count
+read:
+    swap
+    dup
+    syscall fgetc       ; count fd byte
+    push -1
+    eq
+    jnz eof
+    swap
+    push 1
+    add
+    jmp read
+eof:
+    syscall close       ; count 0
+    pop
+    print_string "bytes: "
+    print_int
+    syscall newline
+
+    push missing
+    push 11
+    push 0
+    syscall open
+    print_string "missing: "
+    print_int
+    syscall newline
+    halt
@@ -0,0 +1,17 @@
+; Count the lines of standard input
+    push 0              ; lines
+loop:
+    syscall getc        ; lines c
+    dup
+    push -1
+    eq
+    jnz done
+    push '\n'
+    eq
+    add
+    jmp loop
+done:
+    pop
+    print_int
+    syscall newline
+    syscall exit
@@ -0,0 +1,77 @@
+; Read a line into data memory and print it back reversed, then show
+; loads of each width from one 64-bit value
+.memory 128
+.data
+title: .ascii "reversed: "
+
+.code
+    push 16             ; n lives at 16
+    push 64             ; the line is read to 64
+    push 64
+    syscall read
+    store64
+    push title
+    push 10
+    syscall write
+    pop
+loop:
+    push 16
+    load64
+    jz done
+    push 16
+    push 16
+    load64
+    push 1
+    sub
+    store64             ; n -= 1
+    push 16
+    load64
+    push 64
+    add
+    load8               ; c = line[n]
+    dup
+    push '\n'
+    eq
+    jnz skip
+    push 8              ; write c from a scratch byte at 8
+    swap
+    store8
+    push 8
+    push 1
+    syscall write
+    pop
+    jmp loop
+skip:
+    pop
+    jmp loop
+done:
+    syscall newline
+
+    push 32             ; 0x0102030405060708 at 32
+    push 0x01020304
+    push 32
+    shl
+    push 0x05060708
+    or
+    store64
+    push 32
+    load8
+    print_int
+    print_char ' '
+    push 32
+    load16
+    print_int
+    print_char ' '
+    push 32
+    load32
+    print_int
+    print_char ' '
+    push 32
+    load64
+    print_int
+    print_char ' '
+    push 33             ; unaligned
+    load16
+    print_int
+    syscall newline
+    halt
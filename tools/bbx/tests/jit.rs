@@ -0,0 +1,273 @@
+//! The JIT must be indistinguishable from the interpreter: every program
+//! here runs both ways and the output and outcome are compared.
+use bbx::bytecode::Program;
+use bbx::host::{Fault, MockEnv};
+use bbx::jit::JitStats;
+use bbx::vm::{Environment, Executor, Step};
+use bbx::{asm, verifier};
+use std::fs;
+use std::path::PathBuf;
+
+type Outcome = (String, Result<i32, Fault>);
+
+fn assemble(source: &str) -> Program {
+    let program = asm::assemble(source).unwrap();
+    verifier::verify(&program).unwrap();
+    program
+}
+
+fn run(program: &Program, input: &[u8], argv: &[String], jit: bool) -> (Outcome, Option<JitStats>) {
+    let mut env = MockEnv::with_input(input);
+    let mut vm = Executor::new(program.clone(), &mut env).with_args(argv.to_vec(), Vec::new());
+    if jit {
+        vm = vm.with_jit();
+    }
+    let result = match vm.step(1_000_000) {
+        Step::Halted => Ok(vm.exit_status().unwrap_or(0)),
+        Step::Fault(e) => Err(Fault::Error(e)),
+        Step::Yielded => Err(Fault::StepLimit),
+    };
+    let stats = vm.jit_stats();
+    drop(vm);
+    ((env.output().to_string(), result), stats)
+}
+
+/// Run both ways, check they agree and return the JIT's statistics
+fn compare(program: &Program) -> (Outcome, JitStats) {
+    let (interpreted, _) = run(program, b"", &[], false);
+    let (compiled, stats) = run(program, b"", &[], true);
+    assert_eq!(compiled, interpreted);
+    (compiled, stats.unwrap())
+}
+
+#[test]
+fn golden_programs_agree() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "asm") {
+            continue;
+        }
+        let program = assemble(&fs::read_to_string(&path).unwrap());
+        let input = fs::read(path.with_extension("in")).unwrap_or_default();
+        let args = fs::read_to_string(path.with_extension("args")).unwrap_or_default();
+        let argv: Vec<String> = args.split_whitespace().map(String::from).collect();
+
+        let (interpreted, _) = run(&program, &input, &argv, false);
+        let (compiled, _) = run(&program, &input, &argv, true);
+        assert_eq!(compiled, interpreted, "{}", path.display());
+    }
+}
+
+#[test]
+fn loops_run_natively() {
+    // Sum 1..=1000, keeping the counter and total in data memory
+    let program = assemble(concat!(
+        "    push 0\n",
+        "    push 1000\n",
+        "    store64\n",
+        "top:\n",
+        "    push 0\n",
+        "    load64\n",
+        "    dup\n",
+        "    jz done\n",
+        "    dup\n",
+        "    push 8\n",
+        "    load64\n",
+        "    add\n",
+        "    push 8\n",
+        "    swap\n",
+        "    store64\n",
+        "    push 1\n",
+        "    sub\n",
+        "    push 0\n",
+        "    swap\n",
+        "    store64\n",
+        "    jmp top\n",
+        "done:\n",
+        "    pop\n",
+        "    push 8\n",
+        "    load64\n",
+        "    print_int\n",
+        "    halt\n",
+        ".memory 16\n",
+    ));
+    let ((output, result), stats) = compare(&program);
+    assert_eq!((output.as_str(), result), ("500500", Ok(0)));
+    assert!(stats.blocks >= 2);
+    assert!(stats.native > 10 * stats.interpreted, "{:?}", stats);
+}
+
+#[test]
+fn arithmetic_edge_cases() {
+    let cases = [
+        "push 0x7fffffff\npush 0x7fffffff\nmul\ndup\nmul\nprint_int\n",
+        "push -2147483648\npush -1\nmul\nprint_int\n",
+        "push 1\npush 63\nshl\npush -1\ndiv\nprint_int\n",
+        "push 1\npush 63\nshl\npush -1\nmod\nprint_int\n",
+        "push -7\npush 2\ndiv\nprint_int\npush -7\npush 2\nmod\nprint_int\n",
+        "push -1\npush 65\nshl\nprint_int\npush -8\npush -1\nsar\nprint_int\npush -8\npush 60\nshr\nprint_int\n",
+        "push 3\npush -3\nlt\nprint_int\npush -3\npush 3\nle\nprint_int\npush 2\npush 2\nge\nprint_int\npush 2\npush 2\nne\nprint_int\n",
+        "push 5\nneg\nprint_int\npush 5\nnot\nprint_int\npush 12\npush 10\nxor\npush 3\nor\npush 6\nand\nprint_int\n",
+        "push 1\npush 0\ndiv\nprint_int\n",
+    ];
+    for source in cases {
+        let _ = compare(&assemble(&format!("{}halt\n", source)));
+    }
+}
+
+#[test]
+fn memory_access_and_faults() {
+    let store_load = concat!(
+        "    push 0\n    push -1\n    store64\n",
+        "    push 8\n    push 0x1234\n    store16\n",
+        "    push 3\n    load8\n    print_int\n",
+        "    push 0\n    load32\n    print_int\n",
+        "    push 8\n    load64\n    print_int\n",
+        "    push 9\n    push 0\n    store8\n    push 8\n    load16\n    print_int\n",
+        "    halt\n",
+        ".memory 16\n",
+    );
+    let ((output, _), _) = compare(&assemble(store_load));
+    assert_eq!(output, "2554294967295466052");
+
+    for address in ["13", "16", "-1", "0x7fffffff"] {
+        let source = format!("push {}\nload32\nprint_int\nhalt\n.memory 16\n", address);
+        let ((_, result), _) = compare(&assemble(&source));
+        assert_eq!(result, Err(Fault::Error("Memory access out of bounds")));
+    }
+    let ((output, result), _) = compare(&assemble("push 12\nload32\nprint_int\nhalt\n.memory 16\n"));
+    assert_eq!((output.as_str(), result), ("0", Ok(0)));
+}
+
+#[test]
+fn stack_overflow_falls_back_to_the_interpreter() {
+    // Five values per frame overflow the stack before the call depth
+    let program = assemble("f:\n    push 1\n    push 2\n    push 3\n    push 4\n    push 5\n    call f\n    ret\n");
+    let ((_, result), _) = compare(&program);
+    assert_eq!(result, Err(Fault::Error("Stack overflow")));
+}
+
+#[test]
+fn slices_match_a_single_run() {
+    let program = assemble("    push 200\ntop:\n    push 1\n    sub\n    dup\n    dup\n    print_int\n    jnz top\n    halt\n");
+    let mut env = MockEnv::default();
+    let mut vm = Executor::new(program.clone(), &mut env).with_jit();
+    let mut slices = 0;
+    while vm.step(7) == Step::Yielded {
+        slices += 1;
+    }
+    drop(vm);
+    assert!(slices > 100);
+    let ((expected, _), _) = compare(&program);
+    assert_eq!(env.output(), expected);
+}
+
+#[test]
+fn a_failed_check_counts_only_what_ran() {
+    // The load's bounds check fails after three instructions of the block
+    let program = assemble("    push 6\n    push 7\n    push 1000000\n    load8\n    print_int\n    halt\n");
+    let ((_, result), stats) = compare(&program);
+    assert!(result.is_err());
+    assert_eq!((stats.blocks, stats.native, stats.interpreted), (1, 3, 1));
+}
+
+/// An environment without executable memory
+struct NoCode(MockEnv);
+
+impl Environment for NoCode {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes)
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.0.read()
+    }
+
+    fn load(&mut self, path: &str) -> Option<Vec<u8>> {
+        self.0.load(path)
+    }
+
+    fn store(&mut self, path: &str, content: Vec<u8>) -> Result<(), isize> {
+        self.0.store(path, content)
+    }
+
+    fn uptime_ms(&mut self) -> u64 {
+        self.0.uptime_ms()
+    }
+
+    fn exit(&mut self, code: i32) {
+        self.0.exit(code)
+    }
+}
+
+#[test]
+fn without_executable_memory_everything_is_interpreted() {
+    let program = assemble("    push 6\n    push 7\n    mul\n    print_int\n    halt\n");
+    let mut env = NoCode(MockEnv::default());
+    let mut vm = Executor::new(program, &mut env).with_jit();
+    assert_eq!(vm.step(100), Step::Halted);
+    let stats = vm.jit_stats().unwrap();
+    assert_eq!((stats.blocks, stats.native, stats.interpreted), (0, 0, 5));
+    drop(vm);
+    assert_eq!(env.0.output(), "42");
+}
+
+/// Small deterministic generator for the random programs below
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, n: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % n
+    }
+}
+
+#[test]
+fn random_straight_line_programs_agree() {
+    const VALUES: [&str; 10] = ["0", "1", "-1", "2", "7", "-13", "63", "64", "0x7fffffff", "-2147483648"];
+    const BINARY: [&str; 18] = [
+        "add", "sub", "mul", "div", "mod", "and", "or", "xor", "shl", "shr", "sar",
+        "eq", "ne", "lt", "le", "gt", "ge", "swap",
+    ];
+    let mut rng = Lcg(1);
+    for _ in 0..500 {
+        let mut source = String::new();
+        let mut depth = 0;
+        for _ in 0..40 {
+            match rng.next(10) {
+                0..=3 => {
+                    source += &format!("push {}\n", VALUES[rng.next(VALUES.len() as u64) as usize]);
+                    depth += 1;
+                }
+                4..=6 if depth >= 2 => {
+                    source += &format!("{}\n", BINARY[rng.next(BINARY.len() as u64) as usize]);
+                    if !source.ends_with("swap\n") {
+                        depth -= 1;
+                    }
+                }
+                7 if depth >= 1 => source += ["neg\n", "not\n", "dup\n", "load8\n", "load64\n"][rng.next(5) as usize],
+                8 if depth >= 2 => {
+                    source += ["store8\n", "store32\n"][rng.next(2) as usize];
+                    depth -= 2;
+                }
+                9 if depth >= 1 => {
+                    source += "print_int\nprint_char ' '\n";
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            if source.ends_with("dup\n") {
+                depth += 1;
+            }
+        }
+        for _ in 0..depth {
+            source += "print_int\nprint_char ' '\n";
+        }
+        source += "halt\n.memory 64\n";
+        let program = asm::assemble(&source).unwrap();
+        if verifier::verify(&program).is_ok() {
+            let _ = compare(&program);
+        }
+    }
+}
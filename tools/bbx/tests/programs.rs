@@ -0,0 +1,171 @@
+use bbx::bytecode::{self, Program};
+use bbx::host::{self, Fault, MockEnv};
+use bbx::vm::{Executor, Step};
+use bbx::{asm, verifier};
+use std::fs;
+use std::path::PathBuf;
+
+fn programs_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../programs")
+}
+
+fn assemble(source: &str) -> Program {
+    let program = asm::assemble(source).unwrap();
+    verifier::verify(&program).unwrap();
+    program
+}
+
+fn output(program: &Program) -> Result<String, Fault> {
+    let mut env = MockEnv::default();
+    host::run(program.clone(), &mut env, 10_000)?;
+    Ok(env.output().to_string())
+}
+
+#[test]
+fn checked_in_binaries_match_their_sources() {
+    for name in ["hello", "count", "bench"] {
+        let source = fs::read_to_string(programs_dir().join(format!("{}.asm", name))).unwrap();
+        let binary = fs::read(programs_dir().join(format!("{}.bin", name))).unwrap();
+        assert_eq!(bytecode::build(&assemble(&source)), binary, "{}.bin is stale", name);
+    }
+}
+
+#[test]
+fn builtin_programs_run() {
+    let hello = bytecode::parse(&fs::read(programs_dir().join("hello.bin")).unwrap()).unwrap();
+    assert_eq!(output(&hello).unwrap(), "Hello, World!\n");
+
+    let count = bytecode::parse(&fs::read(programs_dir().join("count.bin")).unwrap()).unwrap();
+    assert_eq!(output(&count).unwrap(), "Counting: **********\n");
+}
+
+#[test]
+fn disassembly_reassembles() {
+    let program = assemble(".entry main\nsquare:\n dup\n mul\n ret\nmain:\n push -7\n call square\n print_int\n halt\n");
+    let listing = asm::disassemble(&program);
+    assert!(listing.contains(&String::from("0003> push -7")));
+    assert!(listing.contains(&String::from("0008  call 0x0000")));
+
+    // Strip offsets and rebuild from the listing
+    let source: Vec<String> = listing[1..].iter().map(|line| line[6..].to_string()).collect();
+    let rebuilt = asm::assemble(&format!(".entry 3\n{}", source.join("\n"))).unwrap();
+    assert_eq!(rebuilt.code, program.code);
+    assert_eq!(output(&rebuilt).unwrap(), "49");
+}
+
+#[test]
+fn recursion() {
+    let program = assemble(concat!(
+        "    push 10\n",
+        "    call fact\n",
+        "    print_int\n",
+        "    halt\n",
+        "fact:\n",
+        "    dup\n",
+        "    jz base\n",
+        "    dup\n",
+        "    push 1\n",
+        "    sub\n",
+        "    call fact\n",
+        "    mul\n",
+        "    ret\n",
+        "base:\n",
+        "    pop\n",
+        "    push 1\n",
+        "    ret\n",
+    ));
+    assert_eq!(output(&program).unwrap(), "3628800");
+}
+
+#[test]
+fn execution_resumes_across_slices() {
+    let count = bytecode::parse(&fs::read(programs_dir().join("count.bin")).unwrap()).unwrap();
+    let mut env = MockEnv::default();
+    let mut executor = Executor::new(count, &mut env);
+
+    let mut slices = 1;
+    while executor.step(3) == Step::Yielded {
+        slices += 1;
+    }
+    assert!(slices > 10);
+    drop(executor);
+    assert_eq!(env.output(), "Counting: **********\n");
+
+    let mut spin = Executor::new(assemble("top: jmp top\n"), MockEnv::default());
+    assert_eq!(spin.step(1000), Step::Yielded);
+    assert_eq!(spin.ip(), 0);
+}
+
+#[test]
+fn runtime_faults() {
+    let divide = assemble("push 1\npush 0\ndiv\nprint_int\n");
+    assert_eq!(output(&divide), Err(Fault::Error("Division by zero")));
+
+    let spin = assemble("top: jmp top\n");
+    assert_eq!(output(&spin), Err(Fault::StepLimit));
+}
+
+#[test]
+fn verifier_rejects_bad_programs() {
+    let underflow = asm::assemble("push 1\nadd\nhalt\n").unwrap();
+    assert_eq!(verifier::verify(&underflow).unwrap_err().reason, "Stack underflow");
+
+    let stray_ret = asm::assemble("ret\n").unwrap();
+    assert_eq!(verifier::verify(&stray_ret).unwrap_err().reason, "Return outside of a function");
+
+    let mid_instruction = asm::assemble("push 1\njmp 2\n").unwrap();
+    assert_eq!(
+        verifier::verify(&mid_instruction).unwrap_err().reason,
+        "Jump target is not an instruction boundary"
+    );
+}
+
+#[test]
+fn assembler_errors_name_the_line() {
+    let error = asm::assemble("push 1\n\nfrobnicate\n").unwrap_err();
+    assert_eq!(error.to_string(), "line 3: Unknown instruction");
+}
+
+#[test]
+fn arguments_and_exit_status() {
+    // Copy argv[1] into a 4-byte buffer and exit with its full length
+    let program = assemble(concat!(
+        "    push 1\n",
+        "    push 0\n",
+        "    push 4\n",
+        "    syscall argv\n",
+        "    push 0\n",
+        "    push 4\n",
+        "    syscall write\n",
+        "    pop\n",
+        "    exit\n",
+        ".memory 4\n",
+    ));
+    let mut env = MockEnv::default();
+    let argv = vec![String::from("prog"), String::from("truncated")];
+    let vm = Executor::new(program, &mut env).with_args(argv, Vec::new());
+    assert_eq!(host::run_executor(vm, 100), Ok(9));
+    assert_eq!(env.output(), "trun");
+    assert_eq!(env.exited, Some(9));
+
+    let no_status = asm::assemble("exit\n").unwrap();
+    assert_eq!(verifier::verify(&no_status).unwrap_err().reason, "Stack underflow");
+}
+
+#[test]
+fn sys_reaches_every_kernel_call() {
+    // getpid, getppid, sleep_ms, yield, uptime, spawn, waitpid, sbrk,
+    // mmap, unlink, lseek, ftruncate and sync, then exit through sys 0
+    let mut source = String::new();
+    for number in 5..=17 {
+        source += &format!("    push {}\n    push 1\n    push 2\n    push 3\n    syscall sys\n    print_int\n", number);
+    }
+    source += "    push 0\n    push 7\n    push 0\n    push 0\n    syscall sys\n    push 1\n    print_int\n";
+
+    let mut env = MockEnv::default();
+    assert_eq!(host::run(assemble(&source), &mut env, 1000), Ok(7));
+    let calls: Vec<(i64, [i64; 3])> = (5..=17).map(|number| (number, [1, 2, 3])).collect();
+    assert_eq!(env.syscalls, calls);
+    assert_eq!(env.output(), "567891011121314151617");
+    assert_eq!(env.exited, Some(7));
+}
@@ -0,0 +1,233 @@
+use bbx::image::{self, ImageFile};
+use bbx::sfs::{BlockIo, Kind, Sfs, BLOCK_SIZE, MAX_FILE_SIZE, MAX_NAME, ROOT};
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+/// An in-memory disk whose content outlives the filesystem using it, so
+/// a volume can be opened again
+#[derive(Clone)]
+struct MemDisk(Rc<RefCell<Vec<u8>>>);
+
+impl MemDisk {
+    fn new(blocks: usize) -> Self {
+        MemDisk(Rc::new(RefCell::new(vec![0; blocks * BLOCK_SIZE])))
+    }
+}
+
+impl BlockIo for MemDisk {
+    fn block_count(&self) -> u32 {
+        (self.0.borrow().len() / BLOCK_SIZE) as u32
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), &'static str> {
+        let start = block as usize * BLOCK_SIZE;
+        buf.copy_from_slice(self.0.borrow().get(start..start + BLOCK_SIZE).ok_or("Sector out of range")?);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block: u32, buf: &[u8; BLOCK_SIZE]) -> Result<(), &'static str> {
+        let start = block as usize * BLOCK_SIZE;
+        self.0.borrow_mut().get_mut(start..start + BLOCK_SIZE).ok_or("Sector out of range")?.copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+fn clock() -> u64 {
+    1_700_000_000
+}
+
+fn format(blocks: usize) -> (MemDisk, Sfs<MemDisk>) {
+    let disk = MemDisk::new(blocks);
+    let fs = Sfs::format(disk.clone(), clock).unwrap();
+    (disk, fs)
+}
+
+fn read(fs: &Sfs<MemDisk>, ino: u32) -> Vec<u8> {
+    let mut content = vec![0; fs.metadata(ino).unwrap().size];
+    assert_eq!(fs.read_at(ino, 0, &mut content).unwrap(), content.len());
+    content
+}
+
+fn free_blocks(fs: &Sfs<MemDisk>) -> u32 {
+    fs.usage().unwrap().1
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+#[test]
+fn files_and_directories_survive_reopening() {
+    let (disk, mut fs) = format(2048);
+    let docs = fs.create(ROOT, "docs", Kind::Directory).unwrap();
+    let note = fs.create(docs, "note.txt", Kind::File).unwrap();
+    fs.write_at(note, 0, b"hello disk\n").unwrap();
+    fs.symlink(ROOT, "latest", "docs/note.txt").unwrap();
+    fs.link(note, ROOT, "note").unwrap();
+    fs.set_mode(note, 0o600).unwrap();
+    drop(fs);
+
+    let fs = Sfs::open(disk, clock).unwrap();
+    let mut names: Vec<String> = fs.readdir(ROOT).unwrap().into_iter().map(|(name, _)| name).collect();
+    names.sort();
+    assert_eq!(names, ["docs", "latest", "note"]);
+    assert_eq!(fs.lookup(docs, "note.txt").unwrap(), note);
+    assert_eq!(fs.lookup(ROOT, "note").unwrap(), note);
+    assert_eq!(read(&fs, note), b"hello disk\n");
+    assert_eq!(fs.read_link(fs.lookup(ROOT, "latest").unwrap()).unwrap(), "docs/note.txt");
+
+    let metadata = fs.metadata(note).unwrap();
+    assert_eq!((metadata.kind, metadata.mode, metadata.nlink), (Kind::File, 0o600, 2));
+    assert_eq!(metadata.modified, clock());
+    let root = fs.metadata(ROOT).unwrap();
+    assert_eq!((root.kind, root.size, root.nlink), (Kind::Directory, 3, 3));
+}
+
+#[test]
+fn large_and_sparse_files_use_indirect_blocks() {
+    let (_, mut fs) = format(4096);
+    let file = fs.create(ROOT, "big", Kind::File).unwrap();
+    // Past the direct and single indirect blocks
+    let content = pattern(200 * BLOCK_SIZE + 100);
+    assert_eq!(fs.write_at(file, 0, &content).unwrap(), content.len());
+    assert_eq!(read(&fs, file), content);
+
+    let sparse = fs.create(ROOT, "sparse", Kind::File).unwrap();
+    let before = free_blocks(&fs);
+    fs.write_at(sparse, 1_000_000, b"end").unwrap();
+    // The last content block, the double indirect block and one table
+    assert_eq!(before - free_blocks(&fs), 3);
+    let mut hole = vec![1; 10];
+    fs.read_at(sparse, 500_000, &mut hole).unwrap();
+    assert_eq!(hole, [0; 10]);
+    assert_eq!(fs.metadata(sparse).unwrap().size, 1_000_003);
+
+    assert_eq!(fs.write_at(file, MAX_FILE_SIZE, b"x"), Err("File too large"));
+}
+
+#[test]
+fn truncate_and_unlink_free_blocks() {
+    let (_, mut fs) = format(4096);
+    let empty = free_blocks(&fs);
+    let file = fs.create(ROOT, "data", Kind::File).unwrap();
+    fs.write_at(file, 0, &pattern(300 * BLOCK_SIZE)).unwrap();
+
+    fs.truncate(file, 1000).unwrap();
+    assert_eq!(empty - free_blocks(&fs), 1 + 2);
+    assert_eq!(read(&fs, file), &pattern(1000)[..]);
+    // Growing again reads zeros past the cut, not the old bytes
+    fs.truncate(file, 1500).unwrap();
+    assert_eq!(read(&fs, file)[1000..], [0; 500]);
+
+    fs.link(file, ROOT, "other").unwrap();
+    fs.unlink(ROOT, "data").unwrap();
+    assert_eq!(read(&fs, file).len(), 1500);
+    fs.unlink(ROOT, "other").unwrap();
+    assert_eq!(fs.metadata(file).unwrap_err(), "File not found");
+    // Only the root directory's entry block stays
+    assert_eq!(empty - free_blocks(&fs), 1);
+    assert_eq!(fs.usage().unwrap().3, fs.usage().unwrap().2 - 2);
+}
+
+#[test]
+fn directory_rules_match_the_ramfs() {
+    let (_, mut fs) = format(1024);
+    let dir = fs.create(ROOT, "dir", Kind::Directory).unwrap();
+    fs.create(dir, "file", Kind::File).unwrap();
+    assert_eq!(fs.create(dir, "file", Kind::File), Err("File exists"));
+    assert_eq!(fs.unlink(ROOT, "dir"), Err("Directory not empty"));
+    assert_eq!(fs.link(dir, ROOT, "alias"), Err("Is a directory"));
+    assert_eq!(fs.read_at(dir, 0, &mut [0; 4]), Err("Is a directory"));
+    assert_eq!(fs.lookup(ROOT, "missing"), Err("File not found"));
+    assert_eq!(fs.create(ROOT, &"n".repeat(MAX_NAME + 1), Kind::File), Err("File name too long"));
+    fs.create(ROOT, &"n".repeat(MAX_NAME), Kind::File).unwrap();
+
+    // Freed entries are reused
+    for i in 0..20 {
+        fs.create(dir, &format!("f{}", i), Kind::File).unwrap();
+    }
+    fs.unlink(dir, "f3").unwrap();
+    let before = free_blocks(&fs);
+    fs.create(dir, "again", Kind::File).unwrap();
+    assert_eq!(free_blocks(&fs), before);
+    assert_eq!(fs.metadata(dir).unwrap().size, 21);
+
+    for entry in fs.readdir(dir).unwrap() {
+        fs.unlink(dir, &entry.0).unwrap();
+    }
+    fs.unlink(ROOT, "dir").unwrap();
+    assert_eq!(fs.metadata(ROOT).unwrap().nlink, 2);
+}
+
+#[test]
+fn a_full_disk_reports_it() {
+    let (_, mut fs) = format(128);
+    let file = fs.create(ROOT, "fill", Kind::File).unwrap();
+    let written = fs.write_at(file, 0, &pattern(128 * BLOCK_SIZE)).unwrap();
+    assert!(written > 0 && written < 128 * BLOCK_SIZE);
+    assert_eq!(fs.metadata(file).unwrap().size, written);
+    assert_eq!(fs.write_at(file, written, b"more"), Err("Filesystem full"));
+    // An empty file needs no blocks, but its content does
+    let other = fs.create(ROOT, "other", Kind::File).unwrap();
+    assert_eq!(fs.write_at(other, 0, b"x"), Err("Filesystem full"));
+    fs.truncate(file, 0).unwrap();
+    assert_eq!(fs.write_at(other, 0, b"x"), Ok(1));
+}
+
+#[test]
+fn images_need_a_valid_superblock() {
+    let disk = MemDisk::new(64);
+    assert_eq!(Sfs::open(disk, clock).err(), Some("Not an sfs filesystem"));
+    assert_eq!(Sfs::format(MemDisk::new(2), clock).err(), Some("Disk too small"));
+}
+
+/// Overwrite the bytes of inode `ino` at `offset` on the disk
+fn patch_inode(disk: &MemDisk, ino: u32, offset: usize, value: &[u8]) {
+    let mut bytes = disk.0.borrow_mut();
+    let inode_table = u32::from_le_bytes(bytes[24..28].try_into().unwrap()) as usize;
+    let start = (inode_table + ino as usize / 4) * BLOCK_SIZE + ino as usize % 4 * 128 + offset;
+    bytes[start..start + value.len()].copy_from_slice(value);
+}
+
+#[test]
+fn corrupt_inodes_are_errors() {
+    let (disk, mut fs) = format(4096);
+    let file = fs.create(ROOT, "sparse", Kind::File).unwrap();
+    fs.write_at(file, 8_400_000, b"end").unwrap();
+    let small = fs.create(ROOT, "small", Kind::File).unwrap();
+    fs.write_at(small, 0, b"data").unwrap();
+    drop(fs);
+
+    // A size past the double indirect block
+    patch_inode(&disk, file, 8, &(u64::MAX / 2).to_le_bytes());
+    // A block pointer into the superblock, and no links left
+    patch_inode(&disk, small, 32, &1u32.to_le_bytes());
+    patch_inode(&disk, small, 4, &0u32.to_le_bytes());
+
+    let mut fs = Sfs::open(disk, clock).unwrap();
+    assert_eq!(fs.read_at(file, 8_400_000, &mut [0; 3]), Err("Corrupt filesystem"));
+    assert_eq!(fs.read_at(small, 0, &mut [0; 4]), Err("Corrupt filesystem"));
+    assert_eq!(fs.write_at(small, 0, b"x"), Err("Corrupt filesystem"));
+    assert_eq!(fs.unlink(ROOT, "small"), Err("Corrupt filesystem"));
+    assert_eq!(fs.lookup(ROOT, "small"), Ok(small));
+}
+
+#[test]
+fn mkfs_installs_files_in_an_image() {
+    let path = std::env::temp_dir().join(format!("bbx-mkfs-{}.img", std::process::id()));
+    fs::write(&path, vec![0; 1024 * 1024]).unwrap();
+    let path = path.to_str().unwrap();
+
+    let program: &[u8] = b"not really a program";
+    drop(image::make_filesystem(ImageFile::open(path).unwrap(), &[("hello.bin", program), ("notes.txt", b"hi")]).unwrap());
+
+    let volume = Sfs::open(ImageFile::open(path).unwrap(), image::now).unwrap();
+    let hello = volume.lookup(ROOT, "hello.bin").unwrap();
+    let mut content = vec![0; program.len()];
+    volume.read_at(hello, 0, &mut content).unwrap();
+    assert_eq!(content, program);
+    assert_eq!(volume.metadata(hello).unwrap().mode, 0o755);
+    assert_eq!(volume.metadata(volume.lookup(ROOT, "notes.txt").unwrap()).unwrap().mode, 0o644);
+    fs::remove_file(path).unwrap();
+}